@@ -0,0 +1,244 @@
+//! gRPC level [`Probe`] for the active health checking of
+//! [`HealthCheckDiscover`](volo::discovery::health_check::HealthCheckDiscover).
+
+use std::{io, sync::Arc};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{
+    header::{CONTENT_TYPE, TE},
+    HeaderMap, HeaderValue, Method, Request,
+};
+use http_body_util::{BodyExt, Full};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use motore::make::MakeConnection;
+use volo::{
+    discovery::{health_check::Probe, Instance},
+    net::{dial::DefaultMakeTransport, Address},
+    FastStr,
+};
+
+/// The path of the `Check` method of the standard health checking service.
+const HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+
+/// The `SERVING` status of `grpc.health.v1.HealthCheckResponse`.
+const SERVING: u64 = 1;
+
+/// [`HealthProbe`] calls the standard gRPC health checking service `grpc.health.v1.Health` of
+/// the instance, and considers the instance healthy if the service is `SERVING`.
+///
+/// See the [gRPC Health Checking Protocol](https://github.com/grpc/grpc/blob/master/doc/health-checking.md).
+#[derive(Debug, Clone, Default)]
+pub struct HealthProbe {
+    service: FastStr,
+    make_transport: DefaultMakeTransport,
+}
+
+impl HealthProbe {
+    /// Creates a probe checking the overall health of the server.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the name of the service to check.
+    ///
+    /// Default is empty, which means the overall health of the server.
+    pub fn service(mut self, service: impl Into<FastStr>) -> Self {
+        self.service = service.into();
+        self
+    }
+
+    /// Encodes the `grpc.health.v1.HealthCheckRequest` with the gRPC message prefix.
+    fn encode_request(&self) -> Bytes {
+        let mut message = BytesMut::new();
+        if !self.service.is_empty() {
+            // field 1, wire type 2
+            message.put_u8(0x0a);
+            put_varint(&mut message, self.service.len() as u64);
+            message.put_slice(self.service.as_bytes());
+        }
+        let mut buf = BytesMut::with_capacity(5 + message.len());
+        buf.put_u8(0);
+        buf.put_u32(message.len() as u32);
+        buf.put_slice(&message);
+        buf.freeze()
+    }
+
+    async fn check(&self, address: &Address) -> io::Result<bool> {
+        let authority = match address {
+            Address::Ip(addr) => addr.to_string(),
+            #[cfg(target_family = "unix")]
+            Address::Unix(_) => "localhost".to_owned(),
+        };
+        let conn = self.make_transport.make_connection(address.clone()).await?;
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(conn))
+                .await
+                .map_err(io::Error::other)?;
+        tokio::spawn(conn);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{authority}{HEALTH_CHECK_PATH}"))
+            .header(CONTENT_TYPE, HeaderValue::from_static("application/grpc"))
+            .header(TE, HeaderValue::from_static("trailers"))
+            .body(Full::new(self.encode_request()))
+            .map_err(io::Error::other)?;
+        let resp = sender.send_request(req).await.map_err(io::Error::other)?;
+        if !resp.status().is_success() {
+            return Ok(false);
+        }
+        let ok = is_ok(resp.headers());
+        let body = resp.into_body().collect().await.map_err(io::Error::other)?;
+        let ok = ok || body.trailers().is_some_and(is_ok);
+        Ok(ok && decode_status(&body.to_bytes()) == Some(SERVING))
+    }
+}
+
+impl Probe for HealthProbe {
+    async fn probe(&self, instance: Arc<Instance>) -> bool {
+        match self.check(&instance.address).await {
+            Ok(healthy) => healthy,
+            Err(err) => {
+                tracing::debug!(
+                    "[VOLO] health check calls {} error: {}",
+                    instance.address,
+                    err
+                );
+                false
+            }
+        }
+    }
+}
+
+fn is_ok(headers: &HeaderMap) -> bool {
+    headers
+        .get("grpc-status")
+        .is_some_and(|status| status.as_bytes() == b"0")
+}
+
+fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first()?;
+        *buf = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Decodes the status of the `grpc.health.v1.HealthCheckResponse` with the gRPC message prefix,
+/// which is `UNKNOWN` (0) if it's absent.
+fn decode_status(body: &[u8]) -> Option<u64> {
+    if body.len() < 5 || body[0] != 0 {
+        return None;
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    let mut message = body.get(5..5 + len)?;
+    let mut status = 0;
+    while !message.is_empty() {
+        let tag = get_varint(&mut message)?;
+        match tag & 0x7 {
+            0 => {
+                let value = get_varint(&mut message)?;
+                if tag >> 3 == 1 {
+                    status = value;
+                }
+            }
+            1 => message = message.get(8..)?,
+            2 => {
+                let len = get_varint(&mut message)? as usize;
+                message = message.get(len..)?;
+            }
+            5 => message = message.get(4..)?,
+            _ => return None,
+        }
+    }
+    Some(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Arc};
+
+    use bytes::Bytes;
+    use http::{HeaderMap, HeaderValue, Request, Response};
+    use http_body::Frame;
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper::body::Incoming;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use tokio::net::TcpListener;
+    use volo::{
+        discovery::{health_check::Probe, Instance},
+        net::Address,
+    };
+
+    use super::{decode_status, HealthProbe};
+
+    type Body =
+        StreamBody<futures::stream::Iter<std::vec::IntoIter<Result<Frame<Bytes>, Infallible>>>>;
+
+    /// Answers `SERVING` for the service `foo`, and `NOT_SERVING` for the others.
+    async fn health(req: Request<Incoming>) -> Result<Response<Body>, Infallible> {
+        assert_eq!(req.uri().path(), "/grpc.health.v1.Health/Check");
+        let body = req.into_body().collect().await.unwrap().to_bytes();
+        let status = if body.ends_with(b"foo") { 1 } else { 2 };
+        let message = Bytes::from(vec![0, 0, 0, 0, 2, 0x08, status]);
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let frames = vec![Ok(Frame::data(message)), Ok(Frame::trailers(trailers))];
+        Ok(Response::builder()
+            .header("content-type", "application/grpc")
+            .body(StreamBody::new(futures::stream::iter(frames)))
+            .unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_health_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let instance = Arc::new(Instance {
+            address: Address::Ip(listener.local_addr().unwrap()),
+            weight: 1,
+            tags: Default::default(),
+        });
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), hyper::service::service_fn(health)),
+                );
+            }
+        });
+
+        assert!(
+            HealthProbe::new()
+                .service("foo")
+                .probe(instance.clone())
+                .await
+        );
+        assert!(!HealthProbe::new().service("bar").probe(instance).await);
+    }
+
+    #[test]
+    fn test_decode_status() {
+        assert_eq!(decode_status(&[0, 0, 0, 0, 2, 0x08, 1]), Some(1));
+        assert_eq!(decode_status(&[0, 0, 0, 0, 0]), Some(0));
+        // skips the unknown fields
+        assert_eq!(
+            decode_status(&[0, 0, 0, 0, 6, 0x12, 2, b'a', b'b', 0x08, 3]),
+            Some(3)
+        );
+        assert_eq!(decode_status(&[0, 0, 0, 0, 2, 0x08]), None);
+    }
+}
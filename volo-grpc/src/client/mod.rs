@@ -6,9 +6,15 @@
 //! For users need to specify some options at call time, they may use [`CallOpt`].
 
 mod callopt;
+pub mod health_check;
 mod meta;
 
-use std::{cell::RefCell, marker::PhantomData, sync::Arc, time::Duration};
+use std::{
+    cell::RefCell,
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 pub use callopt::CallOpt;
 pub use meta::MetaService;
@@ -19,16 +25,16 @@ use motore::{
 };
 use volo::{
     client::{MkClient, WithOptService},
-    context::{Endpoint, Role, RpcInfo},
+    context::{Context, Deadline, Endpoint, Role, RpcInfo},
     discovery::{Discover, DummyDiscover},
-    loadbalance::{random::WeightedRandomBalance, MkLbLayer},
+    loadbalance::{outlier::OutlierDetection, random::WeightedRandomBalance, MkLbLayer},
     net::Address,
     FastStr,
 };
 
 use crate::{
     codec::compression::CompressionEncoding,
-    context::{ClientContext, Config},
+    context::{ClientContext, Config, ForkFn},
     layer::loadbalance::LbConfig,
     transport::ClientTransport,
     Request, Response, Status,
@@ -124,6 +130,13 @@ impl<IL, OL, C, LB, T, U, DISC> ClientBuilder<IL, OL, C, LbConfig<LB, DISC>, T,
             tls_config: self.tls_config,
         }
     }
+
+    /// Enables the outlier detection of the client, which ejects the instances that keep
+    /// failing for a while.
+    pub fn outlier_detection(mut self, config: OutlierDetection) -> Self {
+        self.mk_lb = self.mk_lb.outlier_detection(config);
+        self
+    }
 }
 
 impl<IL, OL, C, LB, T, U> ClientBuilder<IL, OL, C, LB, T, U> {
@@ -238,6 +251,23 @@ impl<IL, OL, C, LB, T, U> ClientBuilder<IL, OL, C, LB, T, U> {
         self
     }
 
+    /// Sets the timeout for the whole rpc call, including the time spent in the inner layers.
+    ///
+    /// Default is no timeout.
+    pub fn rpc_timeout(mut self, timeout: Duration) -> Self {
+        self.rpc_config.rpc_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the function copying the tags and the extensions of the context to the forked one
+    /// of a hedged request, which are not copied by default since they are not cloneable.
+    ///
+    /// Without it, the calls whose callee has any tags are not hedged.
+    pub fn fork_with(mut self, f: ForkFn) -> Self {
+        self.rpc_config.fork_with = Some(f);
+        self
+    }
+
     /// Sets the timeout for connecting to a URL.
     ///
     /// Default is no timeout.
@@ -262,6 +292,14 @@ impl<IL, OL, C, LB, T, U> ClientBuilder<IL, OL, C, LB, T, U> {
         self
     }
 
+    /// Sets the options of the TCP sockets, such as `TCP_NODELAY` and the TCP keepalive.
+    ///
+    /// Default is [`SocketConfig::default`](volo::net::socket::SocketConfig::default).
+    pub fn socket_config(mut self, config: volo::net::socket::SocketConfig) -> Self {
+        self.rpc_config.socket_config = Some(config);
+        self
+    }
+
     /// Sets the caller name for the client.
     ///
     /// Default is the empty string.
@@ -627,7 +665,22 @@ macro_rules! impl_client {
 impl_client!((self, &mut cx, req) => async move {
     let has_metainfo = metainfo::METAINFO.try_with(|_| {}).is_ok();
 
-    let mk_call = async { self.transport.call(cx, req).await };
+    let timeout = cx.rpc_info().config().rpc_timeout;
+    if let Some(timeout) = timeout {
+        // for the inner layers, such as the retry, to know the time left for the call
+        cx.extensions_mut()
+            .insert(Deadline(Instant::now() + timeout));
+    }
+
+    let mk_call = async {
+        match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, self.transport.call(cx, req)).await {
+                Ok(result) => result,
+                Err(_) => Err(Status::deadline_exceeded("rpc timeout")),
+            },
+            None => self.transport.call(cx, req).await,
+        }
+    };
 
     if has_metainfo {
         mk_call.await
@@ -1,7 +1,7 @@
 use std::time::Duration;
 
 pub use volo::context::*;
-use volo::newtype_impl_context;
+use volo::{net::socket::SocketConfig, newtype_impl_context};
 
 use crate::codec::compression::CompressionEncoding;
 
@@ -11,7 +11,64 @@ pub struct ClientCxInner;
 /// during the rpc call lifecycle.
 pub struct ClientContext(pub(crate) RpcCx<ClientCxInner, Config>);
 
-newtype_impl_context!(ClientContext, Config, 0);
+impl Context for ClientContext {
+    type Config = Config;
+
+    #[inline]
+    fn rpc_info(&self) -> &RpcInfo<Self::Config> {
+        self.0.rpc_info()
+    }
+
+    #[inline]
+    fn rpc_info_mut(&mut self) -> &mut RpcInfo<Self::Config> {
+        self.0.rpc_info_mut()
+    }
+
+    #[inline]
+    fn extensions_mut(&mut self) -> &mut Extensions {
+        self.0.extensions_mut()
+    }
+
+    #[inline]
+    fn extensions(&self) -> &Extensions {
+        self.0.extensions()
+    }
+
+    /// Forks the context with the method, the service names and the addresses of the caller and
+    /// the callee, the config and the [`Deadline`].
+    ///
+    /// The tags and the other extensions are type-erased and cannot be cloned, so they are copied
+    /// by the [`ForkFn`] set by
+    /// [`ClientBuilder::fork_with`](crate::client::ClientBuilder::fork_with) if any. Without
+    /// it, the context is not forked if the callee has any tags.
+    fn fork(&self) -> Option<Self> {
+        let rpc_info = &self.rpc_info;
+        let fork_with = rpc_info.config().fork_with;
+        let callee = rpc_info.callee();
+        if fork_with.is_none() && (!callee.faststr_tags.is_empty() || !callee.tags.is_empty()) {
+            return None;
+        }
+        let fork_endpoint = |endpoint: &Endpoint| {
+            let mut forked = Endpoint::new(endpoint.service_name());
+            forked.address = endpoint.address();
+            forked
+        };
+        let mut cx = Self::new(RpcInfo::new(
+            rpc_info.role(),
+            rpc_info.method().clone(),
+            fork_endpoint(rpc_info.caller()),
+            fork_endpoint(callee),
+            rpc_info.config().clone(),
+        ));
+        if let Some(deadline) = self.extensions.get::<Deadline>() {
+            cx.extensions.insert(*deadline);
+        }
+        if let Some(fork_with) = fork_with {
+            fork_with(self, &mut cx);
+        }
+        Some(cx)
+    }
+}
 
 impl ClientContext {
     pub fn new(ri: RpcInfo<Config>) -> Self {
@@ -47,6 +104,15 @@ pub struct ServerContext(pub(crate) RpcCx<ServerCxInner, Config>);
 
 newtype_impl_context!(ServerContext, Config, 0);
 
+impl ServerContext {
+    /// Returns the details of the TLS session that the request is received from, or `None` if
+    /// the connection is not TLS.
+    #[cfg(feature = "__tls")]
+    pub fn tls_session(&self) -> Option<&volo::net::tls::TlsSession> {
+        self.0.extensions.get::<volo::net::tls::TlsSession>()
+    }
+}
+
 impl Default for ServerContext {
     fn default() -> Self {
         Self(RpcCx::new(RpcInfo::with_role(Role::Server), ServerCxInner))
@@ -69,22 +135,34 @@ impl std::ops::DerefMut for ServerContext {
 
 #[derive(Default, Debug, Clone)]
 pub struct Config {
+    /// Amount of time to wait for the whole rpc call.
+    pub(crate) rpc_timeout: Option<Duration>,
     /// Amount of time to wait connecting.
     pub(crate) connect_timeout: Option<Duration>,
     /// Amount of time to wait reading.
     pub(crate) read_timeout: Option<Duration>,
     /// Amount of time to wait reading response.
     pub(crate) write_timeout: Option<Duration>,
+    /// Options of the TCP sockets.
+    pub(crate) socket_config: Option<SocketConfig>,
 
     pub(crate) accept_compressions: Option<Vec<CompressionEncoding>>,
     pub(crate) send_compressions: Option<Vec<CompressionEncoding>>,
+
+    pub(crate) fork_with: Option<ForkFn>,
 }
 
+/// Copies the tags and the extensions of the context to the forked one of a hedged request.
+pub type ForkFn = fn(&ClientContext, &mut ClientContext);
+
 impl Reusable for Config {
     fn clear(&mut self) {
+        self.rpc_timeout = None;
         self.connect_timeout = None;
         self.read_timeout = None;
         self.write_timeout = None;
+        self.socket_config = None;
+        self.fork_with = None;
         if let Some(v) = self.accept_compressions.as_mut() {
             v.clear();
         }
@@ -96,6 +174,9 @@ impl Reusable for Config {
 
 impl Config {
     pub fn merge(&mut self, other: Self) {
+        if let Some(t) = other.rpc_timeout {
+            self.rpc_timeout = Some(t);
+        }
         if let Some(t) = other.connect_timeout {
             self.connect_timeout = Some(t);
         }
@@ -105,11 +186,62 @@ impl Config {
         if let Some(t) = other.write_timeout {
             self.write_timeout = Some(t);
         }
+        if let Some(c) = other.socket_config {
+            self.socket_config = Some(c);
+        }
         if let Some(e) = other.accept_compressions {
             self.accept_compressions = Some(e);
         }
         if let Some(e) = other.send_compressions {
             self.send_compressions = Some(e);
         }
+        if let Some(f) = other.fork_with {
+            self.fork_with = Some(f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{ClientContext, Context, Deadline};
+
+    #[test]
+    fn test_fork() {
+        struct Tag;
+
+        let mut cx = ClientContext::default();
+        let deadline = Deadline(Instant::now() + Duration::from_secs(1));
+        cx.extensions_mut().insert(deadline);
+        let forked = cx.fork().unwrap();
+        assert_eq!(
+            forked.extensions().get::<Deadline>().map(|d| d.0),
+            Some(deadline.0)
+        );
+
+        cx.rpc_info_mut()
+            .callee_mut()
+            .insert_faststr::<Tag>("tag".into());
+        // the tag cannot be copied without the fork function
+        assert!(cx.fork().is_none());
+
+        cx.rpc_info_mut().config_mut().fork_with = Some(|cx, forked| {
+            if let Some(tag) = cx.rpc_info().callee().get_faststr::<Tag>() {
+                forked
+                    .rpc_info_mut()
+                    .callee_mut()
+                    .insert_faststr::<Tag>(tag.clone());
+            }
+        });
+        let forked = cx.fork().unwrap();
+        assert_eq!(
+            forked
+                .rpc_info()
+                .callee()
+                .get_faststr::<Tag>()
+                .map(|t| t.as_str()),
+            Some("tag")
+        );
     }
 }
@@ -5,7 +5,11 @@ use tracing::warn;
 use volo::{
     context::Context,
     discovery::Discover,
-    loadbalance::{error::LoadBalanceError, LoadBalance, MkLbLayer},
+    loadbalance::{
+        error::{LoadBalanceError, Retryable},
+        outlier::{OutlierDetection, OutlierDetector, SkipEjected},
+        CallTracker, LoadBalance, MkLbLayer,
+    },
     Layer,
 };
 
@@ -15,6 +19,7 @@ use crate::Request;
 pub struct LoadBalanceLayer<D, LB> {
     discover: D,
     load_balance: LB,
+    outlier_detection: Option<OutlierDetection>,
 }
 
 impl<D, LB> LoadBalanceLayer<D, LB> {
@@ -22,8 +27,14 @@ impl<D, LB> LoadBalanceLayer<D, LB> {
         LoadBalanceLayer {
             discover,
             load_balance,
+            outlier_detection: None,
         }
     }
+
+    pub fn outlier_detection(mut self, config: Option<OutlierDetection>) -> Self {
+        self.outlier_detection = config;
+        self
+    }
 }
 
 impl<D, LB, S> Layer<S> for LoadBalanceLayer<D, LB>
@@ -34,14 +45,23 @@ where
     type Service = LoadBalanceService<D, LB, S>;
 
     fn layer(self, inner: S) -> Self::Service {
-        LoadBalanceService::new(self.discover, self.load_balance, inner)
+        LoadBalanceService::build(
+            self.discover,
+            self.load_balance,
+            inner,
+            self.outlier_detection,
+        )
     }
 }
 #[derive(Clone)]
-pub struct LoadBalanceService<D, LB, S> {
+pub struct LoadBalanceService<D, LB, S>
+where
+    D: Discover,
+{
     discover: D,
     load_balance: Arc<LB>,
     service: S,
+    outlier_detector: Option<Arc<OutlierDetector<D::Key>>>,
 }
 
 impl<D, LB, S> LoadBalanceService<D, LB, S>
@@ -50,19 +70,37 @@ where
     LB: LoadBalance<D>,
 {
     pub fn new(discover: D, load_balance: LB, service: S) -> Self {
+        Self::build(discover, load_balance, service, None)
+    }
+
+    fn build(
+        discover: D,
+        load_balance: LB,
+        service: S,
+        outlier_detection: Option<OutlierDetection>,
+    ) -> Self {
         let lb = Arc::new(load_balance);
+        let detector = outlier_detection.map(|config| Arc::new(OutlierDetector::new(config)));
 
         let service = Self {
             discover,
             load_balance: lb.clone(),
             service,
+            outlier_detector: detector.clone(),
         };
 
         if let Some(mut channel) = service.discover.watch(None) {
             tokio::spawn(async move {
                 loop {
                     match channel.recv().await {
-                        Ok(recv) => lb.rebalance(recv),
+                        Ok(recv) => {
+                            if let Some(detector) = &detector {
+                                detector.on_change(&recv);
+                            }
+                            lb.rebalance(recv)
+                        }
+                        // the discover has been dropped
+                        Err(async_broadcast::RecvError::Closed) => break,
                         Err(err) => warn!("[VOLO] discovering subscription error {:?}", err),
                     }
                 }
@@ -80,7 +118,7 @@ where
     LB: LoadBalance<D>,
     S: Service<Cx, Request<T>> + 'static + Send + Sync,
     LoadBalanceError: Into<S::Error>,
-    S::Error: Debug,
+    S::Error: Debug + Retryable,
     T: Send + 'static,
 {
     type Response = S::Response;
@@ -94,21 +132,44 @@ where
     ) -> Result<Self::Response, Self::Error> {
         let callee = cx.rpc_info().callee();
 
-        let mut picker = match &callee.address {
+        let picker = match &callee.address {
             None => self
                 .load_balance
-                .get_picker(callee, &self.discover)
+                .get_picker_with_caller(cx.rpc_info().caller(), callee, &self.discover)
                 .await
                 .map_err(|err| err.into())?,
             _ => {
                 return self.service.call(cx, req).await.map_err(Into::into);
             }
         };
+        if let Some(detector) = &self.outlier_detector {
+            detector.sync_instances(&self.discover, callee).await;
+        }
+        let detector = self
+            .outlier_detector
+            .as_deref()
+            .map(|detector| (detector, self.discover.key(callee)));
+        let mut picker = SkipEjected::new(picker, detector.clone());
 
         if let Some(addr) = picker.next() {
             cx.rpc_info_mut().callee_mut().address = Some(addr.clone());
 
-            return match self.service.call(cx, req).await {
+            let tracker = CallTracker::new(self.load_balance.as_ref(), &addr);
+            let result = self.service.call(cx, req).await;
+            tracker.finish(result.is_ok());
+
+            if let Some((detector, key)) = &detector {
+                match &result {
+                    // the circuit breaker has counted the failures of the instance already
+                    Err(err) if err.is_circuit_open() => {}
+                    result => {
+                        let success = !matches!(result, Err(err) if err.retryable());
+                        detector.report(key, &addr, success);
+                    }
+                }
+            }
+
+            return match result {
                 Ok(resp) => Ok(resp),
                 Err(err) => {
                     warn!("[VOLO] call endpoint: {:?} error: {:?}", addr, err);
@@ -124,7 +185,7 @@ where
 
 impl<D, LB, S> Debug for LoadBalanceService<D, LB, S>
 where
-    D: Debug,
+    D: Discover + Debug,
     LB: Debug,
     S: Debug,
 {
@@ -139,6 +200,7 @@ where
 pub struct LbConfig<L, DISC> {
     load_balance: L,
     discover: DISC,
+    outlier_detection: Option<OutlierDetection>,
 }
 
 impl<L, DISC> LbConfig<L, DISC> {
@@ -146,6 +208,7 @@ impl<L, DISC> LbConfig<L, DISC> {
         LbConfig {
             load_balance,
             discover,
+            outlier_detection: None,
         }
     }
 
@@ -153,6 +216,7 @@ impl<L, DISC> LbConfig<L, DISC> {
         LbConfig {
             load_balance,
             discover: self.discover,
+            outlier_detection: self.outlier_detection,
         }
     }
 
@@ -160,8 +224,17 @@ impl<L, DISC> LbConfig<L, DISC> {
         LbConfig {
             load_balance: self.load_balance,
             discover,
+            outlier_detection: self.outlier_detection,
         }
     }
+
+    /// Enables the outlier detection, which ejects the instances that keep failing for a while.
+    ///
+    /// Only the errors that are [`Retryable`] are considered as the failures of the instances.
+    pub fn outlier_detection(mut self, config: OutlierDetection) -> Self {
+        self.outlier_detection = Some(config);
+        self
+    }
 }
 
 impl<LB, DISC> MkLbLayer for LbConfig<LB, DISC> {
@@ -169,5 +242,6 @@ impl<LB, DISC> MkLbLayer for LbConfig<LB, DISC> {
 
     fn make(self) -> Self::Layer {
         LoadBalanceLayer::new(self.discover, self.load_balance)
+            .outlier_detection(self.outlier_detection)
     }
 }
@@ -3,6 +3,7 @@
 //! This module contains the low level component to build a gRPC server.
 
 mod meta;
+pub mod rate_limit;
 mod router;
 mod service;
 
@@ -16,10 +17,13 @@ use motore::{
     BoxError,
 };
 pub use service::ServiceBuilder;
+#[cfg(target_family = "unix")]
+use volo::hotrestart::{HotRestartConfig, DEFAULT_HOT_RESTART};
 #[cfg(feature = "__tls")]
-use volo::net::tls::{Acceptor, ServerTlsConfig};
+use volo::net::tls::ServerTlsConfig;
 use volo::{
-    net::{conn::Conn, incoming::Incoming},
+    net::{conn::Conn, incoming::Incoming, socket::SocketConfig},
+    shutdown::{ConnTracker, ShutdownConfig},
     spawn,
 };
 
@@ -43,6 +47,10 @@ pub struct Server<L> {
     layer: L,
     http2_config: Http2Config,
     router: Router,
+    shutdown_config: ShutdownConfig,
+    socket_config: SocketConfig,
+    #[cfg(target_family = "unix")]
+    hot_restart: Option<HotRestartConfig>,
 
     #[cfg(feature = "__tls")]
     tls_config: Option<ServerTlsConfig>,
@@ -61,6 +69,10 @@ impl Server<Identity> {
             layer: Identity::new(),
             http2_config: Http2Config::default(),
             router: Router::new(),
+            shutdown_config: ShutdownConfig::default(),
+            socket_config: SocketConfig::default(),
+            #[cfg(target_family = "unix")]
+            hot_restart: None,
 
             #[cfg(feature = "__tls")]
             tls_config: None,
@@ -79,6 +91,38 @@ impl<L> Server<L> {
         self
     }
 
+    /// Sets the config of the graceful shutdown.
+    ///
+    /// During the shutdown, each connection is sent a `GOAWAY` frame once the listener is closed,
+    /// and is closed after the in-flight streams are done.
+    ///
+    /// Default is [`ShutdownConfig::default`].
+    pub fn shutdown_config(mut self, config: ShutdownConfig) -> Self {
+        self.shutdown_config = config;
+        self
+    }
+
+    /// Sets the options of the TCP sockets of the accepted connections, such as `TCP_NODELAY`
+    /// and the TCP keepalive.
+    ///
+    /// Default is [`SocketConfig::default`].
+    pub fn socket_config(mut self, config: SocketConfig) -> Self {
+        self.socket_config = config;
+        self
+    }
+
+    /// Enables the hot restart with the config.
+    ///
+    /// The server takes over the listener of the old process if any, and tells the old process
+    /// to stop once it is ready, which then shuts down gracefully with the shutdown config.
+    ///
+    /// Default is disabled.
+    #[cfg(target_family = "unix")]
+    pub fn hot_restart(mut self, config: HotRestartConfig) -> Self {
+        self.hot_restart = Some(config);
+        self
+    }
+
     /// Sets the `SETTINGS_INITIAL_WINDOW_SIZE` option for HTTP2
     /// stream-level flow control.
     ///
@@ -200,6 +244,10 @@ impl<L> Server<L> {
             layer: Stack::new(layer, self.layer),
             http2_config: self.http2_config,
             router: self.router,
+            shutdown_config: self.shutdown_config,
+            socket_config: self.socket_config,
+            #[cfg(target_family = "unix")]
+            hot_restart: self.hot_restart,
             #[cfg(feature = "__tls")]
             tls_config: self.tls_config,
         }
@@ -221,6 +269,10 @@ impl<L> Server<L> {
             layer: Stack::new(self.layer, layer),
             http2_config: self.http2_config,
             router: self.router,
+            shutdown_config: self.shutdown_config,
+            socket_config: self.socket_config,
+            #[cfg(target_family = "unix")]
+            hot_restart: self.hot_restart,
             #[cfg(feature = "__tls")]
             tls_config: self.tls_config,
         }
@@ -240,6 +292,10 @@ impl<L> Server<L> {
             layer: self.layer,
             http2_config: self.http2_config,
             router: self.router.add_service(s),
+            shutdown_config: self.shutdown_config,
+            socket_config: self.socket_config,
+            #[cfg(target_family = "unix")]
+            hot_restart: self.hot_restart,
             #[cfg(feature = "__tls")]
             tls_config: self.tls_config,
         }
@@ -264,6 +320,24 @@ impl<L> Server<L> {
             + 'static,
         <L::Service as Service<ServerContext, Request<BodyIncoming>>>::Error: Into<Status> + Send,
     {
+        #[cfg(target_family = "unix")]
+        if let Some(config) = &self.hot_restart {
+            DEFAULT_HOT_RESTART.initialize_servers(config).await?;
+        }
+        // marks the server as ready once it starts accepting, and stops it on the shutdown signal
+        // or the termination by the new process
+        #[cfg(target_family = "unix")]
+        let signal = {
+            let hot_restart = self.hot_restart.is_some();
+            async move {
+                if hot_restart {
+                    DEFAULT_HOT_RESTART.wait_for_shutdown(signal).await
+                } else {
+                    signal.await
+                }
+            }
+        };
+
         let mut incoming = incoming.make_incoming().await?;
         tracing::info!("[VOLO] server start at: {:?}", incoming);
 
@@ -271,8 +345,16 @@ impl<L> Server<L> {
             .layer(self.layer)
             .service(self.router);
 
+        // keeps accepting during the grace period after the signal
+        let shutdown_config = self.shutdown_config;
+        let signal = async move {
+            let res = signal.await;
+            shutdown_config.wait_grace_period().await;
+            res
+        };
         tokio::pin!(signal);
         let (tx, rx) = tokio::sync::watch::channel(());
+        let conn_tracker = ConnTracker::new();
 
         loop {
             tokio::select! {
@@ -280,8 +362,8 @@ impl<L> Server<L> {
                     drop(rx);
                     tracing::info!("[VOLO] graceful shutdown");
                     let _ = tx.send(());
-                    // Waits for receivers to drop.
-                    tx.closed().await;
+                    // Waits for connections to be drained.
+                    self.shutdown_config.drain(&conn_tracker).await;
                     return Ok(());
                 },
                 conn = incoming.accept() => {
@@ -289,35 +371,10 @@ impl<L> Server<L> {
                         Some(c) => c,
                         None => return Ok(()),
                     };
-                    #[cfg(feature = "__tls")]
-                    let conn = {
-                        let Conn {
-                            stream,
-                            info,
-                        } = conn;
-                        // Only perform TLS handshake if either rustls or native-tls is configured
-                        match (stream, self.tls_config.as_ref().map(|o| &o.acceptor)) {
-                            (volo::net::conn::ConnStream::Tcp(tcp), Some(tls_acceptor)) => {
-                                let stream = match tls_acceptor.accept(tcp).await {
-                                    Ok(stream) => stream,
-                                    Err(err) => {
-                                        tracing::debug!("[VOLO] TLS handshake error: {:?}", err);
-                                        continue;
-                                    },
-                                };
-                                Conn {
-                                    stream,
-                                    info,
-                                }
-                            },
-                            (stream, _) => Conn {
-                                stream,
-                                info
-                            },
-                        }
-                    };
-
                     tracing::trace!("[VOLO] recv a connection from: {:?}", conn.info.peer_addr);
+                    if let Err(err) = self.socket_config.apply_to_conn(&conn) {
+                        tracing::debug!("[VOLO] failed to set the socket options: {:?}", err);
+                    }
                     let peer_addr = conn.info.peer_addr.clone();
 
                     let service = MetaService::new(service.clone(), peer_addr);
@@ -336,11 +393,35 @@ impl<L> Server<L> {
                         .max_header_list_size(self.http2_config.max_header_list_size);
 
                     let mut watch = rx.clone();
-                    spawn(async move {
+                    #[cfg(feature = "__tls")]
+                    let tls_config = self.tls_config.clone();
+                    spawn(conn_tracker.serve(async move {
+                        // Performs the TLS handshake in the task of the connection, so that a
+                        // slow client will not block accepting the other connections.
+                        #[cfg(feature = "__tls")]
+                        let conn = match &tls_config {
+                            Some(tls_config) => match tls_config.accept(conn).await {
+                                Ok(conn) => conn,
+                                Err(err) => {
+                                    tracing::debug!("[VOLO] TLS handshake error: {:?}", err);
+                                    return;
+                                },
+                            },
+                            None => conn,
+                        };
+                        #[cfg(feature = "__tls")]
+                        let tls_session = conn.stream.tls_session();
                         let mut http_conn = server.serve_connection(
                             TokioIo::new(conn),
                             hyper::service::service_fn(move |req| {
                                 let mut cx = ServerContext::default();
+                                #[cfg(feature = "__tls")]
+                                if let Some(session) = &tls_session {
+                                    if let Some(identity) = &session.peer_identity {
+                                        cx.rpc_info.caller_mut().insert(identity.clone());
+                                    }
+                                    cx.extensions.insert(session.clone());
+                                }
                                 let service = service.clone();
                                 async move {
                                     service.call(&mut cx, req).await
@@ -364,7 +445,7 @@ impl<L> Server<L> {
                                 }
                             },
                         }
-                    });
+                    }));
                 },
             }
         }
@@ -0,0 +1,20 @@
+//! Handlers for [`volo::rate_limit::Layer`].
+
+use volo::{context::Context, rate_limit::RateLimitError};
+
+use crate::{context::ServerContext, Status};
+
+/// This handler rejects the request with the status `RESOURCE_EXHAUSTED`.
+#[inline(never)]
+#[allow(clippy::result_large_err)]
+pub fn resource_exhausted<Resp>(
+    cx: &mut ServerContext,
+    err: RateLimitError,
+) -> Result<Resp, Status> {
+    tracing::debug!(
+        "[VOLO] request rejected: {}, rpcinfo: {:?}",
+        err,
+        cx.rpc_info()
+    );
+    Err(Status::resource_exhausted(err.to_string()))
+}
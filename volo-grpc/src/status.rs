@@ -8,7 +8,11 @@ use http::header::{HeaderMap, HeaderValue};
 use percent_encoding::{percent_decode, percent_encode, AsciiSet, CONTROLS};
 use tower::BoxError;
 use tracing::{debug, trace, warn};
-use volo::loadbalance::error::{LoadBalanceError, Retryable};
+use volo::{
+    circuit_breaker::CircuitBreakerError,
+    concurrency_limit::ConcurrencyLimitError,
+    loadbalance::error::{LoadBalanceError, Retryable},
+};
 
 use crate::{body::Body, metadata::MetadataMap, BASE64_ENGINE};
 
@@ -770,6 +774,22 @@ impl From<LoadBalanceError> for Status {
     }
 }
 
+impl From<ConcurrencyLimitError> for Status {
+    fn from(err: ConcurrencyLimitError) -> Self {
+        let mut status = Self::resource_exhausted(err.to_string());
+        status.source = Some(Arc::new(err));
+        status
+    }
+}
+
+impl From<CircuitBreakerError> for Status {
+    fn from(err: CircuitBreakerError) -> Self {
+        let mut status = Self::unavailable(err.to_string());
+        status.source = Some(Arc::new(err));
+        status
+    }
+}
+
 impl From<anyhow::Error> for Status {
     fn from(err: anyhow::Error) -> Self {
         Self::from_error(err.into())
@@ -783,6 +803,12 @@ impl Retryable for Status {
             Code::Internal | Code::Unavailable | Code::Cancelled | Code::ResourceExhausted
         )
     }
+
+    fn is_circuit_open(&self) -> bool {
+        self.source
+            .as_ref()
+            .is_some_and(|source| source.is::<CircuitBreakerError>())
+    }
 }
 
 impl fmt::Display for Status {
@@ -51,7 +51,8 @@ impl<U> ClientTransport<U> {
             rpc_config.connect_timeout,
             rpc_config.read_timeout,
             rpc_config.write_timeout,
-        );
+        )
+        .with_socket_config(rpc_config.socket_config.unwrap_or_default());
         let http_client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
             .timer(TokioTimer::new())
             .http2_only(true)
@@ -83,7 +84,8 @@ impl<U> ClientTransport<U> {
             rpc_config.connect_timeout,
             rpc_config.read_timeout,
             rpc_config.write_timeout,
-        );
+        )
+        .with_socket_config(rpc_config.socket_config.unwrap_or_default());
         let http_client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
             .timer(TokioTimer::new())
             .http2_only(true)
@@ -36,6 +36,7 @@ impl Connector {
             mt.set_connect_timeout(cfg.connect_timeout);
             mt.set_read_timeout(cfg.read_timeout);
             mt.set_write_timeout(cfg.write_timeout);
+            mt.set_socket_config(cfg.socket_config);
         }
         Self::Default(mt)
     }
@@ -66,6 +66,24 @@ impl DnsResolver {
         let mut iter = self.resolver.lookup_ip(host).await.ok()?.into_iter();
         Some(Address::Ip(SocketAddr::new(iter.next()?, port)))
     }
+
+    /// Resolve a host to all its IP addresses in the order of the resolver, such as both the IPv4
+    /// and IPv6 ones of a dual-stack host, and then set the port to them for getting the
+    /// [`Address`]es.
+    ///
+    /// The client connects to them with the Happy Eyeballs algorithm, see
+    /// [`LbConfig::fallback_addresses`][fallback_addresses] for more details.
+    ///
+    /// [fallback_addresses]: crate::client::loadbalance::LbConfig::fallback_addresses
+    pub async fn resolve_all(&self, host: &str, port: u16) -> Vec<Address> {
+        match self.resolver.lookup_ip(host).await {
+            Ok(lookup) => lookup
+                .into_iter()
+                .map(|ip| Address::Ip(SocketAddr::new(ip, port)))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
 }
 
 impl Default for DnsResolver {
@@ -110,14 +128,21 @@ impl Discover for DnsResolver {
             }
         };
 
-        if let Some(address) = self.resolve(endpoint.service_name_ref(), port).await {
-            let instance = Instance {
-                address,
-                weight: 10,
-                tags: Default::default(),
-            };
-            return Ok(vec![Arc::new(instance)]);
-        };
+        let instances: Vec<_> = self
+            .resolve_all(endpoint.service_name_ref(), port)
+            .await
+            .into_iter()
+            .map(|address| {
+                Arc::new(Instance {
+                    address,
+                    weight: 10,
+                    tags: Default::default(),
+                })
+            })
+            .collect();
+        if !instances.is_empty() {
+            return Ok(instances);
+        }
         tracing::error!("[Volo-HTTP] DnsResolver: no address resolved");
         Err(LoadBalanceError::Discover(Box::new(bad_host_name())))
     }
@@ -0,0 +1,125 @@
+//! HTTP level [`Probe`] for the active health checking of
+//! [`HealthCheckDiscover`](volo::discovery::health_check::HealthCheckDiscover).
+
+use std::{io, sync::Arc};
+
+use bytes::Bytes;
+use faststr::FastStr;
+use http::{header, Method, Request};
+use http_body_util::Empty;
+use hyper::client::conn::http1;
+use hyper_util::rt::TokioIo;
+use motore::make::MakeConnection;
+use volo::{
+    discovery::{health_check::Probe, Instance},
+    net::{dial::DefaultMakeTransport, Address},
+};
+
+/// [`HttpProbe`] sends a `GET` request to a path of the instance, and considers the instance
+/// healthy if the response status is `2xx`.
+#[derive(Debug, Clone)]
+pub struct HttpProbe {
+    path: FastStr,
+    host: Option<FastStr>,
+    make_transport: DefaultMakeTransport,
+}
+
+impl HttpProbe {
+    /// Create a probe requesting the path, such as `/health`.
+    pub fn new(path: impl Into<FastStr>) -> Self {
+        Self {
+            path: path.into(),
+            host: None,
+            make_transport: DefaultMakeTransport::default(),
+        }
+    }
+
+    /// Set the `Host` header of the requests.
+    ///
+    /// Default is the address of the instance.
+    pub fn host(mut self, host: impl Into<FastStr>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    async fn get(&self, address: &Address) -> io::Result<bool> {
+        let host = match (&self.host, address) {
+            (Some(host), _) => host.to_string(),
+            (None, Address::Ip(addr)) => addr.to_string(),
+            #[cfg(target_family = "unix")]
+            (None, Address::Unix(_)) => "localhost".to_owned(),
+        };
+        let conn = self.make_transport.make_connection(address.clone()).await?;
+        let (mut sender, conn) = http1::handshake(TokioIo::new(conn))
+            .await
+            .map_err(io::Error::other)?;
+        tokio::spawn(conn);
+
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(self.path.as_str())
+            .header(header::HOST, host)
+            .body(Empty::<Bytes>::new())
+            .map_err(io::Error::other)?;
+        let resp = sender.send_request(req).await.map_err(io::Error::other)?;
+        Ok(resp.status().is_success())
+    }
+}
+
+impl Probe for HttpProbe {
+    async fn probe(&self, instance: Arc<Instance>) -> bool {
+        match self.get(&instance.address).await {
+            Ok(healthy) => healthy,
+            Err(err) => {
+                tracing::debug!(
+                    "[Volo-HTTP] health check requests {} error: {err}",
+                    instance.address,
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use volo::{
+        discovery::{health_check::Probe, Instance},
+        net::Address,
+    };
+
+    use super::HttpProbe;
+
+    #[tokio::test]
+    async fn test_http_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let instance = Arc::new(Instance {
+            address: Address::Ip(listener.local_addr().unwrap()),
+            weight: 1,
+            tags: Default::default(),
+        });
+        // answers `200 OK` for `/health` and `404 Not Found` for the others
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                let resp: &[u8] = if buf[..n].starts_with(b"GET /health ") {
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n"
+                };
+                stream.write_all(resp).await.unwrap();
+            }
+        });
+
+        assert!(HttpProbe::new("/health").probe(instance.clone()).await);
+        assert!(!HttpProbe::new("/foo").probe(instance).await);
+    }
+}
@@ -5,17 +5,25 @@
 //! the retry related codes here.
 //!
 //! In addition, HTTP service can use DNS as service discover, so the default load balance uses a
-//! DNS resolver for pick a target address (the DNS resolver picks only one because it does not
-//! need load balance).
+//! DNS resolver for pick a target address, and the other addresses of the host are passed to the
+//! transport by [`FallbackAddresses`] for connecting with the Happy Eyeballs algorithm.
 
 use std::{fmt::Debug, sync::Arc};
 
+use ahash::AHashMap;
 use faststr::FastStr;
 use motore::{layer::Layer, service::Service};
+use parking_lot::RwLock;
 use volo::{
-    context::Context,
+    context::{Context, Endpoint},
     discovery::Discover,
-    loadbalance::{random::WeightedRandomBalance, LoadBalance, MkLbLayer},
+    loadbalance::{
+        error::Retryable,
+        outlier::{OutlierDetection, OutlierDetector, SkipEjected},
+        random::WeightedRandomBalance,
+        CallTracker, LoadBalance, MkLbLayer,
+    },
+    net::Address,
 };
 
 use super::dns::DnsResolver;
@@ -34,15 +42,35 @@ pub type DefaultLB = LbConfig<WeightedRandomBalance<<DnsResolver as Discover>::K
 /// Default load balance service generated by [`DefaultLB`]
 pub type DefaultLBService<S> = LoadBalanceService<WeightedRandomBalance<FastStr>, DnsResolver, S>;
 
+/// The max number of [`FallbackAddresses`] of [`DefaultLB`].
+const DEFAULT_FALLBACK_ADDRESSES: usize = 8;
+
+/// The other addresses picked by the load balance after the address of the callee, which the
+/// transport races with the Happy Eyeballs algorithm when connecting to the callee.
+///
+/// The picked addresses are kept in the order of the [`Discover`], such as the order of the
+/// addresses resolved by [`DnsResolver`], which the Happy Eyeballs algorithm relies on.
+///
+/// It is inserted into the callee by [`LoadBalanceService`] if
+/// [`LbConfig::fallback_addresses`] is enabled, and the address of the callee is replaced by the
+/// connected one after connecting.
+///
+/// The struct is used for advanced users.
+#[derive(Clone, Debug)]
+pub struct FallbackAddresses(pub Vec<Address>);
+
 /// Load balance layer generator with a [`LoadBalance`] and a [`Discover`]
 pub struct LbConfig<L, D> {
     load_balance: L,
     discover: D,
+    outlier_detection: Option<OutlierDetection>,
+    fallback_addresses: usize,
 }
 
 impl Default for DefaultLB {
     fn default() -> Self {
         LbConfig::new(WeightedRandomBalance::new(), DnsResolver::default())
+            .fallback_addresses(DEFAULT_FALLBACK_ADDRESSES)
     }
 }
 
@@ -52,6 +80,8 @@ impl<L, D> LbConfig<L, D> {
         LbConfig {
             load_balance,
             discover,
+            outlier_detection: None,
+            fallback_addresses: 0,
         }
     }
 
@@ -60,6 +90,8 @@ impl<L, D> LbConfig<L, D> {
         LbConfig {
             load_balance,
             discover: self.discover,
+            outlier_detection: self.outlier_detection,
+            fallback_addresses: self.fallback_addresses,
         }
     }
 
@@ -68,15 +100,45 @@ impl<L, D> LbConfig<L, D> {
         LbConfig {
             load_balance: self.load_balance,
             discover,
+            outlier_detection: self.outlier_detection,
+            fallback_addresses: self.fallback_addresses,
         }
     }
+
+    /// Enable the outlier detection, which ejects the instances that keep failing for a while
+    ///
+    /// Only the errors that are [`Retryable`] are considered as the failures of the instances.
+    pub fn outlier_detection(mut self, config: OutlierDetection) -> Self {
+        self.outlier_detection = Some(config);
+        self
+    }
+
+    /// Set the max number of the other addresses picked after the first one, which are passed to
+    /// the transport by [`FallbackAddresses`].
+    ///
+    /// The transport connects to all of them with the Happy Eyeballs algorithm, so that the
+    /// request can fall back to another address, such as the IPv4 one of a dual-stack host
+    /// resolved by [`DnsResolver`], when the first one is unreachable or slow to connect.
+    ///
+    /// Note that picking the addresses is not free for a [`Discover`] with lots of instances.
+    ///
+    /// Default is 0 which disables it, and [`DefaultLB`] sets it to 8.
+    pub fn fallback_addresses(mut self, max: usize) -> Self {
+        self.fallback_addresses = max;
+        self
+    }
 }
 
 impl<LB, D> MkLbLayer for LbConfig<LB, D> {
     type Layer = LoadBalanceLayer<LB, D>;
 
     fn make(self) -> Self::Layer {
-        LoadBalanceLayer::new(self.load_balance, self.discover)
+        LoadBalanceLayer::new(
+            self.load_balance,
+            self.discover,
+            self.outlier_detection,
+            self.fallback_addresses,
+        )
     }
 }
 
@@ -85,13 +147,22 @@ impl<LB, D> MkLbLayer for LbConfig<LB, D> {
 pub struct LoadBalanceLayer<LB, D> {
     load_balance: LB,
     discover: D,
+    outlier_detection: Option<OutlierDetection>,
+    fallback_addresses: usize,
 }
 
 impl<LB, D> LoadBalanceLayer<LB, D> {
-    fn new(load_balance: LB, discover: D) -> Self {
+    fn new(
+        load_balance: LB,
+        discover: D,
+        outlier_detection: Option<OutlierDetection>,
+        fallback_addresses: usize,
+    ) -> Self {
         LoadBalanceLayer {
             load_balance,
             discover,
+            outlier_detection,
+            fallback_addresses,
         }
     }
 }
@@ -104,16 +175,30 @@ where
     type Service = LoadBalanceService<LB, D, S>;
 
     fn layer(self, inner: S) -> Self::Service {
-        LoadBalanceService::new(self.load_balance, self.discover, inner)
+        LoadBalanceService::new(
+            self.load_balance,
+            self.discover,
+            inner,
+            self.outlier_detection,
+            self.fallback_addresses,
+        )
     }
 }
 
 /// [`Service`] for load balance generated by [`LoadBalanceLayer`]
 #[derive(Clone)]
-pub struct LoadBalanceService<LB, D, S> {
+pub struct LoadBalanceService<LB, D, S>
+where
+    D: Discover,
+{
     load_balance: Arc<LB>,
     discover: D,
     service: S,
+    outlier_detector: Option<Arc<OutlierDetector<D::Key>>>,
+    fallback_addresses: usize,
+    /// The discovered addresses of each key in the order of the discover, which is only used for
+    /// ordering the fallback addresses.
+    discovered: Arc<RwLock<AHashMap<D::Key, Arc<[Address]>>>>,
 }
 
 impl<LB, D, S> LoadBalanceService<LB, D, S>
@@ -121,20 +206,41 @@ where
     LB: LoadBalance<D>,
     D: Discover,
 {
-    fn new(load_balance: LB, discover: D, service: S) -> Self {
+    fn new(
+        load_balance: LB,
+        discover: D,
+        service: S,
+        outlier_detection: Option<OutlierDetection>,
+        fallback_addresses: usize,
+    ) -> Self {
         let lb = Arc::new(load_balance);
+        let detector = outlier_detection.map(|config| Arc::new(OutlierDetector::new(config)));
+        let discovered = Arc::new(RwLock::new(AHashMap::new()));
 
         let service = Self {
             load_balance: lb.clone(),
             discover,
             service,
+            outlier_detector: detector.clone(),
+            fallback_addresses,
+            discovered: discovered.clone(),
         };
 
         if let Some(mut channel) = service.discover.watch(None) {
             tokio::spawn(async move {
                 loop {
                     match channel.recv().await {
-                        Ok(recv) => lb.rebalance(recv),
+                        Ok(recv) => {
+                            if let Some(detector) = &detector {
+                                detector.on_change(&recv);
+                            }
+                            if let Some(addrs) = discovered.write().get_mut(&recv.key) {
+                                *addrs = recv.all.iter().map(|i| i.address.clone()).collect();
+                            }
+                            lb.rebalance(recv)
+                        }
+                        // the discover has been dropped
+                        Err(async_broadcast::RecvError::Closed) => break,
                         Err(err) => {
                             tracing::warn!("[VOLO] discovering subscription error: {:?}", err)
                         }
@@ -144,6 +250,35 @@ where
         }
         service
     }
+
+    /// Sorts the picked addresses in the order of the discover, and the ones not discovered any
+    /// more are put at the end.
+    async fn sort_discovered(&self, endpoint: &Endpoint, addrs: &mut [Address]) {
+        let key = self.discover.key(endpoint);
+        let discovered = self.discovered.read().get(&key).cloned();
+        let discovered = match discovered {
+            Some(discovered) => discovered,
+            None => {
+                let Ok(instances) = self.discover.discover(endpoint).await else {
+                    return;
+                };
+                let discovered: Arc<[Address]> =
+                    instances.iter().map(|i| i.address.clone()).collect();
+                // keep the addresses if they have been updated by the watch meanwhile
+                self.discovered
+                    .write()
+                    .entry(key)
+                    .or_insert(discovered)
+                    .clone()
+            }
+        };
+        addrs.sort_by_key(|addr| {
+            discovered
+                .iter()
+                .position(|discovered| discovered == addr)
+                .unwrap_or(usize::MAX)
+        });
+    }
 }
 
 impl<LB, D, S, B> Service<ClientContext, ClientRequest<B>> for LoadBalanceService<LB, D, S>
@@ -165,28 +300,68 @@ where
     ) -> Result<Self::Response, Self::Error> {
         let callee = cx.rpc_info().callee();
 
-        let mut picker = match &callee.address {
+        let picker = match &callee.address {
             None => self
                 .load_balance
-                .get_picker(callee, &self.discover)
+                .get_picker_with_caller(cx.rpc_info().caller(), callee, &self.discover)
                 .await
                 .map_err(lb_error)?,
             _ => {
                 return self.service.call(cx, req).await;
             }
         };
+        if let Some(detector) = &self.outlier_detector {
+            detector.sync_instances(&self.discover, callee).await;
+        }
+        let detector = self
+            .outlier_detector
+            .as_deref()
+            .map(|detector| (detector, self.discover.key(callee)));
+        let mut picker = SkipEjected::new(picker, detector.clone());
+
+        let mut addr = picker.next().ok_or_else(no_available_endpoint)?;
+        let mut fallbacks = Vec::new();
+        if self.fallback_addresses > 0 {
+            let mut addrs: Vec<_> = std::iter::once(addr)
+                .chain(picker.take(self.fallback_addresses))
+                .collect();
+            if addrs.len() > 1 {
+                self.sort_discovered(callee, &mut addrs).await;
+            }
+            addr = addrs.remove(0);
+            fallbacks = addrs;
+        }
+        let callee = cx.rpc_info_mut().callee_mut();
+        callee.set_address(addr.clone());
+        if !fallbacks.is_empty() {
+            callee.insert(FallbackAddresses(fallbacks));
+        }
+
+        let tracker = CallTracker::new(self.load_balance.as_ref(), &addr);
+        let result = self.service.call(cx, req).await;
+        tracker.finish(result.is_ok());
 
-        let addr = picker.next().ok_or_else(no_available_endpoint)?;
-        cx.rpc_info_mut().callee_mut().set_address(addr);
+        // The transport may have connected to one of the fallback addresses.
+        let addr = cx.rpc_info().callee().address().unwrap_or(addr);
 
-        self.service.call(cx, req).await
+        if let Some((detector, key)) = &detector {
+            match &result {
+                // the circuit breaker has counted the failures of the instance already
+                Err(err) if err.is_circuit_open() => {}
+                result => {
+                    let success = !matches!(result, Err(err) if err.retryable());
+                    detector.report(key, &addr, success);
+                }
+            }
+        }
+        result
     }
 }
 
 impl<LB, D, S> Debug for LoadBalanceService<LB, D, S>
 where
     LB: Debug,
-    D: Debug,
+    D: Discover + Debug,
     S: Debug,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -2,7 +2,12 @@
 //!
 //! See [`Client`] for more details.
 
-use std::{cell::RefCell, error::Error, sync::Arc, time::Duration};
+use std::{
+    cell::RefCell,
+    error::Error,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use faststr::FastStr;
 use http::{
@@ -18,10 +23,11 @@ use motore::{
 use paste::paste;
 use volo::{
     client::MkClient,
-    context::Context,
-    loadbalance::MkLbLayer,
+    context::{Context, Deadline},
+    loadbalance::{outlier::OutlierDetection, MkLbLayer},
     net::{
         dial::{DefaultMakeTransport, MakeTransport},
+        socket::SocketConfig,
         Address,
     },
 };
@@ -49,6 +55,7 @@ use crate::{
 
 pub mod callopt;
 pub mod dns;
+pub mod health_check;
 pub mod loadbalance;
 mod meta;
 mod request_builder;
@@ -182,6 +189,13 @@ impl<IL, OL, C, LB, DISC> ClientBuilder<IL, OL, C, LbConfig<LB, DISC>> {
             tls_config: self.tls_config,
         }
     }
+
+    /// Enable the outlier detection for the client, which ejects the instances that keep
+    /// failing for a while.
+    pub fn outlier_detection(mut self, config: OutlierDetection) -> Self {
+        self.mk_lb = self.mk_lb.outlier_detection(config);
+        self
+    }
 }
 
 impl<IL, OL, C, LB> ClientBuilder<IL, OL, C, LB> {
@@ -607,6 +621,12 @@ impl<IL, OL, C, LB> ClientBuilder<IL, OL, C, LB> {
         self
     }
 
+    /// Set the options of the TCP sockets, such as `TCP_NODELAY` and the TCP keepalive.
+    pub fn set_socket_config(&mut self, config: SocketConfig) -> &mut Self {
+        self.connector.set_socket_config(config);
+        self
+    }
+
     /// Set the maximin idle time for the request.
     ///
     /// The whole request includes connecting, writting, and reading the whole HTTP protocol
@@ -671,6 +691,7 @@ impl<IL, OL, C, LB> ClientBuilder<IL, OL, C, LB> {
             default_call_opt: self.call_opt,
             target_parser: self.target_parser,
             headers: self.headers,
+            default_timeout: self.builder_config.timeout,
         };
         let client = Client {
             service,
@@ -687,6 +708,7 @@ struct ClientInner {
     default_call_opt: CallOpt,
     target_parser: TargetParser,
     headers: HeaderMap,
+    default_timeout: Option<Duration>,
 }
 
 /// An Client for sending HTTP requests and handling HTTP responses.
@@ -880,6 +902,17 @@ where
     ) -> Result<Self::Response, Self::Error> {
         req.headers_mut().extend(self.inner.headers.clone());
 
+        let timeout = cx
+            .rpc_info()
+            .config()
+            .timeout
+            .or(self.inner.default_timeout);
+        if let Some(timeout) = timeout {
+            // for the inner layers, such as the retry, to know the time left for the request
+            cx.extensions_mut()
+                .insert(Deadline(Instant::now() + timeout));
+        }
+
         let has_metainfo = METAINFO.try_with(|_| {}).is_ok();
 
         let fut = self.service.call(cx, req);
@@ -1121,4 +1154,54 @@ mod client_tests {
             format!("{}", bad_scheme()),
         );
     }
+
+    #[tokio::test]
+    async fn client_fallback_addresses() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        };
+        use volo::{discovery::StaticDiscover, loadbalance::random::WeightedRandomBalance};
+
+        use super::loadbalance::LbConfig;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let alive = listener.local_addr().unwrap();
+        // Nothing listens on the port after the listener is dropped, so connecting to it is
+        // refused.
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                        .await;
+                });
+            }
+        });
+
+        let lb = LbConfig::new(
+            WeightedRandomBalance::new(),
+            StaticDiscover::from(vec![dead, alive]),
+        )
+        .fallback_addresses(1);
+        let mut builder = Client::builder().mk_load_balance(lb);
+        builder.host("volo-http.test");
+        let client = builder.build();
+
+        // The dead address is always tried first since it is discovered first, and the requests
+        // are sent to the alive one through the fallback addresses.
+        for _ in 0..16 {
+            let resp = client.get("/").unwrap().send().await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.into_string().await.unwrap(), "ok");
+        }
+    }
 }
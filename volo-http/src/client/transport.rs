@@ -11,6 +11,7 @@ use volo::{
     net::{conn::Conn, dial::DefaultMakeTransport, Address},
 };
 
+use super::loadbalance::FallbackAddresses;
 use crate::{
     context::ClientContext,
     error::client::{no_address, request_error, ClientError},
@@ -60,8 +61,19 @@ impl ClientTransport {
         }
     }
 
-    async fn connect_to(&self, address: Address) -> Result<Conn, ClientError> {
-        self.mk_conn.make_connection(address).await.map_err(|err| {
+    async fn connect_to(
+        &self,
+        address: Address,
+        fallbacks: Option<&FallbackAddresses>,
+    ) -> Result<Conn, ClientError> {
+        let conn = match fallbacks {
+            Some(fallbacks) => {
+                let addrs = std::iter::once(address).chain(fallbacks.0.iter().cloned());
+                self.mk_conn.make_connection_to_any(addrs).await
+            }
+            None => self.mk_conn.make_connection(address).await,
+        };
+        conn.map_err(|err| {
             tracing::error!("[Volo-HTTP] failed to make connection, error: {err}");
             request_error(err)
         })
@@ -81,7 +93,9 @@ impl ClientTransport {
 
         let target_addr = callee.address().ok_or_else(no_address)?;
         tracing::debug!("[Volo-HTTP] connecting to target: {target_addr:?}");
-        let conn = self.connect_to(target_addr).await;
+        let conn = self
+            .connect_to(target_addr, callee.get::<FallbackAddresses>())
+            .await;
         if !https {
             // The request does not use TLS, just return it without TLS handshake
             return conn;
@@ -94,7 +108,6 @@ impl ClientTransport {
             volo::net::conn::ConnStream::Tcp(tcp_stream) => tcp_stream,
             _ => unreachable!(),
         };
-        println!("target_name: {target_name}");
         self.tls_connector
             .connect(target_name, tcp_stream)
             .await
@@ -106,14 +119,16 @@ impl ClientTransport {
 
     #[cfg(not(feature = "__tls"))]
     async fn make_connection(&self, cx: &ClientContext) -> Result<Conn, ClientError> {
-        let target_addr = cx.rpc_info().callee().address().ok_or_else(no_address)?;
+        let callee = cx.rpc_info().callee();
+        let target_addr = callee.address().ok_or_else(no_address)?;
         tracing::debug!("[Volo-HTTP] connecting to target: {target_addr:?}");
-        self.connect_to(target_addr).await
+        self.connect_to(target_addr, callee.get::<FallbackAddresses>())
+            .await
     }
 
     async fn request<B>(
         &self,
-        cx: &ClientContext,
+        cx: &mut ClientContext,
         req: ClientRequest<B>,
     ) -> Result<ClientResponse, ClientError>
     where
@@ -123,6 +138,13 @@ impl ClientTransport {
     {
         tracing::trace!("[Volo-HTTP] requesting {}", req.uri());
         let conn = self.make_connection(cx).await?;
+        if let Some(peer_addr) = &conn.info.peer_addr {
+            let callee = cx.rpc_info_mut().callee_mut();
+            if callee.contains::<FallbackAddresses>() {
+                // Record the address actually connected by the Happy Eyeballs algorithm.
+                callee.set_address(peer_addr.clone());
+            }
+        }
         let io = TokioIo::new(conn);
         let (mut sender, conn) = self.client.handshake(io).await.map_err(|err| {
             tracing::error!("[Volo-HTTP] failed to handshake, error: {err}");
@@ -3,12 +3,12 @@
 use std::time::Duration;
 
 use chrono::{DateTime, Local};
-use volo::{
-    context::{Reusable, Role, RpcCx, RpcInfo},
-    newtype_impl_context,
-};
+use volo::context::{Context, Deadline, Endpoint, Extensions, Reusable, Role, RpcCx, RpcInfo};
 
-use crate::utils::macros::{impl_deref_and_deref_mut, stat_impl};
+use crate::{
+    client::{dns::Port, loadbalance::FallbackAddresses},
+    utils::macros::{impl_deref_and_deref_mut, stat_impl},
+};
 
 /// RPC context of http client
 #[derive(Debug)]
@@ -32,7 +32,81 @@ impl Default for ClientContext {
     }
 }
 
-newtype_impl_context!(ClientContext, Config, 0);
+impl Context for ClientContext {
+    type Config = Config;
+
+    #[inline]
+    fn rpc_info(&self) -> &RpcInfo<Self::Config> {
+        self.0.rpc_info()
+    }
+
+    #[inline]
+    fn rpc_info_mut(&mut self) -> &mut RpcInfo<Self::Config> {
+        self.0.rpc_info_mut()
+    }
+
+    #[inline]
+    fn extensions_mut(&mut self) -> &mut Extensions {
+        self.0.extensions_mut()
+    }
+
+    #[inline]
+    fn extensions(&self) -> &Extensions {
+        self.0.extensions()
+    }
+
+    /// Forks the context with the method, the service names, the addresses and the tags of this
+    /// crate of the caller and the callee, the config and the [`Deadline`].
+    ///
+    /// The other tags are type-erased and cannot be cloned, so the context is not forked if the
+    /// callee has any of them.
+    fn fork(&self) -> Option<Self> {
+        let rpc_info = self.rpc_info();
+        let callee = rpc_info.callee();
+        #[cfg(feature = "__tls")]
+        let https = callee.contains::<crate::client::TlsTransport>();
+        #[cfg(not(feature = "__tls"))]
+        let https = false;
+        let port = callee.get::<Port>().map(|port| port.0);
+        // the fallback addresses are of the address picked for this call, and not copied
+        let known = usize::from(https)
+            + usize::from(port.is_some())
+            + usize::from(callee.contains::<FallbackAddresses>());
+        if !callee.faststr_tags.is_empty() || callee.tags.len() > known {
+            return None;
+        }
+
+        let fork_endpoint = |endpoint: &Endpoint| {
+            let mut forked = Endpoint::new(endpoint.service_name());
+            forked.address = endpoint.address();
+            forked
+        };
+        let mut forked_callee = fork_endpoint(callee);
+        #[cfg(feature = "__tls")]
+        if https {
+            forked_callee.insert(crate::client::TlsTransport);
+        }
+        if let Some(port) = port {
+            forked_callee.insert(Port(port));
+        }
+        let mut cx = Self(RpcCx::new(
+            RpcInfo::new(
+                rpc_info.role(),
+                rpc_info.method().clone(),
+                fork_endpoint(rpc_info.caller()),
+                forked_callee,
+                rpc_info.config().clone(),
+            ),
+            ClientCxInner {
+                stats: ClientStats::default(),
+            },
+        ));
+        if let Some(deadline) = self.extensions().get::<Deadline>() {
+            cx.extensions_mut().insert(*deadline);
+        }
+        Some(cx)
+    }
+}
 
 impl_deref_and_deref_mut!(ClientContext, RpcCx<ClientCxInner, Config>, 0);
 
@@ -71,3 +145,28 @@ impl Reusable for Config {
         *self = Default::default()
     }
 }
+
+#[cfg(test)]
+mod client_context_tests {
+    use volo::context::Context;
+
+    use super::ClientContext;
+    use crate::client::dns::Port;
+
+    #[test]
+    fn fork() {
+        struct Tag;
+
+        let mut cx = ClientContext::new();
+        cx.rpc_info_mut().callee_mut().insert(Port(8080));
+        let forked = cx.fork().unwrap();
+        assert_eq!(
+            forked.rpc_info().callee().get::<Port>().map(|port| port.0),
+            Some(8080)
+        );
+
+        // the tag of users cannot be copied
+        cx.rpc_info_mut().callee_mut().insert(Tag);
+        assert!(cx.fork().is_none());
+    }
+}
@@ -1,9 +1,10 @@
 //! Generic error types for client
 
-use std::{error::Error, fmt};
+use std::{error::Error, fmt, io};
 
 use http::{StatusCode, Uri};
 use paste::paste;
+use volo::{circuit_breaker::CircuitBreakerError, loadbalance::error::Retryable};
 
 use super::BoxError;
 use crate::body::BodyConvertError;
@@ -145,6 +146,38 @@ pub fn status_error(status: StatusCode) -> ClientError {
     ClientError::new(ErrorKind::Status(status), None::<ClientError>)
 }
 
+/// Only the requests that have not been sent are retryable, which are the ones failing to connect
+/// to the instance and the ones rejected by an open circuit, because the method of the request may
+/// not be idempotent.
+impl Retryable for ClientError {
+    fn retryable(&self) -> bool {
+        if self.kind != ErrorKind::Request {
+            return false;
+        }
+        // the transport returns an `io::Error` only when connecting or making the TLS handshake
+        self.source
+            .as_ref()
+            .is_some_and(|source| source.is::<io::Error>() || source.is::<CircuitBreakerError>())
+    }
+
+    fn is_circuit_open(&self) -> bool {
+        self.kind == ErrorKind::Request
+            && self
+                .source
+                .as_ref()
+                .is_some_and(|source| source.is::<CircuitBreakerError>())
+    }
+}
+
+impl From<CircuitBreakerError> for ClientError {
+    /// The request is rejected because the circuit is open, which is an [`ErrorKind::Request`]
+    /// error with the [`CircuitBreakerError`] as the source, so it can be retried on another
+    /// instance.
+    fn from(err: CircuitBreakerError) -> Self {
+        ClientError::new(ErrorKind::Request, Some(err))
+    }
+}
+
 impl From<BodyConvertError> for ClientError {
     fn from(value: BodyConvertError) -> Self {
         ClientError::new(ErrorKind::Body, Some(BoxError::from(value)))
@@ -202,3 +235,31 @@ simple_error!(Builder => BadScheme => "bad scheme");
 simple_error!(Builder => BadHostName => "bad host name");
 simple_error!(Request => Timeout => "request timeout");
 simple_error!(LoadBalance => NoAvailableEndpoint => "no available endpoint");
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use http::StatusCode;
+    use volo::{circuit_breaker::CircuitBreakerError, loadbalance::error::Retryable};
+
+    use super::{request_error, status_error, timeout, ClientError};
+
+    #[test]
+    fn test_retryable() {
+        let connect = request_error(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(connect.retryable());
+        assert!(!connect.is_circuit_open());
+
+        let open = ClientError::from(CircuitBreakerError {
+            method: "/get".into(),
+            address: None,
+        });
+        assert!(open.retryable());
+        assert!(open.is_circuit_open());
+
+        // the request may have been processed by the server
+        assert!(!timeout().retryable());
+        assert!(!status_error(StatusCode::SERVICE_UNAVAILABLE).retryable());
+    }
+}
@@ -61,6 +61,11 @@ pub enum GenericRejectionError {
     BodyCollectionError,
     /// The `Content-Type` is invalid for the extractor
     InvalidContentType,
+    /// The request is not received from a TLS connection
+    ///
+    /// It is a `400 Bad Request`. For the handlers serving both the TLS and the plain
+    /// connections, use `Option<TlsSession>` as the extractor instead.
+    TlsSessionNotFound,
 }
 
 impl fmt::Display for GenericRejectionError {
@@ -68,6 +73,7 @@ impl fmt::Display for GenericRejectionError {
         match self {
             Self::BodyCollectionError => write!(f, "failed to collect the response body"),
             Self::InvalidContentType => write!(f, "invalid content type"),
+            Self::TlsSessionNotFound => write!(f, "no tls session found"),
         }
     }
 }
@@ -80,6 +86,7 @@ impl GenericRejectionError {
         match self {
             Self::BodyCollectionError => StatusCode::INTERNAL_SERVER_ERROR,
             Self::InvalidContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::TlsSessionNotFound => StatusCode::BAD_REQUEST,
         }
     }
 }
@@ -21,7 +21,7 @@ use volo::{context::Context, net::Address};
 use super::IntoResponse;
 use crate::{
     context::ServerContext,
-    error::server::{body_collection_error, ExtractBodyError},
+    error::server::{body_collection_error, ExtractBodyError, GenericRejectionError},
     request::ServerRequest,
 };
 
@@ -189,6 +189,25 @@ impl FromContext for Method {
     }
 }
 
+/// Extracts the TLS session of the connection, and rejects the request with
+/// `400 Bad Request` if it is not received from a TLS connection.
+///
+/// Use `Option<TlsSession>` if the handler also serves the plain connections.
+#[cfg(feature = "__tls")]
+impl FromContext for volo::net::tls::TlsSession {
+    type Rejection = GenericRejectionError;
+
+    async fn from_context(
+        cx: &mut ServerContext,
+        _parts: &mut Parts,
+    ) -> Result<Self, Self::Rejection> {
+        cx.extensions()
+            .get::<Self>()
+            .cloned()
+            .ok_or(GenericRejectionError::TlsSessionNotFound)
+    }
+}
+
 #[cfg(feature = "query")]
 impl<T> FromContext for Query<T>
 where
@@ -2,15 +2,7 @@
 //!
 //! See [`Server`] for more details.
 
-use std::{
-    cell::RefCell,
-    convert::Infallible,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
-    time::Duration,
-};
+use std::{cell::RefCell, convert::Infallible, future::Future, io, pin::Pin, sync::Arc};
 
 use futures::future::BoxFuture;
 use hyper::server::conn::http1;
@@ -22,14 +14,16 @@ use motore::{
     BoxError,
 };
 use parking_lot::RwLock;
-use scopeguard::defer;
-use tokio::sync::Notify;
-use tracing::{info, trace};
+use tokio::sync::{futures::Notified, Notify};
+use tracing::{debug, info, trace};
+#[cfg(target_family = "unix")]
+use volo::hotrestart::{HotRestartConfig, DEFAULT_HOT_RESTART};
 #[cfg(feature = "__tls")]
-use volo::net::{conn::ConnStream, tls::Acceptor, tls::ServerTlsConfig};
+use volo::net::tls::{ServerTlsConfig, TlsSession};
 use volo::{
     context::Context,
-    net::{conn::Conn, incoming::Incoming, Address, MakeIncoming},
+    net::{conn::Conn, incoming::Incoming, socket::SocketConfig, Address, MakeIncoming},
+    shutdown::{ConnTracker, ShutdownConfig},
 };
 
 use crate::{
@@ -45,6 +39,7 @@ pub mod layer;
 pub mod middleware;
 pub mod panic_handler;
 pub mod param;
+pub mod rate_limit;
 pub mod response;
 pub mod route;
 #[cfg(test)]
@@ -95,6 +90,10 @@ pub struct Server<S, L> {
     server: http1::Builder,
     config: Config,
     shutdown_hooks: Vec<Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>>,
+    shutdown_config: ShutdownConfig,
+    socket_config: SocketConfig,
+    #[cfg(target_family = "unix")]
+    hot_restart: Option<HotRestartConfig>,
     #[cfg(feature = "__tls")]
     tls_config: Option<ServerTlsConfig>,
 }
@@ -108,6 +107,10 @@ impl<S> Server<S, Identity> {
             server: http1::Builder::new(),
             config: Config::default(),
             shutdown_hooks: Vec::new(),
+            shutdown_config: ShutdownConfig::default(),
+            socket_config: SocketConfig::default(),
+            #[cfg(target_family = "unix")]
+            hot_restart: None,
             #[cfg(feature = "__tls")]
             tls_config: None,
         }
@@ -137,6 +140,38 @@ impl<S, L> Server<S, L> {
         self
     }
 
+    /// Sets the config of the graceful shutdown.
+    ///
+    /// During the shutdown, the idle connections are closed once the listener is closed, and the
+    /// busy ones are closed after the in-flight responses, which carry `Connection: close`.
+    ///
+    /// Default is [`ShutdownConfig::default`].
+    pub fn shutdown_config(mut self, config: ShutdownConfig) -> Self {
+        self.shutdown_config = config;
+        self
+    }
+
+    /// Sets the options of the TCP sockets of the accepted connections, such as `TCP_NODELAY`
+    /// and the TCP keepalive.
+    ///
+    /// Default is [`SocketConfig::default`].
+    pub fn socket_config(mut self, config: SocketConfig) -> Self {
+        self.socket_config = config;
+        self
+    }
+
+    /// Enables the hot restart with the config.
+    ///
+    /// The server takes over the listener of the old process if any, and tells the old process
+    /// to stop once it is ready, which then shuts down gracefully with the shutdown config.
+    ///
+    /// Default is disabled.
+    #[cfg(target_family = "unix")]
+    pub fn hot_restart(mut self, config: HotRestartConfig) -> Self {
+        self.hot_restart = Some(config);
+        self
+    }
+
     /// Adds a new inner layer to the server.
     ///
     /// The layer's `Service` should be `Send + Sync + Clone + 'static`.
@@ -155,6 +190,10 @@ impl<S, L> Server<S, L> {
             server: self.server,
             config: self.config,
             shutdown_hooks: self.shutdown_hooks,
+            shutdown_config: self.shutdown_config,
+            socket_config: self.socket_config,
+            #[cfg(target_family = "unix")]
+            hot_restart: self.hot_restart,
             #[cfg(feature = "__tls")]
             tls_config: self.tls_config,
         }
@@ -178,6 +217,10 @@ impl<S, L> Server<S, L> {
             server: self.server,
             config: self.config,
             shutdown_hooks: self.shutdown_hooks,
+            shutdown_config: self.shutdown_config,
+            socket_config: self.socket_config,
+            #[cfg(target_family = "unix")]
+            hot_restart: self.hot_restart,
             #[cfg(feature = "__tls")]
             tls_config: self.tls_config,
         }
@@ -260,6 +303,8 @@ impl<S, L> Server<S, L> {
     }
 
     /// The main entry point for the server.
+    ///
+    /// The server is shut down gracefully on SIGINT, SIGHUP or SIGTERM, or Ctrl-C on windows.
     pub async fn run<MI, B, E>(self, mk_incoming: MI) -> Result<(), BoxError>
     where
         S: Service<ServerContext, ServerRequest<B>, Error = E> + Send + Sync + 'static,
@@ -270,57 +315,77 @@ impl<S, L> Server<S, L> {
             Service<ServerContext, ServerRequest, Error = Infallible> + Send + Sync + 'static,
         <L::Service as Service<ServerContext, ServerRequest>>::Response: IntoResponse,
         MI: MakeIncoming,
+    {
+        self.run_with_shutdown(mk_incoming, volo::shutdown::signal())
+            .await
+    }
+
+    /// The main entry point for the server.
+    /// Runs server with a stop signal to control graceful shutdown.
+    pub async fn run_with_shutdown<MI, B, E, F>(
+        self,
+        mk_incoming: MI,
+        signal: F,
+    ) -> Result<(), BoxError>
+    where
+        S: Service<ServerContext, ServerRequest<B>, Error = E> + Send + Sync + 'static,
+        S::Response: IntoResponse,
+        E: IntoResponse,
+        L: Layer<S> + Send + Sync + 'static,
+        L::Service:
+            Service<ServerContext, ServerRequest, Error = Infallible> + Send + Sync + 'static,
+        <L::Service as Service<ServerContext, ServerRequest>>::Response: IntoResponse,
+        MI: MakeIncoming,
+        F: Future<Output = io::Result<()>>,
     {
         let server = Arc::new(self.server);
         let service = Arc::new(self.layer.layer(self.service));
+        #[cfg(target_family = "unix")]
+        if let Some(config) = &self.hot_restart {
+            DEFAULT_HOT_RESTART.initialize_servers(config).await?;
+        }
+        // marks the server as ready once it starts accepting, and stops it on the shutdown signal
+        // or the termination by the new process
+        #[cfg(target_family = "unix")]
+        let signal = {
+            let hot_restart = self.hot_restart.is_some();
+            async move {
+                if hot_restart {
+                    DEFAULT_HOT_RESTART.wait_for_shutdown(signal).await
+                } else {
+                    signal.await
+                }
+            }
+        };
+
         let incoming = mk_incoming.make_incoming().await?;
         info!("[VOLO] server start at: {:?}", incoming);
 
-        // count connections, used for graceful shutdown
-        let conn_cnt = Arc::new(AtomicUsize::new(0));
+        // track connections, used for graceful shutdown
+        let conn_tracker = ConnTracker::new();
         // flag for stopping serve
         let exit_flag = Arc::new(parking_lot::RwLock::new(false));
         // notifier for stopping all inflight connections
         let exit_notify = Arc::new(Notify::const_new());
 
-        let handler = tokio::spawn(serve(
+        let mut handler = tokio::spawn(serve(
             server,
             incoming,
             service,
             self.config,
+            self.socket_config,
             exit_flag.clone(),
-            conn_cnt.clone(),
+            conn_tracker.clone(),
             exit_notify.clone(),
             #[cfg(feature = "__tls")]
             self.tls_config,
         ));
 
-        #[cfg(target_family = "unix")]
-        {
-            // graceful shutdown
-            let mut sigint =
-                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())?;
-            let mut sighup =
-                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
-            let mut sigterm =
-                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
-
-            // graceful shutdown handler
-            tokio::select! {
-                _ = sigint.recv() => {}
-                _ = sighup.recv() => {}
-                _ = sigterm.recv() => {}
-                _ = handler => {},
-            }
-        }
-
-        // graceful shutdown handler for windows
-        #[cfg(target_family = "windows")]
+        // graceful shutdown handler
         tokio::select! {
-            _ = tokio::signal::ctrl_c() => {}
-            _ = handler => {},
+            res = signal => res?,
+            _ = &mut handler => {},
         }
-
         if !self.shutdown_hooks.is_empty() {
             info!("[VOLO] call shutdown hooks");
 
@@ -331,26 +396,21 @@ impl<S, L> Server<S, L> {
 
         // received signal, graceful shutdown now
         info!("[VOLO] received signal, gracefully exiting now");
-        *exit_flag.write() = true;
+        self.shutdown_config.wait_grace_period().await;
 
         // Now we won't accept new connections.
-        // And we want to send crrst reply to the peers in the short future.
-        if conn_cnt.load(Ordering::Relaxed) != 0 {
-            tokio::time::sleep(Duration::from_secs(2)).await;
-        }
+        *exit_flag.write() = true;
+        // stops accepting and closes the listener
+        handler.abort();
+        // closes the idle connections, and the busy ones after the in-flight responses
         exit_notify.notify_waiters();
 
         // wait for all connections to be closed
-        for _ in 0..28 {
-            if conn_cnt.load(Ordering::Relaxed) == 0 {
-                break;
-            }
-            trace!(
-                "[VOLO] gracefully exiting, remaining connection count: {}",
-                conn_cnt.load(Ordering::Relaxed)
-            );
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
+        trace!(
+            "[VOLO] gracefully exiting, remaining connection count: {}",
+            conn_tracker.count()
+        );
+        self.shutdown_config.drain(&conn_tracker).await;
 
         Ok(())
     }
@@ -362,8 +422,9 @@ async fn serve<I, S, E>(
     mut incoming: I,
     service: S,
     config: Config,
+    socket_config: SocketConfig,
     exit_flag: Arc<RwLock<bool>>,
-    conn_cnt: Arc<AtomicUsize>,
+    conn_tracker: ConnTracker,
     exit_notify: Arc<Notify>,
     #[cfg(feature = "__tls")] tls_config: Option<ServerTlsConfig>,
 ) where
@@ -381,24 +442,9 @@ async fn serve<I, S, E>(
             Ok(Some(conn)) => conn,
             _ => continue,
         };
-        #[cfg(feature = "__tls")]
-        let conn = {
-            let Conn { stream, info } = conn;
-            match (stream, &tls_config) {
-                (ConnStream::Tcp(stream), Some(tls_config)) => {
-                    let stream = match tls_config.acceptor.accept(stream).await {
-                        Ok(conn) => conn,
-                        Err(err) => {
-                            trace!("[VOLO] tls handshake error: {err:?}");
-                            continue;
-                        }
-                    };
-                    Conn { stream, info }
-                }
-                (stream, _) => Conn { stream, info },
-            }
-        };
-
+        if let Err(err) = socket_config.apply_to_conn(&conn) {
+            debug!("[VOLO] failed to set the socket options: {err:?}");
+        }
         let peer = match conn.info.peer_addr {
             Some(ref peer) => {
                 trace!(" accept connection from: {peer:?}");
@@ -410,19 +456,49 @@ async fn serve<I, S, E>(
             }
         };
 
-        let hyper_service = HyperService {
-            inner: service.clone(),
-            peer,
-            config: config.clone(),
-        };
-
-        tokio::spawn(serve_conn(
-            server.clone(),
-            conn,
-            hyper_service,
-            conn_cnt.clone(),
-            exit_notify.clone(),
-        ));
+        let service = service.clone();
+        let config = config.clone();
+        let server = server.clone();
+        let exit_flag = exit_flag.clone();
+        let exit_notify = exit_notify.clone();
+        #[cfg(feature = "__tls")]
+        let tls_config = tls_config.clone();
+        tokio::spawn(conn_tracker.serve(async move {
+            // Listens for the shutdown before the handshake, and checks the flag for the shutdown
+            // started before that, which is set before notifying.
+            let notified = exit_notify.notified();
+            tokio::pin!(notified);
+            if *exit_flag.read() {
+                return;
+            }
+            // Performs the TLS handshake in the task of the connection, so that a slow client
+            // will not block accepting the other connections.
+            #[cfg(feature = "__tls")]
+            let conn = match &tls_config {
+                Some(tls_config) => tokio::select! {
+                    _ = &mut notified => {
+                        trace!("[VOLO] closing a connection in the tls handshake");
+                        return;
+                    }
+                    result = tls_config.accept(conn) => match result {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            trace!("[VOLO] tls handshake error: {err:?}");
+                            return;
+                        }
+                    },
+                },
+                None => conn,
+            };
+            let hyper_service = HyperService {
+                inner: service,
+                peer,
+                #[cfg(feature = "__tls")]
+                tls_session: conn.stream.tls_session(),
+                config,
+            };
+            serve_conn(server, conn, hyper_service, notified).await
+        }));
     }
 }
 
@@ -430,19 +506,10 @@ async fn serve_conn<S>(
     server: Arc<http1::Builder>,
     conn: Conn,
     service: S,
-    conn_cnt: Arc<AtomicUsize>,
-    exit_notify: Arc<Notify>,
+    mut notified: Pin<&mut Notified<'_>>,
 ) where
     S: hyper::service::HttpService<hyper::body::Incoming, ResBody = Body>,
 {
-    conn_cnt.fetch_add(1, Ordering::Relaxed);
-    defer! {
-        conn_cnt.fetch_sub(1, Ordering::Relaxed);
-    }
-
-    let notified = exit_notify.notified();
-    tokio::pin!(notified);
-
     let mut http_conn = server.serve_connection(TokioIo::new(conn), service);
 
     tokio::select! {
@@ -470,6 +537,8 @@ async fn serve_conn<S>(
 struct HyperService<S> {
     inner: S,
     peer: Address,
+    #[cfg(feature = "__tls")]
+    tls_session: Option<TlsSession>,
     config: Config,
 }
 
@@ -489,8 +558,64 @@ where
             METAINFO.scope(RefCell::new(MetaInfo::default()), async move {
                 let mut cx = ServerContext::new(service.peer);
                 cx.rpc_info_mut().set_config(service.config);
+                #[cfg(feature = "__tls")]
+                if let Some(session) = service.tls_session {
+                    if let Some(identity) = &session.peer_identity {
+                        cx.rpc_info_mut().caller_mut().insert(identity.clone());
+                    }
+                    cx.extensions_mut().insert(session);
+                }
                 Ok(service.inner.call(&mut cx, req).await.into_response())
             }),
         )
     }
 }
+
+#[cfg(all(test, feature = "rustls"))]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::{TcpListener, TcpStream};
+    use volo::{
+        net::{incoming::DefaultIncoming, tls::ServerTlsConfig},
+        shutdown::ShutdownConfig,
+    };
+
+    use super::{route::get, Router, Server};
+
+    #[tokio::test]
+    async fn test_shutdown_in_tls_handshake() {
+        let data = format!("{}/../examples/data/tls", env!("CARGO_MANIFEST_DIR"));
+        let tls_config = ServerTlsConfig::from_pem_file(
+            format!("{data}/server.pem"),
+            format!("{data}/server.key"),
+        )
+        .unwrap()
+        .handshake_timeout(Duration::from_secs(60));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = Server::new(Router::new().route("/", get(|| async { "hello" })))
+            .tls_config(tls_config)
+            .shutdown_config(ShutdownConfig::new().drain_timeout(Duration::from_secs(60)))
+            .run_with_shutdown(DefaultIncoming::from(listener), async move {
+                let _ = rx.await;
+                Ok(())
+            });
+        // the client never starts the handshake
+        let client = async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            tx.send(()).unwrap();
+            stream
+        };
+
+        // the server stops without waiting for the handshake or the drain timeout
+        let (result, _stream) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(server, client)
+        })
+        .await
+        .unwrap();
+        result.unwrap();
+    }
+}
@@ -0,0 +1,17 @@
+//! Collections for some rate limit handlers
+//!
+//! [`volo::rate_limit::Layer`] rejects the requests exceeding the quota with a handler, which can
+//! respond a [`ServerResponse`].
+
+use http::StatusCode;
+use volo::rate_limit::RateLimitError;
+
+use super::IntoResponse;
+use crate::response::ServerResponse;
+
+/// This function is a rate limit handler and can work with [`volo::rate_limit::Layer`], it will
+/// always return `429 Too Many Requests`.
+pub fn too_many_requests<Cx, E>(_: &mut Cx, err: RateLimitError) -> Result<ServerResponse, E> {
+    tracing::debug!("[Volo-HTTP] rate_limit: {err}");
+    Ok(StatusCode::TOO_MANY_REQUESTS.into_response())
+}
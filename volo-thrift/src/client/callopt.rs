@@ -33,6 +33,8 @@
 //! }
 //! ```
 
+use std::time::Duration;
+
 use metainfo::{FastStrMap, TypeMap};
 use volo::net::Address;
 
@@ -60,4 +62,13 @@ impl CallOpt {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Enables hedging for the call, which sends the request to another instance if the first
+    /// one has not answered within the delay.
+    ///
+    /// The hedges are still limited by the max rate of the hedge policy of the client if any.
+    pub fn with_hedge_delay(mut self, delay: Duration) -> Self {
+        self.config.set_hedge_delay(Some(delay));
+        self
+    }
 }
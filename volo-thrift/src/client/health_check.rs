@@ -0,0 +1,270 @@
+//! Thrift level [`Probe`] for the active health checking of
+//! [`HealthCheckDiscover`](volo::discovery::health_check::HealthCheckDiscover).
+
+use std::{cell::RefCell, sync::Arc, time::Duration};
+
+use motore::make::MakeConnection;
+use pilota::thrift::{
+    TAsyncInputProtocol, TInputProtocol, TLengthProtocol, TMessageIdentifier, TMessageType,
+    TOutputProtocol, TStructIdentifier, TType, ThriftException,
+};
+use volo::{
+    context::{Endpoint, Role, RpcInfo},
+    discovery::{health_check::Probe, Instance},
+    net::{
+        conn::{OwnedReadHalf, OwnedWriteHalf},
+        dial::DefaultMakeTransport,
+    },
+    FastStr,
+};
+
+use crate::{
+    codec::{
+        default::{framed::MakeFramedCodec, thrift::MakeThriftCodec, ttheader::MakeTTHeaderCodec},
+        Decoder, DefaultMakeCodec, Encoder, MakeCodec,
+    },
+    context::{ClientContext, Config},
+    EntryMessage, ThriftMessage,
+};
+
+/// [`PingProbe`] calls a method without arguments, such as `ping`, on the instance, and considers
+/// the instance healthy if it answers with a thrift message.
+///
+/// Any answer, including an exception such as `UNKNOWN_METHOD`, means the server is up and
+/// speaking thrift, so the method doesn't need to exist on the server. The codec must be the same
+/// as the one of the server, which is `TTHeader<Framed<Binary>>` by default.
+#[derive(Clone)]
+pub struct PingProbe<MkC = DefaultMakeCodec<MakeTTHeaderCodec<MakeFramedCodec<MakeThriftCodec>>>> {
+    method: FastStr,
+    make_codec: MkC,
+    make_transport: DefaultMakeTransport,
+    timeout: Duration,
+}
+
+impl PingProbe {
+    pub fn new(method: impl Into<FastStr>) -> Self {
+        Self {
+            method: method.into(),
+            make_codec: DefaultMakeCodec::default(),
+            make_transport: DefaultMakeTransport::default(),
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl<MkC> PingProbe<MkC> {
+    /// Sets the codec used to call the instance.
+    pub fn make_codec<MkC2>(self, make_codec: MkC2) -> PingProbe<MkC2> {
+        PingProbe {
+            method: self.method,
+            make_codec,
+            make_transport: self.make_transport,
+            timeout: self.timeout,
+        }
+    }
+
+    /// Sets the timeout of a ping, and an instance that doesn't answer in time is considered as
+    /// unhealthy.
+    ///
+    /// Default is 1 second.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn ping(&self, instance: &Instance) -> Result<bool, ThriftException>
+    where
+        MkC: MakeCodec<OwnedReadHalf, OwnedWriteHalf> + Sync,
+    {
+        let conn = self
+            .make_transport
+            .make_connection(instance.address.clone())
+            .await?;
+        let (read_half, write_half) = conn.stream.into_split();
+        let (mut encoder, mut decoder) = self.make_codec.make_codec(read_half, write_half);
+
+        let mut cx = ClientContext::new(
+            1,
+            RpcInfo::new(
+                Role::Client,
+                self.method.clone(),
+                Endpoint::new(FastStr::empty()),
+                Endpoint::new(FastStr::empty()),
+                Config::default(),
+            ),
+            TMessageType::Call,
+        );
+        let msg = ThriftMessage::mk_client_msg(&cx, EmptyStruct);
+        encoder.encode(&mut cx, msg).await?;
+        let resp = decoder.decode::<EmptyStruct, _>(&mut cx).await?;
+        Ok(resp.is_some())
+    }
+}
+
+impl<MkC> Probe for PingProbe<MkC>
+where
+    MkC: MakeCodec<OwnedReadHalf, OwnedWriteHalf> + Sync,
+{
+    async fn probe(&self, instance: Arc<Instance>) -> bool {
+        let ping = metainfo::METAINFO.scope(
+            RefCell::new(metainfo::MetaInfo::default()),
+            self.ping(&instance),
+        );
+        match tokio::time::timeout(self.timeout, ping).await {
+            Ok(Ok(healthy)) => healthy,
+            Ok(Err(err)) => {
+                tracing::debug!(
+                    "[VOLO] health check pings {} error: {}",
+                    instance.address,
+                    err
+                );
+                false
+            }
+            Err(_) => {
+                tracing::debug!("[VOLO] health check pings {} timeout", instance.address);
+                false
+            }
+        }
+    }
+}
+
+/// The arguments of the ping method, and its result whose fields are skipped.
+struct EmptyStruct;
+
+const EMPTY_STRUCT: TStructIdentifier = TStructIdentifier {
+    name: "EmptyStruct",
+};
+
+impl EntryMessage for EmptyStruct {
+    fn encode<T: TOutputProtocol>(&self, protocol: &mut T) -> Result<(), ThriftException> {
+        protocol.write_struct_begin(&EMPTY_STRUCT)?;
+        protocol.write_field_stop()?;
+        protocol.write_struct_end()?;
+        Ok(())
+    }
+
+    // The fields are skipped one by one rather than skipping the whole struct, since the unsafe
+    // binary protocol can only skip right after reading a field header.
+    fn decode<T: TInputProtocol>(
+        protocol: &mut T,
+        _msg_ident: &TMessageIdentifier,
+    ) -> Result<Self, ThriftException> {
+        protocol.read_struct_begin()?;
+        loop {
+            let field_ident = protocol.read_field_begin()?;
+            if field_ident.field_type == TType::Stop {
+                break;
+            }
+            protocol.skip(field_ident.field_type)?;
+            protocol.read_field_end()?;
+        }
+        protocol.read_struct_end()?;
+        Ok(EmptyStruct)
+    }
+
+    async fn decode_async<T: TAsyncInputProtocol>(
+        protocol: &mut T,
+        _msg_ident: &TMessageIdentifier,
+    ) -> Result<Self, ThriftException> {
+        protocol.read_struct_begin().await?;
+        loop {
+            let field_ident = protocol.read_field_begin().await?;
+            if field_ident.field_type == TType::Stop {
+                break;
+            }
+            protocol.skip(field_ident.field_type).await?;
+            protocol.read_field_end().await?;
+        }
+        protocol.read_struct_end().await?;
+        Ok(EmptyStruct)
+    }
+
+    fn size<T: TLengthProtocol>(&self, protocol: &mut T) -> usize {
+        protocol.struct_begin_len(&EMPTY_STRUCT)
+            + protocol.field_stop_len()
+            + protocol.struct_end_len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, sync::Arc, time::Duration};
+
+    use pilota::thrift::{ApplicationException, ApplicationExceptionKind, TMessageType};
+    use tokio::net::TcpListener;
+    use volo::{
+        discovery::{health_check::Probe, Instance},
+        net::{conn::Conn, Address},
+    };
+
+    use super::{EmptyStruct, PingProbe};
+    use crate::{
+        codec::{Decoder, DefaultMakeCodec, Encoder, MakeCodec},
+        context::ServerContext,
+        ThriftMessage,
+    };
+
+    #[tokio::test]
+    async fn test_ping_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let instance = Arc::new(Instance {
+            address: Address::Ip(listener.local_addr().unwrap()),
+            weight: 1,
+            tags: Default::default(),
+        });
+
+        // answers every call with an `UNKNOWN_METHOD` exception, and closes the connections
+        // which don't send a call
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    continue;
+                };
+                tokio::spawn(metainfo::METAINFO.scope(
+                    RefCell::new(metainfo::MetaInfo::default()),
+                    async move {
+                        let (read_half, write_half) = Conn::from(stream).stream.into_split();
+                        let (mut encoder, mut decoder) =
+                            DefaultMakeCodec::default().make_codec(read_half, write_half);
+                        let mut cx = ServerContext::default();
+                        let Ok(Some(req)) = decoder.decode::<EmptyStruct, _>(&mut cx).await else {
+                            return;
+                        };
+                        if req.meta.msg_type != TMessageType::Call {
+                            return;
+                        }
+                        cx.msg_type = Some(TMessageType::Exception);
+                        let resp = ThriftMessage::<EmptyStruct>::mk_server_resp(
+                            &cx,
+                            Err(ApplicationException::new(
+                                ApplicationExceptionKind::UNKNOWN_METHOD,
+                                "unknown method",
+                            )),
+                        );
+                        let _ = encoder.encode(&mut cx, resp).await;
+                    },
+                ));
+            }
+        });
+        assert!(PingProbe::new("ping").probe(instance.clone()).await);
+
+        // an instance which doesn't speak thrift
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let instance = Arc::new(Instance {
+            address: Address::Ip(listener.local_addr().unwrap()),
+            weight: 1,
+            tags: Default::default(),
+        });
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            loop {
+                if let Ok((stream, _)) = listener.accept().await {
+                    // keep the connections open without answering
+                    conns.push(stream);
+                }
+            }
+        });
+        let probe = PingProbe::new("ping").timeout(Duration::from_millis(100));
+        assert!(!probe.probe(instance).await);
+    }
+}
@@ -3,6 +3,7 @@
 //! aborted.
 use motore::{layer::Layer, service::Service};
 use tracing::warn;
+use volo::context::{Context, Deadline};
 
 use crate::context::ClientContext;
 
@@ -28,6 +29,7 @@ where
         match cx.rpc_info.config().rpc_timeout() {
             Some(duration) => {
                 let start = std::time::Instant::now();
+                cx.extensions_mut().insert(Deadline(start + duration));
                 match tokio::time::timeout(duration, self.inner.call(cx, req)).await {
                     Ok(r) => r.map_err(Into::into),
                     Err(_) => {
@@ -21,9 +21,13 @@ use volo::{
     client::WithOptService,
     context::{Context, Endpoint, Role, RpcInfo},
     discovery::{Discover, DummyDiscover},
-    loadbalance::{random::WeightedRandomBalance, LbConfig, MkLbLayer},
+    loadbalance::{
+        hedge::HedgePolicy, outlier::OutlierDetection, random::WeightedRandomBalance,
+        retry::RetryPolicy, LbConfig, MkLbLayer,
+    },
     net::{
         dial::{DefaultMakeTransport, MakeTransport},
+        socket::SocketConfig,
         Address,
     },
     FastStr,
@@ -34,7 +38,7 @@ use crate::{
         default::{framed::MakeFramedCodec, thrift::MakeThriftCodec, ttheader::MakeTTHeaderCodec},
         DefaultMakeCodec, MakeCodec,
     },
-    context::{ClientContext, Config, CLIENT_CONTEXT_CACHE},
+    context::{ClientContext, Config, ForkFn, CLIENT_CONTEXT_CACHE},
     transport::{pingpong, pool},
     ClientError, EntryMessage, ThriftMessage,
 };
@@ -44,11 +48,13 @@ pub use callopt::CallOpt;
 
 use self::layer::timeout::TimeoutLayer;
 
+pub mod health_check;
 pub mod layer;
 
 pub struct ClientBuilder<IL, OL, MkClient, Req, Resp, MkT, MkC, LB> {
     config: Config,
     pool: Option<pool::Config>,
+    socket_config: Option<SocketConfig>,
     callee_name: FastStr,
     caller_name: FastStr,
     address: Option<Address>, // maybe address use Arc avoid memory alloc
@@ -83,6 +89,7 @@ impl<C, Req, Resp>
         ClientBuilder {
             config: Default::default(),
             pool: None,
+            socket_config: None,
             caller_name: "".into(),
             callee_name: FastStr::new(service_name),
             address: None,
@@ -103,16 +110,21 @@ impl<C, Req, Resp>
     }
 }
 
-impl<IL, OL, C, Req, Resp, MkT, MkC, LB, DISC>
-    ClientBuilder<IL, OL, C, Req, Resp, MkT, MkC, LbConfig<LB, DISC>>
+/// The [`ClientBuilder`] with the [`LbConfig`].
+type LbClientBuilder<IL, OL, C, Req, Resp, MkT, MkC, LB, DISC, RC> =
+    ClientBuilder<IL, OL, C, Req, Resp, MkT, MkC, LbConfig<LB, DISC, RC>>;
+
+impl<IL, OL, C, Req, Resp, MkT, MkC, LB, DISC, RC>
+    LbClientBuilder<IL, OL, C, Req, Resp, MkT, MkC, LB, DISC, RC>
 {
     pub fn load_balance<NLB>(
         self,
         load_balance: NLB,
-    ) -> ClientBuilder<IL, OL, C, Req, Resp, MkT, MkC, LbConfig<NLB, DISC>> {
+    ) -> LbClientBuilder<IL, OL, C, Req, Resp, MkT, MkC, NLB, DISC, RC> {
         ClientBuilder {
             config: self.config,
             pool: self.pool,
+            socket_config: self.socket_config,
             caller_name: self.caller_name,
             callee_name: self.callee_name,
             address: self.address,
@@ -135,10 +147,11 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB, DISC>
     pub fn discover<NDISC>(
         self,
         discover: NDISC,
-    ) -> ClientBuilder<IL, OL, C, Req, Resp, MkT, MkC, LbConfig<LB, NDISC>> {
+    ) -> LbClientBuilder<IL, OL, C, Req, Resp, MkT, MkC, LB, NDISC, RC> {
         ClientBuilder {
             config: self.config,
             pool: self.pool,
+            socket_config: self.socket_config,
             caller_name: self.caller_name,
             callee_name: self.callee_name,
             address: self.address,
@@ -163,6 +176,53 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB, DISC>
         self.mk_lb = self.mk_lb.retry_count(count);
         self
     }
+
+    /// Sets the retry policy of the client, which replaces the retry count set before.
+    ///
+    /// The retries are skipped when the rest of the rpc timeout is not enough for another
+    /// attempt. The classifier of the policy is given the result of the client, which is
+    /// `Result<&Option<Resp>, &ClientError>`.
+    pub fn retry_policy<NRC>(
+        self,
+        policy: RetryPolicy<NRC>,
+    ) -> LbClientBuilder<IL, OL, C, Req, Resp, MkT, MkC, LB, DISC, NRC> {
+        ClientBuilder {
+            config: self.config,
+            pool: self.pool,
+            socket_config: self.socket_config,
+            caller_name: self.caller_name,
+            callee_name: self.callee_name,
+            address: self.address,
+            inner_layer: self.inner_layer,
+            outer_layer: self.outer_layer,
+            mk_client: self.mk_client,
+            _marker: PhantomData,
+            make_transport: self.make_transport,
+            make_codec: self.make_codec,
+            mk_lb: self.mk_lb.retry_policy(policy),
+
+            disable_timeout_layer: self.disable_timeout_layer,
+            enable_biz_error: self.enable_biz_error,
+
+            #[cfg(feature = "multiplex")]
+            multiplex: self.multiplex,
+        }
+    }
+
+    /// Enables the hedged requests of the client for the methods of the policy.
+    ///
+    /// Hedging can also be enabled for a single call by [`CallOpt::with_hedge_delay`].
+    pub fn hedge_policy(mut self, policy: HedgePolicy) -> Self {
+        self.mk_lb = self.mk_lb.hedge_policy(policy);
+        self
+    }
+
+    /// Enables the outlier detection of the client, which ejects the instances that keep
+    /// failing for a while.
+    pub fn outlier_detection(mut self, config: OutlierDetection) -> Self {
+        self.mk_lb = self.mk_lb.outlier_detection(config);
+        self
+    }
 }
 
 impl<IL, OL, C, Req, Resp, MkT, MkC, LB> ClientBuilder<IL, OL, C, Req, Resp, MkT, MkC, LB> {
@@ -176,6 +236,15 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB> ClientBuilder<IL, OL, C, Req, Resp, MkT
         self
     }
 
+    /// Sets the function copying the tags and the extensions of the context to the forked one
+    /// of a hedged request, which are not copied by default since they are not cloneable.
+    ///
+    /// Without it, the calls whose callee has any tags are not hedged.
+    pub fn fork_with(mut self, f: ForkFn) -> Self {
+        self.config.set_fork_with(Some(f));
+        self
+    }
+
     /// Sets the config for connection pool.
     pub fn pool_config(mut self, config: pool::Config) -> Self {
         self.pool = Some(config);
@@ -194,6 +263,12 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB> ClientBuilder<IL, OL, C, Req, Resp, MkT
         self
     }
 
+    /// Sets the options of the TCP sockets, such as `TCP_NODELAY` and the TCP keepalive.
+    pub fn socket_config(mut self, config: SocketConfig) -> Self {
+        self.socket_config = Some(config);
+        self
+    }
+
     /// Sets the client's name sent to the server.
     pub fn caller_name(mut self, name: impl AsRef<str>) -> Self {
         self.caller_name = FastStr::new(name);
@@ -223,6 +298,7 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB> ClientBuilder<IL, OL, C, Req, Resp, MkT
         ClientBuilder {
             config: self.config,
             pool: self.pool,
+            socket_config: self.socket_config,
             caller_name: self.caller_name,
             callee_name: self.callee_name,
             address: self.address,
@@ -257,6 +333,7 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB> ClientBuilder<IL, OL, C, Req, Resp, MkT
         ClientBuilder {
             config: self.config,
             pool: self.pool,
+            socket_config: self.socket_config,
             caller_name: self.caller_name,
             callee_name: self.callee_name,
             address: self.address,
@@ -285,6 +362,7 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB> ClientBuilder<IL, OL, C, Req, Resp, MkT
         ClientBuilder {
             config: self.config,
             pool: self.pool,
+            socket_config: self.socket_config,
             caller_name: self.caller_name,
             callee_name: self.callee_name,
             address: self.address,
@@ -334,6 +412,7 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB> ClientBuilder<IL, OL, C, Req, Resp, MkT
         ClientBuilder {
             config: self.config,
             pool: self.pool,
+            socket_config: self.socket_config,
             caller_name: self.caller_name,
             callee_name: self.callee_name,
             address: self.address,
@@ -373,6 +452,7 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB> ClientBuilder<IL, OL, C, Req, Resp, MkT
         ClientBuilder {
             config: self.config,
             pool: self.pool,
+            socket_config: self.socket_config,
             caller_name: self.caller_name,
             callee_name: self.callee_name,
             address: self.address,
@@ -412,6 +492,7 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB> ClientBuilder<IL, OL, C, Req, Resp, MkT
         ClientBuilder {
             config: self.config,
             pool: self.pool,
+            socket_config: self.socket_config,
             caller_name: self.caller_name,
             callee_name: self.callee_name,
             address: self.address,
@@ -451,6 +532,7 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB> ClientBuilder<IL, OL, C, Req, Resp, MkT
         ClientBuilder {
             config: self.config,
             pool: self.pool,
+            socket_config: self.socket_config,
             caller_name: self.caller_name,
             callee_name: self.callee_name,
             address: self.address,
@@ -477,6 +559,7 @@ impl<IL, OL, C, Req, Resp, MkT, MkC, LB> ClientBuilder<IL, OL, C, Req, Resp, MkT
         ClientBuilder {
             config: self.config,
             pool: self.pool,
+            socket_config: self.socket_config,
             caller_name: self.caller_name,
             callee_name: self.callee_name,
             address: self.address,
@@ -593,6 +676,9 @@ where
         if let Some(timeout) = self.config.read_write_timeout() {
             self.make_transport.set_write_timeout(Some(timeout));
         }
+        if let Some(config) = self.socket_config {
+            self.make_transport.set_socket_config(config);
+        }
         let msg_svc = MessageService {
             #[cfg(not(feature = "multiplex"))]
             inner: pingpong::Client::new(self.make_transport, self.pool, self.make_codec),
@@ -4,7 +4,7 @@ use chrono::{DateTime, Local};
 use paste::paste;
 use pilota::thrift::TMessageIdentifier;
 use volo::{
-    context::{Context, Reusable, Role, RpcCx, RpcInfo},
+    context::{Context, Deadline, Endpoint, Extensions, Reusable, Role, RpcCx, RpcInfo},
     newtype_impl_context,
 };
 
@@ -205,7 +205,67 @@ pub struct ServerCxInner {
 #[derive(Debug)]
 pub struct ClientContext(pub(crate) RpcCx<ClientCxInner, Config>);
 
-newtype_impl_context!(ClientContext, Config, 0);
+impl Context for ClientContext {
+    type Config = Config;
+
+    #[inline]
+    fn rpc_info(&self) -> &RpcInfo<Self::Config> {
+        self.0.rpc_info()
+    }
+
+    #[inline]
+    fn rpc_info_mut(&mut self) -> &mut RpcInfo<Self::Config> {
+        self.0.rpc_info_mut()
+    }
+
+    #[inline]
+    fn extensions_mut(&mut self) -> &mut Extensions {
+        self.0.extensions_mut()
+    }
+
+    #[inline]
+    fn extensions(&self) -> &Extensions {
+        self.0.extensions()
+    }
+
+    /// Forks the context with the method, the service names and the addresses of the caller and
+    /// the callee, the config and the [`Deadline`].
+    ///
+    /// The tags and the other extensions are type-erased and cannot be cloned, so they are copied
+    /// by the [`ForkFn`] set by [`Config::set_fork_with`] if any. Without it, the context is not
+    /// forked if the callee has any tags, since a hedged request without them may be sent wrongly.
+    fn fork(&self) -> Option<Self> {
+        let rpc_info = &self.rpc_info;
+        let fork_with = rpc_info.config().fork_with();
+        let callee = rpc_info.callee();
+        if fork_with.is_none() && (!callee.faststr_tags.is_empty() || !callee.tags.is_empty()) {
+            return None;
+        }
+        let fork_endpoint = |endpoint: &Endpoint| {
+            let mut forked = Endpoint::new(endpoint.service_name());
+            forked.address = endpoint.address();
+            forked
+        };
+        let mut cx = Self::new(
+            self.seq_id,
+            RpcInfo::new(
+                rpc_info.role(),
+                rpc_info.method().clone(),
+                fork_endpoint(rpc_info.caller()),
+                fork_endpoint(rpc_info.callee()),
+                *rpc_info.config(),
+            ),
+            self.message_type,
+        );
+        if let Some(deadline) = self.extensions.get::<Deadline>() {
+            cx.extensions.insert(*deadline);
+        }
+        if let Some(fork_with) = fork_with {
+            fork_with(self, &mut cx);
+        }
+        Some(cx)
+    }
+}
 
 impl ClientContext {
     #[inline]
@@ -386,8 +446,13 @@ pub struct Config {
     rpc_timeout: Option<Duration>,
     connect_timeout: Option<Duration>,
     read_write_timeout: Option<Duration>,
+    hedge_delay: Option<Duration>,
+    fork_with: Option<ForkFn>,
 }
 
+/// Copies the tags and the extensions of the context to the forked one of a hedged request.
+pub type ForkFn = fn(&ClientContext, &mut ClientContext);
+
 impl Config {
     #[inline]
     pub fn new() -> Self {
@@ -395,6 +460,8 @@ impl Config {
             rpc_timeout: None,
             connect_timeout: None,
             read_write_timeout: None,
+            hedge_delay: None,
+            fork_with: None,
         }
     }
 
@@ -449,6 +516,30 @@ impl Config {
         self.read_write_timeout = timeout;
     }
 
+    #[inline]
+    pub fn hedge_delay(&self) -> Option<Duration> {
+        self.hedge_delay
+    }
+
+    /// Sets the hedge delay, which is only set by the CallOpt.
+    #[inline]
+    pub(crate) fn set_hedge_delay(&mut self, delay: Option<Duration>) {
+        self.hedge_delay = delay;
+    }
+
+    #[inline]
+    pub fn fork_with(&self) -> Option<ForkFn> {
+        self.fork_with
+    }
+
+    /// Sets the function copying the tags and the extensions to the forked context.
+    ///
+    /// This can be set both by the client builder and the CallOpt.
+    #[inline]
+    pub fn set_fork_with(&mut self, f: Option<ForkFn>) {
+        self.fork_with = f;
+    }
+
     #[inline]
     pub fn merge(&mut self, other: Self) {
         if let Some(t) = other.rpc_timeout {
@@ -460,6 +551,12 @@ impl Config {
         if let Some(t) = other.read_write_timeout {
             self.read_write_timeout = Some(t);
         }
+        if let Some(t) = other.hedge_delay {
+            self.hedge_delay = Some(t);
+        }
+        if let Some(f) = other.fork_with {
+            self.fork_with = Some(f);
+        }
     }
 }
 
@@ -468,6 +565,8 @@ impl Reusable for Config {
         self.rpc_timeout = None;
         self.connect_timeout = None;
         self.read_write_timeout = None;
+        self.hedge_delay = None;
+        self.fork_with = None;
     }
 }
 
@@ -495,12 +594,18 @@ impl ::volo::client::Apply<ClientContext> for CallOpt {
             callee.set_address(addr);
         }
         cx.rpc_info.config_mut().merge(self.config);
+        if let Some(delay) = cx.rpc_info.config().hedge_delay() {
+            cx.extensions_mut()
+                .insert(volo::loadbalance::hedge::HedgeDelay(delay));
+        }
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use volo::context::Context;
+
     use super::{Role, RpcInfo};
     use crate::context::ClientContext;
 
@@ -514,4 +619,42 @@ mod tests {
         .rpc_info;
         println!("{:?}", ri);
     }
+
+    #[test]
+    fn test_fork_with() {
+        struct Tag;
+
+        let mut cx = ClientContext::new(
+            1,
+            RpcInfo::with_role(Role::Client),
+            pilota::thrift::TMessageType::Call,
+        );
+        assert!(cx.fork().is_some());
+
+        cx.rpc_info_mut()
+            .callee_mut()
+            .insert_faststr::<Tag>("tag".into());
+        // the tag cannot be copied without the fork function
+        assert!(cx.fork().is_none());
+
+        cx.rpc_info_mut()
+            .config_mut()
+            .set_fork_with(Some(|cx, forked| {
+                if let Some(tag) = cx.rpc_info().callee().get_faststr::<Tag>() {
+                    forked
+                        .rpc_info_mut()
+                        .callee_mut()
+                        .insert_faststr::<Tag>(tag.clone());
+                }
+            }));
+        let forked = cx.fork().unwrap();
+        assert_eq!(
+            forked
+                .rpc_info()
+                .callee()
+                .get_faststr::<Tag>()
+                .map(|t| t.as_str()),
+            Some("tag")
+        );
+    }
 }
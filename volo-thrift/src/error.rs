@@ -6,7 +6,11 @@ pub use pilota::thrift::{
     TransportException,
 };
 use pilota::{AHashMap, FastStr};
-use volo::loadbalance::error::{LoadBalanceError, Retryable};
+use volo::{
+    circuit_breaker::CircuitBreakerError,
+    concurrency_limit::ConcurrencyLimitError,
+    loadbalance::error::{LoadBalanceError, Retryable},
+};
 
 pub type ServerResult<T> = Result<T, ServerError>;
 pub type ClientResult<T> = Result<T, ClientError>;
@@ -19,6 +23,15 @@ pub enum ServerError {
     Biz(#[from] BizError),
 }
 
+impl From<ConcurrencyLimitError> for ServerError {
+    fn from(err: ConcurrencyLimitError) -> Self {
+        ServerError::Application(ApplicationException::new(
+            ApplicationExceptionKind::INTERNAL_ERROR,
+            err.to_string(),
+        ))
+    }
+}
+
 impl From<anyhow::Error> for ServerError {
     fn from(e: anyhow::Error) -> Self {
         e.downcast::<ServerError>().unwrap_or_else(|e| {
@@ -118,6 +131,16 @@ impl Retryable for ClientError {
         }
         false
     }
+
+    fn is_circuit_open(&self) -> bool {
+        match self {
+            Self::Transport(err) => err
+                .io_error()
+                .get_ref()
+                .is_some_and(|source| source.is::<CircuitBreakerError>()),
+            _ => false,
+        }
+    }
 }
 
 impl From<LoadBalanceError> for ClientError {
@@ -130,6 +153,24 @@ impl From<LoadBalanceError> for ClientError {
     }
 }
 
+impl From<CircuitBreakerError> for ClientError {
+    /// The error is a retryable [`TransportException`] whose source is the
+    /// [`CircuitBreakerError`], so the request can be retried on another instance.
+    fn from(err: CircuitBreakerError) -> Self {
+        ClientError::Transport(TransportException::from(io::Error::other(err)))
+    }
+}
+
+impl From<ConcurrencyLimitError> for ClientError {
+    /// The error is not retryable, since the limit is shared by all the instances.
+    fn from(err: ConcurrencyLimitError) -> Self {
+        ClientError::Application(ApplicationException::new(
+            ApplicationExceptionKind::INTERNAL_ERROR,
+            err.to_string(),
+        ))
+    }
+}
+
 impl From<ThriftException> for ClientError {
     fn from(e: ThriftException) -> Self {
         match e {
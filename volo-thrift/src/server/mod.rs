@@ -1,7 +1,7 @@
 use std::{
+    io,
     marker::PhantomData,
     sync::{atomic::Ordering, Arc},
-    time::Duration,
 };
 
 use futures::future::BoxFuture;
@@ -10,19 +10,22 @@ use motore::{
     service::Service,
     BoxError,
 };
-use scopeguard::defer;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     sync::Notify,
 };
-use tracing::{info, trace};
+use tracing::{debug, info, trace};
+#[cfg(target_family = "unix")]
+use volo::hotrestart::{HotRestartConfig, DEFAULT_HOT_RESTART};
 use volo::{
     net::{
         conn::{OwnedReadHalf, OwnedWriteHalf},
         incoming::Incoming,
+        socket::SocketConfig,
         Address,
     },
     service::BoxService,
+    shutdown::{ConnTracker, ShutdownConfig},
 };
 
 use crate::{
@@ -38,6 +41,7 @@ use crate::{
 
 mod layer;
 pub mod panic_handler;
+pub mod rate_limit;
 
 /// This is unstable now and may be changed in the future.
 #[doc(hidden)]
@@ -52,6 +56,10 @@ pub struct Server<S, L, Req, MkC, SP> {
     multiplex: bool,
     span_provider: SP,
     shutdown_hooks: Vec<Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>>,
+    shutdown_config: ShutdownConfig,
+    socket_config: SocketConfig,
+    #[cfg(target_family = "unix")]
+    hot_restart: Option<HotRestartConfig>,
     _marker: PhantomData<Req>,
 }
 
@@ -77,6 +85,10 @@ impl<S, Req>
             multiplex: false,
             span_provider: DefaultProvider {},
             shutdown_hooks: Vec::new(),
+            shutdown_config: ShutdownConfig::default(),
+            socket_config: SocketConfig::default(),
+            #[cfg(target_family = "unix")]
+            hot_restart: None,
             _marker: PhantomData,
         }
     }
@@ -95,6 +107,39 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
         self
     }
 
+    /// Sets the config of the graceful shutdown.
+    ///
+    /// During the shutdown, the responses carry the `crrst` header of TTHeader, which tells the
+    /// clients to close the connections, and the idle connections are closed once the listener
+    /// is closed.
+    ///
+    /// Default is [`ShutdownConfig::default`].
+    pub fn shutdown_config(mut self, config: ShutdownConfig) -> Self {
+        self.shutdown_config = config;
+        self
+    }
+
+    /// Sets the options of the TCP sockets of the accepted connections, such as `TCP_NODELAY`
+    /// and the TCP keepalive.
+    ///
+    /// Default is [`SocketConfig::default`].
+    pub fn socket_config(mut self, config: SocketConfig) -> Self {
+        self.socket_config = config;
+        self
+    }
+
+    /// Enables the hot restart with the config.
+    ///
+    /// The server takes over the listener of the old process if any, and tells the old process
+    /// to stop once it is ready, which then shuts down gracefully with the shutdown config.
+    ///
+    /// Default is disabled.
+    #[cfg(target_family = "unix")]
+    pub fn hot_restart(mut self, config: HotRestartConfig) -> Self {
+        self.hot_restart = Some(config);
+        self
+    }
+
     /// Adds a new inner layer to the server.
     ///
     /// The layer's `Service` should be `Send + Sync + Clone + 'static`.
@@ -116,6 +161,10 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
             multiplex: self.multiplex,
             span_provider: self.span_provider,
             shutdown_hooks: self.shutdown_hooks,
+            shutdown_config: self.shutdown_config,
+            socket_config: self.socket_config,
+            #[cfg(target_family = "unix")]
+            hot_restart: self.hot_restart,
             _marker: PhantomData,
         }
     }
@@ -141,6 +190,10 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
             multiplex: self.multiplex,
             span_provider: self.span_provider,
             shutdown_hooks: self.shutdown_hooks,
+            shutdown_config: self.shutdown_config,
+            socket_config: self.socket_config,
+            #[cfg(target_family = "unix")]
+            hot_restart: self.hot_restart,
             _marker: PhantomData,
         }
     }
@@ -170,15 +223,47 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
             multiplex: self.multiplex,
             span_provider: self.span_provider,
             shutdown_hooks: self.shutdown_hooks,
+            shutdown_config: self.shutdown_config,
+            socket_config: self.socket_config,
+            #[cfg(target_family = "unix")]
+            hot_restart: self.hot_restart,
             _marker: PhantomData,
         }
     }
 
     /// The main entry point for the server.
+    ///
+    /// The server is shut down gracefully on SIGINT, SIGHUP or SIGTERM, or Ctrl-C on windows.
     pub async fn run<MI: volo::net::incoming::MakeIncoming>(
         self,
         make_incoming: MI,
     ) -> Result<(), BoxError>
+    where
+        L: Layer<BoxService<ServerContext, Req, S::Response, crate::ServerError>>,
+        MkC: MakeCodec<OwnedReadHalf, OwnedWriteHalf>,
+        L::Service: Service<ServerContext, Req, Response = S::Response, Error = crate::ServerError>
+            + Send
+            + 'static
+            + Sync,
+        S: Service<ServerContext, Req, Error = crate::ServerError> + Send + 'static + Sync,
+        S::Response: EntryMessage + Send + 'static + Sync,
+        Req: EntryMessage + Send + 'static,
+        SP: SpanProvider,
+    {
+        self.run_with_shutdown(make_incoming, volo::shutdown::signal())
+            .await
+    }
+
+    /// The main entry point for the server.
+    /// Runs server with a stop signal to control graceful shutdown.
+    pub async fn run_with_shutdown<
+        MI: volo::net::incoming::MakeIncoming,
+        F: std::future::Future<Output = io::Result<()>>,
+    >(
+        self,
+        make_incoming: MI,
+        signal: F,
+    ) -> Result<(), BoxError>
     where
         L: Layer<BoxService<ServerContext, Req, S::Response, crate::ServerError>>,
         MkC: MakeCodec<OwnedReadHalf, OwnedWriteHalf>,
@@ -200,11 +285,29 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
         // TODO(lyf1999): type annotation is needed here, figure out why
         let stat_tracer: Arc<[TraceFn]> = Arc::from(self.stat_tracer);
 
+        #[cfg(target_family = "unix")]
+        if let Some(config) = &self.hot_restart {
+            DEFAULT_HOT_RESTART.initialize_servers(config).await?;
+        }
+        // marks the server as ready once it starts accepting, and stops it on the shutdown signal
+        // or the termination by the new process
+        #[cfg(target_family = "unix")]
+        let signal = {
+            let hot_restart = self.hot_restart.is_some();
+            async move {
+                if hot_restart {
+                    DEFAULT_HOT_RESTART.wait_for_shutdown(signal).await
+                } else {
+                    signal.await
+                }
+            }
+        };
+
         let mut incoming = make_incoming.make_incoming().await?;
         info!("[VOLO] server start at: {:?}", incoming);
 
-        let conn_cnt = Arc::new(std::sync::atomic::AtomicUsize::new(0));
-        let gconn_cnt = conn_cnt.clone();
+        let conn_tracker = ConnTracker::new();
+        let gconn_tracker = conn_tracker.clone();
         let (exit_notify, exit_flag, exit_mark) = (
             Arc::new(Notify::const_new()),
             Arc::new(parking_lot::RwLock::new(false)),
@@ -214,7 +317,7 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
             (exit_notify.clone(), exit_flag.clone(), exit_mark.clone());
 
         // spawn accept loop
-        let handler = tokio::spawn(async move {
+        let mut handler = tokio::spawn(async move {
             let exit_flag = exit_flag_inner.clone();
             loop {
                 if *exit_flag.read() {
@@ -222,13 +325,16 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
                 }
                 match incoming.accept().await {
                     Ok(Some(conn)) => {
+                        if let Err(err) = self.socket_config.apply_to_conn(&conn) {
+                            debug!("[VOLO] failed to set the socket options: {:?}", err);
+                        }
                         let peer_addr = conn.info.peer_addr;
                         trace!("[VOLO] accept connection from: {:?}", peer_addr);
                         let (rh, wh) = conn.stream.into_split();
 
                         #[cfg(feature = "multiplex")]
                         if self.multiplex {
-                            tokio::spawn(handle_conn_multiplex(
+                            tokio::spawn(conn_tracker.serve(handle_conn_multiplex(
                                 rh,
                                 wh,
                                 service.clone(),
@@ -236,11 +342,10 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
                                 stat_tracer.clone(),
                                 exit_notify_inner.clone(),
                                 exit_mark_inner.clone(),
-                                conn_cnt.clone(),
                                 peer_addr,
-                            ));
+                            )));
                         } else {
-                            tokio::spawn(handle_conn(
+                            tokio::spawn(conn_tracker.serve(handle_conn(
                                 rh,
                                 wh,
                                 service.clone(),
@@ -248,13 +353,12 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
                                 stat_tracer.clone(),
                                 exit_notify_inner.clone(),
                                 exit_mark_inner.clone(),
-                                conn_cnt.clone(),
                                 peer_addr,
                                 self.span_provider.clone(),
-                            ));
+                            )));
                         }
                         #[cfg(not(feature = "multiplex"))]
-                        tokio::spawn(handle_conn(
+                        tokio::spawn(conn_tracker.serve(handle_conn(
                             rh,
                             wh,
                             service.clone(),
@@ -262,10 +366,9 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
                             stat_tracer.clone(),
                             exit_notify_inner.clone(),
                             exit_mark_inner.clone(),
-                            conn_cnt.clone(),
                             peer_addr,
                             self.span_provider.clone(),
-                        ));
+                        )));
                     }
                     // no more incoming connections
                     Ok(None) => break Ok(()),
@@ -274,40 +377,10 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
             }
         });
 
-        #[cfg(target_family = "unix")]
-        {
-            // graceful shutdown
-            let mut sigint =
-                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())?;
-            let mut sighup =
-                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
-            let mut sigterm =
-                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
-
-            // graceful shutdown handler
-            tokio::select! {
-                _ = sigint.recv() => {}
-                _ = sighup.recv() => {}
-                _ = sigterm.recv() => {}
-                res = handler => {
-                    match res {
-                        Ok(res) => {
-                            match res {
-                                Ok(()) => {}
-                                Err(e) => return Err(Box::new(e))
-                            };
-                        }
-                        Err(e) => return Err(Box::new(e)),
-                    }
-                }
-            }
-        }
-
-        // graceful shutdown handler for windows
-        #[cfg(target_family = "windows")]
+        // graceful shutdown handler
         tokio::select! {
-            _ = tokio::signal::ctrl_c() => {}
-            res = handler => {
+            res = signal => res?,
+            res = &mut handler => {
                 match res {
                     Ok(res) => {
                         match res {
@@ -330,27 +403,23 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
 
         // received signal, graceful shutdown now
         info!("[VOLO] received signal, gracefully exiting now");
-        *exit_flag.write() = true;
+        // From now on, the responses tell the peers to close the connections by crrst.
         exit_mark.store(true, Ordering::Relaxed);
+        self.shutdown_config.wait_grace_period().await;
 
         // Now we won't accept new connections.
-        // And we want to send crrst reply to the peers in the short future.
-        if gconn_cnt.load(Ordering::Relaxed) != 0 {
-            tokio::time::sleep(Duration::from_secs(2)).await;
-        }
+        *exit_flag.write() = true;
+        // stops accepting and closes the listener
+        handler.abort();
+        // closes the idle connections, and the busy ones after the in-flight requests
         exit_notify.notify_waiters();
 
         // wait for all connections to be closed
-        for _ in 0..28 {
-            if gconn_cnt.load(Ordering::Relaxed) == 0 {
-                break;
-            }
-            trace!(
-                "[VOLO] gracefully exiting, remaining connection count: {}",
-                gconn_cnt.load(Ordering::Relaxed)
-            );
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
+        trace!(
+            "[VOLO] gracefully exiting, remaining connection count: {}",
+            gconn_tracker.count()
+        );
+        self.shutdown_config.drain(&gconn_tracker).await;
         Ok(())
     }
 
@@ -368,6 +437,10 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
             multiplex,
             span_provider: self.span_provider,
             shutdown_hooks: self.shutdown_hooks,
+            shutdown_config: self.shutdown_config,
+            socket_config: self.socket_config,
+            #[cfg(target_family = "unix")]
+            hot_restart: self.hot_restart,
             _marker: PhantomData,
         }
     }
@@ -382,6 +455,10 @@ impl<S, L, Req, MkC, SP> Server<S, L, Req, MkC, SP> {
             multiplex: self.multiplex,
             span_provider: provider,
             shutdown_hooks: self.shutdown_hooks,
+            shutdown_config: self.shutdown_config,
+            socket_config: self.socket_config,
+            #[cfg(target_family = "unix")]
+            hot_restart: self.hot_restart,
             _marker: PhantomData,
         }
     }
@@ -396,7 +473,6 @@ async fn handle_conn<R, W, Req, Svc, Resp, MkC, SP>(
     stat_tracer: Arc<[TraceFn]>,
     exit_notify: Arc<Notify>,
     exit_mark: Arc<std::sync::atomic::AtomicBool>,
-    conn_cnt: Arc<std::sync::atomic::AtomicUsize>,
     peer_addr: Option<Address>,
     span_provider: SP,
 ) where
@@ -410,11 +486,6 @@ async fn handle_conn<R, W, Req, Svc, Resp, MkC, SP>(
     MkC: MakeCodec<R, W>,
     SP: SpanProvider,
 {
-    conn_cnt.fetch_add(1, Ordering::Relaxed);
-    defer! {
-        conn_cnt.fetch_sub(1, Ordering::Relaxed);
-    }
-
     let (encoder, decoder) = make_codec.make_codec(rh, wh);
 
     tracing::trace!(
@@ -444,7 +515,6 @@ async fn handle_conn_multiplex<R, W, Req, Svc, Resp, MkC>(
     stat_tracer: Arc<[TraceFn]>,
     exit_notify: Arc<Notify>,
     exit_mark: Arc<std::sync::atomic::AtomicBool>,
-    conn_cnt: Arc<std::sync::atomic::AtomicUsize>,
     peer_addr: Option<Address>,
 ) where
     R: AsyncRead + Unpin + Send + Sync + 'static,
@@ -455,10 +525,6 @@ async fn handle_conn_multiplex<R, W, Req, Svc, Resp, MkC>(
     Resp: EntryMessage + Send + 'static,
     MkC: MakeCodec<R, W>,
 {
-    conn_cnt.fetch_add(1, Ordering::Relaxed);
-    defer! {
-        conn_cnt.fetch_sub(1, Ordering::Relaxed);
-    }
     let (encoder, decoder) = make_codec.make_codec(rh, wh);
 
     info!(
@@ -475,5 +541,4 @@ async fn handle_conn_multiplex<R, W, Req, Svc, Resp, MkC>(
         peer_addr,
     )
     .await;
-    conn_cnt.fetch_sub(1, Ordering::Relaxed);
 }
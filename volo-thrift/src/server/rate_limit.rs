@@ -0,0 +1,22 @@
+//! Handlers for [`volo::rate_limit::Layer`].
+
+use pilota::thrift::{ApplicationException, ApplicationExceptionKind};
+use volo::rate_limit::RateLimitError;
+
+use crate::{context::ServerContext, ServerError};
+
+/// This handler rejects the request with an `ApplicationException` of the kind
+/// `INTERNAL_ERROR` to the client, whose message is the [`RateLimitError`], such as
+/// `rate limit exceeded for {key}`.
+#[inline(never)]
+pub fn reject_with_exception<Resp>(
+    cx: &mut ServerContext,
+    err: RateLimitError,
+) -> Result<Resp, ServerError> {
+    // the rejections are logged at debug level since they may be as many as the requests
+    tracing::debug!("[Volo-Thrift] request rejected: {}, cx: {:?}", err, cx);
+    Err(ServerError::Application(ApplicationException::new(
+        ApplicationExceptionKind::INTERNAL_ERROR,
+        err.to_string(),
+    )))
+}
@@ -0,0 +1,540 @@
+//! A layer that stops sending requests to a failing method or instance for a while.
+//!
+//! The [`Layer`] tracks the failure rate and the slow call rate of each method of each callee
+//! instance over a sliding window. When either of them reaches the threshold, the circuit is
+//! opened and the requests fail fast with [`CircuitBreakerError`]. After the open duration, a
+//! few probing requests are let through in the half-open state, and the circuit is closed again
+//! if all of them succeed.
+//!
+//! A call is considered failed if the error is [`Retryable`], which means it is probably caused
+//! by the callee or the network rather than by the business logic.
+//!
+//! The layer should be put inside the load balance layer, so that the address of the callee is
+//! known, e.g. by `layer_inner` of the client builders. Otherwise the circuits are only keyed by
+//! the method.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use faststr::FastStr;
+
+use crate::{context::Context, loadbalance::error::Retryable, net::Address};
+
+/// The configuration of the circuit breaker.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreakerConfig {
+    window: Duration,
+    buckets: u32,
+    min_requests: u32,
+    failure_rate: f64,
+    slow_call_duration: Duration,
+    slow_call_rate: f64,
+    open_duration: Duration,
+    half_open_calls: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            buckets: 10,
+            min_requests: 20,
+            failure_rate: 0.5,
+            slow_call_duration: Duration::from_secs(1),
+            slow_call_rate: f64::INFINITY,
+            open_duration: Duration::from_secs(5),
+            half_open_calls: 5,
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the length of the sliding window and the number of buckets it is split into.
+    ///
+    /// Default is 10 seconds with 10 buckets.
+    pub fn window(mut self, window: Duration, buckets: u32) -> Self {
+        self.window = window;
+        self.buckets = buckets.max(1);
+        self
+    }
+
+    /// Sets the min number of requests in the window before the circuit can be opened.
+    ///
+    /// Default is 20.
+    pub fn min_requests(mut self, min_requests: u32) -> Self {
+        self.min_requests = min_requests;
+        self
+    }
+
+    /// Sets the failure rate in the window that opens the circuit.
+    ///
+    /// Default is 0.5, and a rate greater than 1.0 disables it.
+    pub fn failure_rate(mut self, rate: f64) -> Self {
+        self.failure_rate = rate;
+        self
+    }
+
+    /// Sets the rate of the calls slower than `duration` in the window that opens the circuit.
+    ///
+    /// Default is disabled.
+    pub fn slow_call(mut self, duration: Duration, rate: f64) -> Self {
+        self.slow_call_duration = duration;
+        self.slow_call_rate = rate;
+        self
+    }
+
+    /// Sets how long the circuit stays open before probing.
+    ///
+    /// Default is 5 seconds.
+    pub fn open_duration(mut self, duration: Duration) -> Self {
+        self.open_duration = duration;
+        self
+    }
+
+    /// Sets the number of probing requests in the half-open state, all of which should succeed
+    /// to close the circuit.
+    ///
+    /// Default is 5.
+    pub fn half_open_calls(mut self, calls: u32) -> Self {
+        self.half_open_calls = calls.max(1);
+        self
+    }
+}
+
+/// The state of a circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests are let through and the results are recorded.
+    Closed,
+    /// Requests fail fast.
+    Open,
+    /// A limited number of requests are let through to probe whether the callee recovers.
+    HalfOpen,
+}
+
+/// The error returned when the circuit is open.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerError {
+    pub method: FastStr,
+    pub address: Option<Address>,
+}
+
+impl fmt::Display for CircuitBreakerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit breaker is open for method `{}`", self.method)?;
+        if let Some(address) = &self.address {
+            write!(f, " of {address}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CircuitBreakerError {}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Bucket {
+    /// The index of the bucket since the circuit is created, used to find out the stale buckets.
+    index: u64,
+    requests: u32,
+    failures: u32,
+    slow_calls: u32,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { in_flight: u32, successes: u32 },
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: State,
+    buckets: Vec<Bucket>,
+    last_used: Instant,
+}
+
+#[derive(Debug)]
+struct Breaker {
+    config: CircuitBreakerConfig,
+    created: Instant,
+    circuit: Mutex<Circuit>,
+}
+
+impl Breaker {
+    fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            created: Instant::now(),
+            circuit: Mutex::new(Circuit {
+                state: State::Closed,
+                buckets: vec![Bucket::default(); config.buckets as usize],
+                last_used: Instant::now(),
+            }),
+        }
+    }
+
+    fn bucket_index(&self, now: Instant) -> u64 {
+        let bucket_duration = self.config.window / self.config.buckets;
+        (now.duration_since(self.created).as_nanos() / bucket_duration.as_nanos().max(1)) as u64
+    }
+
+    fn state(&self) -> CircuitState {
+        match self.circuit.lock().unwrap().state {
+            State::Closed => CircuitState::Closed,
+            State::Open { until } if until > Instant::now() => CircuitState::Open,
+            _ => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Returns whether the request can be sent, and whether it is a probing one.
+    fn acquire(&self) -> Option<bool> {
+        let mut guard = self.circuit.lock().unwrap();
+        let circuit = &mut *guard;
+        circuit.last_used = Instant::now();
+        match &mut circuit.state {
+            State::Closed => Some(false),
+            State::Open { until } => {
+                if *until > Instant::now() {
+                    return None;
+                }
+                circuit.state = State::HalfOpen {
+                    in_flight: 1,
+                    successes: 0,
+                };
+                Some(true)
+            }
+            State::HalfOpen { in_flight, .. } => {
+                if *in_flight >= self.config.half_open_calls {
+                    return None;
+                }
+                *in_flight += 1;
+                Some(true)
+            }
+        }
+    }
+
+    fn record(&self, probe: bool, success: bool, latency: Duration) {
+        let now = Instant::now();
+        let slow = latency >= self.config.slow_call_duration && self.config.slow_call_rate <= 1.0;
+        let mut guard = self.circuit.lock().unwrap();
+        let circuit = &mut *guard;
+        match &mut circuit.state {
+            State::HalfOpen {
+                in_flight,
+                successes,
+            } if probe => {
+                if !success || slow {
+                    tracing::warn!("[VOLO] circuit breaker probing failed, reopen the circuit");
+                    circuit.state = State::Open {
+                        until: now + self.config.open_duration,
+                    };
+                    return;
+                }
+                *in_flight -= 1;
+                *successes += 1;
+                if *successes >= self.config.half_open_calls {
+                    tracing::info!("[VOLO] circuit breaker closes the circuit");
+                    circuit.state = State::Closed;
+                    circuit.buckets.fill(Bucket::default());
+                }
+            }
+            State::Closed if !probe => {
+                let index = self.bucket_index(now);
+                let len = circuit.buckets.len();
+                let bucket = &mut circuit.buckets[(index % len as u64) as usize];
+                if bucket.index != index {
+                    *bucket = Bucket {
+                        index,
+                        ..Default::default()
+                    };
+                }
+                bucket.requests += 1;
+                bucket.failures += !success as u32;
+                bucket.slow_calls += slow as u32;
+
+                let (mut requests, mut failures, mut slow_calls) = (0, 0, 0);
+                for bucket in circuit.buckets.iter() {
+                    if bucket.index + len as u64 > index {
+                        requests += bucket.requests;
+                        failures += bucket.failures;
+                        slow_calls += bucket.slow_calls;
+                    }
+                }
+                if requests < self.config.min_requests.max(1) {
+                    return;
+                }
+                let requests = requests as f64;
+                if failures as f64 >= self.config.failure_rate * requests
+                    || slow_calls as f64 >= self.config.slow_call_rate * requests
+                {
+                    tracing::warn!(
+                        "[VOLO] circuit breaker opens the circuit, failures: {}, slow calls: {}, \
+                         requests: {}",
+                        failures,
+                        slow_calls,
+                        requests
+                    );
+                    circuit.state = State::Open {
+                        until: now + self.config.open_duration,
+                    };
+                }
+            }
+            // the result of a call started in another state is stale
+            _ => {}
+        }
+    }
+}
+
+/// Records the result of a call when finished, or as a failure when dropped, e.g. the call is
+/// cancelled by a timeout.
+struct Permit<'a> {
+    breaker: &'a Breaker,
+    probe: bool,
+    start: Instant,
+    finished: bool,
+}
+
+impl Permit<'_> {
+    fn finish(mut self, success: bool) {
+        self.finished = true;
+        self.breaker
+            .record(self.probe, success, self.start.elapsed());
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.breaker.record(self.probe, false, self.start.elapsed());
+        }
+    }
+}
+
+type Key = (FastStr, Option<Address>);
+
+/// The circuits of the methods and the instances.
+///
+/// A circuit unused for longer than both the window and the open duration is evicted when a new
+/// one is created, since its records are all stale and it would not be open any more, so the
+/// circuits of the instances gone are not kept forever.
+struct Breakers {
+    config: CircuitBreakerConfig,
+    map: DashMap<Key, Arc<Breaker>>,
+    last_evicted: Mutex<Instant>,
+}
+
+impl Breakers {
+    fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            map: DashMap::new(),
+            last_evicted: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        self.config.window.max(self.config.open_duration)
+    }
+
+    fn get(&self, key: Key) -> Arc<Breaker> {
+        if let Some(breaker) = self.map.get(&key) {
+            return breaker.clone();
+        }
+        self.evict();
+        self.map
+            .entry(key)
+            .or_insert_with(|| Arc::new(Breaker::new(self.config)))
+            .clone()
+    }
+
+    /// Evicts the idle circuits, at most once in the idle timeout.
+    fn evict(&self) {
+        let idle_timeout = self.idle_timeout();
+        {
+            let mut last_evicted = self.last_evicted.lock().unwrap();
+            if last_evicted.elapsed() < idle_timeout {
+                return;
+            }
+            *last_evicted = Instant::now();
+        }
+        self.map.retain(|_, breaker| {
+            // a circuit in use may have calls in flight
+            Arc::strong_count(breaker) > 1
+                || breaker.circuit.lock().unwrap().last_used.elapsed() < idle_timeout
+        });
+    }
+}
+
+/// A layer that breaks the circuit of a failing method or instance.
+///
+/// The circuits are shared by all the services made by the clones of the layer.
+#[derive(Clone)]
+pub struct Layer {
+    breakers: Arc<Breakers>,
+}
+
+impl Layer {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            breakers: Arc::new(Breakers::new(config)),
+        }
+    }
+
+    /// Returns the state of the circuit of the method and the instance.
+    pub fn state(&self, method: &FastStr, address: Option<&Address>) -> CircuitState {
+        self.breakers
+            .map
+            .get(&(method.clone(), address.cloned()))
+            .map_or(CircuitState::Closed, |breaker| breaker.state())
+    }
+}
+
+impl Default for Layer {
+    fn default() -> Self {
+        Self::new(CircuitBreakerConfig::default())
+    }
+}
+
+impl<S> crate::layer::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Service {
+            inner,
+            breakers: self.breakers,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Service<S> {
+    inner: S,
+    breakers: Arc<Breakers>,
+}
+
+impl<Cx, Req, S> crate::Service<Cx, Req> for Service<S>
+where
+    Cx: Context + Send + 'static,
+    Req: Send + 'static,
+    S: crate::Service<Cx, Req> + Send + Sync + 'static,
+    S::Error: Retryable,
+    CircuitBreakerError: Into<S::Error>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    async fn call(&self, cx: &mut Cx, req: Req) -> Result<Self::Response, Self::Error> {
+        let method = cx.rpc_info().method().clone();
+        let address = cx.rpc_info().callee().address();
+        let breaker = self.breakers.get((method, address));
+        let Some(probe) = breaker.acquire() else {
+            let (method, address) = (cx.rpc_info().method(), cx.rpc_info().callee().address());
+            return Err(CircuitBreakerError {
+                method: method.clone(),
+                address,
+            }
+            .into());
+        };
+        let permit = Permit {
+            breaker: &breaker,
+            probe,
+            start: Instant::now(),
+            finished: false,
+        };
+        let result = self.inner.call(cx, req).await;
+        permit.finish(!matches!(&result, Err(err) if err.retryable()));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Breaker, Breakers, CircuitBreakerConfig, CircuitState};
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig::new()
+            .min_requests(4)
+            .failure_rate(0.5)
+            .open_duration(Duration::from_millis(20))
+            .half_open_calls(2)
+    }
+
+    #[test]
+    fn test_open_and_close() {
+        let breaker = Breaker::new(config());
+        for success in [true, false, true] {
+            let probe = breaker.acquire().unwrap();
+            breaker.record(probe, success, Duration::ZERO);
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record(false, false, Duration::ZERO);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.acquire().is_none());
+
+        std::thread::sleep(Duration::from_millis(30));
+        // only `half_open_calls` probes are let through
+        assert_eq!(breaker.acquire(), Some(true));
+        assert_eq!(breaker.acquire(), Some(true));
+        assert_eq!(breaker.acquire(), None);
+        breaker.record(true, true, Duration::ZERO);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.record(true, true, Duration::ZERO);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(breaker.acquire(), Some(false));
+    }
+
+    #[test]
+    fn test_reopen_on_probe_failure() {
+        let breaker = Breaker::new(config());
+        for _ in 0..4 {
+            breaker.record(false, false, Duration::ZERO);
+        }
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(breaker.acquire(), Some(true));
+        breaker.record(true, false, Duration::ZERO);
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_slow_calls() {
+        let breaker = Breaker::new(
+            config()
+                .failure_rate(2.0)
+                .slow_call(Duration::from_millis(100), 0.5),
+        );
+        for latency in [10, 200, 10, 200] {
+            breaker.record(false, true, Duration::from_millis(latency));
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_evict_idle() {
+        let breakers = Breakers::new(
+            config()
+                .window(Duration::from_millis(20), 2)
+                .open_duration(Duration::from_millis(20)),
+        );
+        let in_use = breakers.get(("foo".into(), None));
+        let _ = breakers.get(("bar".into(), None));
+        assert_eq!(breakers.map.len(), 2);
+
+        std::thread::sleep(Duration::from_millis(30));
+        let _ = breakers.get(("baz".into(), None));
+        // `bar` is evicted, while `foo` is in use
+        assert_eq!(breakers.map.len(), 2);
+        assert!(breakers.map.contains_key(&("foo".into(), None)));
+        drop(in_use);
+    }
+}
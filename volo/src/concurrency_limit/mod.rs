@@ -0,0 +1,592 @@
+//! A layer that limits the in-flight requests with a limit adapting to the observed latency.
+//!
+//! The limit is updated by a [`LimitAlgorithm`] with the latency of each request, such as
+//! [`Vegas`] and [`Gradient2`] in the style of Netflix's concurrency-limits, so that it follows
+//! the capacity of the service instead of being configured statically. The requests exceeding the
+//! limit are rejected immediately by the [`Handler`] rather than queued.
+//!
+//! It works on both the client side and the server side, and the current limit can be read from
+//! the [`Limiter`] for metrics.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// A sample of a finished request.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    /// The latency of the request.
+    pub rtt: Duration,
+    /// The in-flight requests when the request started, including itself.
+    pub in_flight: usize,
+    /// Whether the request was dropped before finishing, such as by a timeout.
+    pub dropped: bool,
+}
+
+/// The algorithm that updates the concurrency limit with the samples.
+pub trait LimitAlgorithm: Send + 'static {
+    /// Returns the limit before any sample.
+    fn initial_limit(&self) -> usize;
+
+    /// Updates the limit with a sample, and returns the new limit.
+    fn update(&mut self, sample: Sample) -> usize;
+}
+
+/// The Vegas algorithm, which estimates the queue size by comparing the latency with the one
+/// without load, and increases the limit when the queue is small and decreases it when the queue
+/// is large.
+///
+/// The latency without load is the min latency observed, which is probed again periodically in
+/// case the service has changed.
+#[derive(Debug, Clone)]
+pub struct Vegas {
+    limit: f64,
+    min_limit: f64,
+    max_limit: f64,
+    probe_interval: usize,
+    samples: usize,
+    rtt_noload: Option<f64>,
+}
+
+impl Default for Vegas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vegas {
+    pub fn new() -> Self {
+        Self {
+            limit: 20.0,
+            min_limit: 1.0,
+            max_limit: 1000.0,
+            probe_interval: 1000,
+            samples: 0,
+            rtt_noload: None,
+        }
+    }
+
+    /// Sets the initial limit.
+    ///
+    /// Default is 20.
+    pub fn initial_limit(mut self, limit: usize) -> Self {
+        self.limit = limit as f64;
+        self
+    }
+
+    /// Sets the range of the limit.
+    ///
+    /// Default is from 1 to 1000.
+    pub fn limit_range(mut self, min: usize, max: usize) -> Self {
+        self.min_limit = min as f64;
+        self.max_limit = max as f64;
+        self
+    }
+
+    /// Sets the number of samples between the probes of the latency without load.
+    ///
+    /// Default is 1000.
+    pub fn probe_interval(mut self, samples: usize) -> Self {
+        self.probe_interval = samples;
+        self
+    }
+}
+
+impl LimitAlgorithm for Vegas {
+    fn initial_limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    fn update(&mut self, sample: Sample) -> usize {
+        let rtt = sample.rtt.as_secs_f64();
+        self.samples += 1;
+        if self.samples >= self.probe_interval {
+            self.samples = 0;
+            self.rtt_noload = None;
+        }
+        let rtt_noload = match self.rtt_noload {
+            Some(rtt_noload) if rtt_noload <= rtt => rtt_noload,
+            _ => {
+                self.rtt_noload = Some(rtt);
+                return self.limit as usize;
+            }
+        };
+
+        let limit = self.limit;
+        let log = limit.log10().max(1.0);
+        let new_limit = if sample.dropped {
+            limit - log
+        } else if (sample.in_flight as f64) * 2.0 < limit {
+            // the limit is not reached, so the latency says nothing about it
+            return limit as usize;
+        } else {
+            let queue_size = (limit * (1.0 - rtt_noload / rtt)).ceil();
+            if queue_size <= log {
+                limit + 6.0 * log
+            } else if queue_size < 3.0 * log {
+                limit + log
+            } else if queue_size > 6.0 * log {
+                limit - log
+            } else {
+                limit
+            }
+        };
+        self.limit = new_limit.clamp(self.min_limit, self.max_limit);
+        self.limit as usize
+    }
+}
+
+/// The Gradient2 algorithm, which compares the latency with the long-term average of it, and
+/// reduces the limit by the gradient when the latency grows, or grows the limit by a queue size
+/// otherwise.
+///
+/// The long-term average decays when it is much higher than the latency, so that it recovers
+/// quickly after a latency spike.
+#[derive(Debug, Clone)]
+pub struct Gradient2 {
+    limit: f64,
+    min_limit: f64,
+    max_limit: f64,
+    queue_size: f64,
+    tolerance: f64,
+    smoothing: f64,
+    window: usize,
+    long_rtt: Option<f64>,
+}
+
+impl Default for Gradient2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gradient2 {
+    pub fn new() -> Self {
+        Self {
+            limit: 20.0,
+            min_limit: 1.0,
+            max_limit: 1000.0,
+            queue_size: 4.0,
+            tolerance: 1.5,
+            smoothing: 0.2,
+            window: 600,
+            long_rtt: None,
+        }
+    }
+
+    /// Sets the initial limit.
+    ///
+    /// Default is 20.
+    pub fn initial_limit(mut self, limit: usize) -> Self {
+        self.limit = limit as f64;
+        self
+    }
+
+    /// Sets the range of the limit.
+    ///
+    /// Default is from 1 to 1000.
+    pub fn limit_range(mut self, min: usize, max: usize) -> Self {
+        self.min_limit = min as f64;
+        self.max_limit = max as f64;
+        self
+    }
+
+    /// Sets the number of requests allowed to queue, which the limit grows by.
+    ///
+    /// Default is 4.
+    pub fn queue_size(mut self, size: usize) -> Self {
+        self.queue_size = size as f64;
+        self
+    }
+
+    /// Sets how much the latency may exceed the long-term average before the limit is reduced.
+    ///
+    /// Default is 1.5.
+    pub fn tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance.max(1.0);
+        self
+    }
+
+    /// Sets the weight of each update of the limit in `(0.0, 1.0]`.
+    ///
+    /// Default is 0.2.
+    pub fn smoothing(mut self, smoothing: f64) -> Self {
+        self.smoothing = smoothing.clamp(f64::EPSILON, 1.0);
+        self
+    }
+
+    /// Sets the number of samples of the long-term average latency.
+    ///
+    /// Default is 600.
+    pub fn window(mut self, samples: usize) -> Self {
+        self.window = samples.max(1);
+        self
+    }
+}
+
+impl LimitAlgorithm for Gradient2 {
+    fn initial_limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    fn update(&mut self, sample: Sample) -> usize {
+        let rtt = sample.rtt.as_secs_f64().max(f64::EPSILON);
+        let mut long_rtt = match self.long_rtt {
+            Some(long_rtt) => long_rtt + (rtt - long_rtt) * 2.0 / (self.window as f64 + 1.0),
+            None => rtt,
+        };
+        // recovers quickly from the long-term latency after a spike
+        if long_rtt / rtt > 2.0 {
+            long_rtt *= 0.95;
+        }
+        self.long_rtt = Some(long_rtt);
+
+        let limit = self.limit;
+        if (sample.in_flight as f64) * 2.0 < limit {
+            // the limit is not reached, so the latency says nothing about it
+            return limit as usize;
+        }
+        let gradient = (self.tolerance * long_rtt / rtt).clamp(0.5, 1.0);
+        let new_limit = limit * gradient + self.queue_size;
+        self.limit = (limit * (1.0 - self.smoothing) + new_limit * self.smoothing)
+            .clamp(self.min_limit, self.max_limit);
+        self.limit as usize
+    }
+}
+
+/// The error of the requests rejected by the concurrency limit.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimitError {
+    /// The limit when the request was rejected.
+    pub limit: usize,
+}
+
+impl fmt::Display for ConcurrencyLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "concurrency limit {} exceeded", self.limit)
+    }
+}
+
+impl std::error::Error for ConcurrencyLimitError {}
+
+/// Rejects the requests exceeding the limit with the error or the response of the protocol.
+pub trait Handler<S, Cx, Req>
+where
+    S: crate::Service<Cx, Req> + Send + Sync + 'static,
+    Cx: Send + 'static,
+    Req: Send + 'static,
+{
+    fn reject(&self, cx: &mut Cx, err: ConcurrencyLimitError) -> Result<S::Response, S::Error>;
+}
+
+/// Impl this Handler for F so users can use a closure as the handler.
+impl<F, S, Cx, Req> Handler<S, Cx, Req> for F
+where
+    F: Fn(&mut Cx, ConcurrencyLimitError) -> Result<S::Response, S::Error>,
+    S: crate::Service<Cx, Req> + Send + Sync + 'static,
+    Cx: Send + 'static,
+    Req: Send + 'static,
+{
+    fn reject(&self, cx: &mut Cx, err: ConcurrencyLimitError) -> Result<S::Response, S::Error> {
+        self(cx, err)
+    }
+}
+
+/// This handler returns the [`ConcurrencyLimitError`] converted into the error of the service,
+/// such as the errors of the thrift and gRPC clients and servers.
+pub fn into_error<Cx, Resp, E>(_: &mut Cx, err: ConcurrencyLimitError) -> Result<Resp, E>
+where
+    E: From<ConcurrencyLimitError>,
+{
+    Err(err.into())
+}
+
+/// The current state of the concurrency limit, which can be cloned and read for metrics.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug)]
+struct Counters {
+    limit: AtomicUsize,
+    in_flight: AtomicUsize,
+}
+
+impl Limiter {
+    /// Returns the current limit.
+    pub fn limit(&self) -> usize {
+        self.inner.limit.load(Ordering::Relaxed)
+    }
+
+    /// Returns the current in-flight requests.
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+struct Shared<A> {
+    limiter: Limiter,
+    algorithm: Mutex<A>,
+}
+
+impl<A> Shared<A>
+where
+    A: LimitAlgorithm,
+{
+    /// Takes a slot for a request, and returns the in-flight requests including it, or the
+    /// current limit if it is exceeded.
+    fn acquire(&self) -> Result<usize, usize> {
+        let counters = &self.limiter.inner;
+        let in_flight = counters.in_flight.fetch_add(1, Ordering::AcqRel) + 1;
+        let limit = counters.limit.load(Ordering::Relaxed);
+        if in_flight > limit {
+            counters.in_flight.fetch_sub(1, Ordering::AcqRel);
+            return Err(limit);
+        }
+        Ok(in_flight)
+    }
+
+    fn release(&self, sample: Sample) {
+        let counters = &self.limiter.inner;
+        counters.in_flight.fetch_sub(1, Ordering::AcqRel);
+        let limit = self.algorithm.lock().unwrap().update(sample);
+        counters.limit.store(limit.max(1), Ordering::Relaxed);
+    }
+}
+
+/// Releases the slot of a request, which is recorded as dropped if the request does not finish.
+struct Permit<'a, A>
+where
+    A: LimitAlgorithm,
+{
+    shared: &'a Shared<A>,
+    start: Instant,
+    in_flight: usize,
+    finished: bool,
+}
+
+impl<A> Permit<'_, A>
+where
+    A: LimitAlgorithm,
+{
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl<A> Drop for Permit<'_, A>
+where
+    A: LimitAlgorithm,
+{
+    fn drop(&mut self) {
+        self.shared.release(Sample {
+            rtt: self.start.elapsed(),
+            in_flight: self.in_flight,
+            dropped: !self.finished,
+        });
+    }
+}
+
+/// A layer that limits the in-flight requests with an adaptive limit.
+///
+/// The state is shared by the clones of the layer and the services made by them.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let layer = volo::concurrency_limit::Layer::new(
+///     volo::concurrency_limit::Gradient2::new(),
+///     volo::concurrency_limit::into_error,
+/// );
+/// let limiter = layer.limiter();
+/// server.layer_front(layer);
+/// ```
+pub struct Layer<A, H> {
+    shared: Arc<Shared<A>>,
+    handler: H,
+}
+
+impl<A, H> Clone for Layer<A, H>
+where
+    H: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            handler: self.handler.clone(),
+        }
+    }
+}
+
+impl<A, H> Layer<A, H>
+where
+    A: LimitAlgorithm,
+{
+    /// Create a new `Layer` with the given `algorithm` and `handler`.
+    pub fn new(algorithm: A, handler: H) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                limiter: Limiter {
+                    inner: Arc::new(Counters {
+                        limit: AtomicUsize::new(algorithm.initial_limit().max(1)),
+                        in_flight: AtomicUsize::new(0),
+                    }),
+                },
+                algorithm: Mutex::new(algorithm),
+            }),
+            handler,
+        }
+    }
+
+    /// Returns the [`Limiter`] for reading the current limit.
+    pub fn limiter(&self) -> Limiter {
+        self.shared.limiter.clone()
+    }
+}
+
+impl<S, A, H> crate::layer::Layer<S> for Layer<A, H> {
+    type Service = Service<S, A, H>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Service {
+            inner,
+            shared: self.shared,
+            handler: self.handler,
+        }
+    }
+}
+
+pub struct Service<S, A, H> {
+    inner: S,
+    shared: Arc<Shared<A>>,
+    handler: H,
+}
+
+impl<S, A, H> Clone for Service<S, A, H>
+where
+    S: Clone,
+    H: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            shared: self.shared.clone(),
+            handler: self.handler.clone(),
+        }
+    }
+}
+
+impl<Cx, Req, S, A, H> crate::Service<Cx, Req> for Service<S, A, H>
+where
+    S: crate::Service<Cx, Req> + Send + Sync + 'static,
+    A: LimitAlgorithm,
+    H: Handler<S, Cx, Req> + Send + Sync,
+    Cx: Send + 'static,
+    Req: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    async fn call(&self, cx: &mut Cx, req: Req) -> Result<Self::Response, Self::Error> {
+        let in_flight = match self.shared.acquire() {
+            Ok(in_flight) => in_flight,
+            Err(limit) => return self.handler.reject(cx, ConcurrencyLimitError { limit }),
+        };
+        let permit = Permit {
+            shared: &self.shared,
+            start: Instant::now(),
+            in_flight,
+            finished: false,
+        };
+        let result = self.inner.call(cx, req).await;
+        permit.finish();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use motore::{layer::Layer as _, Service as _};
+
+    use super::{ConcurrencyLimitError, Gradient2, Layer, LimitAlgorithm, Sample, Vegas};
+
+    fn sample(rtt_ms: u64, in_flight: usize) -> Sample {
+        Sample {
+            rtt: Duration::from_millis(rtt_ms),
+            in_flight,
+            dropped: false,
+        }
+    }
+
+    #[test]
+    fn test_vegas() {
+        let mut vegas = Vegas::new().initial_limit(10);
+        vegas.update(sample(10, 10));
+        // no queue, grows the limit
+        let limit = vegas.update(sample(10, 10));
+        assert!(limit > 10, "{limit}");
+        // the limit is far from reached, keeps it
+        assert_eq!(vegas.update(sample(10, 1)), limit);
+        // the latency doubles, shrinks the limit
+        let shrunk = vegas.update(sample(20, limit));
+        assert!(shrunk < limit, "{shrunk}");
+        let dropped = vegas.update(Sample {
+            dropped: true,
+            ..sample(10, shrunk)
+        });
+        assert!(dropped < shrunk, "{dropped}");
+    }
+
+    #[test]
+    fn test_gradient2() {
+        let mut gradient = Gradient2::new().initial_limit(20);
+        let mut limit = 20;
+        for _ in 0..10 {
+            limit = gradient.update(sample(10, limit));
+        }
+        assert!(limit > 20, "{limit}");
+        // the latency grows much higher than the long-term one
+        let before = limit;
+        for _ in 0..10 {
+            limit = gradient.update(sample(50, limit));
+        }
+        assert!(limit < before, "{limit}");
+    }
+
+    struct Slow;
+
+    impl motore::Service<(), ()> for Slow {
+        type Response = ();
+        type Error = ConcurrencyLimitError;
+
+        async fn call(&self, _: &mut (), _: ()) -> Result<(), ConcurrencyLimitError> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shed() {
+        let layer = Layer::new(Vegas::new().initial_limit(1), |_: &mut (), err| Err(err));
+        let limiter = layer.limiter();
+        let service = layer.layer(Slow);
+
+        let (mut cx1, mut cx2) = ((), ());
+        let (first, second) = tokio::join!(service.call(&mut cx1, ()), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(limiter.in_flight(), 1);
+            service.call(&mut cx2, ()).await
+        });
+        assert!(first.is_ok());
+        assert_eq!(second.unwrap_err().limit, 1);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+}
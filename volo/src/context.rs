@@ -67,6 +67,19 @@ impl std::ops::DerefMut for Extensions {
     }
 }
 
+/// The deadline of the whole call, which is inserted into the [`Extensions`] by the timeout layer
+/// of the client, so that the inner layers, such as the retry of the load balance, can tell the
+/// time left for the call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline(pub std::time::Instant);
+
+impl Deadline {
+    /// Returns the time left before the deadline, or `None` if it has passed.
+    pub fn remaining(&self) -> Option<std::time::Duration> {
+        self.0.checked_duration_since(std::time::Instant::now())
+    }
+}
+
 pub trait Context {
     type Config: Reusable + Default + Send + Debug;
 
@@ -75,6 +88,17 @@ pub trait Context {
 
     fn extensions(&self) -> &Extensions;
     fn extensions_mut(&mut self) -> &mut Extensions;
+
+    /// Creates a context of the same call for sending the request concurrently, such as the
+    /// hedged requests of the load balance.
+    ///
+    /// Returns `None` by default, which means the context cannot be forked.
+    fn fork(&self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
 }
 
 impl<I, Config> Context for RpcCx<I, Config>
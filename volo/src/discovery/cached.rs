@@ -0,0 +1,274 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_broadcast::Receiver;
+use dashmap::DashMap;
+
+use super::{
+    notifier::{spawn_watch, Notifier},
+    Change, Discover, Instance,
+};
+use crate::{context::Endpoint, loadbalance::error::LoadBalanceError};
+
+struct Entry {
+    instances: Vec<Arc<Instance>>,
+    updated_at: Instant,
+}
+
+struct Shared<D>
+where
+    D: Discover,
+{
+    inner: D,
+    ttl: Duration,
+    cache: DashMap<D::Key, Entry>,
+    notifier: Notifier<D::Key>,
+}
+
+impl<D> Shared<D>
+where
+    D: Discover,
+{
+    /// Starts the background task for refreshing the cache with the changes of the inner discover
+    /// and passing them through if not started.
+    fn start(self: &Arc<Self>) {
+        if !self.notifier.start() {
+            return;
+        }
+        let Some(channel) = self.inner.watch(None) else {
+            return;
+        };
+        spawn_watch(self, channel, |shared, change| {
+            // a change carries all the instances of the key, so it's cached even if the key has
+            // not been discovered yet
+            shared.cache.insert(
+                change.key.clone(),
+                Entry {
+                    instances: change.all.clone(),
+                    updated_at: Instant::now(),
+                },
+            );
+            shared.notifier.notify(change);
+        });
+    }
+}
+
+/// [`CachedDiscover`] caches the results of the inner [`Discover`] by the key for a TTL.
+///
+/// When the inner discover fails after the TTL expires, the stale result is returned instead of
+/// the error. The cache of a key is also refreshed by each change of the key from the inner
+/// discover, including the keys which have not been discovered through the [`CachedDiscover`],
+/// and the changes are passed through by `watch` as is.
+pub struct CachedDiscover<D>
+where
+    D: Discover,
+{
+    shared: Arc<Shared<D>>,
+}
+
+impl<D> Clone for CachedDiscover<D>
+where
+    D: Discover,
+{
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<D> CachedDiscover<D>
+where
+    D: Discover,
+{
+    pub fn new(inner: D, ttl: Duration) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                inner,
+                ttl,
+                cache: DashMap::new(),
+                notifier: Notifier::new(),
+            }),
+        }
+    }
+}
+
+impl<D> Discover for CachedDiscover<D>
+where
+    D: Discover,
+{
+    type Key = D::Key;
+    type Error = D::Error;
+
+    async fn discover<'s>(
+        &'s self,
+        endpoint: &'s Endpoint,
+    ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+        self.shared.start();
+        let key = self.shared.inner.key(endpoint);
+        if let Some(entry) = self.shared.cache.get(&key) {
+            if entry.updated_at.elapsed() < self.shared.ttl {
+                return Ok(entry.instances.clone());
+            }
+        }
+        match self.shared.inner.discover(endpoint).await {
+            Ok(instances) => {
+                self.shared.cache.insert(
+                    key,
+                    Entry {
+                        instances: instances.clone(),
+                        updated_at: Instant::now(),
+                    },
+                );
+                Ok(instances)
+            }
+            Err(err) => match self.shared.cache.get(&key) {
+                Some(entry) => {
+                    let err: LoadBalanceError = err.into();
+                    tracing::warn!(
+                        "[VOLO] discover failed, use the stale instances of {:?} ago: {}",
+                        entry.updated_at.elapsed(),
+                        err
+                    );
+                    Ok(entry.instances.clone())
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    fn key(&self, endpoint: &Endpoint) -> Self::Key {
+        self.shared.inner.key(endpoint)
+    }
+
+    fn watch(&self, _keys: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+        self.shared.start();
+        Some(self.shared.notifier.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use async_broadcast::Receiver;
+
+    use super::CachedDiscover;
+    use crate::{
+        context::Endpoint,
+        discovery::{Change, Discover, Instance},
+        loadbalance::error::LoadBalanceError,
+        net::Address,
+    };
+
+    /// Succeeds on the first call and fails on the others.
+    struct FlakyDiscover {
+        calls: AtomicUsize,
+    }
+
+    impl Discover for FlakyDiscover {
+        type Key = ();
+        type Error = LoadBalanceError;
+
+        async fn discover<'s>(
+            &'s self,
+            _: &'s Endpoint,
+        ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+            if self.calls.fetch_add(1, Ordering::Relaxed) > 0 {
+                return Err(LoadBalanceError::Discover("unavailable".into()));
+            }
+            Ok(vec![Arc::new(Instance {
+                address: Address::Ip("127.0.0.1:8000".parse().unwrap()),
+                weight: 1,
+                tags: Default::default(),
+            })])
+        }
+
+        fn key(&self, _: &Endpoint) -> Self::Key {}
+
+        fn watch(&self, _keys: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_discover() {
+        let endpoint = Endpoint::new("hello".into());
+        let discover = CachedDiscover::new(
+            FlakyDiscover {
+                calls: AtomicUsize::new(0),
+            },
+            Duration::from_millis(20),
+        );
+        let instances = discover.discover(&endpoint).await.unwrap();
+        assert_eq!(discover.discover(&endpoint).await.unwrap(), instances);
+        assert_eq!(discover.shared.inner.calls.load(Ordering::Relaxed), 1);
+
+        // serves the stale result on error
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(discover.discover(&endpoint).await.unwrap(), instances);
+        assert_eq!(discover.shared.inner.calls.load(Ordering::Relaxed), 2);
+    }
+
+    /// Fails on every call, and sends the changes from the receiver.
+    struct WatchOnlyDiscover {
+        receiver: Receiver<Change<()>>,
+    }
+
+    impl Discover for WatchOnlyDiscover {
+        type Key = ();
+        type Error = LoadBalanceError;
+
+        async fn discover<'s>(
+            &'s self,
+            _: &'s Endpoint,
+        ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+            Err(LoadBalanceError::Discover("unavailable".into()))
+        }
+
+        fn key(&self, _: &Endpoint) -> Self::Key {}
+
+        fn watch(&self, _keys: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+            Some(self.receiver.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_discover_watch() {
+        let endpoint = Endpoint::new("hello".into());
+        let (sender, receiver) = async_broadcast::broadcast(8);
+        let discover = CachedDiscover::new(WatchOnlyDiscover { receiver }, Duration::from_secs(10));
+        let mut recv = discover.watch(None).unwrap();
+        let instances = vec![Arc::new(Instance {
+            address: Address::Ip("127.0.0.1:8000".parse().unwrap()),
+            weight: 1,
+            tags: Default::default(),
+        })];
+        sender
+            .broadcast(Change {
+                key: (),
+                all: instances.clone(),
+                added: instances.clone(),
+                updated: vec![],
+                removed: vec![],
+            })
+            .await
+            .unwrap();
+        // the change is passed through after the cache is refreshed
+        let change = tokio::time::timeout(Duration::from_secs(1), recv.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.all, instances);
+        // the key has not been discovered before the change, but it's cached as well
+        assert_eq!(discover.discover(&endpoint).await.unwrap(), instances);
+    }
+}
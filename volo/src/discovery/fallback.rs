@@ -0,0 +1,234 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use async_broadcast::Receiver;
+use dashmap::DashMap;
+
+use super::{
+    diff_instances,
+    notifier::{spawn_watch, Notifier},
+    Change, Discover, Instance,
+};
+use crate::{context::Endpoint, loadbalance::error::LoadBalanceError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Primary,
+    Secondary,
+}
+
+struct Cluster {
+    source: Source,
+    instances: Vec<Arc<Instance>>,
+}
+
+struct Shared<P, S>
+where
+    P: Discover,
+{
+    primary: P,
+    secondary: S,
+    fallback_on_empty: AtomicBool,
+    clusters: DashMap<P::Key, Cluster>,
+    notifier: Notifier<P::Key>,
+}
+
+impl<P, S> Shared<P, S>
+where
+    P: Discover,
+    S: Discover<Key = P::Key>,
+{
+    /// Starts the background tasks for watching both the discovers if not started.
+    fn start(self: &Arc<Self>) {
+        if !self.notifier.start() {
+            return;
+        }
+        for (source, channel) in [
+            (Source::Primary, self.primary.watch(None)),
+            (Source::Secondary, self.secondary.watch(None)),
+        ] {
+            if let Some(channel) = channel {
+                spawn_watch(self, channel, move |shared, change| {
+                    shared.update(source, change)
+                });
+            }
+        }
+    }
+
+    fn fallback_on_empty(&self) -> bool {
+        self.fallback_on_empty.load(Ordering::Relaxed)
+    }
+
+    /// Applies the change if it comes from the source in use of the key, or it makes the
+    /// primary available again.
+    fn update(&self, source: Source, change: Change<P::Key>) {
+        let (prev, next) = {
+            let Some(mut cluster) = self.clusters.get_mut(&change.key) else {
+                return;
+            };
+            let recovered =
+                source == Source::Primary && !(self.fallback_on_empty() && change.all.is_empty());
+            if cluster.source != source && !recovered {
+                return;
+            }
+            if cluster.source != source {
+                tracing::info!("[VOLO] fallback discover switches back to the primary");
+            }
+            cluster.source = source;
+            (
+                std::mem::replace(&mut cluster.instances, change.all.clone()),
+                change.all,
+            )
+        };
+        let (change, changed) = diff_instances(change.key, prev, next);
+        if changed {
+            self.notifier.notify(change);
+        }
+    }
+}
+
+/// [`FallbackDiscover`] discovers the instances from the primary [`Discover`], and falls back to
+/// the secondary one if the primary fails, or returns nothing when `fallback_on_empty` is set.
+///
+/// The changes from the secondary are sent only when a key is using it, and a change with
+/// available instances from the primary makes the key switch back to the primary.
+pub struct FallbackDiscover<P, S>
+where
+    P: Discover,
+{
+    shared: Arc<Shared<P, S>>,
+}
+
+impl<P, S> Clone for FallbackDiscover<P, S>
+where
+    P: Discover,
+{
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<P, S> FallbackDiscover<P, S>
+where
+    P: Discover,
+    S: Discover<Key = P::Key>,
+{
+    pub fn new(primary: P, secondary: S) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                primary,
+                secondary,
+                fallback_on_empty: AtomicBool::new(true),
+                clusters: DashMap::new(),
+                notifier: Notifier::new(),
+            }),
+        }
+    }
+
+    /// Sets whether to fall back when the primary returns no instance, which is shared by all the
+    /// clones of the [`FallbackDiscover`].
+    ///
+    /// Default is true.
+    pub fn fallback_on_empty(self, fallback_on_empty: bool) -> Self {
+        self.shared
+            .fallback_on_empty
+            .store(fallback_on_empty, Ordering::Relaxed);
+        self
+    }
+}
+
+impl<P, S> Discover for FallbackDiscover<P, S>
+where
+    P: Discover,
+    S: Discover<Key = P::Key>,
+{
+    type Key = P::Key;
+    type Error = LoadBalanceError;
+
+    async fn discover<'s>(
+        &'s self,
+        endpoint: &'s Endpoint,
+    ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+        self.shared.start();
+        let primary = match self.shared.primary.discover(endpoint).await {
+            Ok(instances) if !(self.shared.fallback_on_empty() && instances.is_empty()) => {
+                Some(instances)
+            }
+            Ok(_) => None,
+            Err(err) => {
+                let err: LoadBalanceError = err.into();
+                tracing::warn!(
+                    "[VOLO] primary discover failed, fall back to the secondary: {}",
+                    err
+                );
+                None
+            }
+        };
+        let (source, instances) = match primary {
+            Some(instances) => (Source::Primary, instances),
+            None => (
+                Source::Secondary,
+                self.shared
+                    .secondary
+                    .discover(endpoint)
+                    .await
+                    .map_err(Into::into)?,
+            ),
+        };
+        self.shared.clusters.insert(
+            self.shared.primary.key(endpoint),
+            Cluster {
+                source,
+                instances: instances.clone(),
+            },
+        );
+        Ok(instances)
+    }
+
+    fn key(&self, endpoint: &Endpoint) -> Self::Key {
+        self.shared.primary.key(endpoint)
+    }
+
+    fn watch(&self, _keys: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+        self.shared.start();
+        Some(self.shared.notifier.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::FallbackDiscover;
+    use crate::{
+        context::Endpoint,
+        discovery::{Discover, DummyDiscover, Instance, StaticDiscover},
+        net::Address,
+    };
+
+    #[tokio::test]
+    async fn test_fallback_discover() {
+        let endpoint = Endpoint::new("hello".into());
+        let instances = vec![Arc::new(Instance {
+            address: Address::Ip("127.0.0.1:8000".parse().unwrap()),
+            weight: 1,
+            tags: Default::default(),
+        })];
+
+        let discover = FallbackDiscover::new(DummyDiscover, StaticDiscover::new(instances.clone()));
+        assert_eq!(discover.discover(&endpoint).await.unwrap(), instances);
+
+        let discover = FallbackDiscover::new(DummyDiscover, StaticDiscover::new(instances.clone()));
+        // the option also takes effect on the cloned ones
+        let _cloned = discover.clone();
+        let discover = discover.fallback_on_empty(false);
+        assert!(discover.discover(&endpoint).await.unwrap().is_empty());
+
+        let discover = FallbackDiscover::new(StaticDiscover::new(instances.clone()), DummyDiscover);
+        assert_eq!(discover.discover(&endpoint).await.unwrap(), instances);
+    }
+}
@@ -0,0 +1,390 @@
+//! Service discovery from a local file.
+//!
+//! The file maps service names to their instances, and is reloaded when it is edited, e.g. in
+//! YAML:
+//!
+//! ```yaml
+//! hello:
+//!   - address: 127.0.0.1:8080
+//!     weight: 10
+//!     tags:
+//!       zone: us-east-1a
+//!   - address: /tmp/hello.sock
+//! ```
+//!
+//! The `weight` defaults to 1 and the `tags` default to empty. An address which is not an IP
+//! socket address is considered as the path of a unix domain socket.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
+
+use async_broadcast::Receiver;
+use faststr::FastStr;
+use motore::BoxError;
+use serde::Deserialize;
+
+use super::{diff_instances, notifier::Notifier, Change, Discover, Instance};
+use crate::{context::Endpoint, net::Address};
+
+/// The format of the file, which is inferred from the file extension by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl FileFormat {
+    /// Infers the format from the extension of the path.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+
+    fn parse(self, content: &str) -> Result<HashMap<FastStr, Vec<InstanceEntry>>, BoxError> {
+        Ok(match self {
+            Self::Json => serde_json::from_str(content)?,
+            Self::Yaml => serde_yaml::from_str(content)?,
+            Self::Toml => toml::from_str(content)?,
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FileDiscoverError {
+    #[error("unknown format of the discovery file {0:?}")]
+    UnknownFormat(PathBuf),
+    #[error("read the discovery file failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("parse the discovery file failed: {0}")]
+    Parse(BoxError),
+}
+
+#[derive(Debug, Deserialize)]
+struct InstanceEntry {
+    address: FastStr,
+    #[serde(default = "default_weight")]
+    weight: u32,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl InstanceEntry {
+    fn into_instance(self) -> Result<Instance, BoxError> {
+        let address = match self.address.parse() {
+            Ok(addr) => Address::Ip(addr),
+            #[cfg(target_family = "unix")]
+            Err(_) => Address::Unix(std::os::unix::net::SocketAddr::from_pathname(
+                self.address.as_str(),
+            )?),
+            #[cfg(not(target_family = "unix"))]
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Instance {
+            address,
+            weight: self.weight,
+            tags: self
+                .tags
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        })
+    }
+}
+
+type Services = HashMap<FastStr, Vec<Arc<Instance>>>;
+
+fn parse_services(content: &str, format: FileFormat) -> Result<Services, FileDiscoverError> {
+    format
+        .parse(content)
+        .and_then(|services| {
+            services
+                .into_iter()
+                .map(|(name, entries)| {
+                    let instances = entries
+                        .into_iter()
+                        .map(|entry| entry.into_instance().map(Arc::new))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    Ok((name, instances))
+                })
+                .collect()
+        })
+        .map_err(FileDiscoverError::Parse)
+}
+
+/// The modification time and the length of the file, or the error of getting them.
+type Stamp = Result<(SystemTime, u64), io::ErrorKind>;
+
+fn stamp(path: &Path) -> Stamp {
+    let metadata = std::fs::metadata(path).map_err(|err| err.kind())?;
+    let modified = metadata.modified().map_err(|err| err.kind())?;
+    Ok((modified, metadata.len()))
+}
+
+struct Shared {
+    path: PathBuf,
+    format: FileFormat,
+    interval: Mutex<Duration>,
+    /// The stamp of the file last checked, used to skip reading an unchanged file, and to report
+    /// a broken file only once.
+    stamp: Mutex<Stamp>,
+    /// The content of the file last loaded, used to skip reloading a file which is touched
+    /// without being changed.
+    content: Mutex<String>,
+    services: RwLock<Services>,
+    notifier: Notifier<FastStr>,
+}
+
+impl Shared {
+    /// Starts the background task for watching the file if not started.
+    fn start(self: &Arc<Self>) {
+        if !self.notifier.start() {
+            return;
+        }
+        tokio::spawn(Self::watch_loop(Arc::downgrade(self)));
+    }
+
+    async fn watch_loop(shared: Weak<Self>) {
+        while let Some(interval) = shared
+            .upgrade()
+            .map(|shared| *shared.interval.lock().unwrap())
+        {
+            tokio::time::sleep(interval).await;
+            let Some(shared) = shared.upgrade() else {
+                break;
+            };
+            if let Err(err) = shared.reload() {
+                tracing::warn!(
+                    "[VOLO] reload discovery file {:?} failed, keep the previous instances: {}",
+                    shared.path,
+                    err
+                );
+            }
+        }
+    }
+
+    /// Reloads the file if its stamp changed, and sends a [`Change`] for each service whose
+    /// instances changed.
+    ///
+    /// An error is returned only once for each change of the file.
+    fn reload(&self) -> Result<(), FileDiscoverError> {
+        let stamp = stamp(&self.path);
+        if std::mem::replace(&mut *self.stamp.lock().unwrap(), stamp) == stamp {
+            return Ok(());
+        }
+        stamp.map_err(io::Error::from)?;
+        let content = std::fs::read_to_string(&self.path)?;
+        if *self.content.lock().unwrap() == content {
+            return Ok(());
+        }
+        let next = parse_services(&content, self.format)?;
+        *self.content.lock().unwrap() = content;
+        let prev = std::mem::replace(&mut *self.services.write().unwrap(), next.clone());
+
+        let names = prev.keys().chain(next.keys()).collect::<HashSet<_>>();
+        for name in names {
+            let (change, changed) = diff_instances(
+                name.clone(),
+                prev.get(name).cloned().unwrap_or_default(),
+                next.get(name).cloned().unwrap_or_default(),
+            );
+            if changed {
+                self.notifier.notify(change);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// [`FileDiscover`] discovers the instances of services from a JSON, YAML or TOML file, keyed by
+/// the service name of the [`Endpoint`].
+///
+/// The modification time and the length of the file are polled after the first call of
+/// `discover` or `watch`, the file is only read when either of them changes, and the [`Change`]s
+/// are sent through the channel returned by `watch` once it is edited. If the edited file cannot
+/// be loaded, the previous instances are kept and a warning is logged once until the file is
+/// edited again. The polling stops when all the clones of the [`FileDiscover`] are dropped.
+#[derive(Clone)]
+pub struct FileDiscover {
+    shared: Arc<Shared>,
+}
+
+impl FileDiscover {
+    /// Loads the file whose format is inferred from its extension.
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self, FileDiscoverError> {
+        let path = path.into();
+        let format = FileFormat::from_path(&path)
+            .ok_or_else(|| FileDiscoverError::UnknownFormat(path.clone()))?;
+        Self::with_format(path, format)
+    }
+
+    /// Loads the file in the given format.
+    pub fn with_format(
+        path: impl Into<PathBuf>,
+        format: FileFormat,
+    ) -> Result<Self, FileDiscoverError> {
+        let path = path.into();
+        let stamp = stamp(&path);
+        let content = std::fs::read_to_string(&path)?;
+        let services = parse_services(&content, format)?;
+        Ok(Self {
+            shared: Arc::new(Shared {
+                path,
+                format,
+                interval: Mutex::new(Duration::from_secs(1)),
+                stamp: Mutex::new(stamp),
+                content: Mutex::new(content),
+                services: RwLock::new(services),
+                notifier: Notifier::new(),
+            }),
+        })
+    }
+
+    /// Sets the interval for checking whether the file is edited, which is shared by all the
+    /// clones of the [`FileDiscover`].
+    ///
+    /// Default is 1 second.
+    pub fn reload_interval(self, interval: Duration) -> Self {
+        *self.shared.interval.lock().unwrap() = interval;
+        self
+    }
+}
+
+impl Discover for FileDiscover {
+    type Key = FastStr;
+    type Error = Infallible;
+
+    async fn discover<'s>(
+        &'s self,
+        endpoint: &'s Endpoint,
+    ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+        self.shared.start();
+        Ok(self
+            .shared
+            .services
+            .read()
+            .unwrap()
+            .get(&endpoint.service_name)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn key(&self, endpoint: &Endpoint) -> Self::Key {
+        endpoint.service_name()
+    }
+
+    fn watch(&self, _keys: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+        self.shared.start();
+        Some(self.shared.notifier.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::{FileDiscover, FileDiscoverError};
+    use crate::{context::Endpoint, discovery::Discover, net::Address};
+
+    fn temp_file(name: &str, content: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("volo-file-discover-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_formats() {
+        let files = [
+            (
+                "services.json",
+                r#"{"hello": [{"address": "127.0.0.1:8000", "weight": 10, "tags": {"zone": "a"}}]}"#,
+            ),
+            (
+                "services.yaml",
+                "hello:\n  - address: 127.0.0.1:8000\n    weight: 10\n    tags:\n      zone: a\n",
+            ),
+            (
+                "services.toml",
+                "[[hello]]\naddress = \"127.0.0.1:8000\"\nweight = 10\ntags = { zone = \"a\" }\n",
+            ),
+        ];
+        for (name, content) in files {
+            let discover = FileDiscover::new(temp_file(name, content)).unwrap();
+            let instances = discover
+                .discover(&Endpoint::new("hello".into()))
+                .await
+                .unwrap();
+            assert_eq!(instances.len(), 1);
+            assert_eq!(
+                instances[0].address,
+                Address::Ip("127.0.0.1:8000".parse().unwrap())
+            );
+            assert_eq!(instances[0].weight, 10);
+            assert_eq!(instances[0].tags.get("zone").unwrap(), "a");
+
+            let instances = discover
+                .discover(&Endpoint::new("unknown".into()))
+                .await
+                .unwrap();
+            assert!(instances.is_empty());
+        }
+
+        assert!(matches!(
+            FileDiscover::new(temp_file("services.txt", "")),
+            Err(FileDiscoverError::UnknownFormat(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = temp_file(
+            "reload.json",
+            r#"{"hello": [{"address": "127.0.0.1:8000"}]}"#,
+        );
+        let discover = FileDiscover::new(&path).unwrap();
+        // the interval also takes effect on the cloned ones
+        let _cloned = discover.clone();
+        let discover = discover.reload_interval(Duration::from_millis(10));
+        let endpoint = Endpoint::new("hello".into());
+        let mut recv = discover.watch(None).unwrap();
+        assert_eq!(discover.discover(&endpoint).await.unwrap()[0].weight, 1);
+
+        // the broken file is ignored
+        std::fs::write(&path, "{").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(discover.discover(&endpoint).await.unwrap().len(), 1);
+
+        std::fs::write(
+            &path,
+            r#"{"hello": [{"address": "127.0.0.1:8000"}, {"address": "127.0.0.2:8000"}]}"#,
+        )
+        .unwrap();
+        let change = tokio::time::timeout(Duration::from_secs(1), recv.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.key, "hello");
+        assert_eq!(change.all.len(), 2);
+        assert_eq!(
+            change.added[0].address,
+            Address::Ip("127.0.0.2:8000".parse().unwrap())
+        );
+        assert_eq!(discover.discover(&endpoint).await.unwrap().len(), 2);
+    }
+}
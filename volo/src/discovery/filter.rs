@@ -0,0 +1,304 @@
+use std::{future::Future, hash::Hash, sync::Arc};
+
+use async_broadcast::Receiver;
+use dashmap::DashMap;
+
+use super::{
+    diff_instances,
+    notifier::{spawn_watch, Notifier},
+    Change, Discover, Instance,
+};
+use crate::context::Endpoint;
+
+/// [`InstanceFilter`] builds a predicate of instances from the [`Endpoint`], such as matching the
+/// cluster or the env in the tags of the instances with the ones of the callee.
+pub trait InstanceFilter: Send + Sync + 'static {
+    /// `Key` identifies the predicate built from an [`Endpoint`], such as the env of the callee,
+    /// so the endpoints building different predicates must have different keys.
+    type Key: Hash + PartialEq + Eq + Send + Sync + Clone + 'static;
+    type Predicate: Fn(&Instance) -> bool + Send + Sync + 'static;
+
+    fn key(&self, endpoint: &Endpoint) -> Self::Key;
+
+    fn predicate(&self, endpoint: &Endpoint) -> Self::Predicate;
+}
+
+struct Cluster<P> {
+    predicate: Arc<P>,
+    instances: Vec<Arc<Instance>>,
+}
+
+type FilterKey<D, F> = (<D as Discover>::Key, <F as InstanceFilter>::Key);
+
+struct Shared<D, F>
+where
+    D: Discover,
+    F: InstanceFilter,
+{
+    inner: D,
+    filter: F,
+    clusters: DashMap<FilterKey<D, F>, Cluster<F::Predicate>>,
+    notifier: Notifier<FilterKey<D, F>>,
+}
+
+impl<D, F> Shared<D, F>
+where
+    D: Discover,
+    F: InstanceFilter,
+{
+    /// Starts the background task for filtering the changes of the inner discover if not
+    /// started.
+    fn start(self: &Arc<Self>) {
+        if !self.notifier.start() {
+            return;
+        }
+        if let Some(channel) = self.inner.watch(None) {
+            spawn_watch(self, channel, |shared, change| shared.update(change));
+        }
+    }
+
+    /// Filters the instances of the change with each predicate built for the key, and sends the
+    /// changes of the filtered instances.
+    ///
+    /// The change is ignored if the key has never been discovered, since there is no predicate
+    /// for it.
+    fn update(&self, change: Change<D::Key>) {
+        let mut changes = Vec::new();
+        for mut cluster in self.clusters.iter_mut() {
+            if cluster.key().0 != change.key {
+                continue;
+            }
+            let predicate = cluster.predicate.clone();
+            let next = change
+                .all
+                .iter()
+                .filter(|instance| predicate(instance))
+                .cloned()
+                .collect::<Vec<_>>();
+            let prev = std::mem::replace(&mut cluster.instances, next.clone());
+            changes.push(diff_instances(cluster.key().clone(), prev, next));
+        }
+        for (change, changed) in changes {
+            if changed {
+                self.notifier.notify(change);
+            }
+        }
+    }
+}
+
+/// [`FilterDiscover`] wraps a [`Discover`] and keeps only the instances that match the predicate
+/// built by the [`InstanceFilter`] from the [`Endpoint`].
+///
+/// The key is the pair of the keys of the inner discover and the [`InstanceFilter`], and the
+/// changes of a key from the inner discover are filtered with the latest predicate of each pair
+/// discovered.
+pub struct FilterDiscover<D, F>
+where
+    D: Discover,
+    F: InstanceFilter,
+{
+    shared: Arc<Shared<D, F>>,
+}
+
+impl<D, F> Clone for FilterDiscover<D, F>
+where
+    D: Discover,
+    F: InstanceFilter,
+{
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<D, F> FilterDiscover<D, F>
+where
+    D: Discover,
+    F: InstanceFilter,
+{
+    pub fn new(inner: D, filter: F) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                inner,
+                filter,
+                clusters: DashMap::new(),
+                notifier: Notifier::new(),
+            }),
+        }
+    }
+}
+
+impl<D, F> Discover for FilterDiscover<D, F>
+where
+    D: Discover,
+    F: InstanceFilter,
+{
+    type Key = FilterKey<D, F>;
+    type Error = D::Error;
+
+    fn discover<'s>(
+        &'s self,
+        endpoint: &'s Endpoint,
+    ) -> impl Future<Output = Result<Vec<Arc<Instance>>, Self::Error>> + Send {
+        let key = self.key(endpoint);
+        let predicate = Arc::new(self.shared.filter.predicate(endpoint));
+        async move {
+            let instances = self.shared.inner.discover(endpoint).await?;
+            self.shared.start();
+            let instances = instances
+                .into_iter()
+                .filter(|instance| predicate(instance))
+                .collect::<Vec<_>>();
+            self.shared.clusters.insert(
+                key,
+                Cluster {
+                    predicate,
+                    instances: instances.clone(),
+                },
+            );
+            Ok(instances)
+        }
+    }
+
+    fn key(&self, endpoint: &Endpoint) -> Self::Key {
+        (
+            self.shared.inner.key(endpoint),
+            self.shared.filter.key(endpoint),
+        )
+    }
+
+    fn watch(&self, _keys: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+        self.shared.start();
+        Some(self.shared.notifier.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use async_broadcast::Receiver;
+    use faststr::FastStr;
+
+    use super::{FilterDiscover, InstanceFilter};
+    use crate::{
+        context::Endpoint,
+        discovery::{Change, Discover, Instance},
+        net::Address,
+    };
+
+    struct Env;
+
+    /// Keeps the instances in the env of the callee.
+    struct EnvFilter;
+
+    impl InstanceFilter for EnvFilter {
+        type Key = FastStr;
+        type Predicate = Box<dyn Fn(&Instance) -> bool + Send + Sync>;
+
+        fn key(&self, endpoint: &Endpoint) -> Self::Key {
+            endpoint.get_faststr::<Env>().cloned().unwrap_or_default()
+        }
+
+        fn predicate(&self, endpoint: &Endpoint) -> Self::Predicate {
+            let env = self.key(endpoint);
+            Box::new(move |instance: &Instance| {
+                instance.tags.get("env").map(|v| v.as_ref()) == Some(env.as_str())
+            })
+        }
+    }
+
+    fn new_instance(address: &str, env: &'static str) -> Arc<Instance> {
+        Arc::new(Instance {
+            address: Address::Ip(address.parse().unwrap()),
+            weight: 1,
+            tags: [("env".into(), env.into())].into_iter().collect(),
+        })
+    }
+
+    struct MockDiscover {
+        instances: Vec<Arc<Instance>>,
+        receiver: Receiver<Change<()>>,
+    }
+
+    impl Discover for MockDiscover {
+        type Key = ();
+        type Error = std::convert::Infallible;
+
+        async fn discover<'s>(
+            &'s self,
+            _: &'s Endpoint,
+        ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+            Ok(self.instances.clone())
+        }
+
+        fn key(&self, _: &Endpoint) -> Self::Key {}
+
+        fn watch(&self, _keys: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+            Some(self.receiver.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_discover() {
+        let (sender, receiver) = async_broadcast::broadcast(8);
+        let discover = FilterDiscover::new(
+            MockDiscover {
+                instances: vec![
+                    new_instance("127.0.0.1:8000", "prod"),
+                    new_instance("127.0.0.2:8000", "test"),
+                ],
+                receiver,
+            },
+            EnvFilter,
+        );
+        let mut prod = Endpoint::new("hello".into());
+        prod.insert_faststr::<Env>("prod".into());
+        let mut test = Endpoint::new("hello".into());
+        test.insert_faststr::<Env>("test".into());
+        // the endpoints with the same inner key but different predicates don't collide
+        assert_ne!(discover.key(&prod), discover.key(&test));
+        let instances = discover.discover(&prod).await.unwrap();
+        assert_eq!(instances, vec![new_instance("127.0.0.1:8000", "prod")]);
+        let instances = discover.discover(&test).await.unwrap();
+        assert_eq!(instances, vec![new_instance("127.0.0.2:8000", "test")]);
+
+        let mut recv = discover.watch(None).unwrap();
+        sender
+            .broadcast(Change {
+                key: (),
+                all: vec![
+                    new_instance("127.0.0.1:8000", "prod"),
+                    new_instance("127.0.0.3:8000", "prod"),
+                    new_instance("127.0.0.4:8000", "test"),
+                ],
+                added: vec![],
+                updated: vec![],
+                removed: vec![],
+            })
+            .await
+            .unwrap();
+        let mut changes = Vec::new();
+        for _ in 0..2 {
+            let change = tokio::time::timeout(Duration::from_secs(1), recv.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            changes.push(change);
+        }
+        changes.sort_by(|a, b| a.key.1.cmp(&b.key.1));
+        assert_eq!(changes[0].key, ((), FastStr::from_static_str("prod")));
+        assert_eq!(changes[0].all.len(), 2);
+        assert_eq!(
+            changes[0].added,
+            vec![new_instance("127.0.0.3:8000", "prod")]
+        );
+        assert_eq!(changes[1].key, ((), FastStr::from_static_str("test")));
+        assert_eq!(changes[1].all, vec![new_instance("127.0.0.4:8000", "test")]);
+        assert_eq!(
+            changes[1].removed,
+            vec![new_instance("127.0.0.2:8000", "test")]
+        );
+    }
+}
@@ -0,0 +1,471 @@
+//! Active health checking for the instances from a [`Discover`].
+//!
+//! [`HealthCheckDiscover`] wraps any [`Discover`], probes the discovered instances on a schedule
+//! with a [`Probe`], filters the unhealthy instances out of the discovery result, and sends a
+//! [`Change`] when the health of an instance flips.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use async_broadcast::Receiver;
+use dashmap::DashMap;
+use tokio::net::TcpStream;
+#[cfg(target_family = "unix")]
+use tokio::net::UnixStream;
+
+use super::{
+    diff_instances,
+    notifier::{spawn_watch, Notifier},
+    Change, Discover, Instance,
+};
+use crate::{context::Endpoint, net::Address};
+
+/// [`Probe`] checks whether an instance is healthy.
+///
+/// [`TcpProbe`] is provided by default. The protocol level probes are provided by the protocol
+/// crates, which are `PingProbe` of `volo-thrift`, `HealthProbe` of `volo-grpc` and `HttpProbe`
+/// of `volo-http`. Users can also implement this trait with their clients, or simply use an
+/// async closure like `|instance: Arc<Instance>| async move { ... }`.
+pub trait Probe: Send + Sync + 'static {
+    /// Returns `true` if the instance is healthy.
+    fn probe(&self, instance: Arc<Instance>) -> impl Future<Output = bool> + Send;
+}
+
+impl<F, Fut> Probe for F
+where
+    F: Fn(Arc<Instance>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = bool> + Send,
+{
+    fn probe(&self, instance: Arc<Instance>) -> impl Future<Output = bool> + Send {
+        self(instance)
+    }
+}
+
+/// [`TcpProbe`] considers an instance healthy if a connection can be established to it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpProbe;
+
+impl TcpProbe {
+    async fn connect(address: &Address) -> io::Result<()> {
+        match address {
+            Address::Ip(addr) => TcpStream::connect(addr).await.map(|_| ()),
+            #[cfg(target_family = "unix")]
+            Address::Unix(addr) => UnixStream::connect(addr.as_pathname().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "cannot connect to unnamed socket",
+                )
+            })?)
+            .await
+            .map(|_| ()),
+        }
+    }
+}
+
+impl Probe for TcpProbe {
+    async fn probe(&self, instance: Arc<Instance>) -> bool {
+        match Self::connect(&instance.address).await {
+            Ok(()) => true,
+            Err(err) => {
+                tracing::debug!(
+                    "[VOLO] health check connects to {} error: {}",
+                    instance.address,
+                    err
+                );
+                false
+            }
+        }
+    }
+}
+
+/// The configuration of the health checking.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthCheckConfig {
+    interval: Duration,
+    timeout: Duration,
+    unhealthy_threshold: u32,
+    healthy_threshold: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(1),
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the interval between two rounds of probing.
+    ///
+    /// Default is 5 seconds.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the timeout of a probe, and a probe that times out is considered as failed.
+    ///
+    /// Default is 1 second.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of consecutive failed probes to mark a healthy instance as unhealthy.
+    ///
+    /// Default is 3.
+    pub fn unhealthy_threshold(mut self, threshold: u32) -> Self {
+        self.unhealthy_threshold = threshold.max(1);
+        self
+    }
+
+    /// Sets the number of consecutive successful probes to mark an unhealthy instance as healthy.
+    ///
+    /// Default is 2.
+    pub fn healthy_threshold(mut self, threshold: u32) -> Self {
+        self.healthy_threshold = threshold.max(1);
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Health {
+    healthy: bool,
+    /// The number of consecutive probes whose result differs from `healthy`
+    count: u32,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            count: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Cluster {
+    instances: Vec<Arc<Instance>>,
+    health: HashMap<Address, Health>,
+}
+
+impl Cluster {
+    /// Returns the healthy instances.
+    ///
+    /// If all the instances are unhealthy, all of them are returned, since the probe may be wrong
+    /// and returning nothing makes the service unavailable anyway.
+    fn healthy(&self) -> Vec<Arc<Instance>> {
+        let healthy = self
+            .instances
+            .iter()
+            .filter(|instance| {
+                self.health
+                    .get(&instance.address)
+                    .map_or(true, |health| health.healthy)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if healthy.is_empty() {
+            self.instances.clone()
+        } else {
+            healthy
+        }
+    }
+
+    fn update_instances(&mut self, instances: Vec<Arc<Instance>>) {
+        self.health.retain(|address, _| {
+            instances
+                .iter()
+                .any(|instance| &instance.address == address)
+        });
+        self.instances = instances;
+    }
+}
+
+struct Shared<D, P>
+where
+    D: Discover,
+{
+    inner: D,
+    probe: P,
+    config: HealthCheckConfig,
+    clusters: DashMap<D::Key, Cluster>,
+    notifier: Notifier<D::Key>,
+}
+
+impl<D, P> Shared<D, P>
+where
+    D: Discover,
+    P: Probe,
+{
+    /// Starts the background tasks for probing and watching the inner discover if not started.
+    fn start(self: &Arc<Self>) {
+        if !self.notifier.start() {
+            return;
+        }
+
+        let weak = Arc::downgrade(self);
+        tokio::spawn(Self::check_loop(weak, self.config.interval));
+
+        if let Some(channel) = self.inner.watch(None) {
+            spawn_watch(self, channel, |shared, change| {
+                shared.update(change.key, |cluster| cluster.update_instances(change.all));
+            });
+        }
+    }
+
+    async fn check_loop(shared: Weak<Self>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Some(shared) = shared.upgrade() else {
+                break;
+            };
+            shared.check().await;
+        }
+    }
+
+    async fn check(&self) {
+        let targets = self
+            .clusters
+            .iter()
+            .flat_map(|cluster| {
+                let key = cluster.key().clone();
+                cluster
+                    .instances
+                    .iter()
+                    .map(move |instance| (key.clone(), instance.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let results =
+            futures::future::join_all(targets.into_iter().map(|(key, instance)| async move {
+                let healthy =
+                    tokio::time::timeout(self.config.timeout, self.probe.probe(instance.clone()))
+                        .await
+                        .unwrap_or(false);
+                (key, instance, healthy)
+            }))
+            .await;
+
+        let mut grouped: HashMap<D::Key, Vec<(Arc<Instance>, bool)>> = HashMap::new();
+        for (key, instance, healthy) in results {
+            grouped.entry(key).or_default().push((instance, healthy));
+        }
+        for (key, results) in grouped {
+            self.update(key, |cluster| {
+                for (instance, healthy) in results {
+                    if !cluster
+                        .instances
+                        .iter()
+                        .any(|i| i.address == instance.address)
+                    {
+                        // the instance has been removed during probing
+                        continue;
+                    }
+                    let health = cluster.health.entry(instance.address.clone()).or_default();
+                    if health.healthy == healthy {
+                        health.count = 0;
+                        continue;
+                    }
+                    health.count += 1;
+                    let threshold = if healthy {
+                        self.config.healthy_threshold
+                    } else {
+                        self.config.unhealthy_threshold
+                    };
+                    if health.count >= threshold {
+                        tracing::info!(
+                            "[VOLO] health check marks {} as {}",
+                            instance.address,
+                            if healthy { "healthy" } else { "unhealthy" }
+                        );
+                        health.healthy = healthy;
+                        health.count = 0;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Updates the cluster of the key and sends a [`Change`] if the healthy instances changed,
+    /// and returns the healthy instances.
+    fn update(&self, key: D::Key, f: impl FnOnce(&mut Cluster)) -> Vec<Arc<Instance>> {
+        let (prev, next) = {
+            let mut cluster = self.clusters.entry(key.clone()).or_default();
+            let prev = cluster.healthy();
+            f(&mut cluster);
+            (prev, cluster.healthy())
+        };
+        let (change, changed) = diff_instances(key, prev, next.clone());
+        if changed {
+            self.notifier.notify(change);
+        }
+        next
+    }
+}
+
+/// [`HealthCheckDiscover`] wraps a [`Discover`] and filters out the instances which are
+/// considered unhealthy by the [`Probe`].
+///
+/// The probing starts after the first call of `discover` or `watch`, and stops when all the
+/// clones of the [`HealthCheckDiscover`] are dropped. The instances which have never been probed
+/// are considered healthy.
+pub struct HealthCheckDiscover<D, P>
+where
+    D: Discover,
+{
+    shared: Arc<Shared<D, P>>,
+}
+
+impl<D, P> Clone for HealthCheckDiscover<D, P>
+where
+    D: Discover,
+{
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<D, P> HealthCheckDiscover<D, P>
+where
+    D: Discover,
+    P: Probe,
+{
+    pub fn new(inner: D, probe: P, config: HealthCheckConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                inner,
+                probe,
+                config,
+                clusters: DashMap::new(),
+                notifier: Notifier::new(),
+            }),
+        }
+    }
+}
+
+impl<D, P> Discover for HealthCheckDiscover<D, P>
+where
+    D: Discover,
+    P: Probe,
+{
+    type Key = D::Key;
+    type Error = D::Error;
+
+    async fn discover<'s>(
+        &'s self,
+        endpoint: &'s Endpoint,
+    ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+        let instances = self.shared.inner.discover(endpoint).await?;
+        self.shared.start();
+        let key = self.shared.inner.key(endpoint);
+        Ok(self
+            .shared
+            .update(key, |cluster| cluster.update_instances(instances)))
+    }
+
+    fn key(&self, endpoint: &Endpoint) -> Self::Key {
+        self.shared.inner.key(endpoint)
+    }
+
+    fn watch(&self, _keys: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+        self.shared.start();
+        Some(self.shared.notifier.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::{HealthCheckConfig, HealthCheckDiscover, Probe, TcpProbe};
+    use crate::{
+        context::Endpoint,
+        discovery::{Discover, Instance, StaticDiscover},
+        net::Address,
+    };
+
+    #[tokio::test]
+    async fn test_tcp_probe() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let instance = Arc::new(Instance {
+            address: Address::Ip(listener.local_addr().unwrap()),
+            weight: 1,
+            tags: Default::default(),
+        });
+        assert!(TcpProbe.probe(instance.clone()).await);
+        drop(listener);
+        assert!(!TcpProbe.probe(instance).await);
+    }
+
+    #[tokio::test]
+    async fn test_health_check_discover() {
+        let empty = Endpoint::new("".into());
+        let bad: Address = Address::Ip("127.0.0.2:9000".parse().unwrap());
+        let discover = StaticDiscover::from(vec![
+            "127.0.0.1:8000".parse().unwrap(),
+            "127.0.0.2:9000".parse().unwrap(),
+        ]);
+        let failing = Arc::new(AtomicBool::new(true));
+        let probe = {
+            let failing = failing.clone();
+            let bad = bad.clone();
+            move |instance: Arc<Instance>| {
+                let healthy = !(failing.load(Ordering::Relaxed) && instance.address == bad);
+                async move { healthy }
+            }
+        };
+        let discover = HealthCheckDiscover::new(
+            discover,
+            probe,
+            HealthCheckConfig::new()
+                .interval(Duration::from_millis(10))
+                .unhealthy_threshold(2)
+                .healthy_threshold(2),
+        );
+        let mut watcher = discover.watch(None).unwrap();
+
+        assert_eq!(discover.discover(&empty).await.unwrap().len(), 2);
+        // the instances from `discover` are broadcast as well
+        let change = watcher.recv().await.unwrap();
+        assert_eq!(change.added.len(), 2);
+
+        let change = watcher.recv().await.unwrap();
+        assert_eq!(change.removed.len(), 1);
+        assert_eq!(change.removed[0].address, bad);
+        let instances = discover.discover(&empty).await.unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_ne!(instances[0].address, bad);
+
+        failing.store(false, Ordering::Relaxed);
+        let change = watcher.recv().await.unwrap();
+        assert_eq!(change.added.len(), 1);
+        assert_eq!(change.added[0].address, bad);
+        assert_eq!(discover.discover(&empty).await.unwrap().len(), 2);
+    }
+}
@@ -3,6 +3,14 @@
 //! We encourage users to use these traits to implement their own service discovery and
 //! loadbalancer, so that we are able to reuse the same service discovery and loadbalancer
 //! implementation.
+mod cached;
+mod fallback;
+#[cfg(feature = "file-discover")]
+pub mod file;
+mod filter;
+pub mod health_check;
+mod notifier;
+
 use std::{
     borrow::Cow,
     collections::{HashMap, HashSet},
@@ -14,6 +22,9 @@ use std::{
 };
 
 use async_broadcast::Receiver;
+pub use cached::CachedDiscover;
+pub use fallback::FallbackDiscover;
+pub use filter::{FilterDiscover, InstanceFilter};
 
 use crate::{context::Endpoint, loadbalance::error::LoadBalanceError, net::Address};
 
@@ -71,7 +82,8 @@ pub struct Change<K> {
 /// that if the bool is false, the [`Change`] should be ignored, and the discover should not send
 /// the event to loadbalancer.
 ///
-/// If users need to compare the instances by also weight or tags, they should not use this.
+/// If users need to compare the instances by also weight or tags, they should use
+/// [`diff_instances`] instead.
 pub fn diff_address<K>(
     key: K,
     prev: Vec<Arc<Instance>>,
@@ -118,6 +130,55 @@ where
     )
 }
 
+/// [`diff_instances`] compares prev and next by the address, and the instances with the same
+/// address but different weight or tags are put into the `updated` of the [`Change`].
+///
+/// The bool in the return value has the same meaning as the one of [`diff_address`].
+pub fn diff_instances<K>(
+    key: K,
+    prev: Vec<Arc<Instance>>,
+    next: Vec<Arc<Instance>>,
+) -> (Change<K>, bool)
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    let mut added = Vec::new();
+    let mut updated = Vec::new();
+    let mut removed = Vec::new();
+
+    let prev_map = prev
+        .iter()
+        .map(|i| (&i.address, i))
+        .collect::<HashMap<_, _>>();
+    let next_set = next.iter().map(|i| &i.address).collect::<HashSet<_>>();
+
+    for i in &next {
+        match prev_map.get(&i.address) {
+            None => added.push(i.clone()),
+            Some(p) if p.weight != i.weight || p.tags != i.tags => updated.push(i.clone()),
+            Some(_) => {}
+        }
+    }
+    for i in &prev {
+        if !next_set.contains(&i.address) {
+            removed.push(i.clone());
+        }
+    }
+
+    let changed = !added.is_empty() || !updated.is_empty() || !removed.is_empty();
+
+    (
+        Change {
+            key,
+            all: next,
+            added,
+            updated,
+            removed,
+        },
+        changed,
+    )
+}
+
 /// [`StaticDiscover`] is a simple implementation of [`Discover`] that returns a static list of
 /// instances.
 #[derive(Clone)]
@@ -194,7 +255,7 @@ impl From<Infallible> for LoadBalanceError {
 mod tests {
     use std::sync::Arc;
 
-    use super::{Discover, Instance, StaticDiscover};
+    use super::{diff_instances, Discover, Instance, StaticDiscover};
     use crate::{context::Endpoint, net::Address};
 
     #[test]
@@ -219,4 +280,42 @@ mod tests {
         ];
         assert_eq!(resp, expected);
     }
+
+    #[test]
+    fn test_diff_instances() {
+        let new_instance = |address: &str, weight: u32| {
+            Arc::new(Instance {
+                address: Address::Ip(address.parse().unwrap()),
+                weight,
+                tags: Default::default(),
+            })
+        };
+        let prev = vec![
+            new_instance("127.0.0.1:8000", 1),
+            new_instance("127.0.0.2:8000", 1),
+            new_instance("127.0.0.3:8000", 1),
+        ];
+        let mut tagged = (*new_instance("127.0.0.3:8000", 1)).clone();
+        tagged.tags.insert("zone".into(), "a".into());
+        let next = vec![
+            new_instance("127.0.0.1:8000", 1),
+            new_instance("127.0.0.2:8000", 0),
+            Arc::new(tagged),
+            new_instance("127.0.0.4:8000", 1),
+        ];
+
+        let (change, changed) = diff_instances((), prev.clone(), next.clone());
+        assert!(changed);
+        assert_eq!(change.added, next[3..]);
+        assert_eq!(change.updated, next[1..3]);
+        assert!(change.removed.is_empty());
+
+        let (change, changed) = diff_instances((), next.clone(), prev.clone());
+        assert!(changed);
+        assert_eq!(change.updated, prev[1..3]);
+        assert_eq!(change.removed, next[3..]);
+
+        let (_, changed) = diff_instances((), prev.clone(), prev);
+        assert!(!changed);
+    }
 }
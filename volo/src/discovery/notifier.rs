@@ -0,0 +1,76 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use async_broadcast::{InactiveReceiver, Receiver, Sender};
+
+use super::Change;
+
+/// [`Notifier`] is shared by the discover wrappers for starting their background tasks once and
+/// broadcasting their [`Change`]s to the watchers.
+///
+/// The channel overflows, so a slow watcher misses the oldest changes instead of blocking the
+/// others.
+pub(super) struct Notifier<K> {
+    started: AtomicBool,
+    sender: Sender<Change<K>>,
+    receiver: InactiveReceiver<Change<K>>,
+}
+
+impl<K> Notifier<K>
+where
+    K: Clone,
+{
+    pub(super) fn new() -> Self {
+        let (mut sender, receiver) = async_broadcast::broadcast(64);
+        sender.set_overflow(true);
+        Self {
+            started: AtomicBool::new(false),
+            sender,
+            receiver: receiver.deactivate(),
+        }
+    }
+
+    /// Returns `true` only for the first call, in which the background tasks should be started.
+    pub(super) fn start(&self) -> bool {
+        !self.started.swap(true, Ordering::AcqRel)
+    }
+
+    /// Sends the change to all the watchers, and it is dropped if there is no watcher.
+    pub(super) fn notify(&self, change: Change<K>) {
+        let _ = self.sender.try_broadcast(change);
+    }
+
+    /// Returns a new channel of the changes sent after this call.
+    pub(super) fn subscribe(&self) -> Receiver<Change<K>> {
+        self.receiver.activate_cloned()
+    }
+}
+
+/// Spawns a task calling `f` with the owner for each change from the channel, which stops when
+/// the channel is closed or the owner is dropped.
+pub(super) fn spawn_watch<T, K, F>(owner: &Arc<T>, mut channel: Receiver<Change<K>>, f: F)
+where
+    T: Send + Sync + 'static,
+    K: Clone + Send + Sync + 'static,
+    F: Fn(&T, Change<K>) + Send + 'static,
+{
+    let owner = Arc::downgrade(owner);
+    tokio::spawn(async move {
+        loop {
+            let change = match channel.recv().await {
+                Ok(change) => change,
+                Err(async_broadcast::RecvError::Closed) => break,
+                Err(err) => {
+                    tracing::warn!("[VOLO] discovering subscription error: {:?}", err);
+                    continue;
+                }
+            };
+            let Some(owner) = owner.upgrade() else {
+                break;
+            };
+            f(&owner, change);
+        }
+    });
+}
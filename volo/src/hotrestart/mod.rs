@@ -2,11 +2,12 @@ use std::{
     collections::HashMap,
     error::Error,
     fmt::Display,
+    future::Future,
     io::{IoSlice, IoSliceMut},
     os::fd::{AsRawFd, RawFd},
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicI32, Ordering},
+        atomic::{AtomicBool, AtomicI32, Ordering},
         Arc, Mutex as StdMutex, OnceLock,
     },
     time::Duration,
@@ -26,7 +27,7 @@ use nix::{
 use tokio::{
     io::{self, Interest},
     net::UnixDatagram,
-    sync::Mutex,
+    sync::{watch, Mutex},
 };
 
 const HOT_RESTART_PARENT_ADDR: &str = "volo_hot_restart_parent.sock";
@@ -62,6 +63,7 @@ enum HotRestartMsgType {
     PassFdRequest = 1,
     PassFdResponse = 2,
     TerminateParentRequest = 3,
+    PassFdNotFound = 4,
 }
 
 impl From<HotRestartMsgType> for u8 {
@@ -79,6 +81,7 @@ impl TryFrom<u8> for HotRestartMsgType {
             1 => Ok(HotRestartMsgType::PassFdRequest),
             2 => Ok(HotRestartMsgType::PassFdResponse),
             3 => Ok(HotRestartMsgType::TerminateParentRequest),
+            4 => Ok(HotRestartMsgType::PassFdNotFound),
             _ => Err(HotRestartError {
                 message: String::from("unknown msg_type"),
             }),
@@ -86,11 +89,42 @@ impl TryFrom<u8> for HotRestartMsgType {
     }
 }
 
+/// The config of the hot restart of the servers, with which the servers take over the listeners
+/// of the old process, tell it to stop once they are ready, and the old process drains its
+/// connections with the shutdown config of the servers.
+///
+/// The same config should be set for all the servers with hot restart in the process.
+#[derive(Debug, Clone)]
+pub struct HotRestartConfig {
+    sock_dir: PathBuf,
+    servers: i32,
+}
+
+impl HotRestartConfig {
+    /// Creates a config with the directory of the unix sockets between the processes.
+    pub fn new(sock_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            sock_dir: sock_dir.into(),
+            servers: 1,
+        }
+    }
+
+    /// Sets the number of the servers with hot restart in the process, and the old process is
+    /// told to stop once all of them are ready.
+    ///
+    /// Default is 1.
+    pub fn servers(mut self, servers: i32) -> Self {
+        self.servers = servers;
+        self
+    }
+}
+
 // simple self message
 enum HotRestartMessage {
     TerminateParentRequest,
     PassFdRequest(String),
     PassFdResponse(RawFd),
+    PassFdNotFound,
 }
 
 pub struct HotRestart {
@@ -101,6 +135,26 @@ pub struct HotRestart {
     parent_sock_path: OnceLock<PathBuf>,
     child_sock_path: OnceLock<PathBuf>,
     domain_sock: Arc<Mutex<Option<UnixDatagram>>>,
+    ready_num: AtomicI32,
+    termination: Arc<Termination>,
+}
+
+/// How the parent terminates on `TerminateParentRequest`.
+struct Termination {
+    /// Whether the servers are initialized by [`HotRestart::initialize_servers`], which wait for
+    /// the termination and drain their connections, instead of sending SIGTERM to the process.
+    managed: AtomicBool,
+    terminated: watch::Sender<bool>,
+}
+
+impl Termination {
+    fn terminate(&self) {
+        if self.managed.load(Ordering::Relaxed) {
+            self.terminated.send_replace(true);
+        } else {
+            signal::kill(getpid(), signal::SIGTERM).unwrap();
+        }
+    }
 }
 
 impl Default for HotRestart {
@@ -119,9 +173,74 @@ impl HotRestart {
             parent_sock_path: OnceLock::new(),
             child_sock_path: OnceLock::new(),
             domain_sock: Arc::new(Mutex::new(None)),
+            ready_num: AtomicI32::new(0),
+            termination: Arc::new(Termination {
+                managed: AtomicBool::new(false),
+                terminated: watch::channel(false).0,
+            }),
         }
     }
 
+    /// Initializes the hot restart for the servers with the config, which is called by the
+    /// servers before creating the listeners.
+    ///
+    /// Unlike [`HotRestart::initialize`], the new process tells the old one to stop only after
+    /// the servers call [`HotRestart::ready`], and the old process stops its servers by
+    /// [`HotRestart::terminated`] instead of SIGTERM.
+    pub async fn initialize_servers(&self, config: &HotRestartConfig) -> io::Result<()> {
+        self.termination.managed.store(true, Ordering::Relaxed);
+        self.initialize(&config.sock_dir, config.servers).await
+    }
+
+    /// Marks a server as ready for serving on the listeners from the old process, which is told
+    /// to stop once all the servers are ready.
+    pub async fn ready(&self) -> io::Result<()> {
+        let mut state = self.state.lock().await;
+        if *state != HotRestartState::ChildInitialized {
+            return Ok(());
+        }
+        if self.ready_num.fetch_add(1, Ordering::AcqRel) + 1
+            < self.listener_num.load(Ordering::Relaxed)
+        {
+            return Ok(());
+        }
+        self.terminate_parent(&mut state).await
+    }
+
+    /// Waits for the new process to take over, which is when the servers should shut down.
+    pub async fn terminated(&self) {
+        let mut terminated = self.termination.terminated.subscribe();
+        let _ = terminated.wait_for(|terminated| *terminated).await;
+    }
+
+    /// Marks a server as ready, and then waits for the shutdown signal or the new process to take
+    /// over.
+    pub async fn wait_for_shutdown<F>(&self, signal: F) -> io::Result<()>
+    where
+        F: Future<Output = io::Result<()>>,
+    {
+        if let Err(e) = self.ready().await {
+            tracing::warn!("hot_restart ready error: {:?}", e);
+        }
+        tokio::select! {
+            res = signal => res,
+            _ = self.terminated() => {
+                tracing::info!("hot_restart terminated by the new process");
+                Ok(())
+            }
+        }
+    }
+
+    /// Initializes the hot restart with the directory of the unix sockets between the processes,
+    /// which is called before creating the listeners.
+    ///
+    /// `server_listener_num` is the number of the listeners taken over from the old process, and
+    /// the old process is sent SIGTERM once all of them are taken over. Each [`Address`] listener
+    /// is counted as one, and a [`ReusePort`] is also counted as one however many acceptors it
+    /// has, so it is usually the number of the servers.
+    ///
+    /// [`Address`]: crate::net::Address
+    /// [`ReusePort`]: crate::net::incoming::ReusePort
     pub async fn initialize(
         &self,
         sock_dir_path: &Path,
@@ -179,6 +298,7 @@ impl HotRestart {
             domain_sock,
             self.child_sock_path.get().unwrap().clone(),
             fds,
+            self.termination.clone(),
         ));
 
         Ok(())
@@ -188,26 +308,37 @@ impl HotRestart {
         parent_sock: UnixDatagram,
         child_sock_path: PathBuf,
         fds: Arc<StdMutex<HashMap<String, RawFd>>>,
+        termination: Arc<Termination>,
     ) -> io::Result<()> {
         tracing::info!("hot_restart parent_handle");
         loop {
             parent_sock.readable().await?;
             match Self::recv_msg(&parent_sock) {
                 Ok(HotRestartMessage::PassFdRequest(addr)) => {
-                    if let Some(fd) = fds.lock().unwrap().get(&addr) {
+                    let fd = fds.lock().unwrap().get(&addr).copied();
+                    if let Some(fd) = fd {
                         tracing::info!("hot_restart parent passfd: {}, addr: {}", fd, addr);
                         Self::send_msg(
                             &parent_sock,
                             child_sock_path.as_path(),
                             HotRestartMsgType::PassFdResponse,
-                            HotRestartMessage::PassFdResponse(*fd),
+                            HotRestartMessage::PassFdResponse(fd),
+                        )?;
+                    } else {
+                        // e.g. the new process opens more reuseport listeners than the old one
+                        tracing::info!("hot_restart parent no fd for addr: {}", addr);
+                        Self::send_msg(
+                            &parent_sock,
+                            child_sock_path.as_path(),
+                            HotRestartMsgType::PassFdNotFound,
+                            HotRestartMessage::PassFdNotFound,
                         )?;
                     }
                 }
                 Ok(HotRestartMessage::TerminateParentRequest) => {
                     tracing::info!("hot_restart parent terminate");
                     parent_sock.shutdown(std::net::Shutdown::Both)?;
-                    signal::kill(getpid(), signal::SIGTERM).unwrap();
+                    termination.terminate();
                     break;
                 }
                 Ok(_) => {
@@ -275,6 +406,7 @@ impl HotRestart {
                         HotRestartMsgType::TerminateParentRequest => {
                             Ok(HotRestartMessage::TerminateParentRequest)
                         }
+                        HotRestartMsgType::PassFdNotFound => Ok(HotRestartMessage::PassFdNotFound),
                     },
                     Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.message)),
                 }
@@ -312,7 +444,7 @@ impl HotRestart {
                     return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid data"));
                 }
             }
-            HotRestartMsgType::TerminateParentRequest => {
+            HotRestartMsgType::TerminateParentRequest | HotRestartMsgType::PassFdNotFound => {
                 sbuf.push(msg_type as u8);
             }
         }
@@ -333,15 +465,49 @@ impl HotRestart {
         listener_fds.insert(addr, raw_fd);
     }
 
+    /// Takes over the listener registered as `addr` by the old process, or returns `None` if
+    /// there is no old process or no such listener.
+    ///
+    /// The listener is counted as one of the `listener_num` of [`HotRestart::initialize`].
     pub async fn dup_parent_listener_sock(&self, addr: String) -> io::Result<Option<RawFd>> {
+        let mut fds = self.dup_parent_listener_socks(vec![addr]).await?;
+        Ok(fds.pop().flatten())
+    }
+
+    /// Takes over the listeners registered as `addrs` by the old process, such as the listeners
+    /// of [`ReusePort`](crate::net::incoming::ReusePort), and returns the fd of each of them if
+    /// any.
+    ///
+    /// The listeners are counted as one of the `listener_num` of [`HotRestart::initialize`],
+    /// whether or not they are found in the old process.
+    pub async fn dup_parent_listener_socks(
+        &self,
+        addrs: Vec<String>,
+    ) -> io::Result<Vec<Option<RawFd>>> {
         let mut state = self.state.lock().await;
         if *state != HotRestartState::ChildInitialized {
             tracing::info!(
-                "hot_restart skip dup_parent_listener_sock: {}, as parent",
-                addr
+                "hot_restart skip dup_parent_listener_socks: {:?}, as parent",
+                addrs
             );
-            return Ok(None);
+            return Ok(vec![None; addrs.len()]);
+        }
+        let mut fds = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            fds.push(self.request_parent_listener_sock(addr).await?);
         }
+        self.dup_listener_num.fetch_add(1, Ordering::AcqRel);
+        // the servers initialized by `initialize_servers` terminate the parent once ready
+        if !self.termination.managed.load(Ordering::Relaxed)
+            && self.dup_listener_num.load(Ordering::Relaxed)
+                == self.listener_num.load(Ordering::Relaxed)
+        {
+            self.terminate_parent(&mut state).await?;
+        }
+        Ok(fds)
+    }
+
+    async fn request_parent_listener_sock(&self, addr: String) -> io::Result<Option<RawFd>> {
         tracing::info!("hot_restart dup_parent_listener_sock: {}, as child", addr);
         // todo: retry?
         let child_guard = self.domain_sock.lock().await;
@@ -350,62 +516,26 @@ impl HotRestart {
             child_sock,
             self.parent_sock_path.get().unwrap().as_path(),
             HotRestartMsgType::PassFdRequest,
-            HotRestartMessage::PassFdRequest(addr),
+            HotRestartMessage::PassFdRequest(addr.clone()),
         )?;
 
-        child_sock.readable().await?;
-
-        match Self::recv_msg(child_sock) {
+        // the readiness may be left by the last response, so waits again if it's not readable
+        let msg = loop {
+            child_sock.readable().await?;
+            match Self::recv_msg(child_sock) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                msg => break msg,
+            }
+        };
+        match msg {
             Ok(HotRestartMessage::PassFdResponse(fd)) => {
-                self.dup_listener_num.fetch_add(1, Ordering::AcqRel);
                 tracing::info!("hot_restart dup_parent_listener_sock fd: {:?}", fd);
-                if self.dup_listener_num.load(Ordering::Relaxed)
-                    == self.listener_num.load(Ordering::Relaxed)
-                {
-                    tracing::info!("hot_restart send terminate_parent");
-                    Self::send_msg(
-                        child_sock,
-                        self.parent_sock_path.get().unwrap().as_path(),
-                        HotRestartMsgType::TerminateParentRequest,
-                        HotRestartMessage::TerminateParentRequest,
-                    )?;
-                    // child -> parent
-                    *state = HotRestartState::ParentInitialized;
-                    child_sock.shutdown(std::net::Shutdown::Both)?;
-                    drop(child_guard);
-                    {
-                        // reset domain_sock
-                        let mut child_sock = self.domain_sock.lock().await;
-                        *child_sock = None;
-                    }
-                    if let Some(path) = self.parent_sock_path.get() {
-                        if path.exists() {
-                            std::fs::remove_file(path.as_path()).unwrap();
-                        }
-                    }
-
-                    let parent_sock_buf = self.parent_sock_path.get().unwrap().clone();
-                    let child_sock_buf = self.child_sock_path.get().unwrap().clone();
-                    let fds = self.listener_fds.clone();
-                    tokio::spawn(async move {
-                        let mut interval = tokio::time::interval(Duration::from_millis(5));
-
-                        loop {
-                            interval.tick().await;
-                            let Ok(domain_sock) = UnixDatagram::bind(parent_sock_buf.as_path())
-                            else {
-                                continue;
-                            };
-                            tracing::info!("hot_restart child->parent");
-                            Self::parent_handle(domain_sock, child_sock_buf.clone(), fds.clone())
-                                .await?;
-                            break;
-                        }
-                        Ok::<(), io::Error>(())
-                    });
-                }
                 Ok(Some(fd))
             }
+            Ok(HotRestartMessage::PassFdNotFound) => {
+                tracing::info!("hot_restart dup_parent_listener_sock: {}, not found", addr);
+                Ok(None)
+            }
             Ok(_) => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Not PassFdResponse",
@@ -413,4 +543,133 @@ impl HotRestart {
             Err(e) => Err(e),
         }
     }
+
+    /// Tells the parent to terminate, and then becomes the parent for the next restart.
+    async fn terminate_parent(&self, state: &mut HotRestartState) -> io::Result<()> {
+        let Some(child_sock) = self.domain_sock.lock().await.take() else {
+            return Ok(());
+        };
+        tracing::info!("hot_restart send terminate_parent");
+        Self::send_msg(
+            &child_sock,
+            self.parent_sock_path.get().unwrap().as_path(),
+            HotRestartMsgType::TerminateParentRequest,
+            HotRestartMessage::TerminateParentRequest,
+        )?;
+        // child -> parent
+        *state = HotRestartState::ParentInitialized;
+        child_sock.shutdown(std::net::Shutdown::Both)?;
+        if let Some(path) = self.parent_sock_path.get() {
+            if path.exists() {
+                std::fs::remove_file(path.as_path()).unwrap();
+            }
+        }
+
+        let parent_sock_buf = self.parent_sock_path.get().unwrap().clone();
+        let child_sock_buf = self.child_sock_path.get().unwrap().clone();
+        let fds = self.listener_fds.clone();
+        let termination = self.termination.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(5));
+
+            loop {
+                interval.tick().await;
+                let Ok(domain_sock) = UnixDatagram::bind(parent_sock_buf.as_path()) else {
+                    continue;
+                };
+                tracing::info!("hot_restart child->parent");
+                Self::parent_handle(
+                    domain_sock,
+                    child_sock_buf.clone(),
+                    fds.clone(),
+                    termination.clone(),
+                )
+                .await?;
+                break;
+            }
+            Ok::<(), io::Error>(())
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::fd::AsRawFd, time::Duration};
+
+    use tokio::signal::unix::{signal, SignalKind};
+
+    use super::{HotRestart, HotRestartConfig};
+
+    #[tokio::test]
+    async fn test_servers_handover() {
+        let dir = std::env::temp_dir().join(format!("volo_hot_restart_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = HotRestartConfig::new(&dir);
+
+        let parent = HotRestart::new();
+        parent.initialize_servers(&config).await.unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        parent.register_listener_fd(addr.clone(), listener.as_raw_fd());
+
+        let child = HotRestart::new();
+        child.initialize_servers(&config).await.unwrap();
+        assert!(child
+            .dup_parent_listener_sock(addr)
+            .await
+            .unwrap()
+            .is_some());
+
+        // the parent is only told to stop once the child is ready
+        tokio::time::timeout(Duration::from_millis(50), parent.terminated())
+            .await
+            .unwrap_err();
+        child.ready().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), parent.terminated())
+            .await
+            .unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_listeners_handover() {
+        // the parent sends SIGTERM to the process, which is caught by the handler
+        let mut sigterm = signal(SignalKind::terminate()).unwrap();
+        let dir =
+            std::env::temp_dir().join(format!("volo_hot_restart_listeners_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let parent = HotRestart::new();
+        parent.initialize(&dir, 2).await.unwrap();
+        let listeners = (0..2)
+            .map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect::<Vec<_>>();
+        let addr = listeners[0].local_addr().unwrap().to_string();
+        parent.register_listener_fd(addr.clone(), listeners[0].as_raw_fd());
+        parent.register_listener_fd(format!("{addr}#1"), listeners[1].as_raw_fd());
+
+        // a reuseport server with more acceptors than the old one, and a new server
+        let child = HotRestart::new();
+        child.initialize(&dir, 2).await.unwrap();
+        let fds = child
+            .dup_parent_listener_socks(vec![addr.clone(), format!("{addr}#1"), format!("{addr}#2")])
+            .await
+            .unwrap();
+        assert!(fds[0].is_some() && fds[1].is_some() && fds[2].is_none());
+        tokio::time::timeout(Duration::from_millis(50), sigterm.recv())
+            .await
+            .unwrap_err();
+        assert!(child
+            .dup_parent_listener_sock("127.0.0.1:1".to_string())
+            .await
+            .unwrap()
+            .is_none());
+        tokio::time::timeout(Duration::from_secs(1), sigterm.recv())
+            .await
+            .unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -8,10 +8,14 @@ pub use motore::{layer, layer::Layer, service, Service};
 pub use tokio::main;
 
 pub mod catch_panic;
+pub mod circuit_breaker;
+pub mod concurrency_limit;
 pub mod context;
 pub mod discovery;
 pub mod loadbalance;
 pub mod net;
+pub mod rate_limit;
+pub mod shutdown;
 pub mod util;
 pub use hack::Unwrap;
 #[cfg(target_family = "unix")]
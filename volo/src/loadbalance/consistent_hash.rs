@@ -1,4 +1,9 @@
-use std::{cmp::min, collections::HashSet, hash::Hash, sync::Arc};
+use std::{
+    cmp::min,
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    sync::Arc,
+};
 
 use dashmap::{mapref::entry::Entry, DashMap};
 
@@ -187,54 +192,105 @@ where
         }
     }
 
-    fn build_weighted_instances(&self, instances: Vec<Arc<Instance>>) -> WeightedInstances {
-        let mut real_nodes = Vec::with_capacity(instances.len());
-        // total number of virtual nodes
-        let virtual_factor = self.option.virtual_factor;
-        let sum_of_nodes = if self.option.weighted {
-            instances
-                .iter()
-                .fold(0, |lhs, rhs| lhs + (rhs.weight * virtual_factor) as usize)
+    /// The number of the virtual nodes of the instance.
+    fn virtual_node_count(&self, instance: &Instance) -> u32 {
+        if self.option.weighted {
+            self.option.virtual_factor * instance.weight
         } else {
-            instances.len() * virtual_factor as usize
-        };
-        let mut virtual_nodes = Vec::with_capacity(sum_of_nodes);
-        for instance in instances {
-            let real_node = Arc::new(RealNode::from((*instance).clone()));
-            real_nodes.push(real_node.clone());
-            let mut weight = 1;
-            if self.option.weighted {
-                weight = instance.weight;
+            self.option.virtual_factor
+        }
+    }
+
+    fn build_virtual_nodes(&self, real_node: &Arc<RealNode>, virtual_nodes: &mut Vec<VirtualNode>) {
+        let str = real_node.0.address.to_string();
+        let vnode_lens = self.virtual_node_count(&real_node.0);
+        // try to reuse the buffer
+        let mut buf = format!("{}#{}", str, vnode_lens).into_bytes();
+        let mut sharp_pos = 0;
+        for (i, bytei) in buf.iter().enumerate() {
+            if *bytei == b'#' {
+                sharp_pos = i;
+                break;
             }
-            let str = instance.address.to_string();
-            let vnode_lens = virtual_factor * weight;
-            // try to reuse the buffer
-            let mut buf = format!("{}#{}", str, vnode_lens).into_bytes();
-            let mut sharp_pos = 0;
-            for (i, bytei) in buf.iter().enumerate() {
-                if *bytei == b'#' {
-                    sharp_pos = i;
-                    break;
-                }
+        }
+        for i in 0..vnode_lens {
+            let mut serial = i;
+            let mut pos = buf.len();
+            while serial > 0 {
+                pos -= 1;
+                buf[pos] = b'0' + (serial % 10) as u8;
+                serial /= 10;
             }
-            for i in 0..(virtual_factor * weight) {
-                let mut serial = i;
-                let mut pos = buf.len();
-                while serial > 0 {
-                    pos -= 1;
-                    buf[pos] = b'0' + (serial % 10) as u8;
-                    serial /= 10;
-                }
-                for bytej in buf.iter_mut().take(pos).skip(sharp_pos + 1) {
-                    *bytej = b'0';
-                }
-                // get address#i with leading zeros
-                let hash = mur3::murmurhash3_x64_128(&buf, 0).0;
-                virtual_nodes.push(VirtualNode {
-                    real_node: real_node.clone(),
-                    hash,
-                });
+            for bytej in buf.iter_mut().take(pos).skip(sharp_pos + 1) {
+                *bytej = b'0';
             }
+            // get address#i with leading zeros
+            let hash = mur3::murmurhash3_x64_128(&buf, 0).0;
+            virtual_nodes.push(VirtualNode {
+                real_node: real_node.clone(),
+                hash,
+            });
+        }
+    }
+
+    /// Builds the ring of the instances, and the virtual nodes of the instances which are not
+    /// changed since `prev` are reused instead of being hashed again.
+    fn build_weighted_instances(
+        &self,
+        instances: Vec<Arc<Instance>>,
+        prev: Option<&WeightedInstances>,
+    ) -> WeightedInstances {
+        // the instances without any virtual node can never be picked
+        let instances = instances
+            .into_iter()
+            .filter(|instance| self.virtual_node_count(instance) > 0)
+            .collect::<Vec<_>>();
+        let unchanged = prev
+            .map(|prev| {
+                let prev_nodes = prev
+                    .real_nodes
+                    .iter()
+                    .map(|node| (&node.0.address, &node.0))
+                    .collect::<HashMap<_, _>>();
+                instances
+                    .iter()
+                    .filter(|instance| {
+                        prev_nodes
+                            .get(&instance.address)
+                            .is_some_and(|prev| *prev == instance.as_ref())
+                    })
+                    .map(|instance| instance.address.clone())
+                    .collect::<HashSet<_>>()
+            })
+            .unwrap_or_default();
+
+        let sum_of_nodes = instances
+            .iter()
+            .map(|instance| self.virtual_node_count(instance) as usize)
+            .sum();
+        let mut real_nodes = Vec::with_capacity(instances.len());
+        let mut virtual_nodes = Vec::with_capacity(sum_of_nodes);
+        if let Some(prev) = prev {
+            real_nodes.extend(
+                prev.real_nodes
+                    .iter()
+                    .filter(|node| unchanged.contains(&node.0.address))
+                    .cloned(),
+            );
+            virtual_nodes.extend(
+                prev.virtual_nodes
+                    .iter()
+                    .filter(|vnode| unchanged.contains(&vnode.real_node.0.address))
+                    .cloned(),
+            );
+        }
+        for instance in instances {
+            if unchanged.contains(&instance.address) {
+                continue;
+            }
+            let real_node = Arc::new(RealNode::from((*instance).clone()));
+            self.build_virtual_nodes(&real_node, &mut virtual_nodes);
+            real_nodes.push(real_node);
         }
         virtual_nodes.sort_unstable();
         WeightedInstances {
@@ -271,6 +327,7 @@ where
                             .discover(endpoint)
                             .await
                             .map_err(|err| err.into())?,
+                        None,
                     ),
                 );
                 e.insert(instances).value().clone()
@@ -287,7 +344,8 @@ where
 
     fn rebalance(&self, changes: Change<<D as Discover>::Key>) {
         if let Entry::Occupied(entry) = self.router.entry(changes.key.clone()) {
-            entry.replace_entry(Arc::new(self.build_weighted_instances(changes.all)));
+            let instances = self.build_weighted_instances(changes.all, Some(entry.get()));
+            entry.replace_entry(Arc::new(instances));
         }
     }
 }
@@ -405,7 +463,7 @@ mod tests {
         };
         let discovery = StaticDiscover::new(instances.clone());
         let lb = ConsistentHashBalance::new(opt.clone());
-        let weighted_instances = lb.build_weighted_instances(instances.clone());
+        let weighted_instances = lb.build_weighted_instances(instances.clone(), None);
         assert_eq!(
             weighted_instances.virtual_nodes.len(),
             (sum_weight * opt.virtual_factor) as usize
@@ -516,14 +574,51 @@ mod tests {
         let discovery = StaticDiscover::new(instances.clone());
         let mut lb = ConsistentHashBalance::new(opt.clone());
         lb.with_discover(&discovery);
-        let virtual_nodes = lb.build_weighted_instances(instances.clone()).virtual_nodes;
+        let virtual_nodes = lb
+            .build_weighted_instances(instances.clone(), None)
+            .virtual_nodes;
         let virtual_nodes: BTreeSet<_> = virtual_nodes.into_iter().collect();
 
         let remove_index = rng.gen_range(0..instances.len());
         let _remove_instance = instances.remove(remove_index);
-        let new_virtual_nodes = lb.build_weighted_instances(instances.clone()).virtual_nodes;
+        let new_virtual_nodes = lb
+            .build_weighted_instances(instances.clone(), None)
+            .virtual_nodes;
         for node in new_virtual_nodes {
             assert!(virtual_nodes.contains(&node));
         }
     }
+
+    #[test]
+    fn test_consistent_hash_rebuild() {
+        let mut instances = (0..10)
+            .map(|i| new_instance(format!("127.0.0.1:{}", i), 10))
+            .collect::<Vec<_>>();
+        let lb = ConsistentHashBalance::<()>::new(ConsistentHashOption::default());
+        let prev = lb.build_weighted_instances(instances.clone(), None);
+
+        // drains an instance and changes the weight of another one
+        instances[0] = new_instance("127.0.0.1:0".to_string(), 0);
+        instances[1] = new_instance("127.0.0.1:1".to_string(), 20);
+        let rebuilt = lb.build_weighted_instances(instances.clone(), Some(&prev));
+        let expected = lb.build_weighted_instances(instances.clone(), None);
+        assert_eq!(rebuilt.real_nodes.len(), 9);
+        assert_eq!(rebuilt.virtual_nodes.len(), expected.virtual_nodes.len());
+        for (a, b) in rebuilt
+            .virtual_nodes
+            .iter()
+            .zip(expected.virtual_nodes.iter())
+        {
+            assert_eq!(a.hash, b.hash);
+            assert_eq!(a.real_node, b.real_node);
+        }
+
+        // never panics when all the instances are drained
+        let drained = (0..3)
+            .map(|i| new_instance(format!("127.0.0.1:{}", i), 0))
+            .collect::<Vec<_>>();
+        let drained = lb.build_weighted_instances(drained, Some(&rebuilt));
+        assert!(drained.real_nodes.is_empty());
+        assert!(drained.virtual_nodes.is_empty());
+    }
 }
@@ -15,4 +15,11 @@ pub trait Retryable {
     fn retryable(&self) -> bool {
         false
     }
+
+    /// Whether the request is rejected by an open circuit of the
+    /// [circuit breaker](crate::circuit_breaker) without reaching the instance, which is not
+    /// reported to the outlier detection as a failure of the instance.
+    fn is_circuit_open(&self) -> bool {
+        false
+    }
 }
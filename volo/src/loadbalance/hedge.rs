@@ -0,0 +1,124 @@
+//! The hedging policy of the load balance service.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use faststr::FastStr;
+
+/// Enables hedging for a single call with the delay, which is inserted into the extensions of the
+/// context, such as by the `CallOpt` of the client.
+///
+/// The hedges of the call are still limited by the max rate of the [`HedgePolicy`] of the client
+/// if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HedgeDelay(pub Duration);
+
+/// [`HedgePolicy`] enables the hedged requests, which send the same request to another instance
+/// if the first attempt has not answered within the delay, and take whichever answer comes
+/// first, cancelling the other one.
+///
+/// Since a request may be processed twice, hedging should only be enabled for the idempotent
+/// methods, so it is disabled for all the methods by default.
+///
+/// The rate of the hedges is limited by a token bucket, in which each request with hedging enabled
+/// puts `max_rate` of a token, and each hedge takes a token.
+#[derive(Debug, Clone)]
+pub struct HedgePolicy {
+    delay: Option<Duration>,
+    methods: HashMap<FastStr, Duration>,
+    max_rate: f64,
+    tokens: Arc<Mutex<f64>>,
+}
+
+/// The max number of tokens, which is the max hedges in a burst.
+const MAX_TOKENS: f64 = 10.0;
+
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HedgePolicy {
+    pub fn new() -> Self {
+        Self {
+            delay: None,
+            methods: HashMap::new(),
+            max_rate: 0.1,
+            tokens: Arc::new(Mutex::new(MAX_TOKENS)),
+        }
+    }
+
+    /// Enables hedging for all the methods with the delay, such as the p95 latency.
+    ///
+    /// Default is disabled.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Enables hedging for the method with the delay, which overrides the one for all the methods.
+    pub fn method(mut self, method: impl Into<FastStr>, delay: Duration) -> Self {
+        self.methods.insert(method.into(), delay);
+        self
+    }
+
+    /// Sets the max ratio of the hedges to the requests with hedging enabled.
+    ///
+    /// Default is 0.1.
+    pub fn max_rate(mut self, rate: f64) -> Self {
+        self.max_rate = rate;
+        self
+    }
+
+    /// Returns the hedge delay of the method, and puts tokens for the request if it is enabled.
+    pub(super) fn delay_of(&self, method: &str) -> Option<Duration> {
+        let delay = self.methods.get(method).copied().or(self.delay);
+        if delay.is_some() {
+            self.deposit();
+        }
+        delay
+    }
+
+    pub(super) fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.max_rate).min(MAX_TOKENS);
+    }
+
+    /// Takes a token for a hedge, returns `false` if the max rate is reached.
+    pub(super) fn try_hedge(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::HedgePolicy;
+
+    #[test]
+    fn test_hedge_policy() {
+        let policy = HedgePolicy::new()
+            .method("get", Duration::from_millis(10))
+            .max_rate(0.5);
+        assert_eq!(policy.delay_of("set"), None);
+        assert_eq!(policy.delay_of("get"), Some(Duration::from_millis(10)));
+
+        // drains the burst
+        while policy.try_hedge() {}
+        policy.delay_of("get");
+        assert!(!policy.try_hedge());
+        policy.delay_of("get");
+        assert!(policy.try_hedge());
+    }
+}
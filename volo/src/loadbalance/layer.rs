@@ -1,17 +1,40 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use motore::Service;
 use tracing::warn;
 
-use super::error::{LoadBalanceError, Retryable};
-use crate::{context::Context, discovery::Discover, loadbalance::LoadBalance, Layer};
+use super::{
+    error::{LoadBalanceError, Retryable},
+    hedge::{HedgeDelay, HedgePolicy},
+    outlier::{OutlierDetection, OutlierDetector, SkipEjected},
+    retry::{DefaultClassifier, RetryClassifier, RetryPolicy},
+};
+use crate::{
+    context::{Context, Deadline},
+    discovery::Discover,
+    loadbalance::{CallTracker, LoadBalance},
+    net::Address,
+    Layer,
+};
+
+/// The outlier detector and the key of the callee.
+type Detector<'a, K> = Option<(&'a OutlierDetector<K>, K)>;
 
 #[derive(Clone)]
-pub struct LoadBalanceService<D, LB, S> {
+pub struct LoadBalanceService<D, LB, S, C = DefaultClassifier>
+where
+    D: Discover,
+{
     discover: D,
     load_balance: Arc<LB>,
     service: S,
-    retry: usize,
+    retry: RetryPolicy<C>,
+    hedge: Option<HedgePolicy>,
+    outlier_detector: Option<Arc<OutlierDetector<D::Key>>>,
 }
 
 impl<D, LB, S> LoadBalanceService<D, LB, S>
@@ -20,20 +43,54 @@ where
     LB: LoadBalance<D>,
 {
     pub fn new(discover: D, load_balance: LB, service: S, retry: usize) -> Self {
+        Self::build(
+            discover,
+            load_balance,
+            service,
+            RetryPolicy::new().max_retries(retry),
+            None,
+            None,
+        )
+    }
+}
+
+impl<D, LB, S, C> LoadBalanceService<D, LB, S, C>
+where
+    D: Discover,
+    LB: LoadBalance<D>,
+{
+    fn build(
+        discover: D,
+        load_balance: LB,
+        service: S,
+        retry: RetryPolicy<C>,
+        hedge: Option<HedgePolicy>,
+        outlier_detection: Option<OutlierDetection>,
+    ) -> Self {
         let lb = Arc::new(load_balance);
+        let detector = outlier_detection.map(|config| Arc::new(OutlierDetector::new(config)));
 
         let service = Self {
             discover,
             load_balance: lb.clone(),
             service,
             retry,
+            hedge,
+            outlier_detector: detector.clone(),
         };
 
         if let Some(mut channel) = service.discover.watch(None) {
             tokio::spawn(async move {
                 loop {
                     match channel.recv().await {
-                        Ok(recv) => lb.rebalance(recv),
+                        Ok(recv) => {
+                            if let Some(detector) = &detector {
+                                detector.on_change(&recv);
+                            }
+                            lb.rebalance(recv)
+                        }
+                        // the discover has been dropped
+                        Err(async_broadcast::RecvError::Closed) => break,
                         Err(err) => warn!("[VOLO] discovering subscription error: {:?}", err),
                     }
                 }
@@ -43,15 +100,17 @@ where
     }
 }
 
-impl<Cx, Req, D, LB, S> Service<Cx, Req> for LoadBalanceService<D, LB, S>
+impl<Cx, Req, D, LB, S, C> Service<Cx, Req> for LoadBalanceService<D, LB, S, C>
 where
     Cx: 'static + Context + Send + Sync,
     D: Discover,
     LB: LoadBalance<D>,
     S: Service<Cx, Req> + 'static + Send + Sync,
     LoadBalanceError: Into<S::Error>,
-    S::Error: Debug + Retryable,
+    S::Response: Send + 'static,
+    S::Error: Debug + Retryable + Send + 'static,
     Req: Clone + Send + Sync + 'static,
+    C: RetryClassifier<S::Response, S::Error>,
 {
     type Response = S::Response;
 
@@ -67,30 +126,77 @@ where
         let picker = match &callee.address {
             None => self
                 .load_balance
-                .get_picker(callee, &self.discover)
+                .get_picker_with_caller(cx.rpc_info().caller(), callee, &self.discover)
                 .await
                 .map_err(|err| err.into())?,
             _ => {
                 return self.service.call(cx, req).await;
             }
         };
-        let mut call_count = 0;
-        for (addr, _) in picker.zip(0..self.retry + 1) {
-            call_count += 1;
-            cx.rpc_info_mut().callee_mut().address = Some(addr.clone());
+        if let Some(detector) = &self.outlier_detector {
+            detector.sync_instances(&self.discover, callee).await;
+        }
+        let detector = self
+            .outlier_detector
+            .as_deref()
+            .map(|detector| (detector, self.discover.key(callee)));
+        let mut picker = SkipEjected::new(picker, detector.clone());
 
-            match self.service.call(cx, req.clone()).await {
-                Ok(resp) => {
-                    return Ok(resp);
+        let method = cx.rpc_info().method().clone();
+        let max_retries = self.retry.max_retries_of(&method);
+        let hedge_delay = match (cx.extensions().get::<HedgeDelay>(), &self.hedge) {
+            (Some(HedgeDelay(delay)), policy) => {
+                if let Some(policy) = policy {
+                    policy.deposit();
                 }
-                Err(err) => {
-                    warn!("[VOLO] call rpcinfo: {:?}, error: {:?}", cx.rpc_info(), err);
-                    if !err.retryable() {
-                        return Err(err);
-                    }
+                Some(*delay)
+            }
+            (None, Some(policy)) => policy.delay_of(&method),
+            (None, None) => None,
+        };
+        let mut call_count = 0;
+        // the last result classified as retryable, which is returned if no more attempt is made
+        let mut last_result = None;
+        for retries in 0..=max_retries {
+            let Some(addr) = picker.next() else {
+                break;
+            };
+            call_count += 1;
+            let start = Instant::now();
+            let result = match hedge_delay {
+                Some(delay) => {
+                    self.call_hedged(cx, &req, addr, delay, &mut picker, &detector)
+                        .await
                 }
+                None => self.call_instance(cx, req.clone(), addr, &detector).await,
+            };
+
+            let retryable = matches!(&result, Err(err) if err.retryable());
+            if let Some(budget) = self.retry.budget_ref() {
+                budget.record(!retryable);
+            }
+            if let Err(err) = &result {
+                warn!("[VOLO] call rpcinfo: {:?}, error: {:?}", cx.rpc_info(), err);
+            }
+
+            if !self.retry.should_retry(&method, result.as_ref(), retryable) {
+                return result;
+            }
+            let delay = if retries < max_retries {
+                self.retry_delay(cx, &method, retries + 1, start.elapsed())
+            } else {
+                None
+            };
+            last_result = Some(result);
+            match delay {
+                Some(delay) if !delay.is_zero() => tokio::time::sleep(delay).await,
+                Some(_) => {}
+                None => break,
             }
         }
+        if let Some(result) = last_result {
+            return result;
+        }
         if call_count == 0 {
             warn!("[VOLO] zero call count, call rpcinfo: {:?}", cx.rpc_info());
         }
@@ -98,9 +204,140 @@ where
     }
 }
 
-impl<D, LB, S> Debug for LoadBalanceService<D, LB, S>
+impl<D, LB, S, C> LoadBalanceService<D, LB, S, C>
 where
-    D: Debug,
+    D: Discover,
+    LB: LoadBalance<D>,
+{
+    /// Calls the instance and reports the result to the load balance and the outlier detector.
+    async fn call_instance<Cx, Req>(
+        &self,
+        cx: &mut Cx,
+        req: Req,
+        addr: Address,
+        detector: &Detector<'_, D::Key>,
+    ) -> Result<S::Response, S::Error>
+    where
+        Cx: Context + Send,
+        S: Service<Cx, Req>,
+        S::Error: Retryable,
+    {
+        cx.rpc_info_mut().callee_mut().address = Some(addr.clone());
+
+        let tracker = CallTracker::new(self.load_balance.as_ref(), &addr);
+        let result = self.service.call(cx, req).await;
+        tracker.finish(result.is_ok());
+
+        if let Some((detector, key)) = detector {
+            match &result {
+                // the circuit breaker has counted the failures of the instance already
+                Err(err) if err.is_circuit_open() => {}
+                result => {
+                    let success = !matches!(result, Err(err) if err.retryable());
+                    detector.report(key, &addr, success);
+                }
+            }
+        }
+        result
+    }
+
+    /// Calls the instance, and sends a hedge with a forked context to the next instance of the
+    /// picker if the instance has not answered within the delay.
+    ///
+    /// The first answer is taken and the other call is cancelled, unless the first answer is a
+    /// retryable error, in which case the other call is waited for.
+    async fn call_hedged<Cx, Req, I>(
+        &self,
+        cx: &mut Cx,
+        req: &Req,
+        addr: Address,
+        delay: Duration,
+        picker: &mut I,
+        detector: &Detector<'_, D::Key>,
+    ) -> Result<S::Response, S::Error>
+    where
+        Cx: Context + Send,
+        Req: Clone,
+        S: Service<Cx, Req>,
+        S::Error: Retryable,
+        I: Iterator<Item = Address>,
+    {
+        let Some(mut forked) = cx.fork() else {
+            return self.call_instance(cx, req.clone(), addr, detector).await;
+        };
+        let (result, hedged) = {
+            let primary = self.call_instance(cx, req.clone(), addr, detector);
+            tokio::pin!(primary);
+            tokio::select! {
+                result = &mut primary => return result,
+                _ = tokio::time::sleep(delay) => {}
+            }
+
+            // the hedge is an extra attempt like a retry, so it is limited by the retry budget
+            // as well, and the slow call is counted as a failed attempt
+            let budget = self.retry.budget_ref();
+            let hedge_addr = match &self.hedge {
+                _ if budget.is_some_and(|budget| !budget.can_retry()) => None,
+                Some(policy) if !policy.try_hedge() => None,
+                _ => picker.next(),
+            };
+            let Some(hedge_addr) = hedge_addr else {
+                return primary.await;
+            };
+            if let Some(budget) = budget {
+                budget.record(false);
+            }
+            let hedge = self.call_instance(&mut forked, req.clone(), hedge_addr, detector);
+            tokio::pin!(hedge);
+            tokio::select! {
+                result = &mut primary => match result {
+                    Err(err) if err.retryable() => (hedge.await, true),
+                    result => (result, false),
+                },
+                result = &mut hedge => match result {
+                    Err(err) if err.retryable() => (primary.await, false),
+                    result => (result, true),
+                },
+            }
+        };
+        if hedged {
+            cx.rpc_info_mut().callee_mut().address =
+                forked.rpc_info_mut().callee_mut().address.take();
+        }
+        result
+    }
+
+    /// Returns the backoff before the `retries`-th retry, or `None` if the retry is not allowed
+    /// by the budget or the deadline of the call, where `elapsed` is the time of the last attempt.
+    fn retry_delay<Cx: Context>(
+        &self,
+        cx: &Cx,
+        method: &str,
+        retries: usize,
+        elapsed: Duration,
+    ) -> Option<Duration> {
+        if let Some(budget) = self.retry.budget_ref() {
+            if !budget.can_retry() {
+                warn!(
+                    "[VOLO] retry budget exhausted, call rpcinfo: {:?}",
+                    cx.rpc_info()
+                );
+                return None;
+            }
+        }
+        let delay = self.retry.backoff_of(method).delay(retries);
+        if let Some(deadline) = cx.extensions().get::<Deadline>() {
+            if deadline.remaining()? < delay + elapsed {
+                return None;
+            }
+        }
+        Some(delay)
+    }
+}
+
+impl<D, LB, S, C> Debug for LoadBalanceService<D, LB, S, C>
+where
+    D: Discover + Debug,
     LB: Debug,
     S: Debug,
 {
@@ -112,11 +349,13 @@ where
     }
 }
 
-#[derive(Clone, Default, Copy)]
-pub struct LoadBalanceLayer<D, LB> {
+#[derive(Clone, Default)]
+pub struct LoadBalanceLayer<D, LB, C = DefaultClassifier> {
     discover: D,
     load_balance: LB,
-    retry_count: usize,
+    retry_policy: RetryPolicy<C>,
+    hedge_policy: Option<HedgePolicy>,
+    outlier_detection: Option<OutlierDetection>,
 }
 
 impl<D, LB> LoadBalanceLayer<D, LB> {
@@ -124,31 +363,80 @@ impl<D, LB> LoadBalanceLayer<D, LB> {
         LoadBalanceLayer {
             discover,
             load_balance,
-            retry_count,
+            retry_policy: RetryPolicy::new().max_retries(retry_count),
+            hedge_policy: None,
+            outlier_detection: None,
         }
     }
 }
 
-impl<D, LB, S> Layer<S> for LoadBalanceLayer<D, LB>
+impl<D, LB, C> LoadBalanceLayer<D, LB, C> {
+    pub fn retry_policy<NC>(self, policy: RetryPolicy<NC>) -> LoadBalanceLayer<D, LB, NC> {
+        LoadBalanceLayer {
+            discover: self.discover,
+            load_balance: self.load_balance,
+            retry_policy: policy,
+            hedge_policy: self.hedge_policy,
+            outlier_detection: self.outlier_detection,
+        }
+    }
+
+    pub fn hedge_policy(mut self, policy: Option<HedgePolicy>) -> Self {
+        self.hedge_policy = policy;
+        self
+    }
+
+    pub fn outlier_detection(mut self, config: Option<OutlierDetection>) -> Self {
+        self.outlier_detection = config;
+        self
+    }
+}
+
+impl<D, LB, S, C> Layer<S> for LoadBalanceLayer<D, LB, C>
 where
     D: Discover,
     LB: LoadBalance<D>,
 {
-    type Service = LoadBalanceService<D, LB, S>;
+    type Service = LoadBalanceService<D, LB, S, C>;
 
     fn layer(self, inner: S) -> Self::Service {
-        LoadBalanceService::new(self.discover, self.load_balance, inner, self.retry_count)
+        LoadBalanceService::build(
+            self.discover,
+            self.load_balance,
+            inner,
+            self.retry_policy,
+            self.hedge_policy,
+            self.outlier_detection,
+        )
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::convert::Infallible;
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
 
-    use motore::service::service_fn;
+    use motore::{layer::Layer, service::service_fn, Service};
 
-    use super::LoadBalanceService;
-    use crate::{discovery::StaticDiscover, loadbalance::random::WeightedRandomBalance};
+    use super::{LoadBalanceLayer, LoadBalanceService};
+    use crate::{
+        context::{Context, Deadline, Endpoint, Extensions, Reusable, Role, RpcInfo},
+        discovery::StaticDiscover,
+        loadbalance::{
+            error::{LoadBalanceError, Retryable},
+            hedge::HedgePolicy,
+            outlier::OutlierDetection,
+            random::WeightedRandomBalance,
+            retry::{Backoff, RetryBudget, RetryPolicy},
+        },
+        net::Address,
+    };
 
     #[derive(Debug)]
     struct MotoreContext;
@@ -166,4 +454,263 @@ mod tests {
 
         LoadBalanceService::new(discover, lb, service, 1);
     }
+
+    #[derive(Debug, Default)]
+    struct TestConfig;
+
+    impl Reusable for TestConfig {
+        fn clear(&mut self) {}
+    }
+
+    struct TestContext {
+        rpc_info: RpcInfo<TestConfig>,
+        extensions: Extensions,
+    }
+
+    impl TestContext {
+        fn new(method: &'static str) -> Self {
+            Self {
+                rpc_info: RpcInfo::new(
+                    Role::Client,
+                    method.into(),
+                    Endpoint::new("caller".into()),
+                    Endpoint::new("callee".into()),
+                    TestConfig,
+                ),
+                extensions: Extensions::default(),
+            }
+        }
+    }
+
+    impl Context for TestContext {
+        type Config = TestConfig;
+
+        fn rpc_info(&self) -> &RpcInfo<Self::Config> {
+            &self.rpc_info
+        }
+
+        fn rpc_info_mut(&mut self) -> &mut RpcInfo<Self::Config> {
+            &mut self.rpc_info
+        }
+
+        fn extensions(&self) -> &Extensions {
+            &self.extensions
+        }
+
+        fn extensions_mut(&mut self) -> &mut Extensions {
+            &mut self.extensions
+        }
+
+        fn fork(&self) -> Option<Self> {
+            Some(Self::new("hello"))
+        }
+    }
+
+    #[derive(Debug)]
+    struct TestError;
+
+    impl Retryable for TestError {}
+
+    impl From<LoadBalanceError> for TestError {
+        fn from(_: LoadBalanceError) -> Self {
+            TestError
+        }
+    }
+
+    /// Answers with the address of the callee, while the first call is slow.
+    struct SlowFirst(Arc<AtomicUsize>);
+
+    impl Service<TestContext, ()> for SlowFirst {
+        type Response = Address;
+        type Error = TestError;
+
+        async fn call(&self, cx: &mut TestContext, _: ()) -> Result<Self::Response, Self::Error> {
+            if self.0.fetch_add(1, Ordering::Relaxed) == 0 {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(cx.rpc_info().callee().address().unwrap())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hedge() {
+        let discover = StaticDiscover::from(vec![
+            "127.0.0.1:8000".parse().unwrap(),
+            "127.0.0.2:8000".parse().unwrap(),
+        ]);
+        let lb = WeightedRandomBalance::with_discover(&discover);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = LoadBalanceLayer::new(discover, lb, 0)
+            .hedge_policy(Some(
+                HedgePolicy::new().method("hello", Duration::from_millis(10)),
+            ))
+            .retry_policy(RetryPolicy::new().budget(RetryBudget::new(2, 0.0)))
+            .layer(SlowFirst(calls.clone()));
+
+        let mut cx = TestContext::new("hello");
+        let start = tokio::time::Instant::now();
+        let addr = service.call(&mut cx, ()).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        // the address of the hedge is set back
+        assert_eq!(cx.rpc_info().callee().address(), Some(addr));
+
+        // the hedge has taken a token of the budget, so no more hedge is allowed
+        calls.store(0, Ordering::Relaxed);
+        let mut cx = TestContext::new("hello");
+        let start = tokio::time::Instant::now();
+        service.call(&mut cx, ()).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // hedging is disabled for the other methods
+        calls.store(0, Ordering::Relaxed);
+        let mut cx = TestContext::new("world");
+        let start = tokio::time::Instant::now();
+        service.call(&mut cx, ()).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[derive(Debug)]
+    struct Unavailable;
+
+    impl Retryable for Unavailable {
+        fn retryable(&self) -> bool {
+            true
+        }
+    }
+
+    impl From<LoadBalanceError> for Unavailable {
+        fn from(_: LoadBalanceError) -> Self {
+            Unavailable
+        }
+    }
+
+    /// Fails the calls to the address, and counts them.
+    struct FailOn(Address, Arc<AtomicUsize>);
+
+    impl Service<TestContext, ()> for FailOn {
+        type Response = Address;
+        type Error = Unavailable;
+
+        async fn call(&self, cx: &mut TestContext, _: ()) -> Result<Self::Response, Self::Error> {
+            let addr = cx.rpc_info().callee().address().unwrap();
+            if addr == self.0 {
+                self.1.fetch_add(1, Ordering::Relaxed);
+                return Err(Unavailable);
+            }
+            Ok(addr)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outlier_detection() {
+        let bad: Address = "127.0.0.1:8000"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let discover = StaticDiscover::from(vec![
+            "127.0.0.1:8000".parse().unwrap(),
+            "127.0.0.2:8000".parse().unwrap(),
+        ]);
+        let lb = WeightedRandomBalance::with_discover(&discover);
+        let failures = Arc::new(AtomicUsize::new(0));
+        let service = LoadBalanceLayer::new(discover, lb, 0)
+            .outlier_detection(Some(
+                OutlierDetection::new()
+                    .consecutive_failures(1)
+                    .max_ejection_percent(50),
+            ))
+            .layer(FailOn(bad.clone(), failures.clone()));
+
+        for _ in 0..32 {
+            let mut cx = TestContext::new("hello");
+            let _ = service.call(&mut cx, ()).await;
+        }
+        // the bad instance is ejected after its first failure, as one of the two discovered
+        // instances
+        assert_eq!(failures.load(Ordering::Relaxed), 1);
+    }
+
+    /// The retryable error of the `n`-th attempt.
+    #[derive(Debug, PartialEq)]
+    struct AttemptFailed(usize);
+
+    impl Retryable for AttemptFailed {
+        fn retryable(&self) -> bool {
+            true
+        }
+    }
+
+    impl From<LoadBalanceError> for AttemptFailed {
+        fn from(_: LoadBalanceError) -> Self {
+            AttemptFailed(usize::MAX)
+        }
+    }
+
+    /// Fails all the calls, and counts them.
+    struct AlwaysFail(Arc<AtomicUsize>);
+
+    impl Service<TestContext, ()> for AlwaysFail {
+        type Response = ();
+        type Error = AttemptFailed;
+
+        async fn call(&self, _: &mut TestContext, _: ()) -> Result<Self::Response, Self::Error> {
+            Err(AttemptFailed(self.0.fetch_add(1, Ordering::Relaxed)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy() {
+        let discover = StaticDiscover::from(vec![
+            "127.0.0.1:8000".parse().unwrap(),
+            "127.0.0.2:8000".parse().unwrap(),
+            "127.0.0.3:8000".parse().unwrap(),
+        ]);
+        let backoff =
+            Backoff::exponential(Duration::from_millis(20), Duration::from_millis(20)).jitter(0.0);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let new_service = |policy: RetryPolicy| {
+            let lb = WeightedRandomBalance::with_discover(&discover);
+            LoadBalanceLayer::new(discover.clone(), lb, 0)
+                .retry_policy(policy)
+                .layer(AlwaysFail(calls.clone()))
+        };
+
+        // backs off between the attempts, and returns the error of the last one
+        let service = new_service(RetryPolicy::new().max_retries(2).backoff(backoff));
+        let start = Instant::now();
+        assert_eq!(
+            service.call(&mut TestContext::new("hello"), ()).await,
+            Err(AttemptFailed(2))
+        );
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 3);
+
+        // stops retrying when the budget is exhausted
+        let service = new_service(
+            RetryPolicy::new()
+                .max_retries(2)
+                .budget(RetryBudget::new(4, 0.1)),
+        );
+        assert!(service
+            .call(&mut TestContext::new("hello"), ())
+            .await
+            .is_err());
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 2);
+        assert!(service
+            .call(&mut TestContext::new("hello"), ())
+            .await
+            .is_err());
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 1);
+
+        // stops retrying when the deadline leaves no room for the backoff
+        let service = new_service(RetryPolicy::new().max_retries(2).backoff(backoff));
+        let mut cx = TestContext::new("hello");
+        cx.extensions_mut()
+            .insert(Deadline(Instant::now() + Duration::from_millis(30)));
+        assert!(service.call(&mut cx, ()).await.is_err());
+        assert_eq!(calls.swap(0, Ordering::Relaxed), 2);
+    }
 }
@@ -1,11 +1,27 @@
 pub mod consistent_hash;
 pub mod error;
+pub mod hedge;
 mod layer;
+pub mod outlier;
+pub mod p2c;
 pub mod random;
+pub mod retry;
+pub mod round_robin;
+pub mod zone_aware;
+
+use std::{
+    future::Future,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
 
-use std::future::Future;
-
-use self::{error::LoadBalanceError, layer::LoadBalanceLayer};
+use self::{
+    error::LoadBalanceError,
+    hedge::HedgePolicy,
+    layer::LoadBalanceLayer,
+    outlier::OutlierDetection,
+    retry::{DefaultClassifier, RetryPolicy},
+};
 use crate::{
     context::Endpoint,
     discovery::{Change, Discover},
@@ -30,8 +46,91 @@ where
         endpoint: &'future Endpoint,
         discover: &'future D,
     ) -> impl Future<Output = Result<Self::InstanceIter, LoadBalanceError>> + Send;
+
+    /// `get_picker_with_caller` is the same as `get_picker`, but also with the endpoint of the
+    /// caller, and it is what the load balance services call.
+    ///
+    /// Load balancers which rely on the caller, such as
+    /// [`ZoneAwareBalance`](zone_aware::ZoneAwareBalance), can override it. The default
+    /// implementation ignores the caller.
+    fn get_picker_with_caller<'future>(
+        &'future self,
+        _caller: &'future Endpoint,
+        callee: &'future Endpoint,
+        discover: &'future D,
+    ) -> impl Future<Output = Result<Self::InstanceIter, LoadBalanceError>> + Send {
+        self.get_picker(callee, discover)
+    }
+
     /// `rebalance` is the callback method be used in service discovering subscription.
     fn rebalance(&self, changes: Change<D::Key>);
+
+    /// `on_call_start` is called by the load balance service right before a request is sent to
+    /// the picked `address`.
+    ///
+    /// Load balancers which rely on the runtime statistics of the instances, such as the number of
+    /// in-flight requests, can override it. The default implementation does nothing.
+    fn on_call_start(&self, _address: &Address) {}
+
+    /// `on_call_end` is called by the load balance service when the call reported by
+    /// `on_call_start` finishes, with the latency and whether the call succeeded.
+    ///
+    /// The default implementation does nothing.
+    fn on_call_end(&self, _address: &Address, _latency: Duration, _success: bool) {}
+}
+
+/// [`CallTracker`] reports a call to the picked instance to the [`LoadBalance`].
+///
+/// It calls [`LoadBalance::on_call_start`] when created and [`LoadBalance::on_call_end`] when
+/// finished. If it is dropped before finished, for example, the call is cancelled by a timeout,
+/// the call will be reported as failed.
+pub struct CallTracker<'a, D, LB>
+where
+    D: Discover,
+    LB: LoadBalance<D>,
+{
+    load_balance: &'a LB,
+    address: &'a Address,
+    start: Instant,
+    finished: bool,
+    _marker: PhantomData<fn(D)>,
+}
+
+impl<'a, D, LB> CallTracker<'a, D, LB>
+where
+    D: Discover,
+    LB: LoadBalance<D>,
+{
+    pub fn new(load_balance: &'a LB, address: &'a Address) -> Self {
+        load_balance.on_call_start(address);
+        Self {
+            load_balance,
+            address,
+            start: Instant::now(),
+            finished: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Reports the end of the call.
+    pub fn finish(mut self, success: bool) {
+        self.finished = true;
+        self.load_balance
+            .on_call_end(self.address, self.start.elapsed(), success);
+    }
+}
+
+impl<D, LB> Drop for CallTracker<'_, D, LB>
+where
+    D: Discover,
+    LB: LoadBalance<D>,
+{
+    fn drop(&mut self) {
+        if !self.finished {
+            self.load_balance
+                .on_call_end(self.address, self.start.elapsed(), false);
+        }
+    }
 }
 
 pub trait MkLbLayer {
@@ -40,10 +139,12 @@ pub trait MkLbLayer {
     fn make(self) -> Self::Layer;
 }
 
-pub struct LbConfig<L, DISC> {
+pub struct LbConfig<L, DISC, C = DefaultClassifier> {
     load_balance: L,
     discover: DISC,
-    retry_count: usize,
+    retry_policy: RetryPolicy<C>,
+    hedge_policy: Option<HedgePolicy>,
+    outlier_detection: Option<OutlierDetection>,
 }
 
 impl<L, DISC> LbConfig<L, DISC> {
@@ -51,40 +152,77 @@ impl<L, DISC> LbConfig<L, DISC> {
         LbConfig {
             load_balance,
             discover,
-            retry_count: 0,
+            retry_policy: RetryPolicy::new(),
+            hedge_policy: None,
+            outlier_detection: None,
         }
     }
+}
 
-    pub fn load_balance<NL>(self, load_balance: NL) -> LbConfig<NL, DISC> {
+impl<L, DISC, C> LbConfig<L, DISC, C> {
+    pub fn load_balance<NL>(self, load_balance: NL) -> LbConfig<NL, DISC, C> {
         LbConfig {
             load_balance,
             discover: self.discover,
-            retry_count: self.retry_count,
+            retry_policy: self.retry_policy,
+            hedge_policy: self.hedge_policy,
+            outlier_detection: self.outlier_detection,
         }
     }
 
-    pub fn discover<NDISC>(self, discover: NDISC) -> LbConfig<L, NDISC> {
+    pub fn discover<NDISC>(self, discover: NDISC) -> LbConfig<L, NDISC, C> {
         LbConfig {
             load_balance: self.load_balance,
             discover,
-            retry_count: self.retry_count,
+            retry_policy: self.retry_policy,
+            hedge_policy: self.hedge_policy,
+            outlier_detection: self.outlier_detection,
         }
     }
 
     /// Sets the retry count of the client.
     pub fn retry_count(mut self, count: usize) -> Self {
-        self.retry_count = count;
+        self.retry_policy = self.retry_policy.max_retries(count);
+        self
+    }
+
+    /// Sets the retry policy of the client, which replaces the retry count set before.
+    pub fn retry_policy<NC>(self, policy: RetryPolicy<NC>) -> LbConfig<L, DISC, NC> {
+        LbConfig {
+            load_balance: self.load_balance,
+            discover: self.discover,
+            retry_policy: policy,
+            hedge_policy: self.hedge_policy,
+            outlier_detection: self.outlier_detection,
+        }
+    }
+
+    /// Enables the hedged requests for the methods of the policy.
+    pub fn hedge_policy(mut self, policy: HedgePolicy) -> Self {
+        self.hedge_policy = Some(policy);
+        self
+    }
+
+    /// Enables the outlier detection, which ejects the instances that keep failing for a while.
+    ///
+    /// Only the errors that are [`Retryable`](error::Retryable) are considered as the failures
+    /// of the instances.
+    pub fn outlier_detection(mut self, config: OutlierDetection) -> Self {
+        self.outlier_detection = Some(config);
         self
     }
 }
 
 pub struct CustomLayer<L>(pub L);
 
-impl<LB, DISC> MkLbLayer for LbConfig<LB, DISC> {
-    type Layer = LoadBalanceLayer<DISC, LB>;
+impl<LB, DISC, C> MkLbLayer for LbConfig<LB, DISC, C> {
+    type Layer = LoadBalanceLayer<DISC, LB, C>;
 
     fn make(self) -> Self::Layer {
-        LoadBalanceLayer::new(self.discover, self.load_balance, self.retry_count)
+        LoadBalanceLayer::new(self.discover, self.load_balance, 0)
+            .retry_policy(self.retry_policy)
+            .hedge_policy(self.hedge_policy)
+            .outlier_detection(self.outlier_detection)
     }
 }
 
@@ -0,0 +1,630 @@
+//! Passive outlier detection for the load balance service.
+//!
+//! The load balance service reports the result of every call to the [`OutlierDetector`], and the
+//! instances which keep failing will be ejected for a while, so the following requests will not
+//! be sent to them.
+
+use std::{
+    hash::Hash,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+use crate::{
+    context::Endpoint,
+    discovery::{Change, Discover},
+    net::Address,
+};
+
+/// The configuration of the outlier detection.
+#[derive(Debug, Clone, Copy)]
+pub struct OutlierDetection {
+    consecutive_failures: u32,
+    failure_rate: f64,
+    min_requests: u32,
+    interval: Duration,
+    base_ejection_time: Duration,
+    max_ejection_time: Duration,
+    max_ejection_percent: u8,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 5,
+            failure_rate: 0.5,
+            min_requests: 10,
+            interval: Duration::from_secs(10),
+            base_ejection_time: Duration::from_secs(30),
+            max_ejection_time: Duration::from_secs(300),
+            max_ejection_percent: 50,
+        }
+    }
+}
+
+impl OutlierDetection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of consecutive failures that ejects an instance.
+    ///
+    /// Default is 5, and 0 disables it.
+    pub fn consecutive_failures(mut self, count: u32) -> Self {
+        self.consecutive_failures = count;
+        self
+    }
+
+    /// Sets the failure rate in an interval that ejects an instance, which only takes effect when
+    /// there are at least `min_requests` requests in the interval.
+    ///
+    /// Default is 0.5 with 10 requests, and a rate greater than 1.0 disables it.
+    pub fn failure_rate(mut self, rate: f64, min_requests: u32) -> Self {
+        self.failure_rate = rate;
+        self.min_requests = min_requests;
+        self
+    }
+
+    /// Sets the interval for counting the failure rate.
+    ///
+    /// Default is 10 seconds.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the base ejection time.
+    ///
+    /// An instance is ejected for `base_ejection_time * n` when it is ejected for the `n`-th time
+    /// without any successful call in between, but no longer than `max_ejection_time`.
+    ///
+    /// Default is 30 seconds and 300 seconds.
+    pub fn ejection_time(mut self, base: Duration, max: Duration) -> Self {
+        self.base_ejection_time = base;
+        self.max_ejection_time = max;
+        self
+    }
+
+    /// Sets the max percent of the instances of a service that can be ejected at the same time.
+    ///
+    /// Default is 50.
+    pub fn max_ejection_percent(mut self, percent: u8) -> Self {
+        self.max_ejection_percent = percent.min(100);
+        self
+    }
+}
+
+#[derive(Debug)]
+struct InstanceState {
+    consecutive_failures: u32,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+    ejected_until: Option<Instant>,
+    ejection_count: u32,
+}
+
+impl InstanceState {
+    fn new(now: Instant) -> Self {
+        Self {
+            consecutive_failures: 0,
+            window_start: now,
+            requests: 0,
+            failures: 0,
+            ejected_until: None,
+            ejection_count: 0,
+        }
+    }
+
+    fn is_ejected(&self, now: Instant) -> bool {
+        matches!(self.ejected_until, Some(until) if until > now)
+    }
+
+    /// Clears the ejection if it has expired, and returns whether it is cleared, in which case
+    /// the instance should no longer be counted as ejected.
+    fn expire(&mut self, now: Instant) -> bool {
+        match self.ejected_until {
+            Some(until) if until <= now => {
+                self.ejected_until = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Cluster {
+    instances: DashMap<Address, Mutex<InstanceState>>,
+    /// The number of the instances whose `ejected_until` is set.
+    ejected: AtomicUsize,
+}
+
+impl Cluster {
+    fn expire(&self, state: &mut InstanceState, now: Instant) {
+        if state.expire(now) {
+            self.ejected.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// [`OutlierDetector`] records the results of the calls to each instance, grouped by the
+/// [`Discover::Key`](crate::discovery::Discover::Key), and decides which instances are ejected.
+#[derive(Debug)]
+pub struct OutlierDetector<K>
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    config: OutlierDetection,
+    clusters: DashMap<K, Arc<Cluster>>,
+    /// The number of the discovered instances of each service, which the max ejection percent is
+    /// computed against, and `None` if the discovery has failed.
+    sizes: DashMap<K, Option<usize>>,
+}
+
+impl<K> OutlierDetector<K>
+where
+    K: Hash + PartialEq + Eq + Clone + Send + Sync + 'static,
+{
+    pub fn new(config: OutlierDetection) -> Self {
+        Self {
+            config,
+            clusters: DashMap::new(),
+            sizes: DashMap::new(),
+        }
+    }
+
+    /// Discovers the instances of the endpoint for the number of the instances if it has not been
+    /// discovered, which is then kept up to date by [`OutlierDetector::on_change`].
+    ///
+    /// The discovery is done only once for each key even if it fails, in which case the max
+    /// ejection percent is computed against the instances which have reported results until the
+    /// next change.
+    pub async fn sync_instances<D>(&self, discover: &D, endpoint: &Endpoint)
+    where
+        D: Discover<Key = K>,
+    {
+        let key = discover.key(endpoint);
+        if self.sizes.contains_key(&key) {
+            return;
+        }
+        let size = discover
+            .discover(endpoint)
+            .await
+            .ok()
+            .map(|instances| instances.len());
+        // keep the size if it has been updated by `on_change` meanwhile
+        self.sizes.entry(key).or_insert(size);
+    }
+
+    /// Updates the number of the instances and removes the states of the removed instances, which
+    /// should be called with the changes watched from the service discovery.
+    pub fn on_change(&self, change: &Change<K>) {
+        self.sizes
+            .insert(change.key.clone(), Some(change.all.len()));
+        self.remove(
+            &change.key,
+            change
+                .removed
+                .iter()
+                .map(|instance| instance.address.clone()),
+        );
+    }
+
+    fn cluster(&self, key: &K) -> Arc<Cluster> {
+        if let Some(cluster) = self.clusters.get(key) {
+            return cluster.clone();
+        }
+        self.clusters.entry(key.clone()).or_default().clone()
+    }
+
+    /// Returns whether the instance is ejected now.
+    pub fn is_ejected(&self, key: &K, address: &Address) -> bool {
+        let Some(cluster) = self.clusters.get(key) else {
+            return false;
+        };
+        let Some(state) = cluster.instances.get(address) else {
+            return false;
+        };
+        let now = Instant::now();
+        let mut state = state.lock().unwrap();
+        cluster.expire(&mut state, now);
+        state.is_ejected(now)
+    }
+
+    /// Reports the result of a call to the instance.
+    ///
+    /// `success` should be false only if the failure is caused by the instance, such as a
+    /// connection error, rather than an error returned by the business logic.
+    pub fn report(&self, key: &K, address: &Address, success: bool) {
+        let now = Instant::now();
+        let cluster = self.cluster(key);
+        let should_eject = {
+            let entry = cluster
+                .instances
+                .entry(address.clone())
+                .or_insert_with(|| Mutex::new(InstanceState::new(now)));
+            let mut state = entry.lock().unwrap();
+            cluster.expire(&mut state, now);
+            if now.duration_since(state.window_start) >= self.config.interval {
+                state.window_start = now;
+                state.requests = 0;
+                state.failures = 0;
+            }
+            state.requests += 1;
+            if success {
+                state.consecutive_failures = 0;
+                if !state.is_ejected(now) {
+                    state.ejection_count = 0;
+                }
+                return;
+            }
+            state.failures += 1;
+            state.consecutive_failures += 1;
+
+            !state.is_ejected(now)
+                && ((self.config.consecutive_failures > 0
+                    && state.consecutive_failures >= self.config.consecutive_failures)
+                    || (state.requests >= self.config.min_requests
+                        && state.failures as f64
+                            >= self.config.failure_rate * state.requests as f64))
+        };
+        if should_eject {
+            self.eject(key, &cluster, address, now);
+        }
+    }
+
+    fn eject(&self, key: &K, cluster: &Cluster, address: &Address, now: Instant) {
+        for state in cluster.instances.iter() {
+            cluster.expire(&mut state.lock().unwrap(), now);
+        }
+        let size = match self.sizes.get(key).and_then(|size| *size) {
+            Some(size) => size,
+            None => cluster.instances.len(),
+        };
+        let max = size * self.config.max_ejection_percent as usize;
+        // reserve the ejection in one step, so that the concurrent ejections can't exceed the max
+        if cluster
+            .ejected
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |ejected| {
+                ((ejected + 1) * 100 <= max).then_some(ejected + 1)
+            })
+            .is_err()
+        {
+            return;
+        }
+        let Some(entry) = cluster.instances.get(address) else {
+            cluster.ejected.fetch_sub(1, Ordering::AcqRel);
+            return;
+        };
+        let mut state = entry.lock().unwrap();
+        cluster.expire(&mut state, now);
+        if state.ejected_until.is_some() {
+            // ejected by a concurrent call
+            cluster.ejected.fetch_sub(1, Ordering::AcqRel);
+            return;
+        }
+        state.ejection_count += 1;
+        let ejection_time = self
+            .config
+            .base_ejection_time
+            .saturating_mul(state.ejection_count)
+            .min(self.config.max_ejection_time);
+        tracing::warn!(
+            "[VOLO] outlier detection ejects {} for {:?}",
+            address,
+            ejection_time
+        );
+        state.ejected_until = Some(now + ejection_time);
+        state.consecutive_failures = 0;
+        state.window_start = now;
+        state.requests = 0;
+        state.failures = 0;
+    }
+
+    /// Removes the states of the instances, which is called when they are removed from the
+    /// service discovery.
+    pub fn remove(&self, key: &K, addresses: impl Iterator<Item = Address>) {
+        if let Some(cluster) = self.clusters.get(key) {
+            for address in addresses {
+                if let Some((_, state)) = cluster.instances.remove(&address) {
+                    if state.into_inner().unwrap().ejected_until.is_some() {
+                        cluster.ejected.fetch_sub(1, Ordering::AcqRel);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An iterator that skips the ejected instances of a picker.
+///
+/// If all the instances from the picker are ejected, the first one is yielded anyway, so that the
+/// outlier detection never makes a service completely unavailable.
+pub struct SkipEjected<'a, K, I>
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    picker: I,
+    detector: Option<(&'a OutlierDetector<K>, K)>,
+    first: Option<Address>,
+    yielded: bool,
+}
+
+impl<'a, K, I> SkipEjected<'a, K, I>
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    pub fn new(picker: I, detector: Option<(&'a OutlierDetector<K>, K)>) -> Self {
+        Self {
+            picker,
+            detector,
+            first: None,
+            yielded: false,
+        }
+    }
+}
+
+impl<K, I> Iterator for SkipEjected<'_, K, I>
+where
+    K: Hash + PartialEq + Eq + Clone + Send + Sync + 'static,
+    I: Iterator<Item = Address>,
+{
+    type Item = Address;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some((detector, key)) = &self.detector else {
+            return self.picker.next();
+        };
+        for address in self.picker.by_ref() {
+            if !detector.is_ejected(key, &address) {
+                self.yielded = true;
+                return Some(address);
+            }
+            if self.first.is_none() {
+                self.first = Some(address);
+            }
+        }
+        if self.yielded {
+            return None;
+        }
+        self.yielded = true;
+        self.first.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Barrier,
+        },
+        time::Duration,
+    };
+
+    use async_broadcast::Receiver;
+
+    use super::{OutlierDetection, OutlierDetector, SkipEjected};
+    use crate::{
+        context::Endpoint,
+        discovery::{Change, Discover, Instance},
+        loadbalance::error::LoadBalanceError,
+        net::Address,
+    };
+
+    fn addresses(n: usize) -> Vec<Address> {
+        (0..n)
+            .map(|i| Address::Ip(format!("127.0.0.{}:8000", i + 1).parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_consecutive_failures() {
+        let detector = OutlierDetector::new(
+            OutlierDetection::new()
+                .consecutive_failures(3)
+                .failure_rate(2.0, 0)
+                .ejection_time(Duration::from_millis(50), Duration::from_secs(1)),
+        );
+        let addrs = addresses(2);
+        detector.report(&(), &addrs[1], true);
+        for _ in 0..2 {
+            detector.report(&(), &addrs[0], false);
+        }
+        detector.report(&(), &addrs[0], true);
+        detector.report(&(), &addrs[0], false);
+        assert!(!detector.is_ejected(&(), &addrs[0]));
+        for _ in 0..2 {
+            detector.report(&(), &addrs[0], false);
+        }
+        assert!(detector.is_ejected(&(), &addrs[0]));
+        assert!(!detector.is_ejected(&(), &addrs[1]));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!detector.is_ejected(&(), &addrs[0]));
+
+        // the ejection time grows if the instance still fails after coming back
+        for _ in 0..3 {
+            detector.report(&(), &addrs[0], false);
+        }
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(detector.is_ejected(&(), &addrs[0]));
+    }
+
+    #[test]
+    fn test_failure_rate() {
+        let detector = OutlierDetector::new(
+            OutlierDetection::new()
+                .consecutive_failures(0)
+                .failure_rate(0.5, 4),
+        );
+        let addrs = addresses(2);
+        detector.report(&(), &addrs[1], true);
+        for success in [false, true, false] {
+            detector.report(&(), &addrs[0], success);
+        }
+        assert!(!detector.is_ejected(&(), &addrs[0]));
+        detector.report(&(), &addrs[0], true);
+        detector.report(&(), &addrs[0], false);
+        assert!(detector.is_ejected(&(), &addrs[0]));
+    }
+
+    #[test]
+    fn test_max_ejection_percent() {
+        let detector = OutlierDetector::new(
+            OutlierDetection::new()
+                .consecutive_failures(1)
+                .max_ejection_percent(50),
+        );
+        let addrs = addresses(4);
+        for addr in addrs.iter() {
+            detector.report(&(), addr, true);
+        }
+        for addr in addrs.iter() {
+            detector.report(&(), addr, false);
+        }
+        let ejected = addrs
+            .iter()
+            .filter(|addr| detector.is_ejected(&(), addr))
+            .count();
+        assert_eq!(ejected, 2);
+    }
+
+    #[test]
+    fn test_max_ejection_percent_of_discovered() {
+        let detector = OutlierDetector::new(
+            OutlierDetection::new()
+                .consecutive_failures(1)
+                .max_ejection_percent(50),
+        );
+        let addrs = addresses(4);
+        detector.on_change(&Change {
+            key: (),
+            all: addrs
+                .iter()
+                .map(|address| {
+                    Arc::new(Instance {
+                        address: address.clone(),
+                        weight: 1,
+                        tags: Default::default(),
+                    })
+                })
+                .collect(),
+            added: Vec::new(),
+            updated: Vec::new(),
+            removed: Vec::new(),
+        });
+        // only the first instance has reported, but it can be ejected as 1 of 4
+        detector.report(&(), &addrs[0], false);
+        assert!(detector.is_ejected(&(), &addrs[0]));
+        detector.report(&(), &addrs[1], false);
+        assert!(detector.is_ejected(&(), &addrs[1]));
+        detector.report(&(), &addrs[2], false);
+        assert!(!detector.is_ejected(&(), &addrs[2]));
+    }
+
+    #[test]
+    fn test_skip_ejected() {
+        let detector = OutlierDetector::new(OutlierDetection::new().consecutive_failures(1));
+        let addrs = addresses(3);
+        for addr in addrs.iter() {
+            detector.report(&(), addr, true);
+        }
+        detector.report(&(), &addrs[0], false);
+
+        let picked =
+            SkipEjected::new(addrs.clone().into_iter(), Some((&detector, ()))).collect::<Vec<_>>();
+        assert_eq!(picked, addrs[1..]);
+
+        // yields the first one if all the instances are ejected
+        let picked =
+            SkipEjected::new(addrs[..1].iter().cloned(), Some((&detector, ()))).collect::<Vec<_>>();
+        assert_eq!(picked, addrs[..1]);
+    }
+    #[test]
+    fn test_max_ejection_percent_concurrent() {
+        let detector = Arc::new(OutlierDetector::new(
+            OutlierDetection::new()
+                .consecutive_failures(1)
+                .max_ejection_percent(50),
+        ));
+        let addrs = addresses(8);
+        for addr in addrs.iter() {
+            detector.report(&(), addr, true);
+        }
+        let barrier = Arc::new(Barrier::new(addrs.len()));
+        let handles = addrs
+            .iter()
+            .cloned()
+            .map(|addr| {
+                let detector = detector.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    detector.report(&(), &addr, false);
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let ejected = addrs
+            .iter()
+            .filter(|addr| detector.is_ejected(&(), addr))
+            .count();
+        assert_eq!(ejected, 4);
+    }
+
+    #[derive(Default)]
+    struct FailingDiscover {
+        calls: AtomicUsize,
+    }
+
+    impl Discover for FailingDiscover {
+        type Key = ();
+        type Error = LoadBalanceError;
+
+        async fn discover<'s>(
+            &'s self,
+            _: &'s Endpoint,
+        ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Err(LoadBalanceError::Discover("unavailable".into()))
+        }
+
+        fn key(&self, _: &Endpoint) -> Self::Key {}
+
+        fn watch(&self, _: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_instances_once() {
+        let detector = OutlierDetector::new(
+            OutlierDetection::new()
+                .consecutive_failures(1)
+                .max_ejection_percent(50),
+        );
+        let discover = FailingDiscover::default();
+        let endpoint = Endpoint::new("hello".into());
+        for _ in 0..3 {
+            detector.sync_instances(&discover, &endpoint).await;
+        }
+        assert_eq!(discover.calls.load(Ordering::Relaxed), 1);
+
+        // falls back to the reported instances
+        let addrs = addresses(2);
+        detector.report(&(), &addrs[0], true);
+        detector.report(&(), &addrs[1], false);
+        assert!(detector.is_ejected(&(), &addrs[1]));
+        detector.report(&(), &addrs[0], false);
+        assert!(!detector.is_ejected(&(), &addrs[0]));
+    }
+}
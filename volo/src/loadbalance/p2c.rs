@@ -0,0 +1,470 @@
+use std::{
+    hash::Hash,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use rand::Rng;
+
+use super::{error::LoadBalanceError, LoadBalance};
+use crate::{
+    context::Endpoint,
+    discovery::{Change, Discover, Instance},
+    net::Address,
+};
+
+#[derive(Debug, Clone)]
+pub struct P2cOption {
+    /// The decay time of the peak EWMA latency.
+    ///
+    /// The larger the value, the longer a latency peak of an instance will be remembered.
+    decay: Duration,
+
+    /// The latency assumed for the instances which have never finished a call.
+    default_latency: Duration,
+}
+
+impl P2cOption {
+    pub fn new(decay: Duration, default_latency: Duration) -> Self {
+        P2cOption {
+            decay,
+            default_latency,
+        }
+    }
+}
+
+impl Default for P2cOption {
+    fn default() -> Self {
+        P2cOption {
+            decay: Duration::from_secs(10),
+            default_latency: Duration::from_millis(30),
+        }
+    }
+}
+
+/// The peak EWMA of the latency in nanoseconds.
+///
+/// A latency higher than the current value is taken immediately, while a lower one is merged
+/// into the average smoothly.
+#[derive(Debug)]
+struct PeakEwma {
+    value: f64,
+    stamp: Instant,
+}
+
+impl PeakEwma {
+    /// Returns the value decayed to `now`, so that an instance which has been slow and then left
+    /// idle will be picked again.
+    fn decayed(&self, now: Instant, decay: Duration) -> f64 {
+        let elapsed = now.saturating_duration_since(self.stamp).as_nanos() as f64;
+        self.value * (-elapsed / decay.as_nanos() as f64).exp()
+    }
+
+    fn update(&mut self, latency: Duration, decay: Duration) {
+        let now = Instant::now();
+        let latency = latency.as_nanos() as f64;
+        let current = self.decayed(now, decay);
+        self.value = if latency > current {
+            latency
+        } else {
+            let elapsed = now.saturating_duration_since(self.stamp).as_nanos() as f64;
+            let weight = (-elapsed / decay.as_nanos() as f64).exp();
+            current * weight + latency * (1.0 - weight)
+        };
+        self.stamp = now;
+    }
+}
+
+/// The runtime statistics of an instance reported by the load balance service.
+#[derive(Debug)]
+struct InstanceStats {
+    in_flight: AtomicUsize,
+    latency: Mutex<PeakEwma>,
+}
+
+impl InstanceStats {
+    fn new(default_latency: Duration) -> Self {
+        Self {
+            in_flight: AtomicUsize::new(0),
+            latency: Mutex::new(PeakEwma {
+                value: default_latency.as_nanos() as f64,
+                stamp: Instant::now(),
+            }),
+        }
+    }
+
+    /// The load of the instance, which is the latency multiplied by the number of in-flight
+    /// requests (plus one, so that an idle instance is still compared by the latency).
+    fn load(&self, now: Instant, decay: Duration) -> f64 {
+        let in_flight = self.in_flight.load(Ordering::Relaxed) as f64;
+        let latency = self.latency.lock().unwrap().decayed(now, decay);
+        latency * (in_flight + 1.0)
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    instance: Arc<Instance>,
+    stats: Arc<InstanceStats>,
+}
+
+#[derive(Debug)]
+struct WeightedInstances {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug)]
+pub struct InstancePicker {
+    shared_instances: Arc<WeightedInstances>,
+    decay: Duration,
+    /// The offsets of the instances that have been picked by this picker
+    tried: Vec<usize>,
+}
+
+impl InstancePicker {
+    /// Returns the offset of the `n`-th instance which has not been tried.
+    fn untried(&self, n: usize) -> usize {
+        if self.tried.is_empty() {
+            return n;
+        }
+        (0..self.shared_instances.nodes.len())
+            .filter(|offset| !self.tried.contains(offset))
+            .nth(n)
+            .unwrap()
+    }
+
+    /// The load of the instance divided by its weight.
+    fn cost(&self, offset: usize, now: Instant) -> f64 {
+        let node = &self.shared_instances.nodes[offset];
+        node.stats.load(now, self.decay) / node.instance.weight as f64
+    }
+}
+
+impl Iterator for InstancePicker {
+    type Item = Address;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let remaining = self.shared_instances.nodes.len() - self.tried.len();
+        let offset = match remaining {
+            0 => return None,
+            1 => self.untried(0),
+            _ => {
+                let mut rng = rand::thread_rng();
+                let first = rng.gen_range(0..remaining);
+                let mut second = rng.gen_range(0..remaining - 1);
+                if second >= first {
+                    second += 1;
+                }
+                let (first, second) = (self.untried(first), self.untried(second));
+                let now = Instant::now();
+                if self.cost(first, now) <= self.cost(second, now) {
+                    first
+                } else {
+                    second
+                }
+            }
+        };
+        self.tried.push(offset);
+        Some(self.shared_instances.nodes[offset].instance.address.clone())
+    }
+}
+
+/// [`P2cBalance`] picks two random instances and routes the request to the less loaded one,
+/// which is known as "the power of two choices".
+///
+/// The load of an instance is measured by its peak EWMA latency multiplied by the number of its
+/// in-flight requests, both of which are reported by the load balance service through
+/// [`LoadBalance::on_call_start`] and [`LoadBalance::on_call_end`]. The load is then divided by
+/// the weight of the instance, and the instances with weight 0 are never picked.
+///
+/// When retrying, the picker will skip the instances which have already been tried.
+#[derive(Debug)]
+pub struct P2cBalance<K>
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    option: P2cOption,
+    router: DashMap<K, Arc<WeightedInstances>>,
+    /// The stats are shared by all the keys routing to the same address, since the calls are
+    /// reported only with the address.
+    stats: DashMap<Address, Arc<InstanceStats>>,
+}
+
+impl<K> P2cBalance<K>
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    pub fn with_discover<D>(&mut self, _: &D) -> &mut Self
+    where
+        D: Discover<Key = K>,
+    {
+        self
+    }
+
+    pub fn new(option: P2cOption) -> Self {
+        Self {
+            option,
+            router: DashMap::new(),
+            stats: DashMap::new(),
+        }
+    }
+
+    fn build_weighted_instances(&self, instances: Vec<Arc<Instance>>) -> WeightedInstances {
+        let nodes = instances
+            .into_iter()
+            .filter(|instance| instance.weight > 0)
+            .map(|instance| {
+                let stats = self
+                    .stats
+                    .entry(instance.address.clone())
+                    .or_insert_with(|| Arc::new(InstanceStats::new(self.option.default_latency)))
+                    .clone();
+                Node { instance, stats }
+            })
+            .collect();
+        WeightedInstances { nodes }
+    }
+}
+
+impl<K> Default for P2cBalance<K>
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new(P2cOption::default())
+    }
+}
+
+impl<D> LoadBalance<D> for P2cBalance<D::Key>
+where
+    D: Discover,
+{
+    type InstanceIter = InstancePicker;
+
+    async fn get_picker<'future>(
+        &'future self,
+        endpoint: &'future Endpoint,
+        discover: &'future D,
+    ) -> Result<Self::InstanceIter, LoadBalanceError> {
+        let key = discover.key(endpoint);
+        let weighted_list = match self.router.entry(key) {
+            Entry::Occupied(e) => e.get().clone(),
+            Entry::Vacant(e) => {
+                let instances = Arc::new(
+                    self.build_weighted_instances(
+                        discover
+                            .discover(endpoint)
+                            .await
+                            .map_err(|err| err.into())?,
+                    ),
+                );
+                e.insert(instances).value().clone()
+            }
+        };
+        Ok(InstancePicker {
+            shared_instances: weighted_list,
+            decay: self.option.decay,
+            tried: Vec::new(),
+        })
+    }
+
+    fn rebalance(&self, changes: Change<D::Key>) {
+        if let Entry::Occupied(entry) = self.router.entry(changes.key.clone()) {
+            entry.replace_entry(Arc::new(self.build_weighted_instances(changes.all)));
+        }
+        // only drop the stats of the addresses that no key routes to any more
+        for instance in changes.removed.iter() {
+            let in_use = self.router.iter().any(|instances| {
+                instances
+                    .nodes
+                    .iter()
+                    .any(|node| node.instance.address == instance.address)
+            });
+            if !in_use {
+                self.stats.remove(&instance.address);
+            }
+        }
+    }
+
+    fn on_call_start(&self, address: &Address) {
+        if let Some(stats) = self.stats.get(address) {
+            stats.in_flight.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn on_call_end(&self, address: &Address, latency: Duration, _success: bool) {
+        if let Some(stats) = self.stats.get(address) {
+            // the stats may be recreated by `rebalance` during the call
+            let _ = stats
+                .in_flight
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+            stats
+                .latency
+                .lock()
+                .unwrap()
+                .update(latency, self.option.decay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use async_broadcast::Receiver;
+    use faststr::FastStr;
+
+    use super::{LoadBalance, P2cBalance, P2cOption};
+    use crate::{
+        context::Endpoint,
+        discovery::{Change, Discover, Instance, StaticDiscover},
+        net::Address,
+    };
+
+    fn new_instance(address: &str, weight: u32) -> Arc<Instance> {
+        Arc::new(Instance {
+            address: Address::Ip(address.parse().unwrap()),
+            weight,
+            tags: Default::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_p2c_picker() {
+        let empty = Endpoint::new("".into());
+        let discover = StaticDiscover::new(vec![
+            new_instance("127.0.0.1:8000", 1),
+            new_instance("127.0.0.2:8000", 0),
+            new_instance("127.0.0.3:8000", 1),
+            new_instance("127.0.0.4:8000", 1),
+        ]);
+        let lb = P2cBalance::default();
+        let picker = lb.get_picker(&empty, &discover).await.unwrap();
+        let mut all = picker.collect::<Vec<_>>();
+        // the instance with weight 0 should never be picked
+        assert_eq!(all.len(), 3);
+        all.sort_by_key(|addr| addr.to_string());
+        all.dedup();
+        assert_eq!(all.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_p2c_least_loaded() {
+        let empty = Endpoint::new("".into());
+        let busy = new_instance("127.0.0.1:8000", 1);
+        let idle = new_instance("127.0.0.2:8000", 1);
+        let discover = StaticDiscover::new(vec![busy.clone(), idle.clone()]);
+        let lb = P2cBalance::default();
+        // make sure the instances are discovered
+        let _ = lb.get_picker(&empty, &discover).await.unwrap();
+
+        for _ in 0..3 {
+            LoadBalance::<StaticDiscover>::on_call_start(&lb, &busy.address);
+        }
+        for _ in 0..10 {
+            let mut picker = lb.get_picker(&empty, &discover).await.unwrap();
+            assert_eq!(picker.next().unwrap(), idle.address);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_p2c_lowest_latency() {
+        let empty = Endpoint::new("".into());
+        let slow = new_instance("127.0.0.1:8000", 1);
+        let fast = new_instance("127.0.0.2:8000", 1);
+        let discover = StaticDiscover::new(vec![slow.clone(), fast.clone()]);
+        let lb = P2cBalance::new(P2cOption::new(
+            Duration::from_secs(10),
+            Duration::from_millis(30),
+        ));
+        let _ = lb.get_picker(&empty, &discover).await.unwrap();
+
+        for (instance, latency) in [(&slow, 500), (&fast, 5)] {
+            LoadBalance::<StaticDiscover>::on_call_start(&lb, &instance.address);
+            LoadBalance::<StaticDiscover>::on_call_end(
+                &lb,
+                &instance.address,
+                Duration::from_millis(latency),
+                true,
+            );
+        }
+        for _ in 0..10 {
+            let mut picker = lb.get_picker(&empty, &discover).await.unwrap();
+            assert_eq!(picker.next().unwrap(), fast.address);
+        }
+    }
+    /// Discovers the same instances for all the services, keyed by the service name.
+    struct SharedDiscover {
+        instances: Vec<Arc<Instance>>,
+    }
+
+    impl Discover for SharedDiscover {
+        type Key = FastStr;
+        type Error = std::convert::Infallible;
+
+        async fn discover<'s>(
+            &'s self,
+            _: &'s Endpoint,
+        ) -> Result<Vec<Arc<Instance>>, Self::Error> {
+            Ok(self.instances.clone())
+        }
+
+        fn key(&self, endpoint: &Endpoint) -> Self::Key {
+            endpoint.service_name()
+        }
+
+        fn watch(&self, _: Option<&[Self::Key]>) -> Option<Receiver<Change<Self::Key>>> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_p2c_rebalance_shared_address() {
+        let hello = Endpoint::new("hello".into());
+        let world = Endpoint::new("world".into());
+        let busy = new_instance("127.0.0.1:8000", 1);
+        let idle = new_instance("127.0.0.2:8000", 1);
+        let discover = SharedDiscover {
+            instances: vec![busy.clone(), idle.clone()],
+        };
+        let lb = P2cBalance::default();
+        let _ = lb.get_picker(&hello, &discover).await.unwrap();
+        let _ = lb.get_picker(&world, &discover).await.unwrap();
+
+        // removing the busy instance from "hello" keeps its stats for "world"
+        LoadBalance::<SharedDiscover>::rebalance(
+            &lb,
+            Change {
+                key: "hello".into(),
+                all: vec![idle.clone()],
+                added: Vec::new(),
+                updated: Vec::new(),
+                removed: vec![busy.clone()],
+            },
+        );
+        for _ in 0..3 {
+            LoadBalance::<SharedDiscover>::on_call_start(&lb, &busy.address);
+        }
+        for _ in 0..10 {
+            let mut picker = lb.get_picker(&world, &discover).await.unwrap();
+            assert_eq!(picker.next().unwrap(), idle.address);
+        }
+
+        // the stats are dropped once no key routes to the address
+        LoadBalance::<SharedDiscover>::rebalance(
+            &lb,
+            Change {
+                key: "world".into(),
+                all: vec![idle.clone()],
+                added: Vec::new(),
+                updated: Vec::new(),
+                removed: vec![busy.clone()],
+            },
+        );
+        assert!(!lb.stats.contains_key(&busy.address));
+    }
+}
@@ -19,7 +19,7 @@ fn pick_one(weight: isize, iter: &[Arc<Instance>]) -> Option<(usize, Arc<Instanc
     let mut weight = rand::thread_rng().gen_range(0..weight);
     for (offset, instance) in iter.iter().enumerate() {
         weight -= instance.weight as isize;
-        if weight <= 0 {
+        if weight < 0 {
             return Some((offset, instance.clone()));
         }
     }
@@ -72,7 +72,9 @@ struct WeightedInstances {
 }
 
 impl From<Vec<Arc<Instance>>> for WeightedInstances {
-    fn from(instances: Vec<Arc<Instance>>) -> Self {
+    fn from(mut instances: Vec<Arc<Instance>>) -> Self {
+        // the instances with weight 0 are being drained, which should never be picked
+        instances.retain(|instance| instance.weight > 0);
         let sum_of_weights = instances
             .iter()
             .fold(0, |lhs, rhs| lhs + rhs.weight as isize);
@@ -153,8 +155,22 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use super::{LoadBalance, WeightedRandomBalance};
-    use crate::{context::Endpoint, discovery::StaticDiscover};
+    use crate::{
+        context::Endpoint,
+        discovery::{diff_instances, Instance, StaticDiscover},
+        net::Address,
+    };
+
+    fn new_instance(address: &str, weight: u32) -> Arc<Instance> {
+        Arc::new(Instance {
+            address: Address::Ip(address.parse().unwrap()),
+            weight,
+            tags: Default::default(),
+        })
+    }
 
     #[tokio::test]
     async fn test_weighted_random() {
@@ -169,4 +185,29 @@ mod tests {
         assert_eq!(all.len(), 2);
         assert_ne!(all[0], all[1]);
     }
+
+    #[tokio::test]
+    async fn test_weighted_random_rebalance() {
+        let empty = Endpoint::new("".into());
+        let prev = vec![
+            new_instance("127.0.0.1:8000", 1),
+            new_instance("127.0.0.2:8000", 1),
+        ];
+        let discover = StaticDiscover::new(prev.clone());
+        let lb = WeightedRandomBalance::with_discover(&discover);
+        let _ = lb.get_picker(&empty, &discover).await.unwrap();
+
+        // drains the first instance by dropping its weight to 0
+        let next = vec![
+            new_instance("127.0.0.1:8000", 0),
+            new_instance("127.0.0.2:8000", 1),
+        ];
+        let (change, changed) = diff_instances((), prev, next.clone());
+        assert!(changed);
+        LoadBalance::<StaticDiscover>::rebalance(&lb, change);
+        for _ in 0..10 {
+            let picker = lb.get_picker(&empty, &discover).await.unwrap();
+            assert_eq!(picker.collect::<Vec<_>>(), vec![next[1].address.clone()]);
+        }
+    }
 }
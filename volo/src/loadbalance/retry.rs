@@ -0,0 +1,363 @@
+//! The retry policy of the load balance service.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use faststr::FastStr;
+use rand::Rng;
+
+/// The exponential backoff between the attempts.
+///
+/// The delay before the `n`-th retry is `base * multiplier ^ (n - 1)`, but no longer than `max`,
+/// and then a random part of it, which is `jitter` of the delay at most, is subtracted so that the
+/// retries of different requests are spread out.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    multiplier: f64,
+    jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl Backoff {
+    /// Creates an exponential backoff with the multiplier 2 and full jitter.
+    pub fn exponential(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            multiplier: 2.0,
+            jitter: 1.0,
+        }
+    }
+
+    /// Retries immediately.
+    pub fn none() -> Self {
+        Self::exponential(Duration::ZERO, Duration::ZERO)
+    }
+
+    /// Sets the multiplier of the delays.
+    ///
+    /// Default is 2.0.
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Sets the max part of the delay that is randomly subtracted, in `[0.0, 1.0]`.
+    ///
+    /// Default is 1.0, which is known as the full jitter.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Returns the delay before the `retry`-th retry, which starts from 1.
+    pub fn delay(&self, retry: usize) -> Duration {
+        if self.base.is_zero() {
+            return Duration::ZERO;
+        }
+        let exp = self
+            .multiplier
+            .powi(retry.saturating_sub(1).min(i32::MAX as usize) as i32);
+        let delay = self.base.mul_f64(exp.min(u32::MAX as f64)).min(self.max);
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        delay.mul_f64(1.0 - self.jitter * rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// [`RetryBudget`] limits the retries of a client during a partial outage, so that the retries do
+/// not overload the callee.
+///
+/// It is a token bucket in the same way as the retry throttling of gRPC. The bucket starts full
+/// with `max_tokens`, each failed attempt takes a token, and each successful one puts back
+/// `token_ratio` of a token. The retries are only allowed when there are more than half of the
+/// tokens in the bucket.
+#[derive(Debug)]
+pub struct RetryBudget {
+    max_tokens: f64,
+    token_ratio: f64,
+    tokens: Mutex<f64>,
+}
+
+impl RetryBudget {
+    pub fn new(max_tokens: u32, token_ratio: f64) -> Self {
+        Self {
+            max_tokens: max_tokens as f64,
+            token_ratio,
+            tokens: Mutex::new(max_tokens as f64),
+        }
+    }
+
+    /// Records the result of an attempt.
+    pub fn record(&self, success: bool) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = if success {
+            (*tokens + self.token_ratio).min(self.max_tokens)
+        } else {
+            (*tokens - 1.0).max(0.0)
+        };
+    }
+
+    /// Returns whether a retry is allowed now.
+    pub fn can_retry(&self) -> bool {
+        *self.tokens.lock().unwrap() > self.max_tokens / 2.0
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self::new(100, 0.1)
+    }
+}
+
+/// [`RetryClassifier`] decides whether to retry with the method name and the result of an
+/// attempt, in which `Resp` and `E` are the response and the error types of the service.
+///
+/// Returning `None` falls back to the default behavior, which retries only the
+/// [`Retryable`](super::error::Retryable) errors. It is implemented for
+/// `Fn(&str, Result<&Resp, &E>) -> Option<bool>`.
+pub trait RetryClassifier<Resp, E>: Send + Sync + 'static {
+    fn classify(&self, method: &str, result: Result<&Resp, &E>) -> Option<bool>;
+}
+
+impl<F, Resp, E> RetryClassifier<Resp, E> for F
+where
+    F: Fn(&str, Result<&Resp, &E>) -> Option<bool> + Send + Sync + 'static,
+{
+    fn classify(&self, method: &str, result: Result<&Resp, &E>) -> Option<bool> {
+        self(method, result)
+    }
+}
+
+/// The [`RetryClassifier`] that always falls back to the default behavior.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultClassifier;
+
+impl<Resp, E> RetryClassifier<Resp, E> for DefaultClassifier {
+    fn classify(&self, _method: &str, _result: Result<&Resp, &E>) -> Option<bool> {
+        None
+    }
+}
+
+/// [`RetryPolicy`] decides whether and when the load balance service retries a request on
+/// another instance.
+///
+/// A retry is sent only if the attempt is classified as retryable, the retry count of the method
+/// is not used up, the [`RetryBudget`] allows it, and the [`Deadline`](crate::context::Deadline)
+/// of the request, if any, still leaves room for another attempt after the backoff, assuming the
+/// attempt takes as long as the last one.
+pub struct RetryPolicy<C = DefaultClassifier> {
+    max_retries: Option<usize>,
+    backoff: Option<Backoff>,
+    budget: Option<Arc<RetryBudget>>,
+    classifier: Arc<C>,
+    methods: HashMap<FastStr, RetryPolicy>,
+}
+
+impl RetryPolicy {
+    /// Creates a policy that never retries.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<C: Default> Default for RetryPolicy<C> {
+    fn default() -> Self {
+        Self {
+            max_retries: None,
+            backoff: None,
+            budget: None,
+            classifier: Arc::new(C::default()),
+            methods: HashMap::new(),
+        }
+    }
+}
+
+impl<C> Clone for RetryPolicy<C> {
+    fn clone(&self) -> Self {
+        Self {
+            max_retries: self.max_retries,
+            backoff: self.backoff,
+            budget: self.budget.clone(),
+            classifier: self.classifier.clone(),
+            methods: self.methods.clone(),
+        }
+    }
+}
+
+impl<C> RetryPolicy<C> {
+    /// Sets the max number of retries.
+    ///
+    /// Default is 0.
+    pub fn max_retries(mut self, count: usize) -> Self {
+        self.max_retries = Some(count);
+        self
+    }
+
+    /// Sets the backoff between the attempts.
+    ///
+    /// Default is retrying immediately.
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = Some(backoff);
+        self
+    }
+
+    /// Sets the retry budget shared by all the requests of the client.
+    ///
+    /// Default is unlimited.
+    pub fn budget(mut self, budget: RetryBudget) -> Self {
+        self.budget = Some(Arc::new(budget));
+        self
+    }
+
+    /// Sets the classifier which decides whether to retry after an attempt, such as
+    /// `|method, result: Result<&Resp, &E>| ...` with the response and the error types of the
+    /// service.
+    pub fn classifier<Resp, E, F>(self, classifier: F) -> RetryPolicy<F>
+    where
+        F: Fn(&str, Result<&Resp, &E>) -> Option<bool> + Send + Sync + 'static,
+    {
+        RetryPolicy {
+            max_retries: self.max_retries,
+            backoff: self.backoff,
+            budget: self.budget,
+            classifier: Arc::new(classifier),
+            methods: self.methods,
+        }
+    }
+
+    /// Overrides the max retries and the backoff for the method, and the ones not set in
+    /// `policy` fall back to this policy.
+    ///
+    /// The budget and the overrides of `policy` are ignored, since the budget is shared by all
+    /// the methods. The classifier is shared as well, which can tell the methods by the name.
+    pub fn method(mut self, method: impl Into<FastStr>, policy: RetryPolicy) -> Self {
+        self.methods.insert(method.into(), policy);
+        self
+    }
+
+    pub(super) fn max_retries_of(&self, method: &str) -> usize {
+        self.methods
+            .get(method)
+            .and_then(|policy| policy.max_retries)
+            .or(self.max_retries)
+            .unwrap_or(0)
+    }
+
+    pub(super) fn backoff_of(&self, method: &str) -> Backoff {
+        self.methods
+            .get(method)
+            .and_then(|policy| policy.backoff)
+            .or(self.backoff)
+            .unwrap_or_default()
+    }
+
+    pub(super) fn budget_ref(&self) -> Option<&RetryBudget> {
+        self.budget.as_deref()
+    }
+
+    /// Returns whether the result of an attempt should be retried.
+    pub(super) fn should_retry<Resp, E>(
+        &self,
+        method: &str,
+        result: Result<&Resp, &E>,
+        retryable: bool,
+    ) -> bool
+    where
+        C: RetryClassifier<Resp, E>,
+    {
+        self.classifier
+            .classify(method, result)
+            .unwrap_or(result.is_err() && retryable)
+    }
+}
+
+impl<C> fmt::Debug for RetryPolicy<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_retries", &self.max_retries)
+            .field("backoff", &self.backoff)
+            .field("budget", &self.budget)
+            .field("classifier", &std::any::type_name::<C>())
+            .field("methods", &self.methods)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Backoff, RetryBudget, RetryPolicy};
+
+    #[test]
+    fn test_backoff() {
+        let backoff =
+            Backoff::exponential(Duration::from_millis(10), Duration::from_millis(50)).jitter(0.0);
+        let delays = (1..=4).map(|n| backoff.delay(n)).collect::<Vec<_>>();
+        assert_eq!(delays, [10, 20, 40, 50].map(Duration::from_millis).to_vec());
+
+        let backoff = backoff.jitter(0.5);
+        for _ in 0..100 {
+            let delay = backoff.delay(2);
+            assert!(delay > Duration::from_millis(10) && delay <= Duration::from_millis(20));
+        }
+        assert_eq!(Backoff::none().delay(3), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_budget() {
+        let budget = RetryBudget::new(10, 0.5);
+        for _ in 0..4 {
+            budget.record(false);
+        }
+        assert!(budget.can_retry());
+        budget.record(false);
+        assert!(!budget.can_retry());
+        budget.record(true);
+        assert!(budget.can_retry());
+    }
+
+    #[test]
+    fn test_classifier() {
+        let policy = RetryPolicy::new()
+            .max_retries(2)
+            .backoff(Backoff::exponential(
+                Duration::from_millis(10),
+                Duration::from_millis(10),
+            ))
+            .method("no_retry", RetryPolicy::new().max_retries(0))
+            .method("fast", RetryPolicy::new().backoff(Backoff::none()))
+            .classifier(|method, result: Result<&String, &()>| {
+                if method == "no_retry" {
+                    return Some(false);
+                }
+                // retries the empty responses
+                result.ok().map(|resp| resp.is_empty())
+            });
+        let (empty, err) = (String::new(), ());
+        assert!(policy.should_retry("foo", Ok(&empty), false));
+        assert!(!policy.should_retry("foo", Ok(&"ok".to_string()), false));
+        assert!(policy.should_retry("foo", Err(&err), true));
+        assert!(!policy.should_retry("foo", Err(&err), false));
+        assert!(!policy.should_retry("no_retry", Err(&err), true));
+        assert_eq!(policy.max_retries_of("foo"), 2);
+        assert_eq!(policy.max_retries_of("no_retry"), 0);
+        // the overrides not set fall back to the policy
+        assert_eq!(policy.max_retries_of("fast"), 2);
+        assert_eq!(policy.backoff_of("fast").delay(1), Duration::ZERO);
+        assert!(!policy.backoff_of("no_retry").delay(1).is_zero());
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+
+use super::{error::LoadBalanceError, LoadBalance};
+use crate::{
+    context::Endpoint,
+    discovery::{Change, Discover, Instance},
+    net::Address,
+};
+
+/// The instances of a service together with the current weights used by the smooth weighted
+/// round-robin algorithm.
+#[derive(Debug)]
+struct WeightedInstances {
+    instances: Vec<Arc<Instance>>,
+    current_weights: Mutex<Vec<isize>>,
+}
+
+impl From<Vec<Arc<Instance>>> for WeightedInstances {
+    fn from(instances: Vec<Arc<Instance>>) -> Self {
+        let current_weights = Mutex::new(vec![0; instances.len()]);
+        Self {
+            instances,
+            current_weights,
+        }
+    }
+}
+
+impl WeightedInstances {
+    /// Picks the next instance and updates the shared current weights, and returns the current
+    /// weights after picking, which are used by the retries of the same picker.
+    fn pick(&self) -> (Option<usize>, Vec<isize>) {
+        let mut current_weights = self.current_weights.lock().unwrap();
+        let picked = pick(&self.instances, &mut current_weights, &[]);
+        (picked, current_weights.clone())
+    }
+}
+
+/// Picks the next instance in the same way as nginx does.
+///
+/// Every instance increases its current weight by its own weight, the one with the largest
+/// current weight is selected, and then the selected one decreases its current weight by the
+/// total weight. The instances whose offsets are in `tried` or whose weight is 0 are skipped.
+fn pick(
+    instances: &[Arc<Instance>],
+    current_weights: &mut [isize],
+    tried: &[usize],
+) -> Option<usize> {
+    let mut total = 0;
+    let mut best: Option<usize> = None;
+    for (offset, instance) in instances.iter().enumerate() {
+        if instance.weight == 0 || tried.contains(&offset) {
+            continue;
+        }
+        let weight = instance.weight as isize;
+        current_weights[offset] += weight;
+        total += weight;
+        match best {
+            Some(best) if current_weights[best] >= current_weights[offset] => {}
+            _ => best = Some(offset),
+        }
+    }
+    let best = best?;
+    current_weights[best] -= total;
+    Some(best)
+}
+
+#[derive(Debug)]
+pub struct InstancePicker {
+    shared_instances: Arc<WeightedInstances>,
+    /// The current weights used by the retries, which are copied from the shared ones after the
+    /// first pick, so that the retries do not skew the sequence of the other callers.
+    current_weights: Option<Vec<isize>>,
+    /// The offsets of the instances that have been picked by this picker
+    tried: Vec<usize>,
+}
+
+impl Iterator for InstancePicker {
+    type Item = Address;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = match &mut self.current_weights {
+            Some(current_weights) => pick(
+                &self.shared_instances.instances,
+                current_weights,
+                &self.tried,
+            )?,
+            None => {
+                let (offset, current_weights) = self.shared_instances.pick();
+                self.current_weights = Some(current_weights);
+                offset?
+            }
+        };
+        self.tried.push(offset);
+        Some(self.shared_instances.instances[offset].address.clone())
+    }
+}
+
+/// [`WeightedRoundRobinBalance`] is an implementation of the smooth weighted round-robin
+/// algorithm used by nginx.
+///
+/// Different from [`WeightedRandomBalance`](super::random::WeightedRandomBalance), the sequence
+/// of the picked instances is deterministic, and the instances are spread evenly even if the
+/// cluster is small. For example, the instances with weights `{ a: 5, b: 1, c: 1 }` will be
+/// picked as `a, a, b, a, c, a, a`.
+///
+/// When retrying, the picker will skip the instances which have already been tried.
+#[derive(Debug)]
+pub struct WeightedRoundRobinBalance<K>
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    router: DashMap<K, Arc<WeightedInstances>>,
+}
+
+impl<K> WeightedRoundRobinBalance<K>
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    pub fn with_discover<D>(_: &D) -> Self
+    where
+        D: Discover<Key = K>,
+    {
+        Self::new()
+    }
+
+    pub fn new() -> Self {
+        Self {
+            router: DashMap::new(),
+        }
+    }
+}
+
+impl<K> Default for WeightedRoundRobinBalance<K>
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D> LoadBalance<D> for WeightedRoundRobinBalance<D::Key>
+where
+    D: Discover,
+{
+    type InstanceIter = InstancePicker;
+
+    async fn get_picker<'future>(
+        &'future self,
+        endpoint: &'future Endpoint,
+        discover: &'future D,
+    ) -> Result<Self::InstanceIter, LoadBalanceError> {
+        let key = discover.key(endpoint);
+        let weighted_list = match self.router.entry(key) {
+            Entry::Occupied(e) => e.get().clone(),
+            Entry::Vacant(e) => {
+                let instances = Arc::new(WeightedInstances::from(
+                    discover
+                        .discover(endpoint)
+                        .await
+                        .map_err(|err| err.into())?,
+                ));
+                e.insert(instances).value().clone()
+            }
+        };
+        Ok(InstancePicker {
+            shared_instances: weighted_list,
+            current_weights: None,
+            tried: Vec::new(),
+        })
+    }
+
+    fn rebalance(&self, changes: Change<D::Key>) {
+        if let Entry::Occupied(entry) = self.router.entry(changes.key.clone()) {
+            entry.replace_entry(Arc::new(WeightedInstances::from(changes.all)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{LoadBalance, WeightedRoundRobinBalance};
+    use crate::{
+        context::Endpoint,
+        discovery::{Instance, StaticDiscover},
+        net::Address,
+    };
+
+    fn new_instance(address: &str, weight: u32) -> Arc<Instance> {
+        Arc::new(Instance {
+            address: Address::Ip(address.parse().unwrap()),
+            weight,
+            tags: Default::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_smooth_weighted_round_robin() {
+        let empty = Endpoint::new("".into());
+        let a = new_instance("127.0.0.1:8000", 5);
+        let b = new_instance("127.0.0.2:8000", 1);
+        let c = new_instance("127.0.0.3:8000", 1);
+        let discover = StaticDiscover::new(vec![a.clone(), b.clone(), c.clone()]);
+        let lb = WeightedRoundRobinBalance::with_discover(&discover);
+
+        let mut picked = Vec::new();
+        for _ in 0..7 {
+            let mut picker = lb.get_picker(&empty, &discover).await.unwrap();
+            picked.push(picker.next().unwrap());
+        }
+        let expected = [&a, &a, &b, &a, &c, &a, &a]
+            .iter()
+            .map(|i| i.address.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, expected);
+    }
+
+    #[tokio::test]
+    async fn test_retry_does_not_skew_sequence() {
+        let empty = Endpoint::new("".into());
+        let a = new_instance("127.0.0.1:8000", 5);
+        let b = new_instance("127.0.0.2:8000", 1);
+        let c = new_instance("127.0.0.3:8000", 1);
+        let discover = StaticDiscover::new(vec![a.clone(), b.clone(), c.clone()]);
+        let lb = WeightedRoundRobinBalance::with_discover(&discover);
+
+        let mut picked = Vec::new();
+        for _ in 0..7 {
+            let mut picker = lb.get_picker(&empty, &discover).await.unwrap();
+            picked.push(picker.next().unwrap());
+            // the retries are picked from the local weights
+            assert!(picker.next().is_some());
+            assert!(picker.next().is_some());
+        }
+        let expected = [&a, &a, &b, &a, &c, &a, &a]
+            .iter()
+            .map(|i| i.address.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, expected);
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_retry() {
+        let empty = Endpoint::new("".into());
+        let discover = StaticDiscover::new(vec![
+            new_instance("127.0.0.1:8000", 3),
+            new_instance("127.0.0.2:8000", 0),
+            new_instance("127.0.0.3:8000", 1),
+            new_instance("127.0.0.4:8000", 2),
+        ]);
+        let lb = WeightedRoundRobinBalance::with_discover(&discover);
+        let picker = lb.get_picker(&empty, &discover).await.unwrap();
+        let mut all = picker.collect::<Vec<_>>();
+        // the instance with weight 0 should never be picked
+        assert_eq!(all.len(), 3);
+        all.sort_by_key(|addr| addr.to_string());
+        all.dedup();
+        assert_eq!(all.len(), 3);
+    }
+}
@@ -0,0 +1,531 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use faststr::FastStr;
+use rand::Rng;
+
+use super::{error::LoadBalanceError, LoadBalance};
+use crate::{
+    context::Endpoint,
+    discovery::{Change, Discover, Instance},
+    net::Address,
+};
+
+/// The tag of the caller [`Endpoint`] that carries the zone where the client is located, which
+/// can be set by `caller.insert_faststr::<Zone>(zone)`.
+pub struct Zone;
+
+#[derive(Debug, Clone)]
+pub struct ZoneAwareOption {
+    /// The zone of the client, used when the caller carries no [`Zone`] tag.
+    zone: Option<FastStr>,
+
+    /// The key of [`Instance::tags`] that carries the zone of an instance.
+    zone_tag: Cow<'static, str>,
+
+    /// The local zone keeps all the traffic as long as its share of the total weight is at least
+    /// this fraction of the share it would have if the weight was spread evenly across the zones.
+    /// Otherwise the traffic spills to the other zones in proportion to the missing capacity.
+    min_local_capacity: f64,
+
+    /// The traffic spills to the other zones when the in-flight requests per weight of the local
+    /// zone exceed this multiple of the ones of the other zones.
+    max_load_imbalance: f64,
+}
+
+impl Default for ZoneAwareOption {
+    fn default() -> Self {
+        Self {
+            zone: None,
+            zone_tag: Cow::Borrowed("zone"),
+            min_local_capacity: 0.7,
+            max_load_imbalance: 2.0,
+        }
+    }
+}
+
+impl ZoneAwareOption {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the zone of the client, which is used when the caller [`Endpoint`] carries no
+    /// [`Zone`] tag.
+    ///
+    /// Default is none, and the traffic of such callers spreads across all the zones.
+    pub fn zone(mut self, zone: impl Into<FastStr>) -> Self {
+        self.zone = Some(zone.into());
+        self
+    }
+
+    /// Sets the key of the tag that carries the zone of an instance.
+    ///
+    /// Default is `zone`.
+    pub fn zone_tag(mut self, tag: impl Into<Cow<'static, str>>) -> Self {
+        self.zone_tag = tag.into();
+        self
+    }
+
+    /// Sets the min capacity of the local zone relative to an even spread before spilling.
+    ///
+    /// Default is 0.7, and 0 disables spilling by capacity.
+    pub fn min_local_capacity(mut self, ratio: f64) -> Self {
+        self.min_local_capacity = ratio;
+        self
+    }
+
+    /// Sets the max load of the local zone relative to the other zones before spilling.
+    ///
+    /// Default is 2.0, and [`f64::INFINITY`] disables spilling by load.
+    pub fn max_load_imbalance(mut self, ratio: f64) -> Self {
+        self.max_load_imbalance = ratio;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Group {
+    instances: Vec<Arc<Instance>>,
+    sum_of_weights: usize,
+    /// The in-flight counters of the zones in the group, which are shared by the instances of
+    /// each zone, so the load is summed by zone rather than by instance.
+    in_flight: Vec<Arc<AtomicUsize>>,
+}
+
+impl Group {
+    fn extend(&mut self, other: &Group) {
+        self.instances.extend(other.instances.iter().cloned());
+        self.sum_of_weights += other.sum_of_weights;
+        self.in_flight.extend(other.in_flight.iter().cloned());
+    }
+
+    /// The in-flight requests per weight, plus one so that the idle groups are still compared by
+    /// the weights.
+    fn load(&self) -> f64 {
+        let in_flight = self
+            .in_flight
+            .iter()
+            .map(|in_flight| in_flight.load(Ordering::Relaxed))
+            .sum::<usize>();
+        (in_flight + 1) as f64 / self.sum_of_weights as f64
+    }
+
+    /// Picks a random instance by the weights, skipping the offsets in `tried`.
+    fn pick(&self, tried: &[usize]) -> Option<usize> {
+        let sum_of_weights = self.sum_of_weights
+            - tried
+                .iter()
+                .map(|offset| self.instances[*offset].weight as usize)
+                .sum::<usize>();
+        if sum_of_weights == 0 {
+            return None;
+        }
+        let mut weight = rand::thread_rng().gen_range(0..sum_of_weights);
+        for (offset, instance) in self.instances.iter().enumerate() {
+            if tried.contains(&offset) {
+                continue;
+            }
+            let instance_weight = instance.weight as usize;
+            if weight < instance_weight {
+                return Some(offset);
+            }
+            weight -= instance_weight;
+        }
+        None
+    }
+}
+
+/// The instances of a service split into the local zone and the other zones.
+#[derive(Debug)]
+struct ZonedInstances {
+    local: Group,
+    remote: Group,
+    /// The share of the traffic that stays in the local zone when the load is even.
+    local_ratio: f64,
+}
+
+/// The instances of a service grouped by zone.
+#[derive(Debug)]
+struct Cluster {
+    zones: HashMap<Option<FastStr>, Group>,
+    /// The instances split by each local zone of the callers, which are built on demand.
+    views: DashMap<Option<FastStr>, Arc<ZonedInstances>>,
+}
+
+impl Cluster {
+    fn zoned(&self, local_zone: Option<&FastStr>, min_local_capacity: f64) -> Arc<ZonedInstances> {
+        let local_zone = local_zone.cloned();
+        if let Some(zoned) = self.views.get(&local_zone) {
+            return zoned.clone();
+        }
+        let mut local = Group::default();
+        let mut remote = Group::default();
+        for (zone, group) in &self.zones {
+            if local_zone.is_some() && zone == &local_zone {
+                local.extend(group);
+            } else {
+                remote.extend(group);
+            }
+        }
+
+        let total = (local.sum_of_weights + remote.sum_of_weights) as f64;
+        let local_ratio = if local.sum_of_weights == 0 {
+            0.0
+        } else if remote.sum_of_weights == 0 {
+            1.0
+        } else {
+            // compares the share of the local zone with the even share of each zone
+            let share = local.sum_of_weights as f64 / total;
+            let even_share = 1.0 / self.zones.len() as f64;
+            if share >= even_share * min_local_capacity {
+                1.0
+            } else {
+                share / even_share
+            }
+        };
+        let zoned = Arc::new(ZonedInstances {
+            local,
+            remote,
+            local_ratio,
+        });
+        self.views.insert(local_zone, zoned.clone());
+        zoned
+    }
+}
+
+#[derive(Debug)]
+pub struct InstancePicker {
+    shared_instances: Arc<ZonedInstances>,
+    /// Whether to pick from the local zone first
+    local_first: bool,
+    /// 0 for the group picked first and 1 for the other one
+    stage: usize,
+    tried: Vec<usize>,
+}
+
+impl InstancePicker {
+    fn group(&self) -> Option<&Group> {
+        match (self.stage, self.local_first) {
+            (0, true) | (1, false) => Some(&self.shared_instances.local),
+            (0, false) | (1, true) => Some(&self.shared_instances.remote),
+            _ => None,
+        }
+    }
+}
+
+impl Iterator for InstancePicker {
+    type Item = Address;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let group = self.group()?;
+            if let Some(offset) = group.pick(&self.tried) {
+                let address = group.instances[offset].address.clone();
+                self.tried.push(offset);
+                return Some(address);
+            }
+            self.stage += 1;
+            self.tried.clear();
+        }
+    }
+}
+
+/// [`ZoneAwareBalance`] prefers the instances in the same zone as the client, which is read from
+/// the tags of the instances, to save the cost and latency of the cross-zone traffic.
+///
+/// The zone of the client is read from the [`Zone`] tag of the caller [`Endpoint`], or the one
+/// set by [`ZoneAwareOption::zone`] if absent.
+///
+/// The traffic spills to the other zones only when the local zone does not have enough capacity
+/// compared with an even spread across the zones, or the local zone is much more loaded than the
+/// others, measured by the in-flight requests reported through [`LoadBalance::on_call_start`]
+/// and [`LoadBalance::on_call_end`]. The instances are picked randomly by the weights within a
+/// zone, and the instances with weight 0 are never picked.
+///
+/// When retrying, the picker tries the rest of the chosen zone before the other zones.
+#[derive(Debug)]
+pub struct ZoneAwareBalance<K>
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    option: ZoneAwareOption,
+    router: DashMap<K, Arc<Cluster>>,
+    /// The in-flight counter of the zone of each instance
+    in_flight: DashMap<Address, Arc<AtomicUsize>>,
+}
+
+impl<K> ZoneAwareBalance<K>
+where
+    K: Hash + PartialEq + Eq + Send + Sync + 'static,
+{
+    pub fn with_discover<D>(&mut self, _: &D) -> &mut Self
+    where
+        D: Discover<Key = K>,
+    {
+        self
+    }
+
+    pub fn new(option: ZoneAwareOption) -> Self {
+        Self {
+            option,
+            router: DashMap::new(),
+            in_flight: DashMap::new(),
+        }
+    }
+
+    /// Groups the instances by zone, and keeps the in-flight counters of the zones in the
+    /// previous cluster.
+    fn build_cluster(&self, instances: Vec<Arc<Instance>>, prev: Option<&Cluster>) -> Cluster {
+        let mut zones: HashMap<Option<FastStr>, Group> = HashMap::new();
+        for instance in instances {
+            if instance.weight == 0 {
+                continue;
+            }
+            let zone = instance
+                .tags
+                .get(&self.option.zone_tag)
+                .map(|zone| FastStr::new(zone.as_ref()));
+            let group = zones.entry(zone.clone()).or_insert_with(|| {
+                let in_flight = prev
+                    .and_then(|prev| prev.zones.get(&zone))
+                    .and_then(|group| group.in_flight.first().cloned())
+                    .unwrap_or_default();
+                Group {
+                    in_flight: vec![in_flight],
+                    ..Default::default()
+                }
+            });
+            self.in_flight
+                .insert(instance.address.clone(), group.in_flight[0].clone());
+            group.sum_of_weights += instance.weight as usize;
+            group.instances.push(instance);
+        }
+        Cluster {
+            zones,
+            views: DashMap::new(),
+        }
+    }
+
+    async fn pick<D>(
+        &self,
+        local_zone: Option<&FastStr>,
+        endpoint: &Endpoint,
+        discover: &D,
+    ) -> Result<InstancePicker, LoadBalanceError>
+    where
+        D: Discover<Key = K>,
+    {
+        let key = discover.key(endpoint);
+        let cluster = match self.router.entry(key) {
+            Entry::Occupied(e) => e.get().clone(),
+            Entry::Vacant(e) => {
+                let cluster = Arc::new(
+                    self.build_cluster(
+                        discover
+                            .discover(endpoint)
+                            .await
+                            .map_err(|err| err.into())?,
+                        None,
+                    ),
+                );
+                e.insert(cluster).value().clone()
+            }
+        };
+        let zoned = cluster.zoned(local_zone, self.option.min_local_capacity);
+        let balanced = || {
+            zoned.remote.instances.is_empty()
+                || zoned.local.load() <= zoned.remote.load() * self.option.max_load_imbalance
+        };
+        let local_first = match zoned.local_ratio {
+            ratio if ratio <= 0.0 => false,
+            ratio if ratio >= 1.0 => balanced(),
+            ratio => rand::thread_rng().gen_bool(ratio) && balanced(),
+        };
+        Ok(InstancePicker {
+            shared_instances: zoned,
+            local_first,
+            stage: 0,
+            tried: Vec::new(),
+        })
+    }
+}
+
+impl<D> LoadBalance<D> for ZoneAwareBalance<D::Key>
+where
+    D: Discover,
+{
+    type InstanceIter = InstancePicker;
+
+    async fn get_picker<'future>(
+        &'future self,
+        endpoint: &'future Endpoint,
+        discover: &'future D,
+    ) -> Result<Self::InstanceIter, LoadBalanceError> {
+        self.pick(self.option.zone.as_ref(), endpoint, discover)
+            .await
+    }
+
+    async fn get_picker_with_caller<'future>(
+        &'future self,
+        caller: &'future Endpoint,
+        callee: &'future Endpoint,
+        discover: &'future D,
+    ) -> Result<Self::InstanceIter, LoadBalanceError> {
+        let local_zone = caller.get_faststr::<Zone>().or(self.option.zone.as_ref());
+        self.pick(local_zone, callee, discover).await
+    }
+
+    fn rebalance(&self, changes: Change<D::Key>) {
+        for instance in changes.removed.iter() {
+            self.in_flight.remove(&instance.address);
+        }
+        if let Entry::Occupied(mut entry) = self.router.entry(changes.key.clone()) {
+            let cluster = self.build_cluster(changes.all, Some(entry.get()));
+            entry.insert(Arc::new(cluster));
+        }
+    }
+
+    fn on_call_start(&self, address: &Address) {
+        if let Some(in_flight) = self.in_flight.get(address) {
+            in_flight.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn on_call_end(&self, address: &Address, _latency: Duration, _success: bool) {
+        if let Some(in_flight) = self.in_flight.get(address) {
+            // the instance may move to another zone by `rebalance` during the call
+            let _ =
+                in_flight.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{LoadBalance, Zone, ZoneAwareBalance, ZoneAwareOption};
+    use crate::{
+        context::Endpoint,
+        discovery::{Instance, StaticDiscover},
+        net::Address,
+    };
+
+    fn new_instance(address: &str, zone: &'static str, weight: u32) -> Arc<Instance> {
+        Arc::new(Instance {
+            address: Address::Ip(address.parse().unwrap()),
+            weight,
+            tags: [("zone".into(), zone.into())].into_iter().collect(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_prefer_local_zone() {
+        let empty = Endpoint::new("".into());
+        let local = new_instance("127.0.0.1:8000", "a", 1);
+        let remote = new_instance("127.0.0.2:8000", "b", 1);
+        let discover = StaticDiscover::new(vec![
+            local.clone(),
+            remote.clone(),
+            new_instance("127.0.0.3:8000", "a", 0),
+        ]);
+        let lb = ZoneAwareBalance::new(ZoneAwareOption::new().zone("a"));
+        for _ in 0..10 {
+            let picker = lb.get_picker(&empty, &discover).await.unwrap();
+            // falls back to the other zones when retrying
+            assert_eq!(
+                picker.collect::<Vec<_>>(),
+                vec![local.address.clone(), remote.address.clone()]
+            );
+        }
+
+        // spills when the local zone is overloaded
+        for _ in 0..5 {
+            LoadBalance::<StaticDiscover>::on_call_start(&lb, &local.address);
+        }
+        let mut picker = lb.get_picker(&empty, &discover).await.unwrap();
+        assert_eq!(picker.next().unwrap(), remote.address);
+    }
+
+    #[tokio::test]
+    async fn test_spill_by_capacity() {
+        let empty = Endpoint::new("".into());
+        let discover = StaticDiscover::new(vec![
+            new_instance("127.0.0.1:8000", "a", 1),
+            new_instance("127.0.0.2:8000", "b", 4),
+            new_instance("127.0.0.3:8000", "c", 5),
+        ]);
+        let lb = ZoneAwareBalance::new(
+            ZoneAwareOption::new()
+                .zone("a")
+                .max_load_imbalance(f64::INFINITY),
+        );
+        let local = Address::Ip("127.0.0.1:8000".parse().unwrap());
+        let mut local_count = 0;
+        for _ in 0..3000 {
+            let mut picker = lb.get_picker(&empty, &discover).await.unwrap();
+            if picker.next().unwrap() == local {
+                local_count += 1;
+            }
+        }
+        // the local zone has 10% of the weight, while the even share is 33%, so about 30% of the
+        // traffic stays in the local zone
+        assert!((700..1100).contains(&local_count), "{local_count}");
+    }
+
+    #[tokio::test]
+    async fn test_spill_by_load_with_partial_capacity() {
+        let empty = Endpoint::new("".into());
+        let discover = StaticDiscover::new(vec![
+            new_instance("127.0.0.1:8000", "a", 1),
+            new_instance("127.0.0.2:8000", "b", 4),
+            new_instance("127.0.0.3:8000", "c", 5),
+        ]);
+        let lb = ZoneAwareBalance::new(ZoneAwareOption::new().zone("a"));
+        let local = Address::Ip("127.0.0.1:8000".parse().unwrap());
+        let _ = lb.get_picker(&empty, &discover).await.unwrap();
+        for _ in 0..10 {
+            LoadBalance::<StaticDiscover>::on_call_start(&lb, &local);
+        }
+        // the local zone keeps part of the traffic by the capacity, but it's overloaded
+        for _ in 0..100 {
+            let mut picker = lb.get_picker(&empty, &discover).await.unwrap();
+            assert_ne!(picker.next().unwrap(), local);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zone_of_caller() {
+        let callee = Endpoint::new("".into());
+        let a = new_instance("127.0.0.1:8000", "a", 1);
+        let b = new_instance("127.0.0.2:8000", "b", 1);
+        let discover = StaticDiscover::new(vec![a.clone(), b.clone()]);
+        let lb = ZoneAwareBalance::new(ZoneAwareOption::new().zone("a"));
+        let mut caller = Endpoint::new("".into());
+        for _ in 0..10 {
+            let mut picker = lb
+                .get_picker_with_caller(&caller, &callee, &discover)
+                .await
+                .unwrap();
+            assert_eq!(picker.next().unwrap(), a.address);
+        }
+        // the zone of the caller overrides the one of the option
+        caller.insert_faststr::<Zone>("b".into());
+        for _ in 0..10 {
+            let mut picker = lb
+                .get_picker_with_caller(&caller, &callee, &discover)
+                .await
+                .unwrap();
+            assert_eq!(picker.next().unwrap(), b.address);
+        }
+    }
+}
@@ -12,11 +12,34 @@ use tokio::{
     net::{tcp, TcpStream},
 };
 
-use super::Address;
+use super::{incoming::ProxyInfo, Address};
 
+/// The information of a connection.
+///
+/// It is non-exhaustive since more information may be recorded in the future, so it should be
+/// created by [`ConnInfo::new`] outside of this crate, and the other fields can be set after that.
 #[derive(Clone)]
+#[non_exhaustive]
 pub struct ConnInfo {
     pub peer_addr: Option<Address>,
+    /// The information from the PROXY protocol header, in which case the `peer_addr` is the
+    /// address of the client rather than the proxy.
+    pub proxy: Option<ProxyInfo>,
+    /// The address of the listener which accepted the connection, which is recorded by
+    /// [`MultiIncoming`](super::incoming::MultiIncoming).
+    pub listener: Option<Address>,
+}
+
+impl ConnInfo {
+    /// Creates the information of a connection from the address of the peer.
+    #[inline]
+    pub fn new(peer_addr: Option<Address>) -> Self {
+        Self {
+            peer_addr,
+            proxy: None,
+            listener: None,
+        }
+    }
 }
 
 pub trait DynStream: AsyncRead + AsyncWrite + Send + 'static {}
@@ -333,6 +356,63 @@ impl ConnStream {
                 .ok(),
         }
     }
+
+    /// Returns the identity of the peer verified in the TLS handshake, or `None` if the stream is
+    /// not TLS or the peer has not presented a certificate.
+    #[cfg(feature = "__tls")]
+    pub fn peer_identity(&self) -> Option<super::tls::PeerIdentity> {
+        match self {
+            #[cfg(feature = "rustls")]
+            Self::Rustls(s) => s.get_ref().1.peer_certificates().map(|certs| {
+                super::tls::PeerIdentity::new(certs.iter().map(|cert| cert.to_vec()).collect())
+            }),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(s) => s
+                .get_ref()
+                .peer_certificate()
+                .ok()
+                .flatten()
+                .and_then(|cert| cert.to_der().ok())
+                .map(|cert| super::tls::PeerIdentity::new(vec![cert])),
+            _ => None,
+        }
+    }
+
+    /// Returns the details of the TLS session, or `None` if the stream is not TLS.
+    #[cfg(feature = "__tls")]
+    pub fn tls_session(&self) -> Option<super::tls::TlsSession> {
+        match self {
+            #[cfg(feature = "rustls")]
+            Self::Rustls(s) => {
+                let server_name = match s {
+                    tokio_rustls::TlsStream::Server(s) => {
+                        s.get_ref().1.server_name().map(faststr::FastStr::new)
+                    }
+                    tokio_rustls::TlsStream::Client(_) => None,
+                };
+                let state = s.get_ref().1;
+                Some(super::tls::TlsSession {
+                    server_name,
+                    alpn_protocol: state.alpn_protocol().map(<[u8]>::to_vec),
+                    protocol_version: state
+                        .protocol_version()
+                        .and_then(|version| version.as_str())
+                        .map(faststr::FastStr::from_static_str),
+                    cipher_suite: state
+                        .negotiated_cipher_suite()
+                        .and_then(|suite| suite.suite().as_str())
+                        .map(faststr::FastStr::from_static_str),
+                    peer_identity: self.peer_identity(),
+                })
+            }
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(_) => Some(super::tls::TlsSession {
+                peer_identity: self.peer_identity(),
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
 }
 pub struct Conn {
     pub stream: ConnStream,
@@ -354,7 +434,7 @@ where
     fn from(i: T) -> Self {
         let i = i.into();
         let peer_addr = i.peer_addr();
-        Conn::new(i, ConnInfo { peer_addr })
+        Conn::new(i, ConnInfo::new(peer_addr))
     }
 }
 
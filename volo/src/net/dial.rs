@@ -1,20 +1,26 @@
 use std::{future::Future, io, net::SocketAddr};
 
+use futures::{stream::FuturesUnordered, StreamExt};
 use motore::{make::MakeConnection, service::UnaryService};
-use socket2::{Domain, Protocol, Socket, Type};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 #[cfg(target_family = "unix")]
 use tokio::net::UnixStream;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::{TcpSocket, TcpStream},
-    time::{timeout, Duration},
+    time::{sleep, timeout, Duration},
 };
 
 use super::{
-    conn::{Conn, OwnedReadHalf, OwnedWriteHalf},
+    conn::{Conn, ConnStream, OwnedReadHalf, OwnedWriteHalf},
+    probe::probe,
+    socket::SocketConfig,
     Address,
 };
 
+/// The delay between starting two connection attempts recommended by RFC 8305.
+const DEFAULT_CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
 /// [`MakeTransport`] creates an [`AsyncRead`] and an [`AsyncWrite`] for the given [`Address`].
 pub trait MakeTransport: Clone + Send + Sync + 'static {
     type ReadHalf: AsyncRead + Send + Sync + Unpin + 'static;
@@ -27,6 +33,8 @@ pub trait MakeTransport: Clone + Send + Sync + 'static {
     fn set_connect_timeout(&mut self, timeout: Option<Duration>);
     fn set_read_timeout(&mut self, timeout: Option<Duration>);
     fn set_write_timeout(&mut self, timeout: Option<Duration>);
+    /// Sets the options of the TCP sockets, which is ignored by default.
+    fn set_socket_config(&mut self, _config: SocketConfig) {}
 }
 
 #[derive(Default, Debug, Clone, Copy)]
@@ -39,6 +47,11 @@ pub struct Config {
     pub connect_timeout: Option<Duration>,
     pub read_timeout: Option<Duration>,
     pub write_timeout: Option<Duration>,
+    /// The options of the TCP sockets.
+    pub socket_config: SocketConfig,
+    /// The delay before starting the next connection attempt when connecting to multiple
+    /// addresses, `None` means the 250ms recommended by RFC 8305.
+    pub connection_attempt_delay: Option<Duration>,
 }
 
 impl Config {
@@ -51,6 +64,8 @@ impl Config {
             connect_timeout,
             read_timeout,
             write_timeout,
+            socket_config: SocketConfig::default(),
+            connection_attempt_delay: None,
         }
     }
 
@@ -68,12 +83,139 @@ impl Config {
         self.write_timeout = timeout;
         self
     }
+
+    pub fn with_socket_config(mut self, config: SocketConfig) -> Self {
+        self.socket_config = config;
+        self
+    }
+
+    pub fn with_connection_attempt_delay(mut self, delay: Option<Duration>) -> Self {
+        self.connection_attempt_delay = delay;
+        self
+    }
 }
 
 impl DefaultMakeTransport {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Sets the delay between the connection attempts of
+    /// [`make_connection_to_any`](Self::make_connection_to_any).
+    pub fn set_connection_attempt_delay(&mut self, delay: Option<Duration>) {
+        self.cfg = self.cfg.with_connection_attempt_delay(delay);
+    }
+
+    /// Connects to any of the addresses with the Happy Eyeballs algorithm of RFC 8305, which is
+    /// useful when a host resolves to multiple addresses such as both IPv4 and IPv6 ones.
+    ///
+    /// The addresses are reordered to alternate between the address families, starting with the
+    /// family of the first one, and the addresses of the families not supported by the host are
+    /// skipped. The connection attempts are started one by one, the next one is started once the
+    /// previous one fails or the
+    /// [`connection_attempt_delay`](Config::connection_attempt_delay) elapses, and the first
+    /// established connection is returned while the other attempts are cancelled.
+    ///
+    /// If all the attempts fail, the error of the last failed one is returned.
+    pub async fn make_connection_to_any(
+        &self,
+        addrs: impl IntoIterator<Item = Address>,
+    ) -> io::Result<Conn> {
+        let mut addrs = sort_addresses(addrs.into_iter().collect()).into_iter();
+        if addrs.len() == 1 {
+            return self.call(addrs.next().unwrap()).await;
+        }
+
+        let delay = self
+            .cfg
+            .connection_attempt_delay
+            .unwrap_or(DEFAULT_CONNECTION_ATTEMPT_DELAY);
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+        loop {
+            if let Some(addr) = addrs.next() {
+                tracing::trace!("[VOLO] start connection attempt to {addr}");
+                attempts.push(async move { (self.call(addr.clone()).await, addr) });
+            }
+            if attempts.is_empty() {
+                break;
+            }
+
+            tokio::select! {
+                Some((result, addr)) = attempts.next() => match result {
+                    Ok(conn) => return Ok(conn),
+                    Err(err) => {
+                        tracing::debug!("[VOLO] failed to connect to {addr}, error: {err}");
+                        last_err = Some(err);
+                    }
+                },
+                _ = sleep(delay), if addrs.len() > 0 => {}
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::AddrNotAvailable, "no address to connect")
+        }))
+    }
+}
+
+/// The address family used by [`sort_addresses`].
+#[derive(PartialEq, Eq)]
+enum Family {
+    V4,
+    V6,
+    #[cfg(target_family = "unix")]
+    Unix,
+}
+
+impl Family {
+    fn of(addr: &Address) -> Self {
+        match addr {
+            Address::Ip(SocketAddr::V4(_)) => Family::V4,
+            Address::Ip(SocketAddr::V6(_)) => Family::V6,
+            #[cfg(target_family = "unix")]
+            Address::Unix(_) => Family::Unix,
+        }
+    }
+
+    fn supported(&self) -> bool {
+        match self {
+            Family::V4 => probe().ipv4,
+            Family::V6 => probe().ipv6,
+            #[cfg(target_family = "unix")]
+            Family::Unix => true,
+        }
+    }
+}
+
+/// Skips the addresses of the unsupported families unless all of them are unsupported, and
+/// interleaves the rest.
+fn sort_addresses(mut addrs: Vec<Address>) -> Vec<Address> {
+    if addrs.iter().any(|addr| Family::of(addr).supported()) {
+        addrs.retain(|addr| Family::of(addr).supported());
+    }
+    interleave(addrs)
+}
+
+/// Interleaves the addresses of the first family with the others as described in RFC 8305.
+fn interleave(addrs: Vec<Address>) -> Vec<Address> {
+    let Some(first) = addrs.first().map(Family::of) else {
+        return addrs;
+    };
+
+    let (preferred, others): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| Family::of(addr) == first);
+    let mut preferred = preferred.into_iter();
+    let mut others = others.into_iter();
+    let mut sorted = Vec::with_capacity(preferred.len() + others.len());
+    loop {
+        match (preferred.next(), others.next()) {
+            (None, None) => break,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+    sorted
 }
 
 impl MakeTransport for DefaultMakeTransport {
@@ -98,6 +240,10 @@ impl MakeTransport for DefaultMakeTransport {
     fn set_write_timeout(&mut self, timeout: Option<Duration>) {
         self.cfg = self.cfg.with_write_timeout(timeout);
     }
+
+    fn set_socket_config(&mut self, config: SocketConfig) {
+        self.cfg = self.cfg.with_socket_config(config);
+    }
 }
 
 pub(super) async fn make_tcp_connection(
@@ -109,6 +255,10 @@ pub(super) async fn make_tcp_connection(
     socket.set_nonblocking(true)?;
     socket.set_read_timeout(cfg.read_timeout)?;
     socket.set_write_timeout(cfg.write_timeout)?;
+    cfg.socket_config.apply(SockRef::from(&socket))?;
+    if let Some(local_addr) = cfg.socket_config.local_addr {
+        socket.bind(&SocketAddr::new(local_addr, 0).into())?;
+    }
 
     #[cfg(unix)]
     let socket = unsafe {
@@ -138,8 +288,8 @@ impl UnaryService<Address> for DefaultMakeTransport {
         match addr {
             Address::Ip(addr) => {
                 let stream = make_tcp_connection(&self.cfg, addr).await?;
-                stream.set_nodelay(true)?;
-                Ok(Conn::from(stream))
+                // `TCP_NODELAY` has been set by the config
+                Ok(Conn::from(ConnStream::Tcp(stream)))
             }
             #[cfg(target_family = "unix")]
             Address::Unix(addr) => UnixStream::connect(addr.as_pathname().ok_or_else(|| {
@@ -153,3 +303,72 @@ impl UnaryService<Address> for DefaultMakeTransport {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{net::TcpListener, time::Duration};
+
+    use super::{interleave, DefaultMakeTransport};
+    use crate::net::Address;
+
+    fn addr(s: &str) -> Address {
+        Address::from(s.parse::<SocketAddr>().unwrap())
+    }
+
+    #[test]
+    fn test_interleave() {
+        let addrs = vec![
+            addr("[::1]:80"),
+            addr("[::2]:80"),
+            addr("[::3]:80"),
+            addr("127.0.0.1:80"),
+            addr("127.0.0.2:80"),
+        ];
+        assert_eq!(
+            interleave(addrs),
+            vec![
+                addr("[::1]:80"),
+                addr("127.0.0.1:80"),
+                addr("[::2]:80"),
+                addr("127.0.0.2:80"),
+                addr("[::3]:80"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_make_connection_to_any() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut mk_conn = DefaultMakeTransport::new();
+        mk_conn.set_connection_attempt_delay(Some(Duration::from_millis(10)));
+
+        // the first address is refused
+        let conn = mk_conn
+            .make_connection_to_any([Address::from(refused), Address::from(target)])
+            .await
+            .unwrap();
+        assert_eq!(conn.info.peer_addr, Some(Address::from(target)));
+
+        // the first address does not respond
+        let conn = mk_conn
+            .make_connection_to_any([addr("192.0.2.1:80"), Address::from(target)])
+            .await
+            .unwrap();
+        assert_eq!(conn.info.peer_addr, Some(Address::from(target)));
+
+        assert!(mk_conn
+            .make_connection_to_any([Address::from(refused)])
+            .await
+            .is_err());
+        assert!(mk_conn.make_connection_to_any([]).await.is_err());
+    }
+}
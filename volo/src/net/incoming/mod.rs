@@ -14,8 +14,19 @@ use tokio::net::UnixListener;
 use tokio_stream::wrappers::UnixListenerStream;
 use tokio_stream::{wrappers::TcpListenerStream, StreamExt};
 
+#[cfg(target_family = "unix")]
+pub use self::reuse_port::{ReusePort, ReusePortIncoming};
+pub use self::{
+    multi::MultiIncoming,
+    proxy_protocol::{ProxyInfo, ProxyProtocolIncoming, Tlv},
+};
 use super::{conn::Conn, Address};
 
+mod multi;
+mod proxy_protocol;
+#[cfg(target_family = "unix")]
+mod reuse_port;
+
 #[pin_project(project = IncomingProj)]
 #[derive(Debug)]
 pub enum DefaultIncoming {
@@ -47,6 +58,11 @@ impl From<TcpListener> for DefaultIncoming {
 
 pub trait Incoming: fmt::Debug + Send + 'static {
     fn accept(&mut self) -> impl Future<Output = io::Result<Option<Conn>>> + Send;
+
+    /// Returns the local address of the listener, if any.
+    fn local_addr(&self) -> Option<Address> {
+        None
+    }
 }
 
 impl Incoming for DefaultIncoming {
@@ -58,6 +74,14 @@ impl Incoming for DefaultIncoming {
             Ok(None)
         }
     }
+
+    fn local_addr(&self) -> Option<Address> {
+        match self {
+            DefaultIncoming::Tcp(s) => s.as_ref().local_addr().ok().map(Address::from),
+            #[cfg(target_family = "unix")]
+            DefaultIncoming::Unix(s) => s.as_ref().local_addr().ok().map(Address::from),
+        }
+    }
 }
 
 pub trait MakeIncoming {
@@ -128,15 +152,15 @@ mod unix_helper {
     use std::{
         net::{SocketAddr, TcpListener},
         os::{
-            fd::{AsRawFd, FromRawFd, IntoRawFd},
+            fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
             unix::net::UnixListener,
         },
         path::Path,
     };
 
-    use socket2::{Domain, Protocol, Socket, Type};
+    use socket2::{Domain, Protocol, SockRef, Socket, Type};
 
-    use crate::hotrestart::DEFAULT_HOT_RESTART;
+    use crate::{hotrestart::DEFAULT_HOT_RESTART, net::socket::SocketConfig};
 
     /// Returns major and minor kernel version numbers, parsed from
     /// the nix::sys::utsname's release field, or 0, 0 if the version can't be obtained
@@ -251,15 +275,74 @@ mod unix_helper {
     pub async fn create_tcp_listener_with_max_backlog(
         addr: SocketAddr,
     ) -> std::io::Result<TcpListener> {
-        if let Ok(Some(raw_fd)) = DEFAULT_HOT_RESTART
-            .dup_parent_listener_sock(addr.to_string())
+        create_tcp_listener(addr, addr.to_string(), &SocketConfig::default()).await
+    }
+
+    /// Creates a listener with `SO_REUSEPORT` and the socket options, which is registered as
+    /// `key` for the hot restart.
+    ///
+    /// The listener taken over from the old process is reused with the socket options applied
+    /// again, since they may be changed by the new process.
+    pub async fn create_tcp_listener(
+        addr: SocketAddr,
+        key: String,
+        config: &SocketConfig,
+    ) -> std::io::Result<TcpListener> {
+        match DEFAULT_HOT_RESTART
+            .dup_parent_listener_sock(key.clone())
             .await
         {
-            DEFAULT_HOT_RESTART.register_listener_fd(addr.to_string(), raw_fd);
-            let socket = unsafe { Socket::from_raw_fd(raw_fd) };
-            return Ok(socket.into());
+            Ok(Some(raw_fd)) => inherit_tcp_listener(key, raw_fd, config),
+            _ => bind_tcp_listener(addr, key, config),
         }
+    }
 
+    /// Creates the listeners with `SO_REUSEPORT` and the socket options on the same address, which
+    /// are taken over from the old process as one listener of the hot restart.
+    ///
+    /// The listeners after the first one are bound to the local address of the first one, so
+    /// that they share the same port even if the port of `addr` is 0.
+    pub async fn create_tcp_listeners(
+        mut addr: SocketAddr,
+        keys: Vec<String>,
+        config: &SocketConfig,
+    ) -> std::io::Result<Vec<TcpListener>> {
+        let fds = DEFAULT_HOT_RESTART
+            .dup_parent_listener_socks(keys.clone())
+            .await
+            .unwrap_or_else(|_| vec![None; keys.len()]);
+        let mut listeners = Vec::with_capacity(keys.len());
+        for (key, fd) in keys.into_iter().zip(fds) {
+            let listener = match fd {
+                Some(raw_fd) => inherit_tcp_listener(key, raw_fd, config)?,
+                None => bind_tcp_listener(addr, key, config)?,
+            };
+            if listeners.is_empty() {
+                addr = listener.local_addr()?;
+            }
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    /// Applies the socket options to the listener taken over from the old process, and registers
+    /// it as `key` for the next restart.
+    pub(super) fn inherit_tcp_listener(
+        key: String,
+        raw_fd: RawFd,
+        config: &SocketConfig,
+    ) -> std::io::Result<TcpListener> {
+        let socket = unsafe { Socket::from_raw_fd(raw_fd) };
+        config.apply(SockRef::from(&socket))?;
+        DEFAULT_HOT_RESTART.register_listener_fd(key, raw_fd);
+        Ok(socket.into())
+    }
+
+    fn bind_tcp_listener(
+        addr: SocketAddr,
+        key: String,
+        config: &SocketConfig,
+    ) -> std::io::Result<TcpListener> {
         let domain = if addr.is_ipv4() {
             Domain::IPV4
         } else {
@@ -271,6 +354,8 @@ mod unix_helper {
         socket.set_nonblocking(true)?;
         socket.set_reuse_port(true)?;
         socket.set_cloexec(true)?;
+        // The accepted connections inherit the options of the listener on most platforms.
+        config.apply(SockRef::from(&socket))?;
 
         socket.bind(&socket2::SockAddr::from(addr))?;
 
@@ -280,7 +365,7 @@ mod unix_helper {
         let backlog = libc::SOMAXCONN;
         socket.listen(backlog)?;
 
-        DEFAULT_HOT_RESTART.register_listener_fd(addr.to_string(), socket.as_raw_fd());
+        DEFAULT_HOT_RESTART.register_listener_fd(key, socket.as_raw_fd());
         Ok(socket.into())
     }
 
@@ -326,3 +411,23 @@ mod unix_helper {
         }
     }
 }
+
+#[cfg(all(test, target_family = "unix"))]
+mod tests {
+    use std::os::fd::IntoRawFd;
+
+    use socket2::SockRef;
+
+    use super::unix_helper::inherit_tcp_listener;
+    use crate::net::socket::SocketConfig;
+
+    #[test]
+    fn test_inherit_tcp_listener() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let key = listener.local_addr().unwrap().to_string();
+        let config = SocketConfig::new().with_recv_buffer_size(Some(8 * 1024));
+        let listener = inherit_tcp_listener(key, listener.into_raw_fd(), &config).unwrap();
+        // the size is doubled by linux for the bookkeeping overhead
+        assert!(SockRef::from(&listener).recv_buffer_size().unwrap() <= 16 * 1024);
+    }
+}
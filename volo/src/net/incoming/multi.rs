@@ -0,0 +1,147 @@
+use std::io;
+
+use futures::future::select_all;
+
+use super::{Incoming, MakeIncoming};
+use crate::net::{conn::Conn, Address};
+
+/// An [`Incoming`] which merges multiple listeners, such as the listeners of an IPv4 address, an
+/// IPv6 address and a unix socket, so that one server can listen on all of them.
+///
+/// The local address of the listener which accepted a connection is recorded in
+/// [`ConnInfo::listener`](crate::net::conn::ConnInfo::listener). All the listeners are closed
+/// together when the server shuts down, and a listener which is closed by itself is removed
+/// without affecting the others.
+///
+/// ```no_run
+/// use volo::net::{incoming::MultiIncoming, Address};
+///
+/// let incoming = MultiIncoming::new(vec![
+///     Address::from("0.0.0.0:8080".parse::<std::net::SocketAddr>().unwrap()),
+///     Address::from("[::]:8080".parse::<std::net::SocketAddr>().unwrap()),
+/// ]);
+/// ```
+#[derive(Debug)]
+pub struct MultiIncoming<I> {
+    incomings: Vec<I>,
+    listeners: Vec<Option<Address>>,
+    /// The index of the listener polled first, which is rotated to avoid starving the others.
+    next: usize,
+}
+
+impl<I> MultiIncoming<I> {
+    pub fn new(incomings: Vec<I>) -> Self {
+        Self {
+            incomings,
+            listeners: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Adds a listener.
+    pub fn listen(mut self, incoming: I) -> Self {
+        self.incomings.push(incoming);
+        self
+    }
+}
+
+impl<I> Default for MultiIncoming<I> {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl<I> FromIterator<I> for MultiIncoming<I> {
+    fn from_iter<T: IntoIterator<Item = I>>(iter: T) -> Self {
+        Self::new(iter.into_iter().collect())
+    }
+}
+
+impl<M> MakeIncoming for MultiIncoming<M>
+where
+    M: MakeIncoming + Send,
+{
+    type Incoming = MultiIncoming<M::Incoming>;
+
+    async fn make_incoming(self) -> io::Result<Self::Incoming> {
+        let mut incomings = Vec::with_capacity(self.incomings.len());
+        for incoming in self.incomings {
+            incomings.push(incoming.make_incoming().await?);
+        }
+        let listeners = incomings.iter().map(Incoming::local_addr).collect();
+        Ok(MultiIncoming {
+            incomings,
+            listeners,
+            next: 0,
+        })
+    }
+}
+
+impl<I: Incoming> Incoming for MultiIncoming<I> {
+    async fn accept(&mut self) -> io::Result<Option<Conn>> {
+        loop {
+            if self.incomings.is_empty() {
+                return Ok(None);
+            }
+            if self.listeners.len() != self.incomings.len() {
+                self.listeners = self.incomings.iter().map(Incoming::local_addr).collect();
+            }
+
+            let len = self.incomings.len();
+            let start = self.next % len;
+            self.next = start + 1;
+            let (head, tail) = self.incomings.split_at_mut(start);
+            let accepts = tail
+                .iter_mut()
+                .chain(head.iter_mut())
+                .map(|incoming| Box::pin(incoming.accept()));
+            let (result, index, _) = select_all(accepts).await;
+            let index = (start + index) % len;
+
+            match result? {
+                Some(mut conn) => {
+                    conn.info.listener = self.listeners[index].clone();
+                    return Ok(Some(conn));
+                }
+                None => {
+                    tracing::info!(
+                        "[VOLO] listener {:?} is closed",
+                        self.listeners[index].as_ref()
+                    );
+                    self.incomings.remove(index);
+                    self.listeners.remove(index);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::MultiIncoming;
+    use crate::net::{incoming::Incoming, Address, DefaultIncoming};
+
+    #[tokio::test]
+    async fn test_multi_incoming() {
+        let listeners = [
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let addrs = listeners
+            .iter()
+            .map(|l| l.local_addr().unwrap())
+            .collect::<Vec<_>>();
+        let mut incoming = listeners
+            .map(DefaultIncoming::from)
+            .into_iter()
+            .collect::<MultiIncoming<_>>();
+
+        for addr in addrs.iter().rev().chain(addrs.iter()) {
+            let _client = TcpStream::connect(addr).await.unwrap();
+            let conn = incoming.accept().await.unwrap().unwrap();
+            assert_eq!(conn.info.listener, Some(Address::from(*addr)));
+        }
+    }
+}
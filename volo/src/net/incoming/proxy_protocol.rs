@@ -0,0 +1,520 @@
+//! Support of the [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt),
+//! which is used by the L4 load balancers such as HAProxy and AWS NLB to pass the address of the
+//! client.
+
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::{Incoming, MakeIncoming};
+use crate::net::{conn::Conn, Address};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The maximum length of a v1 header, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A TLV (type-length-value) carried by the v2 header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tlv {
+    pub kind: u8,
+    pub value: Vec<u8>,
+}
+
+impl Tlv {
+    pub const ALPN: u8 = 0x01;
+    pub const AUTHORITY: u8 = 0x02;
+    pub const CRC32C: u8 = 0x03;
+    pub const NOOP: u8 = 0x04;
+    pub const UNIQUE_ID: u8 = 0x05;
+    pub const SSL: u8 = 0x20;
+    pub const NETNS: u8 = 0x30;
+}
+
+/// The information from the PROXY protocol header, which is stored in
+/// [`ConnInfo`](crate::net::conn::ConnInfo).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyInfo {
+    /// The peer address of the accepted connection, which is the address of the proxy.
+    pub proxy_addr: Option<Address>,
+    /// The address that the client connected to.
+    pub destination: Option<SocketAddr>,
+    /// The TLVs of the v2 header, which is always empty for v1.
+    pub tlvs: Vec<Tlv>,
+}
+
+impl ProxyInfo {
+    /// Returns the value of the first TLV of the type.
+    pub fn tlv(&self, kind: u8) -> Option<&[u8]> {
+        self.tlvs
+            .iter()
+            .find(|tlv| tlv.kind == kind)
+            .map(|tlv| tlv.value.as_slice())
+    }
+}
+
+/// An [`Incoming`] which reads the PROXY protocol header of each connection, and replaces the
+/// peer address of the connection with the address of the client in the header.
+///
+/// Both the v1 text header and the v2 binary header are supported. The connections from the
+/// trusted sources without a valid header are closed, and the connections from the others are
+/// accepted as is.
+///
+/// The headers are read concurrently, so that a slow client will not block accepting the other
+/// connections.
+///
+/// No source is trusted by default, and the trusted sources must be set by
+/// [`ProxyProtocolIncoming::trusted_source`] to the addresses of the load balancers. A trusted
+/// source can claim any client address in the header, so trusting the sources that the clients
+/// can connect from directly lets them spoof their addresses.
+pub struct ProxyProtocolIncoming<I> {
+    inner: I,
+    trusted_sources: Vec<(IpAddr, u8)>,
+    trust_unix_sockets: bool,
+    header_timeout: Duration,
+    pending: FuturesUnordered<BoxFuture<'static, Option<Conn>>>,
+}
+
+impl<I> ProxyProtocolIncoming<I> {
+    pub fn new(inner: I) -> Self {
+        Self {
+            inner,
+            trusted_sources: Vec::new(),
+            trust_unix_sockets: false,
+            header_timeout: DEFAULT_HEADER_TIMEOUT,
+            pending: FuturesUnordered::new(),
+        }
+    }
+
+    /// Adds a network of which the first `prefix_len` bits are the same as `addr` to the
+    /// trusted sources, such as `(10.0.0.0, 8)`.
+    ///
+    /// Default is to trust none of the sources.
+    pub fn trusted_source(mut self, addr: IpAddr, prefix_len: u8) -> Self {
+        self.trusted_sources.push((addr.to_canonical(), prefix_len));
+        self
+    }
+
+    /// Sets whether to trust the connections from the unix sockets, such as from a sidecar proxy
+    /// on the same host.
+    ///
+    /// Default is false.
+    pub fn trust_unix_sockets(mut self, trust: bool) -> Self {
+        self.trust_unix_sockets = trust;
+        self
+    }
+
+    /// Sets the timeout of reading the header, the connection is closed if it's exceeded.
+    ///
+    /// Default is 5 seconds.
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.header_timeout = timeout;
+        self
+    }
+
+    fn is_trusted(&self, addr: Option<&Address>) -> bool {
+        match addr {
+            Some(Address::Ip(addr)) => {
+                let ip = addr.ip().to_canonical();
+                self.trusted_sources
+                    .iter()
+                    .any(|(network, prefix_len)| in_network(ip, *network, *prefix_len))
+            }
+            #[cfg(target_family = "unix")]
+            Some(Address::Unix(_)) => self.trust_unix_sockets,
+            None => false,
+        }
+    }
+}
+
+impl<I: fmt::Debug> fmt::Debug for ProxyProtocolIncoming<I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyProtocolIncoming")
+            .field("inner", &self.inner)
+            .field("trusted_sources", &self.trusted_sources)
+            .field("trust_unix_sockets", &self.trust_unix_sockets)
+            .field("header_timeout", &self.header_timeout)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl<M> MakeIncoming for ProxyProtocolIncoming<M>
+where
+    M: MakeIncoming + Send,
+{
+    type Incoming = ProxyProtocolIncoming<M::Incoming>;
+
+    async fn make_incoming(self) -> io::Result<Self::Incoming> {
+        if self.trusted_sources.is_empty() && !self.trust_unix_sockets {
+            tracing::warn!(
+                "[VOLO] no trusted source of the PROXY protocol is set, all the connections are \
+                 accepted without reading the header"
+            );
+        }
+        Ok(ProxyProtocolIncoming {
+            inner: self.inner.make_incoming().await?,
+            trusted_sources: self.trusted_sources,
+            trust_unix_sockets: self.trust_unix_sockets,
+            header_timeout: self.header_timeout,
+            pending: self.pending,
+        })
+    }
+}
+
+impl<I: Incoming> Incoming for ProxyProtocolIncoming<I> {
+    async fn accept(&mut self) -> io::Result<Option<Conn>> {
+        loop {
+            tokio::select! {
+                Some(conn) = self.pending.next(), if !self.pending.is_empty() => {
+                    if let Some(conn) = conn {
+                        return Ok(Some(conn));
+                    }
+                }
+                conn = self.inner.accept() => {
+                    let Some(conn) = conn? else {
+                        return Ok(None);
+                    };
+                    if !self.is_trusted(conn.info.peer_addr.as_ref()) {
+                        return Ok(Some(conn));
+                    }
+                    self.pending
+                        .push(Box::pin(read_proxy_header(conn, self.header_timeout)));
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Option<Address> {
+        self.inner.local_addr()
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let shift = 32u32.saturating_sub(prefix_len as u32);
+            u32::from(ip).checked_shr(shift).unwrap_or(0)
+                == u32::from(network).checked_shr(shift).unwrap_or(0)
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let shift = 128u32.saturating_sub(prefix_len as u32);
+            u128::from(ip).checked_shr(shift).unwrap_or(0)
+                == u128::from(network).checked_shr(shift).unwrap_or(0)
+        }
+        _ => false,
+    }
+}
+
+async fn read_proxy_header(mut conn: Conn, timeout: Duration) -> Option<Conn> {
+    let header = match tokio::time::timeout(timeout, read_header(&mut conn)).await {
+        Ok(Ok(header)) => header,
+        Ok(Err(err)) => {
+            tracing::debug!(
+                "[VOLO] failed to read the PROXY protocol header from {:?}: {err}",
+                conn.info.peer_addr
+            );
+            return None;
+        }
+        Err(_) => {
+            tracing::debug!(
+                "[VOLO] reading the PROXY protocol header from {:?} timed out",
+                conn.info.peer_addr
+            );
+            return None;
+        }
+    };
+    let proxy_addr = match header.source {
+        Some(source) => conn.info.peer_addr.replace(Address::Ip(source)),
+        None => conn.info.peer_addr.clone(),
+    };
+    conn.info.proxy = Some(ProxyInfo {
+        proxy_addr,
+        destination: header.destination,
+        tlvs: header.tlvs,
+    });
+    Some(conn)
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Header {
+    source: Option<SocketAddr>,
+    destination: Option<SocketAddr>,
+    tlvs: Vec<Tlv>,
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads the header without consuming any byte after it.
+async fn read_header<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Header> {
+    let mut buf = [0; 16];
+    r.read_exact(&mut buf[..12]).await?;
+    if buf[..12] == V2_SIGNATURE {
+        r.read_exact(&mut buf[12..]).await?;
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        let mut payload = vec![0; len];
+        r.read_exact(&mut payload).await?;
+        parse_v2(buf[12], buf[13], &payload)
+    } else if buf.starts_with(V1_PREFIX) {
+        // The length of the v1 header is unknown, so it has to be read byte by byte.
+        let mut line = buf[..12].to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid_data("PROXY protocol v1 header is too long"));
+            }
+            line.push(r.read_u8().await?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else {
+        Err(invalid_data("no PROXY protocol header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Header> {
+    let line =
+        std::str::from_utf8(line).map_err(|_| invalid_data("invalid PROXY protocol v1 header"))?;
+    let mut fields = line[V1_PREFIX.len()..].split(' ');
+    let is_ipv4 = match fields.next() {
+        Some("TCP4") => true,
+        Some("TCP6") => false,
+        // The addresses of the connection should be used, and the rest of the line is ignored.
+        Some("UNKNOWN") => return Ok(Header::default()),
+        _ => return Err(invalid_data("unsupported PROXY protocol v1 protocol")),
+    };
+    let (Some(src), Some(dst), Some(src_port), Some(dst_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(invalid_data("invalid PROXY protocol v1 header"));
+    };
+    let parse_addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|_| invalid_data("invalid PROXY protocol v1 address"))?;
+        if ip.is_ipv4() != is_ipv4 {
+            return Err(invalid_data("invalid PROXY protocol v1 address"));
+        }
+        let port = port
+            .parse()
+            .map_err(|_| invalid_data("invalid PROXY protocol v1 port"))?;
+        Ok(SocketAddr::new(ip, port))
+    };
+    Ok(Header {
+        source: Some(parse_addr(src, src_port)?),
+        destination: Some(parse_addr(dst, dst_port)?),
+        tlvs: Vec::new(),
+    })
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, payload: &[u8]) -> io::Result<Header> {
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid_data("unsupported PROXY protocol version"));
+    }
+    let addr_len = match family >> 4 {
+        // AF_INET
+        0x1 => 12,
+        // AF_INET6
+        0x2 => 36,
+        // AF_UNIX
+        0x3 => 216,
+        _ => 0,
+    };
+    if payload.len() < addr_len {
+        return Err(invalid_data("truncated PROXY protocol v2 addresses"));
+    }
+    let (addr, tlvs) = payload.split_at(addr_len);
+    let tlvs = parse_tlvs(tlvs)?;
+
+    match ver_cmd & 0x0f {
+        // LOCAL, the connection is established by the proxy itself, such as the health checks.
+        0x0 => Ok(Header {
+            tlvs,
+            ..Default::default()
+        }),
+        // PROXY
+        0x1 => {
+            let (source, destination) = match family >> 4 {
+                0x1 => {
+                    let ip = |i: usize| {
+                        IpAddr::from(Ipv4Addr::from([
+                            addr[i],
+                            addr[i + 1],
+                            addr[i + 2],
+                            addr[i + 3],
+                        ]))
+                    };
+                    let port = |i: usize| u16::from_be_bytes([addr[i], addr[i + 1]]);
+                    (
+                        Some(SocketAddr::new(ip(0), port(8))),
+                        Some(SocketAddr::new(ip(4), port(10))),
+                    )
+                }
+                0x2 => {
+                    let ip = |i: usize| {
+                        let mut octets = [0; 16];
+                        octets.copy_from_slice(&addr[i..i + 16]);
+                        IpAddr::from(Ipv6Addr::from(octets))
+                    };
+                    let port = |i: usize| u16::from_be_bytes([addr[i], addr[i + 1]]);
+                    (
+                        Some(SocketAddr::new(ip(0), port(32))),
+                        Some(SocketAddr::new(ip(16), port(34))),
+                    )
+                }
+                // The unix and unspecified addresses are ignored, and the addresses of the
+                // connection are used.
+                _ => (None, None),
+            };
+            Ok(Header {
+                source,
+                destination,
+                tlvs,
+            })
+        }
+        _ => Err(invalid_data("unsupported PROXY protocol v2 command")),
+    }
+}
+
+fn parse_tlvs(mut buf: &[u8]) -> io::Result<Vec<Tlv>> {
+    let mut tlvs = Vec::new();
+    while !buf.is_empty() {
+        if buf.len() < 3 {
+            return Err(invalid_data("truncated PROXY protocol v2 TLV"));
+        }
+        let len = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+        let Some(value) = buf.get(3..3 + len) else {
+            return Err(invalid_data("truncated PROXY protocol v2 TLV"));
+        };
+        tlvs.push(Tlv {
+            kind: buf[0],
+            value: value.to_vec(),
+        });
+        buf = &buf[3 + len..];
+    }
+    Ok(tlvs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{read_header, Header, ProxyProtocolIncoming, Tlv, V2_SIGNATURE};
+    use crate::net::{incoming::Incoming, Address, DefaultIncoming};
+
+    fn v2_header() -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        // PROXY command over TCP4, with the addresses and an AUTHORITY TLV
+        header.extend_from_slice(&[0x21, 0x11, 0, 12 + 3 + 9]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0x30, 0x39, 0x01, 0xbb]);
+        header.extend_from_slice(&[Tlv::AUTHORITY, 0, 9]);
+        header.extend_from_slice(b"localhost");
+        header
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut v1 = &b"PROXY TCP6 2001:db8::1 2001:db8::2 12345 443\r\nhello"[..];
+        assert_eq!(
+            read_header(&mut v1).await.unwrap(),
+            Header {
+                source: Some("[2001:db8::1]:12345".parse().unwrap()),
+                destination: Some("[2001:db8::2]:443".parse().unwrap()),
+                tlvs: Vec::new(),
+            }
+        );
+        assert_eq!(v1, b"hello");
+
+        let mut unknown = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_header(&mut unknown).await.unwrap(), Header::default());
+
+        let mut v2 = v2_header();
+        v2.extend_from_slice(b"hello");
+        let mut v2 = v2.as_slice();
+        assert_eq!(
+            read_header(&mut v2).await.unwrap(),
+            Header {
+                source: Some("192.0.2.1:12345".parse().unwrap()),
+                destination: Some("198.51.100.1:443".parse().unwrap()),
+                tlvs: vec![Tlv {
+                    kind: Tlv::AUTHORITY,
+                    value: b"localhost".to_vec(),
+                }],
+            }
+        );
+        assert_eq!(v2, b"hello");
+
+        let mut mismatched = &b"PROXY TCP4 2001:db8::1 2001:db8::2 12345 443\r\n"[..];
+        assert!(read_header(&mut mismatched).await.is_err());
+        let mut missing = &b"GET / HTTP/1.1\r\n\r\n"[..];
+        assert!(read_header(&mut missing).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_incoming() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = ProxyProtocolIncoming::new(DefaultIncoming::from(listener))
+            .trusted_source(IpAddr::from([127, 0, 0, 0]), 8);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut header = v2_header();
+        header.extend_from_slice(b"hello");
+        client.write_all(&header).await.unwrap();
+
+        let mut conn = incoming.accept().await.unwrap().unwrap();
+        let source: SocketAddr = "192.0.2.1:12345".parse().unwrap();
+        assert_eq!(conn.info.peer_addr, Some(Address::Ip(source)));
+        let proxy = conn.info.proxy.as_ref().unwrap();
+        assert_eq!(
+            proxy.proxy_addr,
+            Some(Address::Ip(client.local_addr().unwrap()))
+        );
+        assert_eq!(proxy.tlv(Tlv::AUTHORITY), Some(&b"localhost"[..]));
+        let mut buf = [0; 5];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // the connections from the untrusted sources are accepted as is
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = ProxyProtocolIncoming::new(DefaultIncoming::from(listener))
+            .trusted_source(IpAddr::from([10, 0, 0, 0]), 8);
+        let client = TcpStream::connect(addr).await.unwrap();
+        let conn = incoming.accept().await.unwrap().unwrap();
+        assert_eq!(
+            conn.info.peer_addr,
+            Some(Address::Ip(client.local_addr().unwrap()))
+        );
+        assert!(conn.info.proxy.is_none());
+
+        // no source is trusted by default, so the header cannot spoof the address
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = ProxyProtocolIncoming::new(DefaultIncoming::from(listener));
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&v2_header()).await.unwrap();
+        let conn = incoming.accept().await.unwrap().unwrap();
+        assert_eq!(
+            conn.info.peer_addr,
+            Some(Address::Ip(client.local_addr().unwrap()))
+        );
+        assert!(conn.info.proxy.is_none());
+    }
+}
@@ -0,0 +1,187 @@
+use std::{io, net::SocketAddr};
+
+use tokio::{net::TcpListener, sync::mpsc, task::JoinSet};
+
+use super::{unix_helper, Incoming, MakeIncoming};
+use crate::net::{conn::Conn, socket::SocketConfig, Address};
+
+/// A [`MakeIncoming`] which opens multiple listeners with `SO_REUSEPORT` on the same address,
+/// each of which has its own accept loop spawned on the runtime, so that the kernel spreads the
+/// new connections evenly across the listeners and accepting is not bottlenecked by one loop.
+///
+/// Note that the connections are only spread evenly on Linux.
+///
+/// With the hot restart, the first listener is registered with the address like the listener of
+/// [`Address`], and the others are registered with the address and their index such as
+/// `127.0.0.1:8080#1`. The listeners of the old process are taken over by the index, and the
+/// missing ones are created. All the listeners are counted as one listener of the hot restart.
+#[derive(Debug, Clone)]
+pub struct ReusePort {
+    addr: SocketAddr,
+    acceptors: usize,
+    socket_config: SocketConfig,
+}
+
+impl ReusePort {
+    /// Creates the config of `acceptors` listeners on `addr`, which is usually the number of the
+    /// runtime workers.
+    pub fn new(addr: SocketAddr, acceptors: usize) -> Self {
+        Self {
+            addr,
+            acceptors: acceptors.max(1),
+            socket_config: SocketConfig::default(),
+        }
+    }
+
+    /// Sets the options of the listeners, which are inherited by the accepted connections on
+    /// most platforms.
+    pub fn socket_config(mut self, config: SocketConfig) -> Self {
+        self.socket_config = config;
+        self
+    }
+}
+
+impl MakeIncoming for ReusePort {
+    type Incoming = ReusePortIncoming;
+
+    async fn make_incoming(self) -> io::Result<Self::Incoming> {
+        let keys = (0..self.acceptors)
+            .map(|i| {
+                if i == 0 {
+                    self.addr.to_string()
+                } else {
+                    format!("{}#{i}", self.addr)
+                }
+            })
+            .collect();
+        let listeners =
+            unix_helper::create_tcp_listeners(self.addr, keys, &self.socket_config).await?;
+        let listeners = listeners
+            .into_iter()
+            .map(TcpListener::from_std)
+            .collect::<io::Result<Vec<_>>>()?;
+        let local_addr = listeners[0].local_addr()?;
+
+        let (tx, rx) = mpsc::channel(self.acceptors);
+        let mut acceptors = JoinSet::new();
+        for listener in listeners {
+            let tx = tx.clone();
+            acceptors.spawn(async move {
+                loop {
+                    let conn = listener
+                        .accept()
+                        .await
+                        .map(|(stream, _)| Conn::from(stream));
+                    if tx.send(conn).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        Ok(ReusePortIncoming {
+            local_addr,
+            rx,
+            acceptors,
+        })
+    }
+}
+
+/// The [`Incoming`] of [`ReusePort`], the listeners are closed when it's dropped.
+#[derive(Debug)]
+pub struct ReusePortIncoming {
+    local_addr: SocketAddr,
+    rx: mpsc::Receiver<io::Result<Conn>>,
+    /// The accept loops, which are aborted on drop.
+    #[allow(dead_code)]
+    acceptors: JoinSet<()>,
+}
+
+impl Incoming for ReusePortIncoming {
+    async fn accept(&mut self) -> io::Result<Option<Conn>> {
+        match self.rx.recv().await {
+            Some(conn) => {
+                let conn = conn?;
+                tracing::trace!("[VOLO] recv a connection from: {:?}", conn.info.peer_addr);
+                Ok(Some(conn))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn local_addr(&self) -> Option<Address> {
+        Some(Address::Ip(self.local_addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::ReusePort;
+    use crate::net::{
+        incoming::{unix_helper, Incoming, MakeIncoming},
+        socket::SocketConfig,
+    };
+
+    #[tokio::test]
+    async fn test_reuse_port() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut incoming = ReusePort::new(addr, 4).make_incoming().await.unwrap();
+
+        let mut clients = Vec::new();
+        for _ in 0..16 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+        for _ in 0..16 {
+            let conn = incoming.accept().await.unwrap().unwrap();
+            assert!(conn.info.peer_addr.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reuse_port_zero() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let keys = (0..4).map(|i| format!("reuse_port_zero#{i}")).collect();
+        let listeners = unix_helper::create_tcp_listeners(addr, keys, &SocketConfig::default())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|listener| TcpListener::from_std(listener).unwrap())
+            .collect::<Vec<_>>();
+        let addr = listeners[0].local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        for listener in listeners.iter() {
+            assert_eq!(listener.local_addr().unwrap(), addr);
+        }
+
+        let mut clients = Vec::new();
+        for _ in 0..64 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+        // the kernel only spreads the connections across the listeners on Linux
+        #[cfg(target_os = "linux")]
+        for listener in listeners.iter() {
+            tokio::time::timeout(std::time::Duration::from_secs(1), listener.accept())
+                .await
+                .expect("every listener should receive connections")
+                .unwrap();
+        }
+
+        let mut incoming = ReusePort::new("127.0.0.1:0".parse().unwrap(), 4)
+            .make_incoming()
+            .await
+            .unwrap();
+        let addr = incoming.local_addr().unwrap();
+        let crate::net::Address::Ip(addr) = addr else {
+            unreachable!()
+        };
+        assert_ne!(addr.port(), 0);
+        let _client = TcpStream::connect(addr).await.unwrap();
+        assert!(incoming.accept().await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,237 @@
+//! Options of the TCP sockets, which are applied to the sockets of the clients by
+//! [`DefaultMakeTransport`](super::dial::DefaultMakeTransport) and to the listeners and the
+//! accepted connections of the servers.
+//!
+//! Some options are only supported on some platforms, and they are ignored on the others.
+
+use std::{io, net::IpAddr, time::Duration};
+
+use socket2::{SockRef, TcpKeepalive};
+
+use super::conn::{Conn, ConnStream};
+
+/// The TCP keepalive options, with which the half-dead connections, such as the ones dropped
+/// by the NAT, are detected and closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// The time the connection is idle before the first keepalive probe.
+    pub idle: Duration,
+    /// The interval of the keepalive probes.
+    pub interval: Option<Duration>,
+    /// The number of the unacknowledged probes before the connection is closed, which is not
+    /// supported on Windows.
+    pub retries: Option<u32>,
+}
+
+impl Keepalive {
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            interval: None,
+            retries: None,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Option<Duration>) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_retries(mut self, retries: Option<u32>) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    fn to_socket2(self) -> TcpKeepalive {
+        let keepalive = TcpKeepalive::new().with_time(self.idle);
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "windows",
+        ))]
+        let keepalive = match self.interval {
+            Some(interval) => keepalive.with_interval(interval),
+            None => keepalive,
+        };
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+        ))]
+        let keepalive = match self.retries {
+            Some(retries) => keepalive.with_retries(retries),
+            None => keepalive,
+        };
+        keepalive
+    }
+}
+
+/// The options of the TCP sockets, the `None` options are left as the system defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketConfig {
+    /// `TCP_NODELAY`, which is enabled by default.
+    pub nodelay: bool,
+    /// `SO_KEEPALIVE` with `TCP_KEEPIDLE`, `TCP_KEEPINTVL` and `TCP_KEEPCNT`.
+    pub keepalive: Option<Keepalive>,
+    /// `SO_SNDBUF`.
+    pub send_buffer_size: Option<usize>,
+    /// `SO_RCVBUF`.
+    pub recv_buffer_size: Option<usize>,
+    /// `TCP_USER_TIMEOUT`, which is only supported on Linux.
+    pub user_timeout: Option<Duration>,
+    /// `SO_LINGER`.
+    pub linger: Option<Duration>,
+    /// `IP_TOS` for IPv4 or `IPV6_TCLASS` for IPv6, of which the upper 6 bits are the DSCP.
+    pub tos: Option<u32>,
+    /// The local address which the client sockets are bound to before connecting, which is
+    /// ignored by the servers.
+    pub local_addr: Option<IpAddr>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            user_timeout: None,
+            linger: None,
+            tos: None,
+            local_addr: None,
+        }
+    }
+}
+
+impl SocketConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: Option<Keepalive>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    pub fn with_send_buffer_size(mut self, size: Option<usize>) -> Self {
+        self.send_buffer_size = size;
+        self
+    }
+
+    pub fn with_recv_buffer_size(mut self, size: Option<usize>) -> Self {
+        self.recv_buffer_size = size;
+        self
+    }
+
+    pub fn with_user_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.user_timeout = timeout;
+        self
+    }
+
+    pub fn with_linger(mut self, linger: Option<Duration>) -> Self {
+        self.linger = linger;
+        self
+    }
+
+    pub fn with_tos(mut self, tos: Option<u32>) -> Self {
+        self.tos = tos;
+        self
+    }
+
+    pub fn with_local_addr(mut self, addr: Option<IpAddr>) -> Self {
+        self.local_addr = addr;
+        self
+    }
+
+    /// Applies the options to the accepted connection if it's a TCP connection.
+    pub fn apply_to_conn(&self, conn: &Conn) -> io::Result<()> {
+        match &conn.stream {
+            ConnStream::Tcp(stream) => self.apply(SockRef::from(stream)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the options except `local_addr` to the socket.
+    pub(crate) fn apply(&self, socket: SockRef<'_>) -> io::Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = self.keepalive {
+            socket.set_tcp_keepalive(&keepalive.to_socket2())?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(timeout) = self.user_timeout {
+            socket.set_tcp_user_timeout(Some(timeout))?;
+        }
+        if let Some(linger) = self.linger {
+            socket.set_linger(Some(linger))?;
+        }
+        if let Some(tos) = self.tos {
+            if socket.local_addr()?.is_ipv6() {
+                #[cfg(any(
+                    target_os = "android",
+                    target_os = "freebsd",
+                    target_os = "ios",
+                    target_os = "linux",
+                    target_os = "macos",
+                ))]
+                socket.set_tclass_v6(tos)?;
+            } else {
+                socket.set_tos(tos)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use socket2::SockRef;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{Keepalive, SocketConfig};
+
+    #[tokio::test]
+    async fn test_apply() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let config = SocketConfig::new()
+            .with_nodelay(false)
+            .with_keepalive(Some(
+                Keepalive::new(Duration::from_secs(30)).with_interval(Some(Duration::from_secs(5))),
+            ))
+            .with_linger(Some(Duration::from_secs(1)))
+            .with_tos(Some(0x10));
+        config.apply(SockRef::from(&stream)).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(!socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.linger().unwrap(), Some(Duration::from_secs(1)));
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+            assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+            assert_eq!(socket.tos().unwrap(), 0x10);
+        }
+    }
+}
@@ -3,17 +3,20 @@ use std::{
     future::Future,
     io::{self, Result},
     path::Path,
+    sync::Arc,
     time::Duration,
 };
 
+use faststr::FastStr;
 use motore::{make::MakeConnection, UnaryService};
-use tokio::net::TcpStream;
 #[cfg(target_family = "unix")]
 use tokio::net::UnixStream;
+use tokio::{net::TcpStream, sync::Semaphore};
 
 use super::{
     conn::ConnStream,
     dial::{make_tcp_connection, Config, MakeTransport},
+    socket::SocketConfig,
 };
 use crate::net::{
     conn::{Conn, OwnedReadHalf, OwnedWriteHalf},
@@ -22,11 +25,13 @@ use crate::net::{
 
 #[cfg(feature = "native-tls")]
 mod native_tls;
+mod reload;
 #[cfg(feature = "rustls")]
 mod rustls;
 
 #[cfg(feature = "native-tls")]
 use self::native_tls::{NativeTlsAcceptor, NativeTlsConnector};
+pub use self::reload::Reloadable;
 #[cfg(feature = "rustls")]
 use self::rustls::{RustlsAcceptor, RustlsConnector};
 
@@ -38,6 +43,10 @@ pub enum TlsConnector {
 
     #[cfg(feature = "native-tls")]
     NativeTls(NativeTlsConnector),
+
+    /// The connector which can be swapped for the new connections, such as with the rotated
+    /// client certificate.
+    Reloadable(Reloadable<TlsConnector>),
 }
 
 /// A wrapper around [`tokio_rustls::TlsAcceptor`] and [`tokio_native_tls::TlsAcceptor`].
@@ -48,6 +57,10 @@ pub enum TlsAcceptor {
 
     #[cfg(feature = "native-tls")]
     NativeTls(NativeTlsAcceptor),
+
+    /// The acceptor which can be swapped for the new connections, such as with the rotated
+    /// server certificate.
+    Reloadable(Reloadable<TlsAcceptor>),
 }
 
 pub trait Connector: Sized {
@@ -91,12 +104,28 @@ impl Connector for TlsConnector {
     }
 
     async fn connect(&self, server_name: &str, tcp_stream: TcpStream) -> Result<Conn> {
+        match self {
+            Self::Reloadable(connector) => {
+                connector
+                    .get()
+                    .connect_backend(server_name, tcp_stream)
+                    .await
+            }
+            _ => self.connect_backend(server_name, tcp_stream).await,
+        }
+    }
+}
+
+impl TlsConnector {
+    async fn connect_backend(&self, server_name: &str, tcp_stream: TcpStream) -> Result<Conn> {
         match self {
             #[cfg(feature = "rustls")]
             Self::Rustls(connector) => connector.connect(server_name, tcp_stream).await,
 
             #[cfg(feature = "native-tls")]
             Self::NativeTls(connector) => connector.connect(server_name, tcp_stream).await,
+
+            Self::Reloadable(_) => Err(nested_reloadable()),
         }
     }
 }
@@ -104,11 +133,8 @@ impl Connector for TlsConnector {
 impl Acceptor for TlsAcceptor {
     async fn accept(&self, tcp_stream: TcpStream) -> Result<ConnStream> {
         match self {
-            #[cfg(feature = "rustls")]
-            Self::Rustls(acceptor) => acceptor.accept(tcp_stream).await,
-
-            #[cfg(feature = "native-tls")]
-            Self::NativeTls(acceptor) => acceptor.accept(tcp_stream).await,
+            Self::Reloadable(acceptor) => acceptor.get().accept_backend(tcp_stream).await,
+            _ => self.accept_backend(tcp_stream).await,
         }
     }
 
@@ -129,6 +155,55 @@ impl Acceptor for TlsAcceptor {
     }
 }
 
+impl TlsAcceptor {
+    /// Creates a rustls acceptor which requires the clients to present the certificates signed
+    /// by the CAs in `client_ca`, which is a PEM bundle.
+    ///
+    /// This is only available with the `rustls` feature, since native-tls has no API to request
+    /// and verify the client certificates.
+    #[cfg(feature = "rustls")]
+    pub fn from_pem_with_client_auth(
+        cert: Vec<u8>,
+        key: Vec<u8>,
+        client_ca: Vec<u8>,
+    ) -> Result<Self> {
+        Ok(Self::Rustls(RustlsAcceptor::from_pem_with_client_auth(
+            cert, key, client_ca,
+        )?))
+    }
+
+    async fn accept_backend(&self, tcp_stream: TcpStream) -> Result<ConnStream> {
+        match self {
+            #[cfg(feature = "rustls")]
+            Self::Rustls(acceptor) => acceptor.accept(tcp_stream).await,
+
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(acceptor) => acceptor.accept(tcp_stream).await,
+
+            Self::Reloadable(_) => Err(nested_reloadable()),
+        }
+    }
+}
+
+fn nested_reloadable() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "a reloadable tls config cannot be nested in another one",
+    )
+}
+
+impl From<Reloadable<TlsConnector>> for TlsConnector {
+    fn from(connector: Reloadable<TlsConnector>) -> Self {
+        Self::Reloadable(connector)
+    }
+}
+
+impl From<Reloadable<TlsAcceptor>> for TlsAcceptor {
+    fn from(acceptor: Reloadable<TlsAcceptor>) -> Self {
+        Self::Reloadable(acceptor)
+    }
+}
+
 impl fmt::Debug for TlsConnector {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -137,6 +212,8 @@ impl fmt::Debug for TlsConnector {
 
             #[cfg(feature = "native-tls")]
             Self::NativeTls(_) => f.debug_tuple("TlsConnector::NativeTls").finish(),
+
+            Self::Reloadable(_) => f.debug_tuple("TlsConnector::Reloadable").finish(),
         }
     }
 }
@@ -149,6 +226,8 @@ impl fmt::Debug for TlsAcceptor {
 
             #[cfg(feature = "native-tls")]
             Self::NativeTls(_) => f.debug_tuple("TlsAcceptor::NativeTls").finish(),
+
+            Self::Reloadable(_) => f.debug_tuple("TlsAcceptor::Reloadable").finish(),
         }
     }
 }
@@ -156,6 +235,7 @@ impl fmt::Debug for TlsAcceptor {
 pub struct TlsConnectorBuilder {
     pub(super) default_root_certs: bool,
     pub(super) pems: Vec<Vec<u8>>,
+    pub(super) identity: Option<(Vec<u8>, Vec<u8>)>,
 }
 
 impl Default for TlsConnectorBuilder {
@@ -163,6 +243,7 @@ impl Default for TlsConnectorBuilder {
         Self {
             default_root_certs: true,
             pems: Vec::new(),
+            identity: None,
         }
     }
 }
@@ -187,6 +268,25 @@ impl TlsConnectorBuilder {
         Ok(self)
     }
 
+    /// Sets the certificate chain and the PKCS#8 private key in PEM, which are presented to the
+    /// servers requiring client authentication.
+    pub fn identity(mut self, cert: Vec<u8>, key: Vec<u8>) -> Self {
+        self.identity = Some((cert, key));
+        self
+    }
+
+    /// Reads the certificate chain and the PKCS#8 private key in PEM from the files, which are
+    /// presented to the servers requiring client authentication.
+    pub fn identity_from_file<CP, KP>(self, cert_path: CP, key_path: KP) -> Result<Self>
+    where
+        CP: AsRef<Path>,
+        KP: AsRef<Path>,
+    {
+        let cert = std::fs::read(cert_path.as_ref())?;
+        let key = std::fs::read(key_path.as_ref())?;
+        Ok(self.identity(cert, key))
+    }
+
     #[cfg(feature = "rustls")]
     pub fn build(self) -> Result<TlsConnector> {
         Self::build_rustls(self)
@@ -224,17 +324,33 @@ impl ClientTlsConfig {
     }
 }
 
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_CONCURRENT_HANDSHAKES: usize = 1024;
+
 /// TLS configuration for a server.
+///
+/// It is created by [`ServerTlsConfig::new`], the `from_pem*` constructors or the `From` impls of
+/// the acceptors, and it cannot be created by a struct literal since it holds the private
+/// handshake settings.
 #[derive(Clone)]
 pub struct ServerTlsConfig {
     pub acceptor: TlsAcceptor,
+    handshake_timeout: Duration,
+    handshake_limiter: Arc<Semaphore>,
 }
 
 impl ServerTlsConfig {
+    /// Creates a config with the acceptor.
+    pub fn new(acceptor: impl Into<TlsAcceptor>) -> Self {
+        Self {
+            acceptor: acceptor.into(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            handshake_limiter: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_HANDSHAKES)),
+        }
+    }
+
     pub fn from_pem(cert: Vec<u8>, key: Vec<u8>) -> Result<Self> {
-        Ok(Self {
-            acceptor: TlsAcceptor::from_pem(cert, key)?,
-        })
+        Ok(Self::new(TlsAcceptor::from_pem(cert, key)?))
     }
 
     pub fn from_pem_file<CP, KP>(cert_path: CP, key_path: KP) -> Result<Self>
@@ -246,6 +362,147 @@ impl ServerTlsConfig {
         let key = std::fs::read(key_path.as_ref())?;
         Self::from_pem(cert, key)
     }
+
+    /// Creates a config which requires the clients to present the certificates signed by the
+    /// CAs in `client_ca`, which is a PEM bundle.
+    ///
+    /// The verified identity of the client is stored in the tags of the caller of the server
+    /// context as [`PeerIdentity`].
+    ///
+    /// This is only available with the `rustls` feature, since native-tls has no API to request
+    /// and verify the client certificates.
+    #[cfg(feature = "rustls")]
+    pub fn from_pem_with_client_auth(
+        cert: Vec<u8>,
+        key: Vec<u8>,
+        client_ca: Vec<u8>,
+    ) -> Result<Self> {
+        Ok(Self::new(TlsAcceptor::from_pem_with_client_auth(
+            cert, key, client_ca,
+        )?))
+    }
+
+    /// Reads the files and creates a config which requires the clients to present the
+    /// certificates signed by the CAs in the file at `client_ca_path`.
+    #[cfg(feature = "rustls")]
+    pub fn from_pem_file_with_client_auth<CP, KP, CAP>(
+        cert_path: CP,
+        key_path: KP,
+        client_ca_path: CAP,
+    ) -> Result<Self>
+    where
+        CP: AsRef<Path>,
+        KP: AsRef<Path>,
+        CAP: AsRef<Path>,
+    {
+        let cert = std::fs::read(cert_path.as_ref())?;
+        let key = std::fs::read(key_path.as_ref())?;
+        let client_ca = std::fs::read(client_ca_path.as_ref())?;
+        Self::from_pem_with_client_auth(cert, key, client_ca)
+    }
+
+    /// Sets the timeout of the TLS handshake, including the time waiting for the other
+    /// handshakes when the number of them reaches the limit.
+    ///
+    /// Default is 10 seconds.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum number of the TLS handshakes performed concurrently, the handshakes of
+    /// the other connections will wait until one of them is finished.
+    ///
+    /// The waiting connections are still held by their tasks, and the number of them is bounded
+    /// by the handshake timeout, which also applies to the wait, rather than by this limit.
+    ///
+    /// Default is 1024.
+    pub fn max_concurrent_handshakes(mut self, max: usize) -> Self {
+        self.handshake_limiter = Arc::new(Semaphore::new(max));
+        self
+    }
+
+    /// Performs the TLS handshake on the connection if it's a TCP connection, the other
+    /// connections are returned as is.
+    ///
+    /// The servers call this in the task of each connection, so that a slow client will not
+    /// block accepting the other connections. The handshake timeout covers both waiting for the
+    /// handshake limit and the handshake itself, so a connection never waits longer than that.
+    pub async fn accept(&self, conn: Conn) -> Result<Conn> {
+        let Conn { stream, info } = conn;
+        let tcp = match stream {
+            ConnStream::Tcp(tcp) => tcp,
+            stream => return Ok(Conn { stream, info }),
+        };
+        // the permit is acquired in the timeout, so that the queued connections are released
+        // after the timeout as well
+        let handshake = async {
+            let _permit = self
+                .handshake_limiter
+                .acquire()
+                .await
+                .map_err(io::Error::other)?;
+            self.acceptor.accept(tcp).await
+        };
+        let stream = tokio::time::timeout(self.handshake_timeout, handshake)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "tls handshake timed out"))??;
+        Ok(Conn { stream, info })
+    }
+}
+
+impl From<TlsAcceptor> for ServerTlsConfig {
+    fn from(acceptor: TlsAcceptor) -> Self {
+        Self::new(acceptor)
+    }
+}
+
+impl From<Reloadable<TlsAcceptor>> for ServerTlsConfig {
+    fn from(acceptor: Reloadable<TlsAcceptor>) -> Self {
+        Self::new(acceptor)
+    }
+}
+
+/// The details of the TLS session negotiated in the handshake, which is stored in the extensions
+/// of the server context.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsSession {
+    /// The server name sent by the client with SNI, which is only provided by rustls.
+    pub server_name: Option<FastStr>,
+    /// The protocol negotiated with ALPN, such as `h2`, which is only provided by rustls.
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// The version of TLS, such as `TLSv1_3`, which is only provided by rustls.
+    pub protocol_version: Option<FastStr>,
+    /// The cipher suite, such as `TLS13_AES_128_GCM_SHA256`, which is only provided by rustls.
+    pub cipher_suite: Option<FastStr>,
+    /// The identity of the peer, if it has presented a certificate.
+    pub peer_identity: Option<PeerIdentity>,
+}
+
+/// The identity of the peer, which is the certificate chain verified in the TLS handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    certificates: Arc<[Vec<u8>]>,
+}
+
+impl PeerIdentity {
+    pub fn new(certificates: Vec<Vec<u8>>) -> Self {
+        Self {
+            certificates: certificates.into(),
+        }
+    }
+
+    /// Returns the certificate of the peer in DER.
+    pub fn certificate(&self) -> Option<&[u8]> {
+        self.certificates.first().map(Vec::as_slice)
+    }
+
+    /// Returns the certificate chain in DER, starting with the certificate of the peer.
+    ///
+    /// native-tls only provides the certificate of the peer.
+    pub fn certificates(&self) -> &[Vec<u8>] {
+        &self.certificates
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -268,17 +525,10 @@ impl UnaryService<Address> for TlsMakeTransport {
         match addr {
             Address::Ip(addr) => {
                 let tcp = make_tcp_connection(&self.cfg, addr).await?;
-
-                match &self.tls_config.connector {
-                    #[cfg(feature = "rustls")]
-                    TlsConnector::Rustls(connector) => {
-                        connector.connect(&self.tls_config.server_name, tcp).await
-                    }
-                    #[cfg(feature = "native-tls")]
-                    TlsConnector::NativeTls(connector) => {
-                        connector.connect(&self.tls_config.server_name, tcp).await
-                    }
-                }
+                self.tls_config
+                    .connector
+                    .connect(&self.tls_config.server_name, tcp)
+                    .await
             }
             #[cfg(target_family = "unix")]
             Address::Unix(addr) => UnixStream::connect(addr.as_pathname().ok_or_else(|| {
@@ -314,4 +564,149 @@ impl MakeTransport for TlsMakeTransport {
     fn set_write_timeout(&mut self, timeout: Option<Duration>) {
         self.cfg = self.cfg.with_write_timeout(timeout);
     }
+
+    fn set_socket_config(&mut self, config: SocketConfig) {
+        self.cfg = self.cfg.with_socket_config(config);
+    }
+}
+
+#[cfg(all(test, feature = "rustls"))]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{Acceptor, Connector, ServerTlsConfig, TlsAcceptor, TlsConnector};
+
+    fn read(name: &str) -> Vec<u8> {
+        std::fs::read(format!(
+            "{}/../examples/data/tls/{name}",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let acceptor = TlsAcceptor::from_pem_with_client_auth(
+            read("server.pem"),
+            read("server.key"),
+            read("client_ca.pem"),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut identities = Vec::new();
+            for _ in 0..2 {
+                let (tcp, _) = listener.accept().await.unwrap();
+                match acceptor.accept(tcp).await {
+                    Ok(mut stream) => {
+                        identities.push(stream.peer_identity());
+                        stream.write_all(b"ok").await.unwrap();
+                    }
+                    Err(_) => identities.push(None),
+                }
+            }
+            identities
+        });
+
+        let connector = TlsConnector::builder()
+            .enable_default_root_certs(false)
+            .add_pem(read("ca.pem"))
+            .identity(read("client.pem"), read("client.key"))
+            .build()
+            .unwrap();
+        let mut conn = connector
+            .connect("localhost", TcpStream::connect(addr).await.unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0; 2];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ok");
+
+        // the client without a certificate is rejected by the server
+        let connector = TlsConnector::builder()
+            .enable_default_root_certs(false)
+            .add_pem(read("ca.pem"))
+            .build()
+            .unwrap();
+        if let Ok(mut conn) = connector
+            .connect("localhost", TcpStream::connect(addr).await.unwrap())
+            .await
+        {
+            assert!(conn.read_exact(&mut buf).await.is_err());
+        }
+
+        let identities = server.await.unwrap();
+        let client_cert = rustls_pemfile::certs(&mut read("client.pem").as_slice())
+            .next()
+            .unwrap()
+            .unwrap();
+        let identity = identities[0].as_ref().unwrap();
+        assert_eq!(identity.certificate(), Some(client_cert.as_ref()));
+        assert!(identities[1].is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tls_session() {
+        let acceptor = TlsAcceptor::from_pem(read("server.pem"), read("server.key")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(tcp).await.unwrap();
+            stream.write_all(b"ok").await.unwrap();
+            stream.tls_session()
+        });
+
+        let connector = TlsConnector::builder()
+            .enable_default_root_certs(false)
+            .add_pem(read("ca.pem"))
+            .build()
+            .unwrap();
+        let mut conn = connector
+            .connect("localhost", TcpStream::connect(addr).await.unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0; 2];
+        conn.read_exact(&mut buf).await.unwrap();
+
+        let session = server.await.unwrap().unwrap();
+        assert_eq!(session.server_name.as_deref(), Some("localhost"));
+        assert_eq!(session.protocol_version.as_deref(), Some("TLSv1_3"));
+        assert!(session.cipher_suite.is_some());
+        assert!(session.alpn_protocol.is_none());
+        assert!(session.peer_identity.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let config = ServerTlsConfig::from_pem(read("server.pem"), read("server.key"))
+            .unwrap()
+            .handshake_timeout(Duration::from_millis(100))
+            .max_concurrent_handshakes(1);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // the client connects but never sends the client hello
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        let (tcp, _) = listener.accept().await.unwrap();
+        let idle = tokio::spawn({
+            let config = config.clone();
+            async move { config.accept(tcp.into()).await }
+        });
+
+        // the handshake waiting for the idle one also times out
+        let _pending = TcpStream::connect(addr).await.unwrap();
+        let (tcp, _) = listener.accept().await.unwrap();
+        let err = config.accept(tcp.into()).await.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        let err = idle.await.unwrap().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
 }
@@ -30,6 +30,11 @@ impl Connector for NativeTlsConnector {
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
             builder.add_root_certificate(cert);
         }
+        if let Some((cert, key)) = config.identity {
+            let identity = Identity::from_pkcs8(&cert, &key)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            builder.identity(identity);
+        }
         let connector = builder
             .build()
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
@@ -0,0 +1,162 @@
+//! Reloading the certificates without restarting the servers or the clients.
+
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
+
+/// A value that can be swapped atomically, such as a [`TlsAcceptor`](super::TlsAcceptor) or a
+/// [`TlsConnector`](super::TlsConnector) with the certificates to use for the new handshakes.
+///
+/// The connections established before a swap keep using the old certificates.
+///
+/// The value can be swapped by [`Reloadable::set`] from a callback, or by
+/// [`Reloadable::watch_files`] when the certificate files change.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let acceptor = Reloadable::watch_files(
+///     ["cert.pem", "key.pem"],
+///     Duration::from_secs(60),
+///     || TlsAcceptor::from_pem_file("cert.pem", "key.pem"),
+/// )?;
+/// let server = server.tls_config(ServerTlsConfig::from(acceptor));
+/// ```
+#[derive(Debug)]
+pub struct Reloadable<T> {
+    current: Arc<RwLock<T>>,
+}
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Self {
+            current: self.current.clone(),
+        }
+    }
+}
+
+impl<T: Clone> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(value)),
+        }
+    }
+
+    /// Returns the current value.
+    pub fn get(&self) -> T {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Swaps the value, which is used by the new handshakes from now on.
+    pub fn set(&self, value: T) {
+        *self.current.write().unwrap() = value;
+    }
+
+    /// Loads the value with `load`, and then reloads it whenever the modification time of any
+    /// of the files changes, which is checked every `interval`.
+    ///
+    /// The value is kept if the reload fails, such as when only one of the certificate and the
+    /// key has been updated, and the reload is retried at the next check. The watching stops
+    /// when all the clones of the returned value are dropped.
+    ///
+    /// This must be called within a tokio runtime.
+    pub fn watch_files<P, F>(
+        paths: impl IntoIterator<Item = P>,
+        interval: Duration,
+        load: F,
+    ) -> io::Result<Self>
+    where
+        P: Into<PathBuf>,
+        F: Fn() -> io::Result<T> + Send + 'static,
+        T: Send + Sync + 'static,
+    {
+        let paths = paths.into_iter().map(Into::into).collect::<Vec<_>>();
+        let mut modified = modified_times(&paths);
+        let reloadable = Self::new(load()?);
+        let current = Arc::downgrade(&reloadable.current);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(current) = Weak::upgrade(&current) else {
+                    break;
+                };
+                let now = modified_times(&paths);
+                if now == modified {
+                    continue;
+                }
+                match load() {
+                    Ok(value) => {
+                        tracing::info!("[VOLO] tls certificates reloaded from {:?}", paths);
+                        *current.write().unwrap() = value;
+                        modified = now;
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            "[VOLO] failed to reload tls certificates from {:?}: {}",
+                            paths,
+                            e
+                        );
+                    }
+                }
+            }
+        });
+        Ok(reloadable)
+    }
+}
+
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::Reloadable;
+
+    #[tokio::test]
+    async fn test_watch_files() {
+        let path = std::env::temp_dir().join(format!("volo_tls_reload_{}", std::process::id()));
+        std::fs::write(&path, "1").unwrap();
+        let loads = Arc::new(AtomicUsize::new(0));
+        let reloadable = Reloadable::watch_files([path.clone()], Duration::from_millis(10), {
+            let (path, loads) = (path.clone(), loads.clone());
+            move || {
+                loads.fetch_add(1, Ordering::Relaxed);
+                std::fs::read_to_string(&path)
+            }
+        })
+        .unwrap();
+        assert_eq!(reloadable.get(), "1");
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+
+        std::fs::write(&path, "2").unwrap();
+        // makes sure the modification time changes on the coarse file systems
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(reloadable.get(), "2");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
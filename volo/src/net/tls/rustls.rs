@@ -1,7 +1,7 @@
 use std::{io, io::Result, path::Path, sync::Arc};
 
-use rustls::{pki_types::ServerName, RootCertStore, ServerConfig};
-use rustls_pki_types::PrivateKeyDer;
+use rustls::{pki_types::ServerName, server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
 use tokio::net::TcpStream;
 use tokio_rustls::{rustls::ClientConfig, TlsAcceptor, TlsConnector};
 
@@ -44,9 +44,13 @@ impl Connector for RustlsConnector {
                 .add(cert)
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
         }
-        let client_config = ClientConfig::builder()
-            .with_root_certificates(certs)
-            .with_no_client_auth();
+        let client_config = ClientConfig::builder().with_root_certificates(certs);
+        let client_config = match builder.identity {
+            Some((cert, key)) => client_config
+                .with_client_auth_cert(load_certs(&cert)?, load_key(&key)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            None => client_config.with_no_client_auth(),
+        };
         let connector = TlsConnector::from(Arc::new(client_config));
         Ok(Self(connector))
     }
@@ -64,17 +68,37 @@ impl Connector for RustlsConnector {
     }
 }
 
+impl RustlsAcceptor {
+    /// Creates an acceptor which requires the clients to present the certificates signed by the
+    /// CAs in `client_ca`, which is a PEM bundle.
+    pub fn from_pem_with_client_auth(
+        cert: Vec<u8>,
+        key: Vec<u8>,
+        client_ca: Vec<u8>,
+    ) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for ca in load_certs(&client_ca)? {
+            roots
+                .add(ca)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let server_config = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(load_certs(&cert)?, load_key(&key)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        Ok(Self(acceptor))
+    }
+}
+
 impl Acceptor for RustlsAcceptor {
     fn from_pem(cert: Vec<u8>, key: Vec<u8>) -> Result<Self> {
-        let cert = rustls_pemfile::certs(&mut cert.as_ref()).collect::<Result<Vec<_>>>()?;
-        let key = rustls_pemfile::pkcs8_private_keys(&mut key.as_ref())
-            .collect::<Result<Vec<_>>>()?
-            .pop()
-            .map(PrivateKeyDer::Pkcs8)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No private key found"))?;
         let server_config = ServerConfig::builder()
             .with_no_client_auth()
-            .with_single_cert(cert, key)
+            .with_single_cert(load_certs(&cert)?, load_key(&key)?)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
         let acceptor = TlsAcceptor::from(Arc::new(server_config));
         Ok(Self(acceptor))
@@ -96,6 +120,25 @@ impl Acceptor for RustlsAcceptor {
     }
 }
 
+fn load_certs(mut pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut pem).collect::<Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No certificate found in the provided PEM",
+        ));
+    }
+    Ok(certs)
+}
+
+fn load_key(mut pem: &[u8]) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::pkcs8_private_keys(&mut pem)
+        .collect::<Result<Vec<_>>>()?
+        .pop()
+        .map(PrivateKeyDer::Pkcs8)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No private key found"))
+}
+
 impl From<ClientConfig> for super::TlsConnector {
     fn from(client_config: ClientConfig) -> Self {
         Self::Rustls(RustlsConnector(TlsConnector::from(Arc::new(client_config))))
@@ -0,0 +1,435 @@
+//! A layer that limits the rate of the requests on the server side.
+//!
+//! The requests are keyed by a [`KeyExtractor`], such as by the method with [`ByMethod`] or by the
+//! service name of the caller with [`ByCaller`], and each key has its own [`Quota`]. The requests
+//! exceeding the quota are rejected by the [`Handler`], which returns the error or the response of
+//! the protocol.
+//!
+//! For example of `Handler` implementations, see the `server::rate_limit` modules in
+//! `volo-thrift`, `volo-grpc` and `volo-http` crates.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use faststr::FastStr;
+
+use crate::context::Context;
+
+/// The quota of the requests of a key.
+#[derive(Debug, Clone, Copy)]
+pub struct Quota(Algorithm);
+
+#[derive(Debug, Clone, Copy)]
+enum Algorithm {
+    TokenBucket { rate: f64, burst: f64 },
+    SlidingWindow { limit: f64, window: Duration },
+}
+
+impl Quota {
+    /// Allows `rate` requests per second with a token bucket, in which the burst is also `rate`.
+    pub fn per_second(rate: u32) -> Self {
+        Self::token_bucket(rate as f64, rate)
+    }
+
+    /// Allows `rate` requests per second with a token bucket, which holds `burst` tokens at most.
+    pub fn token_bucket(rate: f64, burst: u32) -> Self {
+        Self(Algorithm::TokenBucket {
+            rate,
+            burst: burst as f64,
+        })
+    }
+
+    /// Allows `limit` requests in any `window` with a sliding window, which is estimated by the
+    /// counts of the current fixed window and the previous one.
+    pub fn sliding_window(limit: u32, window: Duration) -> Self {
+        Self(Algorithm::SlidingWindow {
+            limit: limit as f64,
+            window,
+        })
+    }
+
+    fn limiter(&self) -> Limiter {
+        let now = Instant::now();
+        let state = match self.0 {
+            Algorithm::TokenBucket { burst, .. } => State::TokenBucket {
+                tokens: burst,
+                updated_at: now,
+            },
+            Algorithm::SlidingWindow { .. } => State::SlidingWindow {
+                prev_count: 0.0,
+                count: 0.0,
+                started_at: now,
+            },
+        };
+        Limiter {
+            quota: *self,
+            state: Mutex::new(state),
+        }
+    }
+}
+
+enum State {
+    TokenBucket {
+        tokens: f64,
+        updated_at: Instant,
+    },
+    SlidingWindow {
+        prev_count: f64,
+        count: f64,
+        started_at: Instant,
+    },
+}
+
+struct Limiter {
+    quota: Quota,
+    state: Mutex<State>,
+}
+
+impl Limiter {
+    /// Takes a permit for a request, returns `false` if the quota is exceeded.
+    fn acquire(&self) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match (&mut *state, self.quota.0) {
+            (State::TokenBucket { tokens, updated_at }, Algorithm::TokenBucket { rate, burst }) => {
+                let elapsed = now.saturating_duration_since(*updated_at).as_secs_f64();
+                *tokens = (*tokens + elapsed * rate).min(burst);
+                *updated_at = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    true
+                } else {
+                    false
+                }
+            }
+            (
+                State::SlidingWindow {
+                    prev_count,
+                    count,
+                    started_at,
+                },
+                Algorithm::SlidingWindow { limit, window },
+            ) => {
+                let mut elapsed = now.saturating_duration_since(*started_at);
+                if elapsed >= window {
+                    // the previous window is empty if more than one window has passed
+                    *prev_count = if elapsed < window * 2 { *count } else { 0.0 };
+                    *count = 0.0;
+                    let windows = elapsed.as_nanos() / window.as_nanos().max(1);
+                    *started_at += window * windows.min(u32::MAX as u128) as u32;
+                    elapsed = now.saturating_duration_since(*started_at);
+                }
+                let prev_weight = 1.0 - elapsed.as_secs_f64() / window.as_secs_f64();
+                if *prev_count * prev_weight + *count < limit {
+                    *count += 1.0;
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => unreachable!("the state always matches the quota"),
+        }
+    }
+
+    /// Returns whether the limiter is the same as a new one, which can be evicted without
+    /// changing the result of the later requests.
+    fn is_idle(&self, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        match (&*state, self.quota.0) {
+            (State::TokenBucket { tokens, updated_at }, Algorithm::TokenBucket { rate, burst }) => {
+                let elapsed = now.saturating_duration_since(*updated_at).as_secs_f64();
+                *tokens + elapsed * rate >= burst
+            }
+            (State::SlidingWindow { started_at, .. }, Algorithm::SlidingWindow { window, .. }) => {
+                now.saturating_duration_since(*started_at) >= window * 2
+            }
+            _ => unreachable!("the state always matches the quota"),
+        }
+    }
+}
+
+/// The error of the requests rejected by the rate limit.
+#[derive(Debug, Clone)]
+pub struct RateLimitError {
+    /// The key of the request.
+    pub key: FastStr,
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limit exceeded for {}", self.key)
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+/// Extracts the key of the rate limit from the context, or `None` if the request is not limited.
+///
+/// It is implemented for `Fn(&Cx) -> Option<FastStr>`.
+pub trait KeyExtractor<Cx>: Send + Sync + 'static {
+    fn extract(&self, cx: &Cx) -> Option<FastStr>;
+}
+
+impl<F, Cx> KeyExtractor<Cx> for F
+where
+    F: Fn(&Cx) -> Option<FastStr> + Send + Sync + 'static,
+{
+    fn extract(&self, cx: &Cx) -> Option<FastStr> {
+        self(cx)
+    }
+}
+
+/// Keys the requests by the method.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByMethod;
+
+impl<Cx: Context> KeyExtractor<Cx> for ByMethod {
+    fn extract(&self, cx: &Cx) -> Option<FastStr> {
+        Some(cx.rpc_info().method().clone())
+    }
+}
+
+/// Keys the requests by the service name of the caller.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByCaller;
+
+impl<Cx: Context> KeyExtractor<Cx> for ByCaller {
+    fn extract(&self, cx: &Cx) -> Option<FastStr> {
+        Some(cx.rpc_info().caller().service_name())
+    }
+}
+
+/// Rejects the requests exceeding the quota with the error or the response of the protocol.
+pub trait Handler<S, Cx, Req>
+where
+    S: crate::Service<Cx, Req> + Send + Sync + 'static,
+    Cx: Send + 'static,
+    Req: Send + 'static,
+{
+    fn reject(&self, cx: &mut Cx, err: RateLimitError) -> Result<S::Response, S::Error>;
+}
+
+/// Impl this Handler for F so users can use a closure as the handler.
+impl<F, S, Cx, Req> Handler<S, Cx, Req> for F
+where
+    F: Fn(&mut Cx, RateLimitError) -> Result<S::Response, S::Error>,
+    S: crate::Service<Cx, Req> + Send + Sync + 'static,
+    Cx: Send + 'static,
+    Req: Send + 'static,
+{
+    fn reject(&self, cx: &mut Cx, err: RateLimitError) -> Result<S::Response, S::Error> {
+        self(cx, err)
+    }
+}
+
+/// A layer that limits the rate of the requests by the keys.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// server.layer_front(
+///     volo::rate_limit::Layer::new(
+///         volo::rate_limit::ByMethod,
+///         volo_thrift::server::rate_limit::reject_with_exception,
+///     )
+///     .quota("GetItem", volo::rate_limit::Quota::per_second(100)),
+/// )
+/// ```
+#[derive(Clone)]
+pub struct Layer<K, H> {
+    key_extractor: K,
+    handler: H,
+    default_quota: Option<Quota>,
+    quotas: HashMap<FastStr, Quota>,
+}
+
+impl<K, H> Layer<K, H> {
+    /// Create a new `Layer` with the given `key_extractor` and `handler`.
+    ///
+    /// No request is limited until the quotas are set.
+    pub fn new(key_extractor: K, handler: H) -> Self {
+        Self {
+            key_extractor,
+            handler,
+            default_quota: None,
+            quotas: HashMap::new(),
+        }
+    }
+
+    /// Sets the quota of each key that has no quota set by [`Layer::quota`].
+    ///
+    /// Default is unlimited.
+    pub fn default_quota(mut self, quota: Quota) -> Self {
+        self.default_quota = Some(quota);
+        self
+    }
+
+    /// Sets the quota of the key.
+    pub fn quota(mut self, key: impl Into<FastStr>, quota: Quota) -> Self {
+        self.quotas.insert(key.into(), quota);
+        self
+    }
+}
+
+impl<S, K, H> crate::layer::Layer<S> for Layer<K, H> {
+    type Service = Service<S, K, H>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Service {
+            inner,
+            key_extractor: self.key_extractor,
+            handler: self.handler,
+            limits: Arc::new(Limits {
+                default_quota: self.default_quota,
+                quotas: self.quotas,
+                limiters: DashMap::new(),
+                last_evicted: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+}
+
+/// The interval of evicting the idle limiters.
+const EVICT_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Limits {
+    default_quota: Option<Quota>,
+    quotas: HashMap<FastStr, Quota>,
+    /// The limiters of the keys, in which the idle ones are evicted so that the map does not grow
+    /// with the keys that are not requested anymore, such as the forged callers.
+    limiters: DashMap<FastStr, Limiter>,
+    last_evicted: Mutex<Instant>,
+}
+
+impl Limits {
+    /// Returns `false` if the request of the key should be rejected.
+    fn acquire(&self, key: &FastStr) -> bool {
+        self.evict();
+        if let Some(limiter) = self.limiters.get(key) {
+            return limiter.acquire();
+        }
+        let Some(quota) = self.quotas.get(key).or(self.default_quota.as_ref()) else {
+            return true;
+        };
+        self.limiters
+            .entry(key.clone())
+            .or_insert_with(|| quota.limiter())
+            .acquire()
+    }
+
+    /// Evicts the idle limiters, at most once in the [`EVICT_INTERVAL`].
+    fn evict(&self) {
+        let now = Instant::now();
+        {
+            let mut last_evicted = self.last_evicted.lock().unwrap();
+            if now.saturating_duration_since(*last_evicted) < EVICT_INTERVAL {
+                return;
+            }
+            *last_evicted = now;
+        }
+        self.limiters.retain(|_, limiter| !limiter.is_idle(now));
+    }
+}
+
+#[derive(Clone)]
+pub struct Service<S, K, H> {
+    inner: S,
+    key_extractor: K,
+    handler: H,
+    limits: Arc<Limits>,
+}
+
+impl<Cx, Req, S, K, H> crate::Service<Cx, Req> for Service<S, K, H>
+where
+    S: crate::Service<Cx, Req> + Send + Sync + 'static,
+    K: KeyExtractor<Cx>,
+    H: Handler<S, Cx, Req> + Send + Sync,
+    Cx: Send + 'static,
+    Req: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+
+    async fn call(&self, cx: &mut Cx, req: Req) -> Result<Self::Response, Self::Error> {
+        if let Some(key) = self.key_extractor.extract(cx) {
+            if !self.limits.acquire(&key) {
+                return self.handler.reject(cx, RateLimitError { key });
+            }
+        }
+        self.inner.call(cx, req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use faststr::FastStr;
+    use motore::{layer::Layer as _, service::service_fn, Service as _};
+
+    use super::{Layer, Quota, RateLimitError};
+
+    async fn handle(_: &mut FastStr, _: ()) -> Result<(), RateLimitError> {
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = Quota::token_bucket(20.0, 2).limiter();
+        assert!(limiter.acquire());
+        assert!(limiter.acquire());
+        assert!(!limiter.acquire());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.acquire());
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let limiter = Quota::sliding_window(2, Duration::from_millis(50)).limiter();
+        assert!(limiter.acquire());
+        assert!(limiter.acquire());
+        assert!(!limiter.acquire());
+        // the previous window is forgotten after two windows
+        std::thread::sleep(Duration::from_millis(110));
+        assert!(limiter.acquire());
+    }
+
+    #[test]
+    fn test_is_idle() {
+        let start = Instant::now();
+        let limiter = Quota::token_bucket(10.0, 2).limiter();
+        assert!(limiter.is_idle(start));
+        assert!(limiter.acquire());
+        assert!(!limiter.is_idle(Instant::now()));
+        assert!(limiter.is_idle(Instant::now() + Duration::from_millis(100)));
+
+        let limiter = Quota::sliding_window(2, Duration::from_millis(50)).limiter();
+        assert!(limiter.acquire());
+        assert!(!limiter.is_idle(Instant::now()));
+        assert!(limiter.is_idle(Instant::now() + Duration::from_millis(100)));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_layer() {
+        let service = Layer::new(
+            |cx: &FastStr| Some(cx.clone()),
+            |_: &mut FastStr, err| Err(err),
+        )
+        .quota("limited", Quota::per_second(1))
+        .layer(service_fn(handle));
+
+        let mut limited = FastStr::from_static_str("limited");
+        assert!(service.call(&mut limited, ()).await.is_ok());
+        let err = service.call(&mut limited, ()).await.unwrap_err();
+        assert_eq!(err.key, "limited");
+        let mut other = FastStr::from_static_str("other");
+        for _ in 0..10 {
+            assert!(service.call(&mut other, ()).await.is_ok());
+        }
+    }
+}
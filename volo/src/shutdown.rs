@@ -0,0 +1,236 @@
+//! Utilities for the graceful shutdown of the servers.
+
+use std::{
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+use tracing::{info, trace, warn};
+
+/// Waits for SIGINT, SIGHUP or SIGTERM on unix, or Ctrl-C on windows, which is the default
+/// shutdown signal of the servers.
+pub async fn signal() -> io::Result<()> {
+    #[cfg(target_family = "unix")]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sighup = signal(SignalKind::hangup())?;
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = sigint.recv() => {}
+            _ = sighup.recv() => {}
+            _ = sigterm.recv() => {}
+        }
+        Ok(())
+    }
+
+    #[cfg(target_family = "windows")]
+    tokio::signal::ctrl_c().await
+}
+
+/// The config of the graceful shutdown of the servers.
+///
+/// After the shutdown signal, the server shuts down in three phases:
+///
+/// 1. The server keeps accepting and serving for the grace period, so that the load balancers can
+///    deregister the instance.
+/// 2. The listener is closed, and each connection is drained in the way of its protocol, such as
+///    closing it after the in-flight requests are answered.
+/// 3. If some connections are still alive after the drain timeout, they are either closed by force
+///    or left running, depending on the force close option.
+#[derive(Debug, Clone, Copy)]
+pub struct ShutdownConfig {
+    grace_period: Duration,
+    drain_timeout: Duration,
+    force_close: bool,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownConfig {
+    pub fn new() -> Self {
+        Self {
+            grace_period: Duration::ZERO,
+            drain_timeout: Duration::from_secs(30),
+            force_close: false,
+        }
+    }
+
+    /// Sets the time to keep serving before closing the listener.
+    ///
+    /// Default is zero.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Sets the max time to wait for the connections to be drained after the listener is closed.
+    ///
+    /// Default is 30 seconds.
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Sets whether to close the connections that are still alive after the drain timeout, which
+    /// cancels the in-flight requests on them.
+    ///
+    /// Default is false, which leaves them running when the server returns.
+    pub fn force_close(mut self, force_close: bool) -> Self {
+        self.force_close = force_close;
+        self
+    }
+
+    /// Waits for the grace period.
+    pub async fn wait_grace_period(&self) {
+        if !self.grace_period.is_zero() {
+            info!(
+                "[VOLO] waiting {:?} before closing the listener",
+                self.grace_period
+            );
+            tokio::time::sleep(self.grace_period).await;
+        }
+    }
+
+    /// Waits for the connections of the tracker to be drained until the drain timeout, and then
+    /// closes the remaining ones if force close is enabled.
+    pub async fn drain(&self, tracker: &ConnTracker) {
+        if tokio::time::timeout(self.drain_timeout, tracker.drained())
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        warn!(
+            "[VOLO] drain timeout, remaining connection count: {}",
+            tracker.count()
+        );
+        if self.force_close {
+            tracker.close();
+            tracker.drained().await;
+        }
+    }
+}
+
+/// Tracks the connections of a server, so that the shutdown can wait for them to be drained
+/// instead of polling.
+#[derive(Debug, Clone, Default)]
+pub struct ConnTracker {
+    inner: Arc<TrackerInner>,
+}
+
+#[derive(Debug, Default)]
+struct TrackerInner {
+    count: AtomicUsize,
+    drained: Notify,
+    closed: AtomicBool,
+    close: Notify,
+}
+
+impl ConnTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tracks the connection served by the future, which is counted from now until the future
+    /// completes or the tracker is closed.
+    pub fn serve<F>(&self, conn: F) -> impl Future<Output = ()> + Send + 'static
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.inner.count.fetch_add(1, Ordering::Relaxed);
+        let guard = ConnGuard(self.clone());
+        async move {
+            tokio::select! {
+                _ = conn => {}
+                _ = guard.0.closed() => {
+                    trace!("[VOLO] connection closed by force");
+                }
+            }
+            drop(guard);
+        }
+    }
+
+    /// Returns the number of the alive connections.
+    pub fn count(&self) -> usize {
+        self.inner.count.load(Ordering::Relaxed)
+    }
+
+    /// Waits for all the connections to be done.
+    pub async fn drained(&self) {
+        loop {
+            // the waiter is registered once created, so no wakeup is missed after the check
+            let drained = self.inner.drained.notified();
+            if self.count() == 0 {
+                return;
+            }
+            drained.await;
+        }
+    }
+
+    /// Closes all the connections by dropping their futures.
+    pub fn close(&self) {
+        self.inner.closed.store(true, Ordering::Relaxed);
+        self.inner.close.notify_waiters();
+    }
+
+    async fn closed(&self) {
+        loop {
+            let close = self.inner.close.notified();
+            if self.inner.closed.load(Ordering::Relaxed) {
+                return;
+            }
+            close.await;
+        }
+    }
+}
+
+struct ConnGuard(ConnTracker);
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        if self.0.inner.count.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.0.inner.drained.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ConnTracker, ShutdownConfig};
+
+    #[tokio::test]
+    async fn test_drain() {
+        let tracker = ConnTracker::new();
+        tokio::spawn(tracker.serve(tokio::time::sleep(Duration::from_millis(20))));
+        assert_eq!(tracker.count(), 1);
+        let config = ShutdownConfig::new().drain_timeout(Duration::from_secs(1));
+        tokio::time::timeout(Duration::from_millis(500), config.drain(&tracker))
+            .await
+            .unwrap();
+        assert_eq!(tracker.count(), 0);
+
+        // the stuck connection is closed by force after the drain timeout
+        tokio::spawn(tracker.serve(futures::future::pending()));
+        let config = config
+            .drain_timeout(Duration::from_millis(20))
+            .force_close(true);
+        tokio::time::timeout(Duration::from_millis(500), config.drain(&tracker))
+            .await
+            .unwrap();
+        assert_eq!(tracker.count(), 0);
+    }
+}